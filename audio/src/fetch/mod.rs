@@ -8,7 +8,7 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ByteOrder};
-use futures_util::{future, StreamExt, TryFutureExt, TryStreamExt};
+use futures_util::{future, StreamExt, TryStreamExt};
 use librespot_core::channel::{ChannelData, ChannelError, ChannelHeaders};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::FileId;
@@ -91,6 +91,7 @@ pub enum AudioFile {
 #[derive(Debug)]
 enum StreamLoaderCommand {
     Fetch(Range),       // signal the stream loader to fetch a range of the file
+    FetchUrgent(Range), // like Fetch, for data a reader is waiting for, so it isn't throttled
     RandomAccessMode(), // optimise download strategy for random access
     StreamMode(),       // optimise download strategy for streaming
     Close(),            // terminate and don't load any more data
@@ -131,6 +132,17 @@ impl StreamLoaderController {
         })
     }
 
+    /// The number of bytes downloaded contiguously from the current read position.
+    pub fn downloaded_ahead(&self) -> usize {
+        self.stream_shared.as_ref().map_or(self.len(), |shared| {
+            let read_position = shared.read_position.load(atomic::Ordering::Relaxed);
+            let download_status = shared.download_status.lock().unwrap();
+            download_status
+                .downloaded
+                .contained_length_from_value(read_position)
+        })
+    }
+
     pub fn ping_time(&self) -> Duration {
         Duration::from_millis(self.stream_shared.as_ref().map_or(0, |shared| {
             shared.ping_time_ms.load(atomic::Ordering::Relaxed) as u64
@@ -159,7 +171,7 @@ impl StreamLoaderController {
             range.length = self.len() - range.start;
         }
 
-        self.fetch(range);
+        self.send_stream_loader_command(StreamLoaderCommand::FetchUrgent(range));
 
         if let Some(ref shared) = self.stream_shared {
             let mut download_status = shared.download_status.lock().unwrap();
@@ -181,7 +193,7 @@ impl StreamLoaderController {
                 {
                     // For some reason, the requested range is neither downloaded nor requested.
                     // This could be due to a network error. Request it again.
-                    self.fetch(range);
+                    self.send_stream_loader_command(StreamLoaderCommand::FetchUrgent(range));
                 }
             }
         }
@@ -207,6 +219,18 @@ impl StreamLoaderController {
         }
     }
 
+    /// Requests everything from the current read position to the end of the file.
+    pub fn fetch_remainder(&self) {
+        self.fetch_next(self.len());
+    }
+
+    /// Keeps at least `length` bytes downloaded ahead of the read position while streaming.
+    pub fn set_read_ahead(&self, length: usize) {
+        if let Some(ref shared) = self.stream_shared {
+            shared.read_ahead.store(length, atomic::Ordering::Relaxed);
+        }
+    }
+
     pub fn set_random_access_mode(&self) {
         // optimise download strategy for random access
         self.send_stream_loader_command(StreamLoaderCommand::RandomAccessMode());
@@ -233,6 +257,8 @@ pub struct AudioFileStreaming {
 struct AudioFileDownloadStatus {
     requested: RangeSet,
     downloaded: RangeSet,
+    // the part of `requested` still waiting for the download throttle
+    queued: RangeSet,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -251,6 +277,8 @@ struct AudioFileShared {
     number_of_open_requests: AtomicUsize,
     ping_time_ms: AtomicUsize,
     read_position: AtomicUsize,
+    // requested ahead of the read position while streaming, on top of `READ_AHEAD_DURING_PLAYBACK`
+    read_ahead: AtomicUsize,
 }
 
 impl AudioFile {
@@ -267,6 +295,75 @@ impl AudioFile {
 
         debug!("Downloading file {}", file_id);
 
+        let (streaming, complete_rx) =
+            Self::open_streaming(session, file_id, bytes_per_second, play_from_beginning).await?;
+
+        let session_ = session.clone();
+        session.spawn(async move {
+            let mut file = match complete_rx.await {
+                Ok(file) => file,
+                Err(_) => return,
+            };
+            if let Some(cache) = session_.cache().cloned() {
+                debug!("File {} complete, saving to cache", file_id);
+                // copying the file into the cache blocks while holding its lock
+                let _ = session_
+                    .spawn_blocking(move || cache.save_file(file_id, &mut file))
+                    .await;
+            } else {
+                debug!("File {} complete", file_id);
+            }
+        });
+
+        Ok(AudioFile::Streaming(streaming))
+    }
+
+    /// Downloads the whole file into the cache, unless it is cached already.
+    pub async fn prefetch(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+    ) -> Result<(), ChannelError> {
+        let cache = match session.cache() {
+            Some(cache) => cache,
+            None => {
+                warn!("Can't prefetch file {} without a cache", file_id);
+                return Err(ChannelError);
+            }
+        };
+
+        if cache.file(file_id).is_some() {
+            debug!("File {} already in cache", file_id);
+            return Ok(());
+        }
+
+        debug!("Prefetching file {}", file_id);
+
+        let (streaming, complete_rx) =
+            Self::open_streaming(session, file_id, bytes_per_second, true).await?;
+
+        let file = AudioFile::Streaming(streaming);
+        let stream_loader_controller = file.get_stream_loader_controller();
+        stream_loader_controller.fetch(Range::new(0, stream_loader_controller.len()));
+
+        let mut data = complete_rx.await.map_err(|_| ChannelError)?;
+        let cache = cache.clone();
+        session
+            .spawn_blocking(move || cache.save_file(file_id, &mut data))
+            .await
+            .map_err(|_| ChannelError)?;
+
+        debug!("File {} prefetched", file_id);
+
+        Ok(())
+    }
+
+    async fn open_streaming(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+        play_from_beginning: bool,
+    ) -> Result<(AudioFileStreaming, oneshot::Receiver<NamedTempFile>), ChannelError> {
         let (complete_tx, complete_rx) = oneshot::channel();
         let mut initial_data_length = if play_from_beginning {
             INITIAL_DOWNLOAD_SIZE
@@ -293,19 +390,10 @@ impl AudioFile {
             file_id,
             complete_tx,
             bytes_per_second,
-        );
-
-        let session_ = session.clone();
-        session.spawn(complete_rx.map_ok(move |mut file| {
-            if let Some(cache) = session_.cache() {
-                debug!("File {} complete, saving to cache", file_id);
-                cache.save_file(file_id, &mut file);
-            } else {
-                debug!("File {} complete", file_id);
-            }
-        }));
+        )
+        .await?;
 
-        Ok(AudioFile::Streaming(streaming.await?))
+        Ok((streaming, complete_rx))
     }
 
     pub fn get_stream_loader_controller(&self) -> StreamLoaderController {
@@ -355,14 +443,24 @@ impl AudioFileStreaming {
             download_status: Mutex::new(AudioFileDownloadStatus {
                 requested: RangeSet::new(),
                 downloaded: RangeSet::new(),
+                queued: RangeSet::new(),
             }),
             download_strategy: Mutex::new(DownloadStrategy::RandomAccess()), // start with random access mode until someone tells us otherwise
             number_of_open_requests: AtomicUsize::new(0),
             ping_time_ms: AtomicUsize::new(0),
             read_position: AtomicUsize::new(0),
+            read_ahead: AtomicUsize::new(0),
         });
 
-        let mut write_file = NamedTempFile::new().unwrap();
+        let temp_location = session.cache().and_then(|cache| cache.temp_location());
+        let mut write_file = match temp_location.map(NamedTempFile::new_in) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => {
+                warn!("Cannot create download file in {:?}: {}", temp_location, e);
+                NamedTempFile::new().unwrap()
+            }
+            None => NamedTempFile::new().unwrap(),
+        };
         write_file.as_file().set_len(size as u64).unwrap();
         write_file.seek(SeekFrom::Start(0)).unwrap();
 
@@ -413,11 +511,16 @@ impl Read for AudioFileStreaming {
 
                 let length_to_request = length
                     + max(
-                        (READ_AHEAD_DURING_PLAYBACK.as_secs_f32()
-                            * self.shared.stream_data_rate as f32) as usize,
-                        (READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS
-                            * ping_time_seconds
-                            * self.shared.stream_data_rate as f32) as usize,
+                        max(
+                            (READ_AHEAD_DURING_PLAYBACK.as_secs_f32()
+                                * self.shared.stream_data_rate as f32)
+                                as usize,
+                            (READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS
+                                * ping_time_seconds
+                                * self.shared.stream_data_rate as f32)
+                                as usize,
+                        ),
+                        self.shared.read_ahead.load(atomic::Ordering::Relaxed),
                     );
                 min(length_to_request, self.shared.file_size - offset)
             }
@@ -428,11 +531,12 @@ impl Read for AudioFileStreaming {
 
         let mut download_status = self.shared.download_status.lock().unwrap();
         ranges_to_request.subtract_range_set(&download_status.downloaded);
-        ranges_to_request.subtract_range_set(&download_status.requested);
+        ranges_to_request
+            .subtract_range_set(&download_status.requested.minus(&download_status.queued));
 
         for &range in ranges_to_request.iter() {
             self.stream_loader_command_tx
-                .send(StreamLoaderCommand::Fetch(range))
+                .send(StreamLoaderCommand::FetchUrgent(range))
                 .unwrap();
         }
 
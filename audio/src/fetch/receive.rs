@@ -52,6 +52,61 @@ pub fn request_range(session: &Session, file: FileId, offset: usize, length: usi
     channel
 }
 
+// Requests the queued `ranges` a chunk at a time, each once the session's download throttle
+// allows, and only what is still queued by then: an urgent fetch may have taken over the rest.
+async fn throttled_requests(
+    session: Session,
+    shared: Arc<AudioFileShared>,
+    file_data_tx: mpsc::UnboundedSender<ReceivedData>,
+    ranges: RangeSet,
+) {
+    let throttle = match session.download_throttle() {
+        Some(throttle) => throttle,
+        None => return,
+    };
+
+    // about a second's worth, so that it's received at the throttled rate instead of in bursts
+    let chunk_size = max(MINIMUM_DOWNLOAD_SIZE, throttle.bytes_per_second() / 4 * 4);
+
+    for range in ranges.iter() {
+        let mut start = range.start;
+        while start < range.end() {
+            let chunk = Range::new(start, min(chunk_size, range.end() - start));
+            start = chunk.end();
+
+            throttle.wait().await;
+            if file_data_tx.is_closed() {
+                return;
+            }
+
+            let chunk = {
+                let mut download_status = shared.download_status.lock().unwrap();
+                let mut chunk_set = RangeSet::new();
+                chunk_set.add_range(&chunk);
+                let chunk_set = chunk_set.intersection(&download_status.queued);
+                download_status.queued.subtract_range_set(&chunk_set);
+                chunk_set
+            };
+
+            for part in chunk.iter() {
+                let (_headers, data) =
+                    request_range(&session, shared.file_id, part.start, part.length).split();
+
+                receive_data(
+                    session.clone(),
+                    shared.clone(),
+                    file_data_tx.clone(),
+                    data,
+                    part.start,
+                    part.length,
+                    Instant::now(),
+                )
+                .await;
+            }
+        }
+    }
+}
+
 struct PartialFileData {
     offset: usize,
     data: Bytes,
@@ -63,6 +118,7 @@ enum ReceivedData {
 }
 
 async fn receive_data(
+    session: Session,
     shared: Arc<AudioFileShared>,
     file_data_tx: mpsc::UnboundedSender<ReceivedData>,
     mut data_rx: ChannelData,
@@ -95,6 +151,9 @@ async fn receive_data(
             measure_ping_time = false;
         }
         let data_size = data.len();
+        if let Some(throttle) = session.download_throttle() {
+            throttle.received(data_size);
+        }
         let _ = file_data_tx.send(ReceivedData::Data(PartialFileData {
             offset: data_offset,
             data,
@@ -162,7 +221,7 @@ impl AudioFileFetch {
         *(self.shared.download_strategy.lock().unwrap())
     }
 
-    fn download_range(&mut self, mut offset: usize, mut length: usize) {
+    fn download_range(&mut self, mut offset: usize, mut length: usize, urgent: bool) {
         if length < MINIMUM_DOWNLOAD_SIZE {
             length = MINIMUM_DOWNLOAD_SIZE;
         }
@@ -195,7 +254,29 @@ impl AudioFileFetch {
         let mut download_status = self.shared.download_status.lock().unwrap();
 
         ranges_to_request.subtract_range_set(&download_status.downloaded);
-        ranges_to_request.subtract_range_set(&download_status.requested);
+        if urgent {
+            // what is still waiting for the throttle is requested right away instead
+            ranges_to_request
+                .subtract_range_set(&download_status.requested.minus(&download_status.queued));
+            download_status
+                .queued
+                .subtract_range_set(&ranges_to_request);
+        } else {
+            ranges_to_request.subtract_range_set(&download_status.requested);
+        }
+
+        if !urgent && self.session.download_throttle().is_some() {
+            download_status.requested.add_range_set(&ranges_to_request);
+            download_status.queued.add_range_set(&ranges_to_request);
+
+            self.session.spawn(throttled_requests(
+                self.session.clone(),
+                self.shared.clone(),
+                self.file_data_tx.clone(),
+                ranges_to_request,
+            ));
+            return;
+        }
 
         for range in ranges_to_request.iter() {
             let (_headers, data) = request_range(
@@ -209,6 +290,7 @@ impl AudioFileFetch {
             download_status.requested.add_range(range);
 
             self.session.spawn(receive_data(
+                self.session.clone(),
                 self.shared.clone(),
                 self.file_data_tx.clone(),
                 data,
@@ -246,7 +328,7 @@ impl AudioFileFetch {
                 let range = tail_end.get_range(0);
                 let offset = range.start;
                 let length = min(range.length, bytes_to_go);
-                self.download_range(offset, length);
+                self.download_range(offset, length, false);
                 requests_to_go -= 1;
                 bytes_to_go -= length;
             } else if !missing_data.is_empty() {
@@ -254,7 +336,7 @@ impl AudioFileFetch {
                 let range = missing_data.get_range(0);
                 let offset = range.start;
                 let length = min(range.length, bytes_to_go);
-                self.download_range(offset, length);
+                self.download_range(offset, length, false);
                 requests_to_go -= 1;
                 bytes_to_go -= length;
             } else {
@@ -329,7 +411,10 @@ impl AudioFileFetch {
     fn handle_stream_loader_command(&mut self, cmd: StreamLoaderCommand) -> ControlFlow {
         match cmd {
             StreamLoaderCommand::Fetch(request) => {
-                self.download_range(request.start, request.length);
+                self.download_range(request.start, request.length, false);
+            }
+            StreamLoaderCommand::FetchUrgent(request) => {
+                self.download_range(request.start, request.length, true);
             }
             StreamLoaderCommand::RandomAccessMode() => {
                 *(self.shared.download_strategy.lock().unwrap()) = DownloadStrategy::RandomAccess();
@@ -351,6 +436,8 @@ impl AudioFileFetch {
     }
 }
 
+// tokio::select! expands to poll_fn, which is newer than the MSRV
+#[allow(clippy::incompatible_msrv)]
 pub(super) async fn audio_file_fetch(
     session: Session,
     shared: Arc<AudioFileShared>,
@@ -371,6 +458,7 @@ pub(super) async fn audio_file_fetch(
     }
 
     session.spawn(receive_data(
+        session.clone(),
         shared.clone(),
         file_data_tx.clone(),
         initial_data_rx,
@@ -1,4 +1,5 @@
 #![allow(clippy::unused_io_amount, clippy::too_many_arguments)]
+#![deny(clippy::print_stdout)]
 
 #[macro_use]
 extern crate log;
@@ -1,3 +1,5 @@
+#![deny(clippy::print_stdout)]
+
 #[macro_use]
 extern crate log;
 
@@ -1,9 +1,11 @@
+use std::convert::TryFrom;
 use std::future::Future;
 use std::pin::Pin;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::context::StationContext;
-use crate::core::config::ConnectConfig;
+use crate::core::cache::{DevicePreferences, LastSession};
+use crate::core::config::{ConnectConfig, ControlPolicy, ControlSource};
 use crate::core::mercury::{MercuryError, MercurySender};
 use crate::core::session::Session;
 use crate::core::spotify_id::{SpotifyAudioType, SpotifyId, SpotifyIdError};
@@ -19,7 +21,7 @@ use futures_util::stream::FusedStream;
 use futures_util::{FutureExt, StreamExt};
 use protobuf::{self, Message};
 use rand::seq::SliceRandom;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 enum SpircPlayStatus {
@@ -55,6 +57,10 @@ struct SpircTask {
     state: State,
     play_request_id: Option<u64>,
     play_status: SpircPlayStatus,
+    // how fast the position advances, as last reported by the player
+    speed: f64,
+    // unplayable tracks skipped since the last one which could be played
+    consecutive_skips: u32,
 
     subscription: BoxedStream<Frame>,
     sender: MercurySender,
@@ -66,6 +72,17 @@ struct SpircTask {
     context_fut: BoxedFuture<Result<serde_json::Value, MercuryError>>,
     autoplay_fut: BoxedFuture<Result<String, MercuryError>>,
     context: Option<StationContext>,
+
+    // source, command and time of the last accepted playback command
+    last_control: Option<(ControlSource, &'static str, i64)>,
+
+    // the last state announced by another device while it was active
+    active_device_frame: Option<Frame>,
+    take_over_pending: bool,
+    // resumes the last session once the other devices had time to answer
+    resume_fut: BoxedFuture<()>,
+    // saves the volume once it stopped changing
+    save_preferences_fut: BoxedFuture<()>,
 }
 
 pub enum SpircCommand {
@@ -76,20 +93,123 @@ pub enum SpircCommand {
     Next,
     VolumeUp,
     VolumeDown,
+    Seek(u32),
     Shutdown,
     Shuffle,
+    SetShuffle(bool),
+    SetRepeat(bool),
+    GetQueue(oneshot::Sender<Option<PlayQueue>>),
+    AddToQueue(SpotifyId),
+    RemoveFromQueue(usize, oneshot::Sender<Result<(), QueueError>>),
+    MoveInQueue(usize, usize, oneshot::Sender<Result<(), QueueError>>),
+    TakeOver,
+    Resume,
+    SetMaxVolume(u16),
+    LoadContext {
+        context_uri: String,
+        tracks: Vec<SpotifyId>,
+        index: u32,
+        shuffle: Option<bool>,
+        fade_in: Option<Duration>,
+    },
+}
+
+impl SpircCommand {
+    fn control_name(&self) -> Option<&'static str> {
+        match self {
+            SpircCommand::Play => Some("play"),
+            SpircCommand::PlayPause => Some("playpause"),
+            SpircCommand::Pause => Some("pause"),
+            SpircCommand::Prev => Some("prev"),
+            SpircCommand::Next => Some("next"),
+            SpircCommand::VolumeUp => Some("volumeup"),
+            SpircCommand::VolumeDown => Some("volumedown"),
+            SpircCommand::Seek(_) => Some("seek"),
+            SpircCommand::SetShuffle(_) => Some("shuffle"),
+            SpircCommand::SetRepeat(_) => Some("repeat"),
+            SpircCommand::Shutdown
+            | SpircCommand::Shuffle
+            | SpircCommand::GetQueue(_)
+            | SpircCommand::AddToQueue(_)
+            | SpircCommand::RemoveFromQueue(..)
+            | SpircCommand::MoveInQueue(..)
+            | SpircCommand::TakeOver
+            | SpircCommand::Resume
+            | SpircCommand::SetMaxVolume(_)
+            | SpircCommand::LoadContext { .. } => None,
+        }
+    }
+}
+
+/// The play queue of the active device.
+#[derive(Clone, Debug)]
+pub struct PlayQueue {
+    pub context_uri: String,
+    pub current_track: Option<QueueTrack>,
+    /// The tracks which will be played after the current one, in order.
+    pub next_tracks: Vec<QueueTrack>,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueTrack {
+    pub uri: String,
+    /// Whether the track was added to the queue, rather than being part of the context.
+    pub queued: bool,
+}
+
+/// Why a track couldn't be removed from or moved in the play queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// This isn't the active device.
+    NotActive,
+    /// There's no track at this position of `PlayQueue::next_tracks`.
+    NoTrack(usize),
+}
+
+fn frame_control_name(typ: MessageType) -> Option<&'static str> {
+    match typ {
+        MessageType::kMessageTypePlay => Some("play"),
+        MessageType::kMessageTypePlayPause => Some("playpause"),
+        MessageType::kMessageTypePause => Some("pause"),
+        MessageType::kMessageTypePrev => Some("prev"),
+        MessageType::kMessageTypeNext => Some("next"),
+        MessageType::kMessageTypeVolumeUp => Some("volumeup"),
+        MessageType::kMessageTypeVolumeDown => Some("volumedown"),
+        MessageType::kMessageTypeVolume => Some("volume"),
+        MessageType::kMessageTypeSeek => Some("seek"),
+        MessageType::kMessageTypeRepeat => Some("repeat"),
+        MessageType::kMessageTypeShuffle => Some("shuffle"),
+        _ => None,
+    }
 }
 
 struct SpircTaskConfig {
     autoplay: bool,
+    control_policy: ControlPolicy,
+    max_consecutive_skips: u32,
+    volume_step_size: u16,
+    max_volume: u16,
+    quantize_volume: bool,
 }
 
 const CONTEXT_TRACKS_HISTORY: usize = 10;
 const CONTEXT_FETCH_THRESHOLD: u32 = 5;
 
+// How long the other devices have to tell whether one is playing before the last session is
+// resumed.
+const RESUME_WAIT: Duration = Duration::from_secs(3);
+
+// Commands from different sources within this window are considered to be in conflict.
+const CONTROL_CONFLICT_WINDOW_MS: i64 = 3000;
+
+// Volume changes come in bursts while a slider is dragged, and so may shuffle and repeat, so the
+// device preferences are only saved once they didn't change for this long.
+const PREFERENCES_SAVE_DELAY: Duration = Duration::from_secs(1);
+
 const VOLUME_STEPS: i64 = 64;
 const VOLUME_STEP_SIZE: u16 = 1024; // (u16::MAX + 1) / VOLUME_STEPS
 
+#[derive(Clone)]
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
 }
@@ -104,7 +224,16 @@ fn initial_state() -> State {
     frame
 }
 
+fn volume_steps(config: &ConnectConfig) -> i64 {
+    match config.volume_steps {
+        Some(steps) => steps as i64,
+        None if config.has_volume_ctrl => VOLUME_STEPS,
+        None => 0,
+    }
+}
+
 fn initial_device_state(config: ConnectConfig) -> DeviceState {
+    let volume_steps = volume_steps(&config);
     {
         let mut msg = DeviceState::new();
         msg.set_sw_version(version::VERSION_STRING.to_string());
@@ -164,11 +293,7 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg.set_typ(protocol::spirc::CapabilityType::kVolumeSteps);
                 {
                     let repeated = msg.mut_intValue();
-                    if config.has_volume_ctrl {
-                        repeated.push(VOLUME_STEPS)
-                    } else {
-                        repeated.push(0)
-                    }
+                    repeated.push(volume_steps)
                 };
                 msg
             };
@@ -204,7 +329,9 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                     let repeated = msg.mut_stringValue();
                     repeated.push(::std::convert::Into::into("audio/local"));
                     repeated.push(::std::convert::Into::into("audio/track"));
-                    repeated.push(::std::convert::Into::into("audio/episode"));
+                    if config.can_play_episodes {
+                        repeated.push(::std::convert::Into::into("audio/episode"));
+                    }
                     repeated.push(::std::convert::Into::into("local"));
                     repeated.push(::std::convert::Into::into("track"))
                 };
@@ -219,6 +346,14 @@ fn url_encode(bytes: impl AsRef<[u8]>) -> String {
     form_urlencoded::byte_serialize(bytes.as_ref()).collect()
 }
 
+// The index in the tracks of the state of `index` of `PlayQueue::next_tracks`, if there is one.
+fn next_track_index(playing_index: u32, index: usize, tracks_len: usize) -> Option<usize> {
+    (playing_index as usize)
+        .checked_add(1)
+        .and_then(|next_index| next_index.checked_add(index))
+        .filter(|track_index| *track_index < tracks_len)
+}
+
 impl Spirc {
     pub fn new(
         config: ConnectConfig,
@@ -254,6 +389,14 @@ impl Spirc {
         let initial_volume = config.initial_volume;
         let task_config = SpircTaskConfig {
             autoplay: config.autoplay,
+            control_policy: config.control_policy,
+            max_consecutive_skips: config.max_consecutive_skips,
+            volume_step_size: match volume_steps(&config) {
+                steps if steps > 0 => ((u16::MAX as i64 + 1) / steps).min(u16::MAX as i64) as u16,
+                _ => VOLUME_STEP_SIZE,
+            },
+            max_volume: config.max_volume,
+            quantize_volume: config.quantize_volume,
         };
 
         let device = initial_device_state(config);
@@ -273,6 +416,8 @@ impl Spirc {
             state: initial_state(),
             play_request_id: None,
             play_status: SpircPlayStatus::Stopped,
+            speed: 1.0,
+            consecutive_skips: 0,
 
             subscription,
             sender,
@@ -285,8 +430,25 @@ impl Spirc {
             context_fut: Box::pin(future::pending()),
             autoplay_fut: Box::pin(future::pending()),
             context: None,
+
+            last_control: None,
+
+            active_device_frame: None,
+            take_over_pending: false,
+            resume_fut: Box::pin(future::pending()),
+            save_preferences_fut: Box::pin(future::pending()),
         };
 
+        let preferences = task
+            .session
+            .cache()
+            .and_then(|cache| cache.device_preferences(task.device.get_name()));
+        if let Some(preferences) = preferences {
+            task.state.set_shuffle(preferences.shuffle);
+            task.state.set_repeat(preferences.repeat);
+        }
+        task.emit_playback_mode();
+
         if let Some(volume) = initial_volume {
             task.set_volume(volume);
         } else {
@@ -328,9 +490,77 @@ impl Spirc {
     pub fn shuffle(&self) {
         let _ = self.commands.send(SpircCommand::Shuffle);
     }
+    /// Seeks to `position_ms` of the current track.
+    pub fn seek(&self, position_ms: u32) {
+        let _ = self.commands.send(SpircCommand::Seek(position_ms));
+    }
+    pub fn set_shuffle(&self, shuffle: bool) {
+        let _ = self.commands.send(SpircCommand::SetShuffle(shuffle));
+    }
+    pub fn set_repeat(&self, repeat: bool) {
+        let _ = self.commands.send(SpircCommand::SetRepeat(repeat));
+    }
+    /// The play queue, if this is the active device. Tracks can only be added, removed or moved
+    /// while this device is active, too.
+    pub fn queue(&self) -> oneshot::Receiver<Option<PlayQueue>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.commands.send(SpircCommand::GetQueue(tx));
+        rx
+    }
+    pub fn add_to_queue(&self, track_id: SpotifyId) {
+        let _ = self.commands.send(SpircCommand::AddToQueue(track_id));
+    }
+    /// Removes the track at `index` of `PlayQueue::next_tracks`.
+    pub fn remove_from_queue(&self, index: usize) -> oneshot::Receiver<Result<(), QueueError>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.commands.send(SpircCommand::RemoveFromQueue(index, tx));
+        rx
+    }
+    /// Moves the track at `from` of `PlayQueue::next_tracks` to `to`.
+    pub fn move_in_queue(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> oneshot::Receiver<Result<(), QueueError>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.commands.send(SpircCommand::MoveInQueue(from, to, tx));
+        rx
+    }
+    /// Continues the playback of the active device on this one.
+    pub fn take_over(&self) {
+        let _ = self.commands.send(SpircCommand::TakeOver);
+    }
+    /// Changes `ConnectConfig::max_volume`, lowering the volume if it's above the new cap.
+    pub fn set_max_volume(&self, max_volume: u16) {
+        let _ = self.commands.send(SpircCommand::SetMaxVolume(max_volume));
+    }
+    /// Continues what this device played last, unless another device turns out to be playing.
+    pub fn resume(&self) {
+        let _ = self.commands.send(SpircCommand::Resume);
+    }
+    /// Plays `tracks` of `context_uri` from `index` on this device, even if another device is
+    /// playing. Shuffles the tracks after the first if `shuffle`, or keeps the shuffle setting.
+    pub fn load_context(
+        &self,
+        context_uri: String,
+        tracks: Vec<SpotifyId>,
+        index: u32,
+        shuffle: Option<bool>,
+        fade_in: Option<Duration>,
+    ) {
+        let _ = self.commands.send(SpircCommand::LoadContext {
+            context_uri,
+            tracks,
+            index,
+            shuffle,
+            fade_in,
+        });
+    }
 }
 
 impl SpircTask {
+    // tokio::select! expands to poll_fn, which is newer than the MSRV
+    #[allow(clippy::incompatible_msrv)]
     async fn run(mut self) {
         while !self.session.is_invalid() && !self.shutdown {
             let commands = self.commands.as_mut();
@@ -382,6 +612,12 @@ impl SpircTask {
                         }
                     }
                 },
+                _ = &mut self.resume_fut, if !self.resume_fut.is_terminated() => {
+                    self.resume_unless_playing_elsewhere();
+                },
+                _ = &mut self.save_preferences_fut, if !self.save_preferences_fut.is_terminated() => {
+                    self.save_preferences();
+                },
                 autoplay = &mut self.autoplay_fut, if !self.autoplay_fut.is_terminated() => {
                     match autoplay {
                         Ok(autoplay_station_uri) => {
@@ -397,6 +633,10 @@ impl SpircTask {
             }
         }
 
+        if !self.save_preferences_fut.is_terminated() {
+            self.save_preferences();
+        }
+
         if self.sender.flush().await.is_err() {
             warn!("Cannot flush spirc event sender.");
         }
@@ -417,8 +657,53 @@ impl SpircTask {
         self.state.set_position_ms(position_ms);
     }
 
+    // Returns false if the command must be ignored because of a recent
+    // conflicting command from the other source.
+    fn accept_control(&mut self, source: ControlSource, command: &'static str) -> bool {
+        let now = self.now_ms();
+
+        if let Some((last_source, last_command, last_at)) = self.last_control {
+            if last_source != source && now - last_at < CONTROL_CONFLICT_WINDOW_MS {
+                let ignore = match self.config.control_policy {
+                    ControlPolicy::LastWriterWins => false,
+                    ControlPolicy::LocalPriority => source == ControlSource::Connect,
+                    ControlPolicy::ConnectPriority => source == ControlSource::Local,
+                };
+
+                if ignore {
+                    info!(
+                        "Ignoring {} command \"{}\" in favour of recent {} command \"{}\"",
+                        source, command, last_source, last_command
+                    );
+                    self.player
+                        .emit_command_overridden_event(source, command, last_source);
+                    return false;
+                }
+
+                debug!(
+                    "{} command \"{}\" overrides recent {} command \"{}\"",
+                    source, command, last_source, last_command
+                );
+                self.player
+                    .emit_command_overridden_event(last_source, last_command, source);
+            }
+        }
+
+        self.last_control = Some((source, command, now));
+        true
+    }
+
     fn handle_command(&mut self, cmd: SpircCommand) {
         let active = self.device.get_is_active();
+
+        if active {
+            if let Some(command) = cmd.control_name() {
+                if !self.accept_control(ControlSource::Local, command) {
+                    return;
+                }
+            }
+        }
+
         match cmd {
             SpircCommand::Play => {
                 if active {
@@ -477,6 +762,9 @@ impl SpircTask {
                 }
             }
             SpircCommand::Shutdown => {
+                if active {
+                    self.save_last_session();
+                }
                 CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
                 self.player.stop();
                 self.shutdown = true;
@@ -487,6 +775,148 @@ impl SpircTask {
             SpircCommand::Shuffle => {
                 CommandSender::new(self, MessageType::kMessageTypeShuffle).send();
             }
+            SpircCommand::Seek(position_ms) => {
+                if active {
+                    self.handle_seek(position_ms);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeSeek)
+                        .position(position_ms)
+                        .send();
+                }
+            }
+            SpircCommand::SetShuffle(shuffle) => {
+                if active {
+                    self.handle_shuffle(shuffle);
+                    self.notify(None, true);
+                } else {
+                    let mut state = State::new();
+                    state.set_shuffle(shuffle);
+                    CommandSender::new(self, MessageType::kMessageTypeShuffle)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::SetRepeat(repeat) => {
+                if active {
+                    self.handle_repeat(repeat);
+                    self.notify(None, true);
+                } else {
+                    let mut state = State::new();
+                    state.set_repeat(repeat);
+                    CommandSender::new(self, MessageType::kMessageTypeRepeat)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::GetQueue(tx) => {
+                let queue = if active { Some(self.queue()) } else { None };
+                let _ = tx.send(queue);
+            }
+            SpircCommand::AddToQueue(track_id) => {
+                if active && !self.state.get_track().is_empty() {
+                    self.handle_add_to_queue(track_id);
+                    self.notify(None, true);
+                } else {
+                    warn!("Can't add to the queue while not playing");
+                }
+            }
+            SpircCommand::RemoveFromQueue(index, tx) => {
+                let playing_index = self.state.get_playing_track_index();
+                let tracks_len = self.state.get_track().len();
+                let result = match next_track_index(playing_index, index, tracks_len) {
+                    _ if !active => Err(QueueError::NotActive),
+                    Some(track_index) => {
+                        self.state.mut_track().remove(track_index);
+                        self.preload_next_track_again();
+                        self.notify(None, true);
+                        Ok(())
+                    }
+                    None => Err(QueueError::NoTrack(index)),
+                };
+                let _ = tx.send(result);
+            }
+            SpircCommand::MoveInQueue(from, to, tx) => {
+                let playing_index = self.state.get_playing_track_index();
+                let tracks_len = self.state.get_track().len();
+                let result = match (
+                    next_track_index(playing_index, from, tracks_len),
+                    next_track_index(playing_index, to, tracks_len),
+                ) {
+                    _ if !active => Err(QueueError::NotActive),
+                    (Some(from_index), Some(to_index)) => {
+                        let track = self.state.mut_track().remove(from_index);
+                        self.state.mut_track().insert(to_index, track);
+                        self.preload_next_track_again();
+                        self.notify(None, true);
+                        Ok(())
+                    }
+                    (None, _) => Err(QueueError::NoTrack(from)),
+                    (_, None) => Err(QueueError::NoTrack(to)),
+                };
+                let _ = tx.send(result);
+            }
+            SpircCommand::TakeOver => {
+                if active {
+                    debug!("Already the active device");
+                } else if let Some(frame) = self.active_device_frame.take() {
+                    self.take_over(&frame);
+                } else {
+                    // wait for the active device to answer
+                    self.take_over_pending = true;
+                    self.hello();
+                }
+            }
+            SpircCommand::SetMaxVolume(max_volume) => {
+                self.config.max_volume = max_volume;
+                if self.device.get_volume() > u32::from(max_volume) {
+                    self.set_volume(max_volume);
+                    if active {
+                        self.notify(None, true);
+                    }
+                }
+            }
+            SpircCommand::Resume => {
+                if active {
+                    debug!("Already the active device");
+                } else {
+                    // wait for the other devices to answer the hello
+                    self.resume_fut = Box::pin(tokio::time::sleep(RESUME_WAIT).fuse());
+                }
+            }
+            SpircCommand::LoadContext {
+                context_uri,
+                tracks,
+                index,
+                shuffle,
+                fade_in,
+            } => {
+                if tracks.is_empty() {
+                    warn!("No tracks to play for <{}>", context_uri);
+                    return;
+                }
+
+                info!("Playing {} tracks of <{}>", tracks.len(), context_uri);
+                if let Some(fade_in) = fade_in {
+                    self.player.fade_in(fade_in);
+                }
+
+                let track_refs = tracks
+                    .iter()
+                    .map(|track_id| {
+                        let mut track_ref = TrackRef::new();
+                        track_ref.set_gid(track_id.to_raw().to_vec());
+                        track_ref.set_uri(track_id.to_uri().unwrap_or_default());
+                        track_ref
+                    })
+                    .collect();
+                self.load_tracks(context_uri, track_refs, index, 0);
+
+                if let Some(shuffle) = shuffle {
+                    self.handle_shuffle(shuffle);
+                    self.notify(None, true);
+                }
+            }
         }
     }
 
@@ -500,8 +930,11 @@ impl SpircTask {
                 match event {
                     PlayerEvent::EndOfTrack { .. } => self.handle_end_of_track(),
                     PlayerEvent::Loading { .. } => self.notify(None, false),
-                    PlayerEvent::Playing { position_ms, .. } => {
-                        let new_nominal_start_time = self.now_ms() - position_ms as i64;
+                    PlayerEvent::Playing {
+                        position_ms, speed, ..
+                    } => {
+                        self.speed = speed;
+                        let new_nominal_start_time = self.nominal_start_time(position_ms);
                         match self.play_status {
                             SpircPlayStatus::Playing {
                                 ref mut nominal_start_time,
@@ -515,6 +948,7 @@ impl SpircTask {
                             }
                             SpircPlayStatus::LoadingPlay { .. }
                             | SpircPlayStatus::LoadingPause { .. } => {
+                                self.consecutive_skips = 0;
                                 self.state.set_status(PlayStatus::kPlayStatusPlay);
                                 self.update_state_position(position_ms);
                                 self.notify(None, true);
@@ -544,6 +978,7 @@ impl SpircTask {
                             }
                             SpircPlayStatus::LoadingPlay { .. }
                             | SpircPlayStatus::LoadingPause { .. } => {
+                                self.consecutive_skips = 0;
                                 self.state.set_status(PlayStatus::kPlayStatusPause);
                                 self.update_state_position(new_position_ms);
                                 self.notify(None, true);
@@ -566,7 +1001,15 @@ impl SpircTask {
                         }
                     },
                     PlayerEvent::TimeToPreloadNextTrack { .. } => self.handle_preload_next_track(),
-                    PlayerEvent::Unavailable { track_id, .. } => self.handle_unavailable(track_id),
+                    PlayerEvent::Unavailable { track_id, .. } => {
+                        // a track which failed to load, rather than to preload
+                        if let SpircPlayStatus::LoadingPlay { .. }
+                        | SpircPlayStatus::LoadingPause { .. } = self.play_status
+                        {
+                            self.consecutive_skips += 1;
+                        }
+                        self.handle_unavailable(track_id)
+                    }
                     _ => (),
                 }
             }
@@ -597,12 +1040,21 @@ impl SpircTask {
             return;
         }
 
+        if let Some(command) = frame_control_name(frame.get_typ()) {
+            if !self.accept_control(ControlSource::Connect, command) {
+                // let the client know its command didn't change anything
+                self.notify(None, true);
+                return;
+            }
+        }
+
         match frame.get_typ() {
             MessageType::kMessageTypeHello => {
                 self.notify(Some(frame.get_ident()), true);
             }
 
             MessageType::kMessageTypeLoad => {
+                self.take_over_pending = false;
                 if !self.device.get_is_active() {
                     let now = self.now_ms();
                     self.device.set_is_active(true);
@@ -661,27 +1113,12 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeRepeat => {
-                self.state.set_repeat(frame.get_state().get_repeat());
+                self.handle_repeat(frame.get_state().get_repeat());
                 self.notify(None, true);
             }
 
             MessageType::kMessageTypeShuffle => {
-                self.state.set_shuffle(frame.get_state().get_shuffle());
-                if self.state.get_shuffle() {
-                    let current_index = self.state.get_playing_track_index();
-                    let tracks = self.state.mut_track();
-                    if !tracks.is_empty() {
-                        tracks.swap(0, current_index as usize);
-                        if let Some((_, rest)) = tracks.split_first_mut() {
-                            let mut rng = rand::thread_rng();
-                            rest.shuffle(&mut rng);
-                        }
-                        self.state.set_playing_track_index(0);
-                    }
-                } else {
-                    let context = self.state.get_context_uri();
-                    debug!("{:?}", context);
-                }
+                self.handle_shuffle(frame.get_state().get_shuffle());
                 self.notify(None, true);
             }
 
@@ -693,27 +1130,19 @@ impl SpircTask {
             MessageType::kMessageTypeReplace => {
                 self.update_tracks(&frame);
                 self.notify(None, true);
-
-                if let SpircPlayStatus::Playing {
-                    preloading_of_next_track_triggered,
-                    ..
-                }
-                | SpircPlayStatus::Paused {
-                    preloading_of_next_track_triggered,
-                    ..
-                } = self.play_status
-                {
-                    if preloading_of_next_track_triggered {
-                        // Get the next track_id in the playlist
-                        if let Some(track_id) = self.preview_next_track() {
-                            self.player.preload(track_id);
-                        }
-                    }
-                }
+                self.preload_next_track_again();
             }
 
             MessageType::kMessageTypeVolume => {
-                self.set_volume(frame.get_volume() as u16);
+                let volume = frame.get_volume() as u16;
+                if !self.config.quantize_volume {
+                    self.set_volume(volume);
+                } else {
+                    let volume = self.quantize_volume(volume);
+                    if volume != self.device.get_volume() as u16 {
+                        self.set_volume(volume);
+                    }
+                }
                 self.notify(None, true);
             }
 
@@ -728,6 +1157,24 @@ impl SpircTask {
                     self.player.stop();
                     self.play_status = SpircPlayStatus::Stopped;
                 }
+
+                if frame.get_device_state().get_is_active() {
+                    if self.take_over_pending && !self.device.get_is_active() {
+                        self.take_over(&frame);
+                    } else {
+                        self.active_device_frame = Some(frame);
+                        if !self.resume_fut.is_terminated() {
+                            self.resume_fut = Box::pin(future::pending());
+                            self.resume_unless_playing_elsewhere();
+                        }
+                    }
+                } else if self
+                    .active_device_frame
+                    .as_ref()
+                    .map_or(false, |active| active.get_ident() == frame.get_ident())
+                {
+                    self.active_device_frame = None;
+                }
             }
 
             _ => (),
@@ -749,7 +1196,7 @@ impl SpircTask {
                 self.state.set_status(PlayStatus::kPlayStatusPlay);
                 self.update_state_position(position_ms);
                 self.play_status = SpircPlayStatus::Playing {
-                    nominal_start_time: self.now_ms() as i64 - position_ms as i64,
+                    nominal_start_time: self.nominal_start_time(position_ms),
                     preloading_of_next_track_triggered,
                 };
             }
@@ -781,12 +1228,13 @@ impl SpircTask {
             } => {
                 self.player.pause();
                 self.state.set_status(PlayStatus::kPlayStatusPause);
-                let position_ms = (self.now_ms() - nominal_start_time) as u32;
+                let position_ms = self.position_since(nominal_start_time);
                 self.update_state_position(position_ms);
                 self.play_status = SpircPlayStatus::Paused {
                     position_ms,
                     preloading_of_next_track_triggered,
                 };
+                self.save_last_session();
             }
             SpircPlayStatus::LoadingPlay { position_ms } => {
                 self.player.pause();
@@ -799,7 +1247,7 @@ impl SpircTask {
     fn handle_seek(&mut self, position_ms: u32) {
         self.update_state_position(position_ms);
         self.player.seek(position_ms);
-        let now = self.now_ms();
+        let new_nominal_start_time = self.nominal_start_time(position_ms);
         match self.play_status {
             SpircPlayStatus::Stopped => (),
             SpircPlayStatus::LoadingPause {
@@ -815,7 +1263,7 @@ impl SpircTask {
             SpircPlayStatus::Playing {
                 ref mut nominal_start_time,
                 ..
-            } => *nominal_start_time = now - position_ms as i64,
+            } => *nominal_start_time = new_nominal_start_time,
         };
     }
 
@@ -971,17 +1419,28 @@ impl SpircTask {
     }
 
     fn handle_volume_up(&mut self) {
-        let volume = (self.device.get_volume() as u16).saturating_add(VOLUME_STEP_SIZE);
+        let volume = (self.device.get_volume() as u16).saturating_add(self.config.volume_step_size);
         self.set_volume(volume);
     }
 
     fn handle_volume_down(&mut self) {
-        let volume = (self.device.get_volume() as u16).saturating_sub(VOLUME_STEP_SIZE);
+        let volume = (self.device.get_volume() as u16).saturating_sub(self.config.volume_step_size);
         self.set_volume(volume);
     }
 
     fn handle_end_of_track(&mut self) {
-        self.handle_next();
+        if self.consecutive_skips >= self.config.max_consecutive_skips {
+            warn!(
+                "Stopping playback, skipped {} unplayable tracks in a row",
+                self.consecutive_skips
+            );
+            self.consecutive_skips = 0;
+            self.state.set_status(PlayStatus::kPlayStatusStop);
+            self.player.stop();
+            self.play_status = SpircPlayStatus::Stopped;
+        } else {
+            self.handle_next();
+        }
         self.notify(None, true);
     }
 
@@ -993,10 +1452,19 @@ impl SpircTask {
             | SpircPlayStatus::Paused { position_ms, .. } => position_ms,
             SpircPlayStatus::Playing {
                 nominal_start_time, ..
-            } => (self.now_ms() - nominal_start_time) as u32,
+            } => self.position_since(nominal_start_time),
         }
     }
 
+    // When playback would have started to be at `position_ms` now, at the current speed.
+    fn nominal_start_time(&mut self, position_ms: u32) -> i64 {
+        self.now_ms() - (f64::from(position_ms) / self.speed) as i64
+    }
+
+    fn position_since(&mut self, nominal_start_time: i64) -> u32 {
+        ((self.now_ms() - nominal_start_time) as f64 * self.speed) as u32
+    }
+
     fn resolve_station(&self, uri: &str) -> BoxedFuture<Result<serde_json::Value, MercuryError>> {
         let radio_uri = format!("hm://radio-apollo/v3/stations/{}", uri);
 
@@ -1105,15 +1573,24 @@ impl SpircTask {
         if state.get_shuffle() {
             self.state.set_shuffle(true);
         }
+        self.emit_playback_mode();
     }
 
     // should this be a method of SpotifyId directly?
     fn get_spotify_id_for_track(&self, track_ref: &TrackRef) -> Result<SpotifyId, SpotifyIdError> {
-        SpotifyId::from_raw(track_ref.get_gid()).or_else(|_| {
-            let uri = track_ref.get_uri();
-            debug!("Malformed or no gid, attempting to parse URI <{}>", uri);
-            SpotifyId::from_uri(uri)
-        })
+        SpotifyId::from_raw(track_ref.get_gid())
+            .map(|mut track_id| {
+                // the gid doesn't tell episodes from tracks
+                if track_ref.get_uri().starts_with("spotify:episode:") {
+                    track_id.audio_type = SpotifyAudioType::Podcast;
+                }
+                track_id
+            })
+            .or_else(|_| {
+                let uri = track_ref.get_uri();
+                debug!("Malformed or no gid, attempting to parse URI <{}>", uri);
+                SpotifyId::from_uri(uri)
+            })
     }
 
     // Helper to find corresponding index(s) for track_id
@@ -1208,6 +1685,7 @@ impl SpircTask {
                     self.state.set_status(PlayStatus::kPlayStatusPause);
                     self.play_status = SpircPlayStatus::LoadingPause { position_ms };
                 }
+                self.save_last_session();
             }
             None => {
                 self.state.set_status(PlayStatus::kPlayStatusStop);
@@ -1217,6 +1695,144 @@ impl SpircTask {
         }
     }
 
+    // Loads the state of the active device, which stops playing once it's told about it.
+    fn take_over(&mut self, frame: &Frame) {
+        info!(
+            "Taking over playback from {}",
+            frame.get_device_state().get_name()
+        );
+        self.take_over_pending = false;
+        self.active_device_frame = None;
+
+        let now = self.now_ms();
+        self.device.set_is_active(true);
+        self.device.set_became_active_at(now);
+
+        self.update_tracks(frame);
+
+        if !self.state.get_track().is_empty() {
+            let state = frame.get_state();
+            let start_playing = state.get_status() == PlayStatus::kPlayStatusPlay;
+            let mut position_ms = state.get_position_ms();
+            if start_playing && state.get_position_measured_at() > 0 {
+                // the other device kept playing since it announced its position
+                // the player clamps it to the duration of the track
+                let elapsed_ms = now - state.get_position_measured_at() as i64;
+                let elapsed_ms = u32::try_from(elapsed_ms.max(0)).unwrap_or(u32::MAX);
+                position_ms = position_ms.saturating_add(elapsed_ms);
+            }
+            self.load_track(start_playing, position_ms);
+        } else {
+            info!("No tracks to take over");
+            self.state.set_status(PlayStatus::kPlayStatusStop);
+        }
+
+        self.notify(None, true);
+    }
+
+    fn resume_unless_playing_elsewhere(&mut self) {
+        if self.device.get_is_active() {
+            debug!("Already the active device");
+        } else if self.active_device_frame.is_some() {
+            info!("Not resuming the last session, another device is playing");
+        } else {
+            self.resume();
+        }
+    }
+
+    // Loads what this device played last, as saved by `save_last_session`.
+    fn resume(&mut self) {
+        let last_session = self
+            .session
+            .cache()
+            .and_then(|cache| cache.device_preferences(self.device.get_name()))
+            .and_then(|preferences| preferences.last_session)
+            .filter(|last_session| !last_session.track_uris.is_empty());
+        let last_session = match last_session {
+            Some(last_session) => last_session,
+            None => {
+                info!("No previous session to resume");
+                return;
+            }
+        };
+
+        info!(
+            "Resuming <{}> at track {} of {}",
+            last_session.context_uri,
+            last_session.index + 1,
+            last_session.track_uris.len()
+        );
+
+        let track_refs = last_session
+            .track_uris
+            .into_iter()
+            .map(|uri| {
+                let mut track_ref = TrackRef::new();
+                if let Ok(track_id) = SpotifyId::from_uri(&uri) {
+                    track_ref.set_gid(track_id.to_raw().to_vec());
+                }
+                track_ref.set_uri(uri);
+                track_ref
+            })
+            .collect();
+
+        self.load_tracks(
+            last_session.context_uri,
+            track_refs,
+            last_session.index,
+            last_session.position_ms,
+        );
+    }
+
+    // Becomes the active device and starts playing `tracks` at `index`.
+    fn load_tracks(
+        &mut self,
+        context_uri: String,
+        tracks: Vec<TrackRef>,
+        index: u32,
+        position_ms: u32,
+    ) {
+        let mut frame = Frame::new();
+        let state = frame.mut_state();
+        state.set_context_uri(context_uri);
+        state.set_playing_track_index(index);
+        state.set_track(protobuf::RepeatedField::from_vec(tracks));
+
+        let now = self.now_ms();
+        self.device.set_is_active(true);
+        self.device.set_became_active_at(now);
+
+        self.update_tracks(&frame);
+        self.load_track(true, position_ms);
+
+        self.notify(None, true);
+    }
+
+    fn save_last_session(&mut self) {
+        let cache = match self.session.cache() {
+            Some(cache) if !self.state.get_track().is_empty() => cache.clone(),
+            _ => return,
+        };
+
+        let track_uris = self
+            .state
+            .get_track()
+            .iter()
+            .map(|track_ref| match self.get_spotify_id_for_track(track_ref) {
+                Ok(track_id) => track_id.to_uri().unwrap_or_default(),
+                Err(_) => track_ref.get_uri().to_string(),
+            })
+            .collect();
+
+        let last_session = LastSession {
+            context_uri: self.state.get_context_uri().to_string(),
+            track_uris,
+            index: self.state.get_playing_track_index(),
+            position_ms: self.position(),
+        };
+        cache.save_last_session(self.device.get_name(), &last_session);
+    }
+
     fn hello(&mut self) {
         CommandSender::new(self, MessageType::kMessageTypeHello).send();
     }
@@ -1239,13 +1855,141 @@ impl SpircTask {
         cs.send();
     }
 
+    // Rounds to the nearest volume step, see `ConnectConfig::quantize_volume`.
+    fn quantize_volume(&self, volume: u16) -> u16 {
+        let step = self.config.volume_step_size as u32;
+        let quantized = (volume as u32 + step / 2) / step * step;
+        quantized.min(u16::MAX as u32) as u16
+    }
+
     fn set_volume(&mut self, volume: u16) {
+        // reported back to the client, so its slider doesn't stay above the cap
+        let volume = if volume > self.config.max_volume {
+            debug!("Capping volume {} at {}", volume, self.config.max_volume);
+            self.config.max_volume
+        } else {
+            volume
+        };
+
         self.device.set_volume(volume as u32);
         self.mixer.set_volume(volume);
+        self.save_preferences_later();
+        self.player.emit_volume_set_event(volume);
+    }
+
+    fn save_preferences_later(&mut self) {
+        self.save_preferences_fut = Box::pin(tokio::time::sleep(PREFERENCES_SAVE_DELAY).fuse());
+    }
+
+    fn save_preferences(&mut self) {
+        self.save_preferences_fut = Box::pin(future::pending());
         if let Some(cache) = self.session.cache() {
-            cache.save_volume(volume)
+            cache.save_volume(self.device.get_volume() as u16)
+        }
+        self.save_device_preferences();
+    }
+
+    // The next track may have changed, so preload it if the previous one was preloaded already.
+    fn preload_next_track_again(&mut self) {
+        if let SpircPlayStatus::Playing {
+            preloading_of_next_track_triggered,
+            ..
+        }
+        | SpircPlayStatus::Paused {
+            preloading_of_next_track_triggered,
+            ..
+        } = self.play_status
+        {
+            if preloading_of_next_track_triggered {
+                // Get the next track_id in the playlist
+                if let Some(track_id) = self.preview_next_track() {
+                    self.player.preload(track_id);
+                }
+            }
+        }
+    }
+
+    fn queue(&self) -> PlayQueue {
+        let queue_track = |track_ref: &TrackRef| QueueTrack {
+            uri: self
+                .get_spotify_id_for_track(track_ref)
+                .ok()
+                .and_then(|track_id| track_id.to_uri().ok())
+                .unwrap_or_else(|| track_ref.get_uri().to_string()),
+            queued: track_ref.get_queued(),
+        };
+
+        let index = self.state.get_playing_track_index() as usize;
+        let tracks = self.state.get_track();
+
+        PlayQueue {
+            context_uri: self.state.get_context_uri().to_string(),
+            current_track: tracks.get(index).map(&queue_track),
+            next_tracks: tracks.iter().skip(index + 1).map(&queue_track).collect(),
+        }
+    }
+
+    // Queued tracks are played after the current track, in the order they were added.
+    fn handle_add_to_queue(&mut self, track_id: SpotifyId) {
+        let mut track_ref = TrackRef::new();
+        track_ref.set_gid(track_id.to_raw().to_vec());
+        if let Ok(uri) = track_id.to_uri() {
+            track_ref.set_uri(uri);
+        }
+        track_ref.set_queued(true);
+
+        let mut index = self.state.get_playing_track_index() as usize + 1;
+        let tracks = self.state.mut_track();
+        while index < tracks.len() && tracks[index].get_queued() {
+            index += 1;
+        }
+        tracks.insert(index.min(tracks.len()), track_ref);
+
+        self.preload_next_track_again();
+    }
+
+    fn handle_shuffle(&mut self, shuffle: bool) {
+        self.state.set_shuffle(shuffle);
+        self.save_preferences_later();
+        if self.state.get_shuffle() {
+            let current_index = self.state.get_playing_track_index();
+            let tracks = self.state.mut_track();
+            if !tracks.is_empty() {
+                tracks.swap(0, current_index as usize);
+                if let Some((_, rest)) = tracks.split_first_mut() {
+                    let mut rng = rand::thread_rng();
+                    rest.shuffle(&mut rng);
+                }
+                self.state.set_playing_track_index(0);
+            }
+        } else {
+            let context = self.state.get_context_uri();
+            debug!("{:?}", context);
+        }
+        self.emit_playback_mode();
+    }
+
+    fn handle_repeat(&mut self, repeat: bool) {
+        self.state.set_repeat(repeat);
+        self.save_preferences_later();
+        self.emit_playback_mode();
+    }
+
+    fn emit_playback_mode(&self) {
+        self.player
+            .emit_playback_mode_event(self.state.get_shuffle(), self.state.get_repeat());
+    }
+
+    fn save_device_preferences(&self) {
+        if let Some(cache) = self.session.cache() {
+            let preferences = DevicePreferences {
+                volume: Some(self.device.get_volume() as u16),
+                shuffle: self.state.get_shuffle(),
+                repeat: self.state.get_repeat(),
+                ..DevicePreferences::default()
+            };
+            cache.save_device_preferences(self.device.get_name(), &preferences);
         }
-        self.player.emit_volume_set_event(volume);
     }
 }
 
@@ -1278,7 +2022,11 @@ impl<'a> CommandSender<'a> {
         self
     }
 
-    #[allow(dead_code)]
+    fn position(mut self, position_ms: u32) -> CommandSender<'a> {
+        self.frame.set_position(position_ms);
+        self
+    }
+
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
         self
@@ -1292,3 +2040,23 @@ impl<'a> CommandSender<'a> {
         self.spirc.sender.send(self.frame.write_to_bytes().unwrap());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_track_index() {
+        // playing the second of five tracks
+        assert_eq!(next_track_index(1, 0, 5), Some(2));
+        assert_eq!(next_track_index(1, 2, 5), Some(4));
+        assert_eq!(next_track_index(1, 3, 5), None);
+
+        // nothing queued after the last track
+        assert_eq!(next_track_index(4, 0, 5), None);
+
+        // huge indices from the control endpoint don't overflow
+        assert_eq!(next_track_index(1, usize::MAX, 5), None);
+        assert_eq!(next_track_index(u32::MAX, usize::MAX, 5), None);
+    }
+}
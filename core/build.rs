@@ -18,4 +18,22 @@ fn main() {
     };
 
     println!("cargo:rustc-env=LIBRESPOT_BUILD_ID={}", build_id);
+
+    // `cfg(target_has_atomic)` is only stable since Rust 1.60, and older compilers don't pass it
+    // to build scripts either. Fall back to the targets we build for without 64-bit atomics.
+    let has_atomic_u64 = match env::var("CARGO_CFG_TARGET_HAS_ATOMIC") {
+        Ok(widths) => widths.split(',').any(|width| width == "64"),
+        Err(_) => {
+            let target = env::var("TARGET").unwrap_or_default();
+            let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+            !(target.starts_with("armv5te")
+                || target.starts_with("thumbv6m")
+                || matches!(arch.as_str(), "mips" | "powerpc" | "riscv32"))
+        }
+    };
+
+    println!("cargo:rustc-check-cfg=cfg(no_atomic_u64)");
+    if !has_atomic_u64 {
+        println!("cargo:rustc-cfg=no_atomic_u64");
+    }
 }
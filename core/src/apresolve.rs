@@ -1,43 +1,79 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use hyper::client::HttpConnector;
 use hyper::{Body, Client, Method, Request, Uri};
-use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_proxy::ProxyConnector;
+use once_cell::sync::Lazy;
 use serde::Deserialize;
 use url::Url;
 
+use crate::cache::Cache;
+use crate::connection;
+use crate::dns::DnsResolver;
+use crate::proxytunnel;
+
 const APRESOLVE_ENDPOINT: &str = "http://apresolve.spotify.com:80";
-const AP_FALLBACK: &str = "ap.spotify.com:443";
+const APRESOLVE_TLS_ENDPOINT: &str = "https://apresolve.spotify.com:443";
+const AP_FALLBACK_HOST: &str = "ap.spotify.com";
+const AP_DEFAULT_PORT: u16 = 443;
 const AP_BLACKLIST: [&str; 2] = ["ap-gew4.spotify.com", "ap-gue1.spotify.com"];
 
+// How long an access point which failed is tried after all others.
+const AP_FAILURE_PENALTY: Duration = Duration::from_secs(600);
+
+// How long the access points resolved before are used instead of resolving them on connect, and
+// when they are refreshed in the background.
+const AP_CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const AP_CACHE_REFRESH_AGE: Duration = Duration::from_secs(60 * 60);
+
+static FAILED_APS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Default::default);
+
 #[derive(Clone, Debug, Deserialize)]
 struct ApResolveData {
     ap_list: Vec<String>,
 }
 
+/// The port of an access point address ("host:port").
+pub fn ap_port(ap: &str) -> Option<u16> {
+    ap.parse::<Uri>().ok()?.port_u16()
+}
+
 async fn try_apresolve(
     proxy: Option<&Url>,
-    ap_port: Option<u16>,
-) -> Result<String, Box<dyn Error>> {
-    let port = ap_port.unwrap_or(443);
+    ap_ports: &[u16],
+    force_tls: bool,
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let endpoint = if force_tls {
+        APRESOLVE_TLS_ENDPOINT
+    } else {
+        APRESOLVE_ENDPOINT
+    };
 
     let mut req = Request::new(Body::empty());
     *req.method_mut() = Method::GET;
-    // panic safety: APRESOLVE_ENDPOINT above is valid url.
-    *req.uri_mut() = APRESOLVE_ENDPOINT.parse().expect("invalid AP resolve URL");
+    // panic safety: the endpoints above are valid urls.
+    *req.uri_mut() = endpoint.parse().expect("invalid AP resolve URL");
+
+    // https:// proxies are connected to with TLS, the request itself is plain http
+    let connector = connection::https_connector(bind_address, dns);
 
     let response = if let Some(url) = proxy {
-        // Panic safety: all URLs are valid URIs
-        let uri = url.to_string().parse().unwrap();
-        let proxy = Proxy::new(Intercept::All, uri);
-        let connector = HttpConnector::new();
+        let proxy = proxytunnel::hyper_proxy(url);
         let proxy_connector = ProxyConnector::from_proxy_unsecured(connector, proxy);
+        if let Some(headers) = proxy_connector.http_headers(req.uri()) {
+            req.headers_mut().extend(headers.clone());
+        }
         Client::builder()
             .build(proxy_connector)
             .request(req)
             .await?
     } else {
-        Client::new().request(req).await?
+        Client::builder().build(connector).request(req).await?
     };
 
     let body = hyper::body::to_bytes(response.into_body()).await?;
@@ -58,51 +94,196 @@ async fn try_apresolve(
         })
         .collect();
 
-    let ap = if ap_port.is_some() || proxy.is_some() {
-        // filter on ports if specified on the command line...
-        aps.into_iter().find_map(|ap| {
-            if ap.parse::<Uri>().ok()?.port()? == port {
-                Some(ap)
-            } else {
-                None
-            }
+    let aps = if !ap_ports.is_empty() {
+        // filter on ports if specified on the command line, in their order of preference
+        by_port_preference(aps, ap_ports)
+    } else if proxy.is_some() {
+        by_port_preference(aps, &[AP_DEFAULT_PORT])
+    } else {
+        aps
+    };
+
+    if aps.is_empty() {
+        return Err("Unable to resolve any viable access points.".into());
+    }
+
+    Ok(aps)
+}
+
+fn by_port_preference(aps: Vec<String>, ports: &[u16]) -> Vec<String> {
+    let mut aps: Vec<(usize, String)> = aps
+        .into_iter()
+        .filter_map(|ap| {
+            let port = ap_port(&ap)?;
+            Some((ports.iter().position(|&p| p == port)?, ap))
         })
+        .collect();
+    aps.sort_by_key(|(preference, _)| *preference);
+    aps.into_iter().map(|(_, ap)| ap).collect()
+}
+
+/// Returns the access points to try, in order. Access points which recently failed come last.
+///
+/// The access points resolved before are taken from `cache` if they are recent enough, saving
+/// a request before connecting.
+///
+/// With `force_tls` only access points on port 443 are returned, resolved over HTTPS.
+pub async fn apresolve(
+    proxy: Option<&Url>,
+    ap_ports: &[u16],
+    force_tls: bool,
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+    cache: Option<&Cache>,
+) -> Vec<String> {
+    let ap_ports: &[u16] = if force_tls {
+        &[AP_DEFAULT_PORT]
+    } else {
+        ap_ports
+    };
+
+    // only the unfiltered list is cached
+    let cache = cache.filter(|_| proxy.is_none() && ap_ports.is_empty());
+    let cached = cache
+        .and_then(Cache::access_points)
+        .filter(|(aps, age)| !aps.is_empty() && *age < AP_CACHE_MAX_AGE);
+
+    let mut aps = match (cached, cache) {
+        (Some((aps, age)), Some(cache)) => {
+            debug!("Using the access points resolved {}s ago", age.as_secs());
+            if age > AP_CACHE_REFRESH_AGE {
+                let cache = cache.clone();
+                let dns = dns.clone();
+                tokio::spawn(async move {
+                    if let Ok(aps) = try_apresolve(None, &[], false, bind_address, &dns).await {
+                        cache.save_access_points(&aps);
+                    }
+                });
+            }
+            aps
+        }
+        _ => match try_apresolve(proxy, ap_ports, force_tls, bind_address, dns).await {
+            Ok(aps) => {
+                if let Some(cache) = cache {
+                    cache.save_access_points(&aps);
+                }
+                aps
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to resolve Access Point: {}. Only the fallback \"{}\" is left to try.",
+                    e, AP_FALLBACK_HOST
+                );
+                Vec::new()
+            }
+        },
+    };
+
+    // one fallback for each port, so none of them is skipped
+    let fallback_ports: &[u16] = if ap_ports.is_empty() {
+        &[AP_DEFAULT_PORT]
     } else {
-        // ...or pick the first on the list
-        aps.into_iter().next()
+        ap_ports
+    };
+    for port in fallback_ports {
+        let fallback = format!("{}:{}", AP_FALLBACK_HOST, port);
+        if !aps.contains(&fallback) {
+            aps.push(fallback);
+        }
     }
-    .ok_or("Unable to resolve any viable access points.")?;
 
-    Ok(ap)
+    let mut failed = FAILED_APS.lock().unwrap();
+    failed.retain(|_, time| time.elapsed() < AP_FAILURE_PENALTY);
+    aps.sort_by_key(|ap| failed.contains_key(ap));
+
+    aps
+}
+
+/// Remembers that connecting to `ap` failed.
+pub fn report_failure(ap: &str) {
+    FAILED_APS
+        .lock()
+        .unwrap()
+        .insert(ap.to_string(), Instant::now());
 }
 
-pub async fn apresolve(proxy: Option<&Url>, ap_port: Option<u16>) -> String {
-    try_apresolve(proxy, ap_port).await.unwrap_or_else(|e| {
-        warn!("Failed to resolve Access Point: {}", e);
-        warn!("Using fallback \"{}\"", AP_FALLBACK);
-        AP_FALLBACK.into()
-    })
+/// Remembers that `ap` works again.
+pub fn report_success(ap: &str) {
+    FAILED_APS.lock().unwrap().remove(ap);
 }
 
 #[cfg(test)]
 mod test {
     use std::net::ToSocketAddrs;
 
-    use super::try_apresolve;
+    use super::*;
 
     #[tokio::test]
     async fn test_apresolve() {
-        let ap = try_apresolve(None, None).await.unwrap();
+        let aps = try_apresolve(None, &[], false, None, &DnsResolver::System)
+            .await
+            .unwrap();
 
         // Assert that the result contains a valid host and port
-        ap.to_socket_addrs().unwrap().next().unwrap();
+        aps[0].to_socket_addrs().unwrap().next().unwrap();
     }
 
     #[tokio::test]
     async fn test_apresolve_port_443() {
-        let ap = try_apresolve(None, Some(443)).await.unwrap();
+        let aps = try_apresolve(None, &[443], false, None, &DnsResolver::System)
+            .await
+            .unwrap();
+
+        for ap in aps {
+            let port = ap.to_socket_addrs().unwrap().next().unwrap().port();
+            assert_eq!(port, 443);
+        }
+    }
+
+    #[test]
+    fn test_port_preference() {
+        let aps = vec![
+            "ap-a.spotify.com:443".to_string(),
+            "ap-b.spotify.com:4070".to_string(),
+            "ap-c.spotify.com:80".to_string(),
+            "ap-d.spotify.com:443".to_string(),
+        ];
+
+        assert_eq!(
+            by_port_preference(aps, &[4070, 443]),
+            [
+                "ap-b.spotify.com:4070",
+                "ap-a.spotify.com:443",
+                "ap-d.spotify.com:443"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_aps_last() {
+        // resolved from the cache, not the network
+        let path = std::env::temp_dir().join(format!("librespot-aps-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&path), None, None).unwrap();
+        cache.save_access_points(&[
+            "ap-failing.spotify.com:443".to_string(),
+            "ap-working.spotify.com:443".to_string(),
+        ]);
+
+        report_failure("ap-failing.spotify.com:443");
+        let aps = apresolve(None, &[], false, None, &DnsResolver::System, Some(&cache)).await;
+        assert_eq!(
+            aps,
+            [
+                "ap-working.spotify.com:443",
+                "ap.spotify.com:443",
+                "ap-failing.spotify.com:443"
+            ]
+        );
+
+        report_success("ap-failing.spotify.com:443");
+        let aps = apresolve(None, &[], false, None, &DnsResolver::System, Some(&cache)).await;
+        assert_eq!(aps[0], "ap-failing.spotify.com:443");
 
-        let port = ap.to_socket_addrs().unwrap().next().unwrap().port();
-        assert_eq!(port, 443);
+        let _ = std::fs::remove_dir_all(path);
     }
 }
@@ -1,12 +1,16 @@
 use std::io::{self, Read};
 
 use aes::Aes192;
+use aes_ctr::cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes128Ctr;
 use byteorder::{BigEndian, ByteOrder};
-use hmac::Hmac;
+use hmac::{Hmac, Mac, NewMac};
 use pbkdf2::pbkdf2;
 use protobuf::ProtobufEnum;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use thiserror::Error;
 
 use crate::protocol::authentication::AuthenticationType;
 
@@ -42,6 +46,16 @@ impl Credentials {
         }
     }
 
+    /// Intialize these credentials from an OAuth access token with the `streaming` scope.
+    /// The username is filled in by the access point once the login succeeds.
+    pub fn with_access_token(token: impl Into<String>) -> Credentials {
+        Credentials {
+            username: String::new(),
+            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+            auth_data: token.into().into_bytes(),
+        }
+    }
+
     pub fn with_blob(
         username: impl Into<String>,
         encrypted_blob: impl AsRef<[u8]>,
@@ -125,6 +139,105 @@ impl Credentials {
     }
 }
 
+const PASSPHRASE_ROUNDS: u32 = 0x4000;
+
+#[derive(Debug, Error)]
+pub enum CredentialsError {
+    #[error("wrong passphrase or corrupted credentials")]
+    Checksum,
+    #[error("invalid IV length")]
+    InvalidIv,
+    #[error("invalid credentials data: {0}")]
+    InvalidData(#[from] serde_json::Error),
+}
+
+/// Credentials encrypted with a key derived from a passphrase, as stored in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedCredentials {
+    #[serde(serialize_with = "serialize_base64")]
+    #[serde(deserialize_with = "deserialize_base64")]
+    salt: Vec<u8>,
+
+    #[serde(serialize_with = "serialize_base64")]
+    #[serde(deserialize_with = "deserialize_base64")]
+    iv: Vec<u8>,
+
+    #[serde(serialize_with = "serialize_base64")]
+    #[serde(deserialize_with = "deserialize_base64")]
+    data: Vec<u8>,
+
+    #[serde(serialize_with = "serialize_base64")]
+    #[serde(deserialize_with = "deserialize_base64")]
+    checksum: Vec<u8>,
+}
+
+impl EncryptedCredentials {
+    /// Encrypts `credentials` using AES-128-CTR with a HMAC-SHA1 checksum over the salt, IV and
+    /// encrypted data. Both keys are derived from `passphrase` and a random salt.
+    pub fn encrypt(credentials: &Credentials, passphrase: &str) -> Result<Self, CredentialsError> {
+        let mut rng = rand::thread_rng();
+
+        let mut salt = vec![0u8; 16];
+        rng.fill_bytes(&mut salt);
+        let mut iv = vec![0u8; 16];
+        rng.fill_bytes(&mut iv);
+
+        let (encryption_key, checksum_key) = Self::keys(passphrase, &salt);
+
+        let mut data = serde_json::to_vec(credentials)?;
+        Aes128Ctr::new_var(&encryption_key, &iv)
+            .expect("key and iv have the right length")
+            .apply_keystream(&mut data);
+
+        let checksum = Self::mac(&checksum_key, &salt, &iv, &data)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        Ok(Self {
+            salt,
+            iv,
+            data,
+            checksum,
+        })
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<Credentials, CredentialsError> {
+        let (encryption_key, checksum_key) = Self::keys(passphrase, &self.salt);
+
+        Self::mac(&checksum_key, &self.salt, &self.iv, &self.data)
+            .verify(&self.checksum)
+            .map_err(|_| CredentialsError::Checksum)?;
+
+        let mut data = self.data.clone();
+        Aes128Ctr::new_var(&encryption_key, &self.iv)
+            .map_err(|_| CredentialsError::InvalidIv)?
+            .apply_keystream(&mut data);
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    fn keys(passphrase: &str, salt: &[u8]) -> ([u8; 16], [u8; 16]) {
+        let mut key = [0u8; 32];
+        pbkdf2::<Hmac<Sha1>>(passphrase.as_bytes(), salt, PASSPHRASE_ROUNDS, &mut key);
+
+        let mut encryption_key = [0u8; 16];
+        let mut checksum_key = [0u8; 16];
+        encryption_key.copy_from_slice(&key[..16]);
+        checksum_key.copy_from_slice(&key[16..]);
+
+        (encryption_key, checksum_key)
+    }
+
+    fn mac(key: &[u8], salt: &[u8], iv: &[u8], data: &[u8]) -> Hmac<Sha1> {
+        let mut h = Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take key of any size");
+        h.update(salt);
+        h.update(iv);
+        h.update(data);
+        h
+    }
+}
+
 fn serialize_protobuf_enum<T, S>(v: &T, ser: S) -> Result<S::Ok, S::Error>
 where
     T: ProtobufEnum,
@@ -157,3 +270,37 @@ where
     let v: String = serde::Deserialize::deserialize(de)?;
     base64::decode(&v).map_err(|e| serde::de::Error::custom(e.to_string()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encrypted_credentials() {
+        let credentials = Credentials::with_password("user", "password");
+        let encrypted = EncryptedCredentials::encrypt(&credentials, "secret").unwrap();
+
+        let decrypted = encrypted.decrypt("secret").unwrap();
+        assert_eq!(decrypted.username, "user");
+        assert_eq!(decrypted.auth_data, b"password");
+
+        assert!(matches!(
+            encrypted.decrypt("wrong"),
+            Err(CredentialsError::Checksum)
+        ));
+
+        let mut tampered = encrypted.clone();
+        tampered.iv[0] ^= 1;
+        assert!(matches!(
+            tampered.decrypt("secret"),
+            Err(CredentialsError::Checksum)
+        ));
+
+        let mut tampered = encrypted;
+        tampered.salt[0] ^= 1;
+        assert!(matches!(
+            tampered.decrypt("secret"),
+            Err(CredentialsError::Checksum)
+        ));
+    }
+}
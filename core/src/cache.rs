@@ -1,15 +1,18 @@
 use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
+use fs2::FileExt;
 use priority_queue::PriorityQueue;
+use serde::{Deserialize, Serialize};
 
-use crate::authentication::Credentials;
-use crate::spotify_id::FileId;
+use crate::authentication::{Credentials, EncryptedCredentials};
+use crate::data_usage::{DataUsage, DataUsageHistory};
+use crate::spotify_id::{FileId, SpotifyId};
 
 /// Some kind of data structure that holds some paths, the size of these files and a timestamp.
 /// It keeps track of the file sizes and is able to pop the path with the oldest timestamp if
@@ -143,6 +146,7 @@ impl FsSizeLimiter {
                 Ok(file_type) if file_type.is_dir() || file_type.is_symlink() => {
                     Self::init_dir(limiter, &entry.path())
                 }
+                Ok(_) if entry.file_name() == LOCK_FILE => (),
                 Ok(file_type) if file_type.is_file() => {
                     let path = entry.path();
                     match Self::get_metadata(&path) {
@@ -225,11 +229,148 @@ impl FsSizeLimiter {
     }
 }
 
+const LOCK_FILE: &str = ".lock";
+
+// Downloads which weren't removed, eg. because the process was killed, are removed after this.
+const STALE_DOWNLOAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An advisory lock on a cache directory, which may be shared by several processes.
+/// It is released when dropped.
+struct DirLock(File);
+
+impl DirLock {
+    fn new(dir: &Path) -> Option<Self> {
+        let lock = || {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(dir.join(LOCK_FILE))?;
+            file.lock_exclusive()?;
+            Ok::<_, io::Error>(file)
+        };
+
+        match lock() {
+            Ok(file) => Some(Self(file)),
+            Err(e) => {
+                warn!("Could not lock cache dir {:?}: {}", dir, e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.0);
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it once complete, so other
+/// processes never see a partially written file.
+fn write_atomically<T, F>(path: &Path, write: F) -> io::Result<T>
+where
+    F: FnOnce(&mut File) -> io::Result<T>,
+{
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    write_atomically_with(path, &options, write)
+}
+
+/// Like `write_atomically`, but the file is only ever accessible by the current user.
+fn write_privately<T, F>(path: &Path, write: F) -> io::Result<T>
+where
+    F: FnOnce(&mut File) -> io::Result<T>,
+{
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    write_atomically_with(path, &options, write)
+}
+
+fn write_atomically_with<T, F>(path: &Path, options: &OpenOptions, write: F) -> io::Result<T>
+where
+    F: FnOnce(&mut File) -> io::Result<T>,
+{
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = PathBuf::from(temp_path);
+
+    // Left over by a crashed process with the same PID
+    let _ = fs::remove_file(&temp_path);
+
+    let result = options
+        .open(&temp_path)
+        .and_then(|mut file| {
+            let result = write(&mut file)?;
+            file.sync_all()?;
+            Ok(result)
+        })
+        .and_then(|result| fs::rename(&temp_path, path).map(|_| result));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// Playback preferences remembered per Connect device name.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevicePreferences {
+    pub volume: Option<u16>,
+    pub shuffle: bool,
+    pub repeat: bool,
+    #[serde(default)]
+    pub last_session: Option<LastSession>,
+    #[serde(default)]
+    pub balance: i8,
+    #[serde(default)]
+    pub swap_channels: bool,
+    /// The device ID announced under this name before, see `Cache::device_id`.
+    #[serde(default)]
+    pub device_id: Option<String>,
+}
+
+/// What a Connect device played last, to resume it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSession {
+    pub context_uri: String,
+    pub track_uris: Vec<String>,
+    pub index: u32,
+    pub position_ms: u32,
+}
+
+/// What's needed to play a track from the audio cache while Spotify can't be reached.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflineTrack {
+    pub name: String,
+    pub duration_ms: u32,
+    pub explicit: bool,
+    /// The `FileFormat` of the file, as numbered in the protocol.
+    pub file_format: i32,
+    pub file_id: [u8; 20],
+    pub key: [u8; 16],
+}
+
 /// A cache for volume, credentials and audio files.
 #[derive(Clone)]
 pub struct Cache {
     credentials_location: Option<PathBuf>,
+    credentials_passphrase: Option<String>,
+    accounts_location: Option<PathBuf>,
     volume_location: Option<PathBuf>,
+    control_token_location: Option<PathBuf>,
+    devices_location: Option<PathBuf>,
+    data_usage_location: Option<PathBuf>,
+    access_points_location: Option<PathBuf>,
+    offline_tracks_location: Option<PathBuf>,
+    temp_location: Option<PathBuf>,
+    spill_downloads: bool,
     audio_location: Option<PathBuf>,
     size_limiter: Option<Arc<FsSizeLimiter>>,
 }
@@ -252,12 +393,29 @@ impl Cache {
         let credentials_location = credentials_path
             .as_ref()
             .map(|p| p.as_ref().join("credentials.json"));
+        let accounts_location = credentials_path
+            .as_ref()
+            .map(|p| p.as_ref().join("accounts"));
 
         if let Some(location) = &volume_path {
             fs::create_dir_all(location)?;
         }
 
         let volume_location = volume_path.as_ref().map(|p| p.as_ref().join("volume"));
+        let control_token_location = volume_path
+            .as_ref()
+            .map(|p| p.as_ref().join("control_token"));
+        let devices_location = volume_path
+            .as_ref()
+            .map(|p| p.as_ref().join("devices.json"));
+        let data_usage_location = volume_path
+            .as_ref()
+            .map(|p| p.as_ref().join("data_usage.json"));
+        let access_points_location = volume_path
+            .as_ref()
+            .map(|p| p.as_ref().join("access_points.json"));
+        let offline_tracks_location = volume_path.as_ref().map(|p| p.as_ref().join("offline"));
+        let temp_location = volume_path.as_ref().map(|p| p.as_ref().join("tmp"));
 
         if let Some(location) = &audio_path {
             fs::create_dir_all(location)?;
@@ -273,7 +431,16 @@ impl Cache {
 
         let cache = Cache {
             credentials_location,
+            credentials_passphrase: None,
+            accounts_location,
             volume_location,
+            control_token_location,
+            devices_location,
+            data_usage_location,
+            access_points_location,
+            offline_tracks_location,
+            temp_location,
+            spill_downloads: false,
             audio_location,
             size_limiter,
         };
@@ -281,18 +448,160 @@ impl Cache {
         Ok(cache)
     }
 
+    /// Encrypt the cached credentials with a key derived from `passphrase`. Credentials
+    /// which were saved in plain text are still read, and encrypted when saved again.
+    pub fn set_credentials_passphrase(&mut self, passphrase: impl Into<String>) {
+        self.credentials_passphrase = Some(passphrase.into());
+    }
+
+    /// Keep the files being downloaded in the cache directory instead of the system's temporary
+    /// directory, which is in memory on many small hosts.
+    pub fn set_spill_downloads(&mut self, spill_downloads: bool) {
+        self.spill_downloads = spill_downloads;
+
+        if let Some(location) = self.temp_location().filter(|_| spill_downloads) {
+            if let Err(e) = fs::create_dir_all(location) {
+                warn!("Cannot create {:?}: {}", location, e);
+                return;
+            }
+            Self::remove_stale_downloads(location);
+        }
+    }
+
+    /// Where to keep the files being downloaded, if not in the system's temporary directory.
+    pub fn temp_location(&self) -> Option<&Path> {
+        self.temp_location
+            .as_deref()
+            .filter(|_| self.spill_downloads)
+    }
+
+    fn remove_stale_downloads(location: &Path) {
+        let entries = match fs::read_dir(location) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Cannot read {:?}: {}", location, e);
+                return;
+            }
+        };
+
+        let now = SystemTime::now();
+        for entry in entries.flatten() {
+            let stale = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| now.duration_since(modified).unwrap_or_default());
+            if matches!(stale, Ok(age) if age > STALE_DOWNLOAD_AGE) {
+                debug!("Removing stale download {:?}", entry.path());
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
     pub fn credentials(&self) -> Option<Credentials> {
         let location = self.credentials_location.as_ref()?;
+        self.read_credentials(location)
+    }
 
-        // This closure is just convencience to enable the question mark operator
-        let read = || {
-            let mut file = File::open(location)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
-        };
+    /// Returns the cached credentials of `username`, if they were saved before.
+    pub fn account_credentials(&self, username: &str) -> Option<Credentials> {
+        self.account_location(username)
+            .and_then(|location| self.read_credentials(&location))
+            .or_else(|| self.credentials().filter(|c| c.username == username))
+    }
 
-        match read() {
+    /// Returns the usernames of all accounts with cached credentials.
+    pub fn accounts(&self) -> Vec<String> {
+        let mut accounts = Vec::new();
+
+        if let Some(location) = &self.accounts_location {
+            match fs::read_dir(location) {
+                Ok(entries) => accounts.extend(entries.filter_map(|entry| {
+                    let name = entry.ok()?.file_name();
+                    let name = name.to_str()?.strip_suffix(".json")?;
+                    let username = base64::decode_config(name, base64::URL_SAFE_NO_PAD).ok()?;
+                    String::from_utf8(username).ok()
+                })),
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    warn!("Error reading accounts from cache: {}", e)
+                }
+                Err(_) => (),
+            }
+        }
+
+        // Credentials saved before accounts were cached separately.
+        if let Some(credentials) = self.credentials() {
+            if !accounts.contains(&credentials.username) {
+                accounts.push(credentials.username);
+            }
+        }
+
+        accounts.sort();
+        accounts
+    }
+
+    /// Saves the credentials as the default ones, and as those of their account.
+    pub fn save_credentials(&self, cred: &Credentials) {
+        if let Some(location) = &self.credentials_location {
+            self.write_credentials(location, cred);
+        }
+
+        if let Some(location) = self.account_location(&cred.username) {
+            self.write_credentials(&location, cred);
+        }
+    }
+
+    fn account_location(&self, username: &str) -> Option<PathBuf> {
+        let name = base64::encode_config(username, base64::URL_SAFE_NO_PAD);
+        let location = self.accounts_location.as_ref()?;
+        Some(location.join(format!("{}.json", name)))
+    }
+
+    /// Saves the credentials exported to `path` on another machine, see `export_credentials`.
+    pub fn import_credentials(&self, path: &Path) -> io::Result<Credentials> {
+        let cred = self.load_credentials(path)?;
+
+        let location = self.credentials_location.as_ref().ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "no directory to cache credentials in")
+        })?;
+        self.store_credentials(location, &cred)?;
+
+        if let Some(location) = self.account_location(&cred.username) {
+            self.store_credentials(&location, &cred)?;
+        }
+
+        Ok(cred)
+    }
+
+    /// Writes the credentials to `path` like they're cached, encrypted if a passphrase is set,
+    /// to import them on another machine. Like the cached ones, only the current user can read
+    /// the file.
+    pub fn export_credentials(&self, path: &Path, cred: &Credentials) -> io::Result<()> {
+        self.store_credentials(path, cred)
+    }
+
+    fn load_credentials(&self, location: &Path) -> io::Result<Credentials> {
+        let mut file = File::open(location)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        if let Ok(encrypted) = serde_json::from_str::<EncryptedCredentials>(&contents) {
+            let passphrase = self.credentials_passphrase.as_ref().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "credentials are encrypted, but no passphrase was given",
+                )
+            })?;
+
+            return encrypted
+                .decrypt(passphrase)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+        }
+
+        serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn read_credentials(&self, location: &Path) -> Option<Credentials> {
+        match self.load_credentials(location) {
             Ok(c) => Some(c),
             Err(e) => {
                 // If the file did not exist, the file was probably not written
@@ -305,17 +614,30 @@ impl Cache {
         }
     }
 
-    pub fn save_credentials(&self, cred: &Credentials) {
-        if let Some(location) = &self.credentials_location {
-            let result = File::create(location).and_then(|mut file| {
-                let data = serde_json::to_string(cred)?;
-                write!(file, "{}", data)
-            });
+    fn write_credentials(&self, location: &Path, cred: &Credentials) {
+        if let Err(e) = self.store_credentials(location, cred) {
+            warn!("Cannot save credentials to cache: {}", e)
+        }
+    }
 
-            if let Err(e) = result {
-                warn!("Cannot save credentials to cache: {}", e)
-            }
+    fn store_credentials(&self, location: &Path, cred: &Credentials) -> io::Result<()> {
+        let write = |file: &mut File| {
+            let data = match &self.credentials_passphrase {
+                Some(passphrase) => {
+                    let encrypted = EncryptedCredentials::encrypt(cred, passphrase)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    serde_json::to_string(&encrypted)?
+                }
+                None => serde_json::to_string(cred)?,
+            };
+            write!(file, "{}", data)
+        };
+
+        match location.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
         }
+        .and_then(|_| write_privately(location, write))
     }
 
     pub fn volume(&self) -> Option<u16> {
@@ -343,13 +665,306 @@ impl Cache {
 
     pub fn save_volume(&self, volume: u16) {
         if let Some(ref location) = self.volume_location {
-            let result = File::create(location).and_then(|mut file| write!(file, "{}", volume));
+            let result = write_atomically(location, |file| write!(file, "{}", volume));
             if let Err(e) = result {
                 warn!("Cannot save volume to cache: {}", e);
             }
         }
     }
 
+    /// The device ID announced as `name` before, kept so that the device stays the same one for
+    /// Spotify however its ID is derived. Instances with different names sharing the cache keep
+    /// different IDs.
+    pub fn device_id(&self, name: &str) -> Option<String> {
+        self.device_preferences(name)?
+            .device_id
+            .filter(|id| !id.is_empty())
+    }
+
+    pub fn save_device_id(&self, name: &str, device_id: &str) {
+        self.update_device_preferences(name, |saved| {
+            saved.device_id = Some(device_id.to_string());
+        });
+    }
+
+    /// Saves the token the control endpoint requires, for local clients to read. Only the user
+    /// running this instance can read it.
+    pub fn save_control_token(&self, token: &str) -> io::Result<PathBuf> {
+        let location = self
+            .control_token_location
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No cache folder"))?;
+
+        write_atomically(location, |file| {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+            write!(file, "{}", token)
+        })?;
+
+        Ok(location.clone())
+    }
+
+    fn all_device_preferences(&self) -> io::Result<HashMap<String, DevicePreferences>> {
+        match &self.devices_location {
+            Some(location) => {
+                let mut file = File::open(location)?;
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    pub fn device_preferences(&self, name: &str) -> Option<DevicePreferences> {
+        match self.all_device_preferences() {
+            Ok(mut preferences) => preferences.remove(name),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Error reading device preferences from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Saves the volume, shuffle and repeat preferences, keeping the last session, the
+    /// channel mix and the device ID.
+    pub fn save_device_preferences(&self, name: &str, preferences: &DevicePreferences) {
+        self.update_device_preferences(name, |saved| {
+            *saved = DevicePreferences {
+                last_session: saved.last_session.take(),
+                balance: saved.balance,
+                swap_channels: saved.swap_channels,
+                device_id: saved.device_id.take(),
+                ..preferences.clone()
+            };
+        });
+    }
+
+    pub fn save_channel_mix(&self, name: &str, balance: i8, swap_channels: bool) {
+        self.update_device_preferences(name, |saved| {
+            saved.balance = balance;
+            saved.swap_channels = swap_channels;
+        });
+    }
+
+    pub fn save_last_session(&self, name: &str, last_session: &LastSession) {
+        self.update_device_preferences(name, |saved| {
+            saved.last_session = Some(last_session.clone());
+        });
+    }
+
+    fn update_device_preferences<F>(&self, name: &str, update: F)
+    where
+        F: FnOnce(&mut DevicePreferences),
+    {
+        if let Some(location) = &self.devices_location {
+            let _lock = location.parent().and_then(DirLock::new);
+
+            let mut all = match self.all_device_preferences() {
+                Ok(all) => all,
+                Err(e) => {
+                    if e.kind() != ErrorKind::NotFound {
+                        warn!("Error reading device preferences from cache: {}", e);
+                    }
+                    HashMap::new()
+                }
+            };
+            update(all.entry(name.to_string()).or_default());
+
+            let result = write_atomically(location, |file| {
+                let data = serde_json::to_string(&all)?;
+                write!(file, "{}", data)
+            });
+
+            if let Err(e) = result {
+                warn!("Cannot save device preferences to cache: {}", e);
+            }
+        }
+    }
+
+    pub fn data_usage(&self) -> Option<DataUsageHistory> {
+        let location = self.data_usage_location.as_ref()?;
+
+        let read = || {
+            let mut file = File::open(location)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        };
+
+        match read() {
+            Ok(h) => Some(h),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Error reading data usage from cache: {}", e);
+                }
+                Some(DataUsageHistory::default())
+            }
+        }
+    }
+
+    pub fn save_data_usage(&self, history: &DataUsageHistory) {
+        if let Some(location) = &self.data_usage_location {
+            let result = write_atomically(location, |file| {
+                let data = serde_json::to_string(history)?;
+                write!(file, "{}", data)
+            });
+
+            if let Err(e) = result {
+                warn!("Cannot save data usage to cache: {}", e);
+            }
+        }
+    }
+
+    /// Adds `usage` to the data usage of `day`. Other processes sharing the cache are
+    /// locked out while the history is updated.
+    pub fn add_data_usage(&self, day: u64, usage: DataUsage) {
+        if let Some(location) = &self.data_usage_location {
+            let _lock = location.parent().and_then(DirLock::new);
+
+            if let Some(mut history) = self.data_usage() {
+                history.add(day, usage);
+                self.save_data_usage(&history);
+            }
+        }
+    }
+
+    /// The access points resolved last, and how long ago that was.
+    pub fn access_points(&self) -> Option<(Vec<String>, Duration)> {
+        let location = self.access_points_location.as_ref()?;
+
+        let read = || {
+            let mut file = File::open(location)?;
+            let age = file.metadata()?.modified()?.elapsed().unwrap_or_default();
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            let aps = serde_json::from_str(&contents)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            Ok::<_, io::Error>((aps, age))
+        };
+
+        match read() {
+            Ok(aps) => Some(aps),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Error reading access points from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn save_access_points(&self, aps: &[String]) {
+        if let Some(location) = &self.access_points_location {
+            let result = write_atomically(location, |file| {
+                let data = serde_json::to_string(aps)?;
+                write!(file, "{}", data)
+            });
+
+            if let Err(e) = result {
+                warn!("Cannot save access points to cache: {}", e);
+            }
+        }
+    }
+
+    fn offline_track_path(&self, track: SpotifyId) -> Option<PathBuf> {
+        let name = track.to_base16().ok()?;
+        let location = self.offline_tracks_location.as_ref()?;
+        Some(location.join(format!("{}.json", name)))
+    }
+
+    /// How to play `track` offline, if it was saved and its audio file is still in the cache.
+    pub fn offline_track(&self, track: SpotifyId) -> Option<OfflineTrack> {
+        let location = self.offline_track_path(track)?;
+
+        let read = || {
+            let mut file = File::open(&location)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            serde_json::from_str::<OfflineTrack>(&contents)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        };
+
+        match read() {
+            Ok(offline_track) => {
+                let file_path = self.file_path(FileId(offline_track.file_id))?;
+                Some(offline_track).filter(|_| file_path.exists())
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Error reading offline track from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn save_offline_track(&self, track: SpotifyId, offline_track: &OfflineTrack) {
+        if let Some(location) = self.offline_track_path(track) {
+            let result = location
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| {
+                    write_atomically(&location, |file| {
+                        let data = serde_json::to_string(offline_track)?;
+                        write!(file, "{}", data)
+                    })
+                });
+
+            if let Err(e) = result {
+                warn!("Cannot save offline track to cache: {}", e);
+            }
+        }
+    }
+
+    /// Whether audio files are cached at all.
+    pub fn has_audio_cache(&self) -> bool {
+        self.audio_location.is_some()
+    }
+
+    /// Returns the number of files and their total size in the audio cache.
+    pub fn audio_cache_size(&self) -> Option<(usize, u64)> {
+        fn dir_size(path: &Path) -> io::Result<(usize, u64)> {
+            let mut files = 0;
+            let mut size = 0;
+
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                if entry.file_name() == LOCK_FILE {
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+
+                if metadata.is_dir() {
+                    let (dir_files, dir_size) = dir_size(&entry.path())?;
+                    files += dir_files;
+                    size += dir_size;
+                } else if metadata.is_file() {
+                    files += 1;
+                    size += metadata.len();
+                }
+            }
+
+            Ok((files, size))
+        }
+
+        let location = self.audio_location.as_ref()?;
+
+        match dir_size(location) {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                warn!("Could not read audio cache {:?}: {}", location, e);
+                None
+            }
+        }
+    }
+
     fn file_path(&self, file: FileId) -> Option<PathBuf> {
         match file.to_base16() {
             Ok(name) => self.audio_location.as_ref().map(|location| {
@@ -390,20 +1005,31 @@ impl Cache {
         };
         let parent = path.parent().unwrap();
 
+        let _lock = self.audio_location.as_deref().and_then(DirLock::new);
+
+        // Another process sharing this cache might have saved it already.
+        if path.exists() {
+            debug!("File {} is already in the cache", file);
+            return;
+        }
+
         let result = fs::create_dir_all(parent)
-            .and_then(|_| File::create(&path))
-            .and_then(|mut file| io::copy(contents, &mut file));
+            .and_then(|_| write_atomically(&path, |file| io::copy(contents, file)));
 
-        if let Ok(size) = result {
-            if let Some(limiter) = self.size_limiter.as_deref() {
-                limiter.add(&path, size);
-                limiter.prune();
+        match result {
+            Ok(size) => {
+                if let Some(limiter) = self.size_limiter.as_deref() {
+                    limiter.add(&path, size);
+                    limiter.prune();
+                }
             }
+            Err(e) => warn!("Cannot save file to cache: {}", e),
         }
     }
 
     pub fn remove_file(&self, file: FileId) -> Result<(), RemoveFileError> {
         let path = self.file_path(file).ok_or(RemoveFileError(()))?;
+        let _lock = self.audio_location.as_deref().and_then(DirLock::new);
 
         if let Err(err) = fs::remove_file(&path) {
             warn!("Unable to remove file from cache: {}", err);
@@ -467,4 +1093,60 @@ mod test {
         assert!(limiter.remove(Path::new("c")));
         assert!(!limiter.exceeds_limit());
     }
+
+    #[test]
+    fn test_device_id_per_name() {
+        let path = std::env::temp_dir().join(format!("librespot-cache-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&path), None, None).unwrap();
+
+        cache.save_device_id("kitchen", "1234");
+        cache.save_device_preferences("kitchen", &DevicePreferences::default());
+        assert_eq!(cache.device_id("kitchen").as_deref(), Some("1234"));
+        assert_eq!(cache.device_id("bedroom"), None);
+
+        cache.save_device_id("bedroom", "5678");
+        assert_eq!(cache.device_id("kitchen").as_deref(), Some("1234"));
+        assert_eq!(cache.device_id("bedroom").as_deref(), Some("5678"));
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exported_credentials_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("librespot-export-{}", std::process::id()));
+        let cache = Cache::new(Some(&path), None, None, None).unwrap();
+        let location = path.join("exported.json");
+
+        let credentials = Credentials::with_password("user", "password");
+        cache.export_credentials(&location, &credentials).unwrap();
+
+        let mode = fs::metadata(&location).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_failed_import_is_an_error() {
+        let path = std::env::temp_dir().join(format!("librespot-import-{}", std::process::id()));
+        let cache = Cache::new(Some(&path), None, None, None).unwrap();
+        let location = path.join("exported.json");
+
+        let credentials = Credentials::with_password("user", "password");
+        cache.export_credentials(&location, &credentials).unwrap();
+
+        // A directory where the credentials should be written
+        fs::create_dir_all(path.join("credentials.json")).unwrap();
+        assert!(cache.import_credentials(&location).is_err());
+
+        fs::remove_dir_all(path.join("credentials.json")).unwrap();
+        let imported = cache.import_credentials(&location).unwrap();
+        assert_eq!(imported.username, "user");
+        assert_eq!(cache.credentials().unwrap().username, "user");
+
+        let _ = fs::remove_dir_all(path);
+    }
 }
@@ -1,13 +1,35 @@
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
+use crate::dns::DnsResolver;
+
 #[derive(Clone, Debug)]
 pub struct SessionConfig {
     pub user_agent: String,
     pub device_id: String,
     pub proxy: Option<Url>,
-    pub ap_port: Option<u16>,
+    // access point ports to connect to, in order of preference; any if empty
+    pub ap_ports: Vec<u16>,
+    // only connect to access points on port 443, resolving them over HTTPS
+    pub force_tls: bool,
+    // connect to this access point ("host:port") instead of resolving one
+    pub ap_address: Option<String>,
+    // local address outbound connections are made from
+    pub bind_address: Option<IpAddr>,
+    // how the host names of Spotify's servers and the proxy are resolved
+    pub dns_resolver: DnsResolver,
+    // how long connecting to the access point may take, and how long it may stay silent beyond
+    // its ping interval
+    pub network_timeout: Option<Duration>,
+    // TCP keepalive interval of the access point connection
+    pub keepalive_interval: Option<Duration>,
+    // bytes per calendar month (UTC), after which the lowest bitrate is used
+    pub data_cap: Option<u64>,
+    // bytes per second audio data is requested at on average
+    pub max_download_rate: Option<usize>,
 }
 
 impl Default for SessionConfig {
@@ -17,7 +39,15 @@ impl Default for SessionConfig {
             user_agent: crate::version::VERSION_STRING.to_string(),
             device_id,
             proxy: None,
-            ap_port: None,
+            ap_ports: Vec::new(),
+            force_tls: false,
+            ap_address: None,
+            bind_address: None,
+            dns_resolver: DnsResolver::System,
+            network_timeout: None,
+            keepalive_interval: None,
+            data_cap: None,
+            max_download_rate: None,
         }
     }
 }
@@ -117,6 +147,50 @@ impl Default for DeviceType {
     }
 }
 
+// Where a playback command came from: the local `Spirc` handle (e.g. LMS),
+// or a Spotify Connect client on the network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlSource {
+    Local,
+    Connect,
+}
+
+impl fmt::Display for ControlSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlSource::Local => f.write_str("local"),
+            ControlSource::Connect => f.write_str("connect"),
+        }
+    }
+}
+
+// Decides who wins when local and Connect commands arrive at about the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlPolicy {
+    LastWriterWins,
+    LocalPriority,
+    ConnectPriority,
+}
+
+impl FromStr for ControlPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::ControlPolicy::*;
+        match s.to_lowercase().as_ref() {
+            "last" | "last-writer-wins" => Ok(LastWriterWins),
+            "local" | "lms" => Ok(LocalPriority),
+            "connect" => Ok(ConnectPriority),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ControlPolicy {
+    fn default() -> ControlPolicy {
+        ControlPolicy::LastWriterWins
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ConnectConfig {
     pub name: String,
@@ -124,6 +198,19 @@ pub struct ConnectConfig {
     pub initial_volume: Option<u16>,
     pub has_volume_ctrl: bool,
     pub autoplay: bool,
+    pub control_policy: ControlPolicy,
+    /// Stop playback after this many unplayable tracks in a row have been skipped.
+    pub max_consecutive_skips: u32,
+    /// Volume steps announced to Spotify clients, 0 for a fixed volume. Defaults to 64 with volume
+    /// control and 0 without.
+    pub volume_steps: Option<u16>,
+    /// The highest volume Spotify clients can set, from 0 to `u16::MAX`.
+    pub max_volume: u16,
+    /// Round the volume Spotify clients set to the volume steps, so dragging the volume slider
+    /// changes it less often.
+    pub quantize_volume: bool,
+    /// Announce that podcast episodes can be played.
+    pub can_play_episodes: bool,
 }
 
 impl Default for ConnectConfig {
@@ -134,6 +221,12 @@ impl Default for ConnectConfig {
             initial_volume: Some(50),
             has_volume_ctrl: true,
             autoplay: false,
+            control_policy: ControlPolicy::default(),
+            max_consecutive_skips: 10,
+            volume_steps: None,
+            max_volume: u16::MAX,
+            quantize_volume: false,
+            can_play_episodes: true,
         }
     }
 }
@@ -35,6 +35,44 @@ pub async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
     Ok(codec.framed(connection))
 }
 
+/// The access point's side of `handshake`, for `crate::mock_ap`.
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+    mut connection: T,
+) -> io::Result<Framed<T, ApCodec>> {
+    let mut accumulator = Vec::new();
+    let header = read_into_accumulator(&mut connection, 6, &mut accumulator).await?;
+    let size = BigEndian::read_u32(&header[2..]) as usize;
+    let data = read_into_accumulator(&mut connection, size - 6, &mut accumulator).await?;
+    let hello = ClientHello::parse_from_bytes(data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let remote_key = hello
+        .get_login_crypto_hello()
+        .get_diffie_hellman()
+        .get_gc()
+        .to_owned();
+
+    let local_keys = DhLocalKeys::random(&mut thread_rng());
+    ap_response(&mut connection, &mut accumulator, local_keys.public_key()).await?;
+
+    let shared_secret = local_keys.shared_secret(&remote_key);
+    let (challenge, send_key, recv_key) = compute_keys(&shared_secret, &accumulator);
+
+    let response: ClientResponsePlaintext = recv_packet(&mut connection, &mut Vec::new()).await?;
+    let hmac = response
+        .get_login_crypto_response()
+        .get_diffie_hellman()
+        .get_hmac();
+    if hmac != &challenge[..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid challenge response",
+        ));
+    }
+
+    Ok(ApCodec::new(&recv_key, &send_key).framed(connection))
+}
+
 async fn client_hello<T>(connection: &mut T, gc: Vec<u8>) -> io::Result<Vec<u8>>
 where
     T: AsyncWrite + Unpin,
@@ -94,6 +132,43 @@ where
     Ok(())
 }
 
+#[cfg(any(test, feature = "test-utils"))]
+async fn ap_response<T>(connection: &mut T, acc: &mut Vec<u8>, gs: Vec<u8>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut server_nonce = vec![0; 0x10];
+    thread_rng().fill_bytes(&mut server_nonce);
+
+    let mut packet = APResponseMessage::new();
+    let challenge = packet.mut_challenge();
+    challenge
+        .mut_login_crypto_challenge()
+        .mut_diffie_hellman()
+        .set_gs(gs);
+    challenge
+        .mut_login_crypto_challenge()
+        .mut_diffie_hellman()
+        .set_server_signature_key(1);
+    challenge
+        .mut_login_crypto_challenge()
+        .mut_diffie_hellman()
+        .set_gs_signature(vec![0; 0x100]);
+    challenge.mut_fingerprint_challenge();
+    challenge.mut_pow_challenge();
+    challenge.mut_crypto_challenge().mut_shannon();
+    challenge.set_server_nonce(server_nonce);
+
+    let mut buffer = vec![];
+    let size = 4 + packet.compute_size();
+    <Vec<u8> as WriteBytesExt>::write_u32::<BigEndian>(&mut buffer, size).unwrap();
+    packet.write_to_vec(&mut buffer).unwrap();
+
+    connection.write_all(&buffer[..]).await?;
+    acc.extend_from_slice(&buffer);
+    Ok(())
+}
+
 async fn recv_packet<T, M>(connection: &mut T, acc: &mut Vec<u8>) -> io::Result<M>
 where
     T: AsyncRead + Unpin,
@@ -2,24 +2,41 @@ mod codec;
 mod handshake;
 
 pub use self::codec::ApCodec;
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) use self::handshake::accept;
 pub use self::handshake::handshake;
 
+use std::convert::TryFrom;
 use std::io::{self, ErrorKind};
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures_util::{SinkExt, StreamExt};
+use hyper::client::HttpConnector;
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
 use protobuf::{self, Message, ProtobufError};
+use socket2::{SockRef, TcpKeepalive};
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
 use tokio_util::codec::Framed;
 use url::Url;
 
 use crate::authentication::Credentials;
+use crate::dns::DnsResolver;
 use crate::protocol::keyexchange::{APLoginFailed, ErrorCode};
 use crate::proxytunnel;
 use crate::version;
 
-pub type Transport = Framed<TcpStream, ApCodec>;
+/// A connection to an access point, either direct or tunneled through a proxy.
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+pub type Transport = Framed<Box<dyn Socket>, ApCodec>;
 
 fn login_error_message(code: &ErrorCode) -> &'static str {
     pub use ErrorCode::*;
@@ -58,49 +75,140 @@ impl From<APLoginFailed> for AuthenticationError {
     }
 }
 
-pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport> {
-    let socket = if let Some(proxy_url) = proxy {
-        info!("Using proxy \"{}\"", proxy_url);
-
-        let socket_addr = proxy_url.socket_addrs(|| None).and_then(|addrs| {
-            addrs.into_iter().next().ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Can't resolve proxy server address",
-                )
-            })
-        })?;
-        let socket = TcpStream::connect(&socket_addr).await?;
-
-        let uri = addr.parse::<http::Uri>().map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Can't parse access point address",
-            )
-        })?;
-        let host = uri.host().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "The access point address contains no hostname",
-            )
-        })?;
-        let port = uri.port().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "The access point address contains no port",
-            )
-        })?;
-
-        proxytunnel::proxy_connect(socket, host, port.as_str()).await?
+/// An http(s) connector for hyper clients, which connects from `bind_address` if set and
+/// resolves host names with `dns`.
+pub fn https_connector(
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+) -> HttpsConnector<HttpConnector<DnsResolver>> {
+    let mut connector = HttpConnector::new_with_resolver(dns.clone());
+    connector.enforce_http(false);
+    connector.set_local_address(bind_address);
+
+    HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(connector)
+}
+
+// Tries every address `host` resolves to in turn, so that hosts which also have IPv4
+// addresses can be reached from IPv6-only networks and vice versa.
+async fn connect_tcp(
+    host: &str,
+    port: u16,
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+    keepalive: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let mut last_error = None;
+
+    for socket_addr in dns.lookup(host, port).await? {
+        if bind_address.map_or(false, |ip| ip.is_ipv4() != socket_addr.is_ipv4()) {
+            continue;
+        }
+
+        let socket = if socket_addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(ip) = bind_address {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+
+        match socket.connect(socket_addr).await {
+            Ok(stream) => {
+                if let Some(interval) = keepalive {
+                    let keepalive = TcpKeepalive::new()
+                        .with_time(interval)
+                        .with_interval(interval);
+                    SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+                }
+                return Ok(stream);
+            }
+            Err(e) => {
+                debug!("Connecting to {} failed: {}", socket_addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Can't resolve \"{}\" to a usable address", host),
+        )
+    }))
+}
+
+// Opens the connection to the proxy itself, which is encrypted for https:// proxies.
+async fn connect_proxy(
+    proxy_url: &Url,
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+    keepalive: Option<Duration>,
+) -> io::Result<Box<dyn Socket>> {
+    let host = proxy_url.host_str().unwrap_or_default();
+    let port = proxy_url.port_or_known_default().unwrap_or_default();
+    let socket = connect_tcp(host, port, bind_address, dns, keepalive).await?;
+
+    if proxy_url.scheme() != "https" {
+        return Ok(Box::new(socket));
+    }
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name =
+        ServerName::try_from(host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_native_roots()
+        .with_no_client_auth();
+    let socket = TlsConnector::from(Arc::new(tls_config))
+        .connect(server_name, socket)
+        .await?;
+
+    Ok(Box::new(socket))
+}
+
+/// Connects to the access point `addr`, probing the connection with TCP keepalives at the
+/// `keepalive` interval if set.
+pub async fn connect(
+    addr: String,
+    proxy: Option<&Url>,
+    bind_address: Option<IpAddr>,
+    dns: &DnsResolver,
+    keepalive: Option<Duration>,
+) -> io::Result<Transport> {
+    let uri = addr.parse::<http::Uri>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Can't parse access point address",
+        )
+    })?;
+    let host = uri.host().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The access point address contains no hostname",
+        )
+    })?;
+    let port = uri.port().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The access point address contains no port",
+        )
+    })?;
+
+    let proxy = proxy.filter(|_| !proxytunnel::is_excluded(host));
+
+    let socket: Box<dyn Socket> = if let Some(proxy_url) = proxy {
+        info!("Using proxy \"{}\"", proxytunnel::redacted(proxy_url));
+
+        let socket = connect_proxy(proxy_url, bind_address, dns, keepalive).await?;
+        proxytunnel::proxy_connect(socket, proxy_url, host, port.as_str()).await?
     } else {
-        let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                "Can't resolve access point address",
-            )
-        })?;
-
-        TcpStream::connect(&socket_addr).await?
+        Box::new(connect_tcp(host, port.as_u16(), bind_address, dns, keepalive).await?)
     };
 
     handshake(socket).await
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ops::Add;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::util::AtomicU64;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Days from 1 January of year 1 to the UNIX epoch.
+const EPOCH_DAYS_FROM_CE: u64 = 719_163;
+
+// Long enough for the longest month, and the stats of the last 30 days.
+const HISTORY_DAYS: u64 = 31;
+
+/// Bytes received from Spotify, split into audio data and everything else
+/// (metadata, keys, ...).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataUsage {
+    pub audio: u64,
+    pub metadata: u64,
+}
+
+impl DataUsage {
+    pub fn total(&self) -> u64 {
+        self.audio + self.metadata
+    }
+}
+
+impl Add for DataUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            audio: self.audio + other.audio,
+            metadata: self.metadata + other.metadata,
+        }
+    }
+}
+
+/// Days since the UNIX epoch (UTC).
+pub fn today() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur,
+        Err(err) => err.duration(),
+    }
+    .as_secs()
+        / SECONDS_PER_DAY
+}
+
+/// The first day of the (UTC) calendar month `day` is in, both in days since the UNIX epoch.
+pub fn month_start(day: u64) -> u64 {
+    let day_of_month = i32::try_from(day + EPOCH_DAYS_FROM_CE)
+        .ok()
+        .and_then(NaiveDate::from_num_days_from_ce_opt)
+        .map_or(1, |date| date.day());
+    day - u64::from(day_of_month - 1)
+}
+
+/// Data usage per day, as persisted in the cache.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DataUsageHistory {
+    days: BTreeMap<u64, DataUsage>,
+}
+
+impl DataUsageHistory {
+    pub fn add(&mut self, day: u64, usage: DataUsage) {
+        let entry = self.days.entry(day).or_default();
+        *entry = *entry + usage;
+
+        let oldest = day.saturating_sub(HISTORY_DAYS - 1);
+        self.days.retain(|&d, _| d >= oldest);
+    }
+
+    pub fn day(&self, day: u64) -> DataUsage {
+        self.days.get(&day).copied().unwrap_or_default()
+    }
+
+    /// Sum of the `days` days up to and including `day`.
+    pub fn last_days(&self, day: u64, days: u64) -> DataUsage {
+        self.days
+            .range(day.saturating_sub(days.saturating_sub(1))..=day)
+            .fold(DataUsage::default(), |sum, (_, usage)| sum + *usage)
+    }
+
+    /// Sum of the days of the calendar month up to and including `day`, which the data cap
+    /// applies to.
+    pub fn month(&self, day: u64) -> DataUsage {
+        self.last_days(day, day - month_start(day) + 1)
+    }
+}
+
+/// Thread safe counters for the data received by a session. Bytes which have
+/// not been written to the cache yet are tracked separately.
+#[derive(Default)]
+pub(crate) struct DataUsageCounter {
+    audio: AtomicU64,
+    metadata: AtomicU64,
+    unsaved_audio: AtomicU64,
+    unsaved_metadata: AtomicU64,
+}
+
+impl DataUsageCounter {
+    pub fn add_audio(&self, bytes: u64) {
+        self.audio.fetch_add(bytes, Ordering::Relaxed);
+        self.unsaved_audio.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_metadata(&self, bytes: u64) {
+        self.metadata.fetch_add(bytes, Ordering::Relaxed);
+        self.unsaved_metadata.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> DataUsage {
+        DataUsage {
+            audio: self.audio.load(Ordering::Relaxed),
+            metadata: self.metadata.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn unsaved(&self) -> u64 {
+        self.unsaved_audio.load(Ordering::Relaxed) + self.unsaved_metadata.load(Ordering::Relaxed)
+    }
+
+    pub fn take_unsaved(&self) -> DataUsage {
+        DataUsage {
+            audio: self.unsaved_audio.swap(0, Ordering::Relaxed),
+            metadata: self.unsaved_metadata.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn history() {
+        let mut history = DataUsageHistory::default();
+        let usage = DataUsage {
+            audio: 100,
+            metadata: 10,
+        };
+
+        history.add(1000, usage);
+        history.add(1000, usage);
+        history.add(1020, usage);
+
+        assert_eq!(history.day(1000).total(), 220);
+        assert_eq!(history.last_days(1020, 30).audio, 300);
+        assert_eq!(history.last_days(1020, 10).audio, 100);
+
+        // old days are dropped
+        history.add(1000 + HISTORY_DAYS, usage);
+        assert_eq!(history.day(1000), DataUsage::default());
+    }
+
+    #[test]
+    fn month() {
+        // 1 January 1970, 31 January 2024, 1 February 2024 and 29 February 2024
+        assert_eq!(month_start(0), 0);
+        assert_eq!(month_start(19753), 19723);
+        assert_eq!(month_start(19754), 19754);
+        assert_eq!(month_start(19782), 19754);
+
+        let mut history = DataUsageHistory::default();
+        let usage = DataUsage {
+            audio: 100,
+            metadata: 10,
+        };
+
+        history.add(19753, usage);
+        history.add(19754, usage);
+        history.add(19760, usage);
+
+        assert_eq!(history.month(19753).total(), 110);
+        assert_eq!(history.month(19760).total(), 220);
+        assert_eq!(history.last_days(19760, 30).total(), 330);
+    }
+}
@@ -0,0 +1,317 @@
+//! Host name resolution with a specific DNS server or DNS-over-HTTPS instead of the system's
+//! resolver, for networks whose resolvers return broken results for Spotify's hosts.
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::service::Service;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+use tokio::net::{lookup_host, UdpSocket};
+use url::Url;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+// large enough for the answers to a single question without EDNS
+const MAX_UDP_RESPONSE: usize = 512;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// How host names are resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsResolver {
+    /// The operating system's resolver.
+    System,
+    /// A DNS server, queried over UDP.
+    Server(SocketAddr),
+    /// A DNS-over-HTTPS (RFC 8484) endpoint, eg. https://cloudflare-dns.com/dns-query. Its own
+    /// host is resolved with the system's resolver.
+    Https(Url),
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::System
+    }
+}
+
+impl DnsResolver {
+    /// Returns the IPv4 and IPv6 addresses of `host`, with `port`.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let (v4, v6) = match self {
+            Self::System => return Ok(lookup_host((host, port)).await?.collect()),
+            Self::Server(server) => {
+                future::join(
+                    query_server(*server, host, TYPE_A),
+                    query_server(*server, host, TYPE_AAAA),
+                )
+                .await
+            }
+            Self::Https(url) => {
+                future::join(
+                    query_https(url, host, TYPE_A),
+                    query_https(url, host, TYPE_AAAA),
+                )
+                .await
+            }
+        };
+
+        let ips = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default()),
+        };
+        let addrs: Vec<SocketAddr> = ips.map(|ip| SocketAddr::new(ip, port)).collect();
+        debug!("Resolved \"{}\" to {:?}", host, addrs);
+
+        Ok(addrs)
+    }
+}
+
+// Lets hyper's connector resolve with it. hyper sets the port afterwards.
+impl Service<Name> for DnsResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move { Ok(resolver.lookup(name.as_str(), 0).await?.into_iter()) })
+    }
+}
+
+async fn query_server(server: SocketAddr, host: &str, record_type: u16) -> io::Result<Vec<IpAddr>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+
+    let id = rand::random();
+    socket.send(&query(id, host, record_type)?).await?;
+
+    let mut response = [0; MAX_UDP_RESPONSE];
+    let len = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS server didn't respond"))??;
+
+    parse_response(id, &response[..len])
+}
+
+async fn query_https(url: &Url, host: &str, record_type: u16) -> io::Result<Vec<IpAddr>> {
+    let other = |e: String| io::Error::new(io::ErrorKind::Other, e);
+
+    // the ID is 0 for responses to be cacheable
+    let request = Request::post(url.as_str())
+        .header(ACCEPT, DNS_MESSAGE)
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .body(Body::from(query(0, host, record_type)?))
+        .map_err(|e| other(e.to_string()))?;
+
+    let mut connector = HttpConnector::new();
+    connector.enforce_http(false);
+    let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_only()
+        .enable_http1()
+        .wrap_connector(connector);
+
+    let response = tokio::time::timeout(
+        DNS_TIMEOUT,
+        Client::builder()
+            .build::<_, Body>(connector)
+            .request(request),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS-over-HTTPS request timed out"))?
+    .map_err(|e| other(e.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(other(format!(
+            "DNS-over-HTTPS server responded with {}",
+            response.status()
+        )));
+    }
+
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| other(e.to_string()))?;
+    parse_response(0, &body)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A query with a single question, asking for recursion.
+fn query(id: u16, host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(18 + host.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid host name \"{}\"", host),
+            ));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(message)
+}
+
+// Returns the addresses among the answers. Others, eg. the CNAMEs leading to them, are skipped.
+fn parse_response(id: u16, message: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let read_u16 = |pos: usize| {
+        message
+            .get(pos..pos + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| invalid_data("Truncated DNS response"))
+    };
+
+    if read_u16(0)? != id {
+        return Err(invalid_data("Unexpected DNS response ID"));
+    }
+
+    let flags = read_u16(2)?;
+    match flags & 0x000f {
+        0 => (),
+        // no such domain
+        3 => return Ok(Vec::new()),
+        rcode => {
+            let error = format!("DNS server responded with error {}", rcode);
+            return Err(io::Error::new(io::ErrorKind::Other, error));
+        }
+    }
+
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        let record_type = read_u16(pos)?;
+        let class = read_u16(pos + 2)?;
+        let len = read_u16(pos + 8)? as usize;
+        pos += 10;
+
+        let data = message
+            .get(pos..pos + len)
+            .ok_or_else(|| invalid_data("Truncated DNS response"))?;
+        pos += len;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        match (record_type, data.len()) {
+            (TYPE_A, 4) => ips.push(IpAddr::from([data[0], data[1], data[2], data[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                ips.push(IpAddr::from(octets));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(ips)
+}
+
+// Returns the position after the name starting at `pos`, which may end in a pointer.
+fn skip_name(message: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        match message.get(pos) {
+            Some(0) => return Ok(pos + 1),
+            Some(len) if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            Some(len) => pos += 1 + *len as usize,
+            None => return Err(invalid_data("Truncated DNS response")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let query = query(0x1234, "ap.spotify.com", TYPE_A).unwrap();
+
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x02ap\x07spotify\x03com\x00\x00\x01\x00\x01");
+        assert_eq!(query, expected);
+
+        assert!(super::query(0, "ap..spotify.com", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = query(0x1234, "ap.spotify.com", TYPE_A).unwrap();
+        // a response with two answers
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+        // a CNAME pointing back to the question's name
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        // and its address
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+
+        let ips = parse_response(0x1234, &response).unwrap();
+        assert_eq!(ips, [IpAddr::from([10, 0, 0, 1])]);
+
+        assert!(parse_response(0x4321, &response).is_err());
+        assert!(parse_response(0x1234, &response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let mut response = query(0x1234, "ap.spotify.com", TYPE_AAAA).unwrap();
+        response[2] = 0x81;
+        response[3] = 0x83;
+
+        assert!(parse_response(0x1234, &response).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ip_address_is_not_resolved() {
+        let resolver = DnsResolver::Server(([192, 0, 2, 1], 53).into());
+        let addrs = resolver.lookup("[::1]", 4070).await.unwrap();
+
+        assert_eq!(addrs, [SocketAddr::from((Ipv6Addr::LOCALHOST, 4070))]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Plain HTTP requests, eg. for cover art, which go through the proxy and bind address of
+//! the session configuration like everything else.
+
+use std::io;
+
+use hyper::body::Bytes;
+use hyper::client::connect::Connect;
+use hyper::header::{LOCATION, USER_AGENT};
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_proxy::ProxyConnector;
+use thiserror::Error;
+use url::Url;
+
+use crate::config::SessionConfig;
+use crate::connection;
+use crate::proxytunnel;
+use crate::version::VERSION_STRING;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("request failed: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("invalid URL: {0}")]
+    Url(#[from] hyper::http::uri::InvalidUri),
+    #[error("server responded with {0}")]
+    Status(StatusCode),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("invalid redirect to {0}")]
+    InvalidRedirect(String),
+    #[error("redirect to {0} isn't HTTPS")]
+    InsecureRedirect(String),
+}
+
+// GitHub release downloads, for example, redirect to a CDN.
+const MAX_REDIRECTS: usize = 5;
+
+/// Fetches `url` and returns the response body, following redirects.
+pub async fn get(url: &str, config: &SessionConfig) -> Result<Bytes, HttpError> {
+    request(Method::GET, url, &[], Bytes::new(), config).await
+}
+
+/// Sends a request with these additional headers and body, eg. to the Web API with an
+/// authorization header, and returns the response body, following redirects.
+pub async fn request(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Bytes,
+    config: &SessionConfig,
+) -> Result<Bytes, HttpError> {
+    let uri: Uri = url.parse()?;
+    let connector = connection::https_connector(config.bind_address, &config.dns_resolver);
+
+    match &config.proxy {
+        Some(proxy_url) => {
+            let proxy = proxytunnel::hyper_proxy(proxy_url);
+            let client =
+                Client::builder().build::<_, Body>(ProxyConnector::from_proxy(connector, proxy)?);
+            fetch(client, method, uri, headers, body).await
+        }
+        None => {
+            let client = Client::builder().build::<_, Body>(connector);
+            fetch(client, method, uri, headers, body).await
+        }
+    }
+}
+
+async fn fetch<C>(
+    client: Client<C>,
+    method: Method,
+    mut uri: Uri,
+    headers: &[(&str, &str)],
+    body: Bytes,
+) -> Result<Bytes, HttpError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(uri.clone())
+            .header(USER_AGENT, VERSION_STRING);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Body::from(body.clone()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let response = client.request(request).await?;
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(HttpError::Status(status))?;
+            uri = redirect_uri(&uri, location)?;
+            continue;
+        }
+
+        if !status.is_success() {
+            return Err(HttpError::Status(status));
+        }
+
+        return Ok(hyper::body::to_bytes(response.into_body()).await?);
+    }
+
+    Err(HttpError::TooManyRedirects)
+}
+
+// `location` may be relative to the URL which redirected to it. Redirects only ever lead to
+// HTTPS URLs, so nothing downloaded, eg. by the self update, is sent in the clear.
+fn redirect_uri(uri: &Uri, location: &str) -> Result<Uri, HttpError> {
+    let invalid = || HttpError::InvalidRedirect(location.to_string());
+    let url = Url::parse(&uri.to_string())
+        .and_then(|base| base.join(location))
+        .map_err(|_| invalid())?;
+
+    if url.scheme() != "https" {
+        return Err(HttpError::InsecureRedirect(url.to_string()));
+    }
+
+    url.as_str().parse().map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redirect_uri() {
+        let uri: Uri = "https://github.com/releases/download/v1/spotty"
+            .parse()
+            .unwrap();
+        let redirect = |location| redirect_uri(&uri, location).map(|uri| uri.to_string());
+
+        assert_eq!(
+            redirect("https://objects.githubusercontent.com/spotty").unwrap(),
+            "https://objects.githubusercontent.com/spotty"
+        );
+        assert_eq!(
+            redirect("/releases/download/v2/spotty").unwrap(),
+            "https://github.com/releases/download/v2/spotty"
+        );
+        assert_eq!(
+            redirect("spotty.exe").unwrap(),
+            "https://github.com/releases/download/v1/spotty.exe"
+        );
+        assert!(matches!(
+            redirect("http://github.com/spotty"),
+            Err(HttpError::InsecureRedirect(_))
+        ));
+        assert!(matches!(
+            redirect("ftp://github.com/spotty"),
+            Err(HttpError::InsecureRedirect(_))
+        ));
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(clippy::unused_io_amount)]
+#![deny(clippy::print_stdout)]
 
 #[macro_use]
 extern crate log;
@@ -15,13 +16,21 @@ pub mod cache;
 pub mod channel;
 pub mod config;
 mod connection;
+pub mod data_usage;
 #[doc(hidden)]
 pub mod diffie_hellman;
+pub mod dns;
+pub mod http;
 pub mod keymaster;
 pub mod mercury;
+#[cfg(any(test, feature = "test-utils"))]
+#[doc(hidden)]
+pub mod mock_ap;
+pub mod oauth;
 mod proxytunnel;
 pub mod session;
 pub mod spotify_id;
+pub mod throttle;
 #[doc(hidden)]
 pub mod util;
 pub mod version;
@@ -0,0 +1,238 @@
+//! A local stand-in for Spotify's access points, so the connection, login, reconnect and mercury
+//! handling can be tested without real credentials.
+
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use bytes::Bytes;
+use futures_util::future::{self, Either};
+use futures_util::{SinkExt, StreamExt};
+use protobuf::Message;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::config::SessionConfig;
+use crate::connection;
+use crate::protocol::authentication::{
+    APWelcome, AccountType, AuthenticationType, ClientResponseEncrypted,
+};
+use crate::protocol::keyexchange::{APLoginFailed, ErrorCode};
+use crate::protocol::mercury::Header;
+
+/// The credentials handed out on login, to be stored and reused instead of the password.
+pub const REUSABLE_CREDENTIALS: &[u8] = b"mock-ap-credentials";
+
+#[derive(Default)]
+struct State {
+    login_error: Mutex<Option<ErrorCode>>,
+    responses: Mutex<Vec<(String, Vec<u8>)>>,
+    requests: Mutex<Vec<String>>,
+    logins: AtomicUsize,
+}
+
+/// An access point listening on localhost, which accepts any login unless told otherwise.
+pub struct MockAp {
+    address: SocketAddr,
+    state: Arc<State>,
+    disconnect: broadcast::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MockAp {
+    pub async fn start() -> io::Result<MockAp> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(State::default());
+        let (disconnect, _) = broadcast::channel(1);
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            let disconnect = disconnect.clone();
+            async move {
+                loop {
+                    let socket = match listener.accept().await {
+                        Ok((socket, _)) => socket,
+                        Err(e) => {
+                            warn!("Mock AP stopped accepting connections: {}", e);
+                            break;
+                        }
+                    };
+
+                    let state = state.clone();
+                    let disconnect = disconnect.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(socket, state, disconnect).await {
+                            debug!("Mock AP connection failed: {}", e);
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(MockAp {
+            address,
+            state,
+            disconnect,
+            task,
+        })
+    }
+
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// A config which connects to this access point instead of resolving Spotify's.
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            ap_address: Some(self.address()),
+            ..SessionConfig::default()
+        }
+    }
+
+    /// Fail the following logins with `error`, or accept them again with `None`.
+    pub fn reject_logins(&self, error: Option<ErrorCode>) {
+        *self.state.login_error.lock().unwrap() = error;
+    }
+
+    /// Answer mercury GET requests for URIs starting with `prefix` with `payload`. Requests
+    /// without a response get a 404, all others succeed without a payload.
+    pub fn respond(&self, prefix: &str, payload: Vec<u8>) {
+        let mut responses = self.state.responses.lock().unwrap();
+        responses.push((prefix.to_string(), payload));
+    }
+
+    /// The number of successful logins so far.
+    pub fn logins(&self) -> usize {
+        self.state.logins.load(Ordering::SeqCst)
+    }
+
+    /// The URIs of the mercury requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Close all open connections, as if the access point went away.
+    pub fn disconnect(&self) {
+        let _ = self.disconnect.send(());
+    }
+}
+
+impl Drop for MockAp {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.disconnect();
+    }
+}
+
+async fn serve(
+    socket: TcpStream,
+    state: Arc<State>,
+    mut disconnect: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let mut transport = connection::accept(socket).await?;
+
+    let (cmd, data) = match transport.next().await {
+        Some(packet) => packet?,
+        None => return Ok(()),
+    };
+    if cmd != 0xab {
+        let msg = format!("Expected a login packet, received: {}", cmd);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    let login = ClientResponseEncrypted::parse_from_bytes(data.as_ref())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let login_error = *state.login_error.lock().unwrap();
+    if let Some(error) = login_error {
+        let mut packet = APLoginFailed::new();
+        packet.set_error_code(error);
+        return transport
+            .send((0xad, packet.write_to_bytes().unwrap()))
+            .await;
+    }
+
+    let mut packet = APWelcome::new();
+    packet.set_canonical_username(login.get_login_credentials().get_username().to_owned());
+    packet.set_account_type_logged_in(AccountType::Spotify);
+    packet.set_credentials_type_logged_in(AccountType::Spotify);
+    packet.set_reusable_auth_credentials_type(
+        AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+    );
+    packet.set_reusable_auth_credentials(REUSABLE_CREDENTIALS.to_vec());
+    transport
+        .send((0xac, packet.write_to_bytes().unwrap()))
+        .await?;
+    state.logins.fetch_add(1, Ordering::SeqCst);
+
+    let mut disconnect = Box::pin(async move {
+        let _ = disconnect.recv().await;
+    });
+
+    loop {
+        let (cmd, data) = match future::select(transport.next(), &mut disconnect).await {
+            Either::Left((Some(packet), _)) => packet?,
+            Either::Left((None, _)) | Either::Right(_) => return Ok(()),
+        };
+        if let Some(response) = mercury_response(&state, cmd, data) {
+            transport.send(response).await?;
+        }
+    }
+}
+
+fn mercury_response(state: &State, cmd: u8, mut data: Bytes) -> Option<(u8, Vec<u8>)> {
+    if !(0xb2..=0xb4).contains(&cmd) {
+        return None;
+    }
+
+    let seq_len = BigEndian::read_u16(data.split_to(2).as_ref()) as usize;
+    let seq = data.split_to(seq_len);
+    let _flags = data.split_to(1);
+    let _count = data.split_to(2);
+    let header_len = BigEndian::read_u16(data.split_to(2).as_ref()) as usize;
+    let request = Header::parse_from_bytes(data.split_to(header_len).as_ref()).ok()?;
+
+    let uri = request.get_uri().to_string();
+    state.requests.lock().unwrap().push(uri.clone());
+
+    let payload = match request.get_method() {
+        "GET" => {
+            let responses = state.responses.lock().unwrap();
+            responses
+                .iter()
+                .find(|(prefix, _)| uri.starts_with(prefix))
+                .map(|(_, payload)| payload.clone())
+        }
+        _ => None,
+    };
+
+    let mut header = Header::new();
+    header.set_uri(uri);
+    header.set_status_code(match (request.get_method(), &payload) {
+        ("GET", None) => 404,
+        _ => 200,
+    });
+
+    let mut packet = Vec::new();
+    packet.write_u16::<BigEndian>(seq.len() as u16).unwrap();
+    packet.write_all(&seq).unwrap();
+    packet.write_u8(1).unwrap(); // Flags: FINAL
+    packet
+        .write_u16::<BigEndian>(1 + payload.is_some() as u16)
+        .unwrap(); // Part count
+
+    packet
+        .write_u16::<BigEndian>(header.compute_size() as u16)
+        .unwrap();
+    header.write_to_writer(&mut packet).unwrap();
+
+    if let Some(payload) = payload {
+        packet.write_u16::<BigEndian>(payload.len() as u16).unwrap();
+        packet.write_all(&payload).unwrap();
+    }
+
+    Some((cmd, packet))
+}
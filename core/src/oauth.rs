@@ -0,0 +1,291 @@
+//! Logging in through the Spotify accounts service, for when neither a password nor
+//! zeroconf discovery can be used.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use hyper_proxy::ProxyConnector;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use url::Url;
+
+use crate::config::SessionConfig;
+use crate::connection;
+use crate::proxytunnel;
+
+/// The client ID of the official Spotify desktop client, which may use the `streaming` scope.
+pub const CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+
+/// Scopes required to log into an access point with the resulting token.
+pub const SCOPES: &str = "streaming";
+
+/// The port of the redirect URI registered for [`CLIENT_ID`].
+pub const REDIRECT_PORT: u16 = 8898;
+
+const AUTHORIZATION_ENDPOINT: &str = "https://accounts.spotify.com/authorize";
+const DEVICE_AUTHORIZATION_ENDPOINT: &str = "https://accounts.spotify.com/oauth2/device/authorize";
+const TOKEN_ENDPOINT: &str = "https://accounts.spotify.com/api/token";
+
+const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+// How long to wait for the browser to be redirected back to us.
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+pub enum OAuthError {
+    #[error("request failed: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("invalid request: {0}")]
+    Request(#[from] hyper::http::Error),
+    #[error("invalid response: {0}")]
+    Response(#[from] serde_json::Error),
+    #[error("{error}: {description}")]
+    Denied { error: String, description: String },
+    #[error("the login was not completed in time")]
+    Expired,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// An access token issued by the accounts service.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+}
+
+/// The code a user has to enter on another device to authorize this one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+async fn post<T: DeserializeOwned>(
+    url: &str,
+    params: &[(&str, &str)],
+    config: &SessionConfig,
+) -> Result<T, OAuthError> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+
+    let request = Request::post(url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))?;
+
+    let connector = connection::https_connector(config.bind_address, &config.dns_resolver);
+
+    let response = match &config.proxy {
+        Some(proxy_url) => {
+            let proxy = proxytunnel::hyper_proxy(proxy_url);
+            Client::builder()
+                .build::<_, Body>(ProxyConnector::from_proxy(connector, proxy)?)
+                .request(request)
+                .await?
+        }
+        None => {
+            Client::builder()
+                .build::<_, Body>(connector)
+                .request(request)
+                .await?
+        }
+    };
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+
+    if !status.is_success() {
+        let error: ErrorResponse = serde_json::from_slice(&body)?;
+        return Err(OAuthError::Denied {
+            error: error.error,
+            description: error.error_description,
+        });
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Starts a device authorization. Show the user code and verification URI to the user,
+/// then wait for the token with [`device_token`].
+pub async fn device_code(
+    client_id: &str,
+    scopes: &str,
+    config: &SessionConfig,
+) -> Result<DeviceCode, OAuthError> {
+    post(
+        DEVICE_AUTHORIZATION_ENDPOINT,
+        &[("client_id", client_id), ("scope", scopes)],
+        config,
+    )
+    .await
+}
+
+/// Polls the accounts service until the user has authorized the device, or the code expired.
+pub async fn device_token(
+    client_id: &str,
+    code: &DeviceCode,
+    config: &SessionConfig,
+) -> Result<OAuthToken, OAuthError> {
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval.unwrap_or(DEFAULT_POLL_INTERVAL));
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        if Instant::now() > deadline {
+            return Err(OAuthError::Expired);
+        }
+
+        let result = post(
+            TOKEN_ENDPOINT,
+            &[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("device_code", &code.device_code),
+                ("client_id", client_id),
+            ],
+            config,
+        )
+        .await;
+
+        match result {
+            Err(OAuthError::Denied { error, .. }) if error == "authorization_pending" => (),
+            Err(OAuthError::Denied { error, .. }) if error == "slow_down" => {
+                interval += Duration::from_secs(DEFAULT_POLL_INTERVAL);
+            }
+            Err(OAuthError::Denied { error, .. }) if error == "expired_token" => {
+                return Err(OAuthError::Expired);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn redirect_uri(port: u16) -> String {
+    format!("http://127.0.0.1:{}/login", port)
+}
+
+/// Logs in with the authorization code flow. `show_url` is called with the URL the user has
+/// to open in a browser on this machine; the accounts service then redirects the browser to
+/// a temporary listener on `port`. The proxy and bind address of `config` are used to reach
+/// the accounts service.
+pub async fn authorization_code_token<F>(
+    client_id: &str,
+    scopes: &str,
+    port: u16,
+    config: &SessionConfig,
+    show_url: F,
+) -> Result<OAuthToken, OAuthError>
+where
+    F: FnOnce(&str),
+{
+    let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).await?;
+    let redirect_uri = redirect_uri(port);
+
+    let random_string = |len| {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect::<String>()
+    };
+
+    // PKCE, so the code is useless to anyone who intercepts it
+    let verifier = random_string(64);
+    let challenge =
+        base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+    let state = random_string(16);
+
+    let mut url = Url::parse(AUTHORIZATION_ENDPOINT).expect("valid authorization URL");
+    url.query_pairs_mut()
+        .append_pair("client_id", client_id)
+        .append_pair("response_type", "code")
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", scopes)
+        .append_pair("state", &state)
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("code_challenge", &challenge);
+
+    show_url(url.as_str());
+
+    let code = tokio::time::timeout(AUTHORIZATION_TIMEOUT, authorization_code(listener, &state))
+        .await
+        .map_err(|_| OAuthError::Expired)??;
+
+    post(
+        TOKEN_ENDPOINT,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("client_id", client_id),
+            ("code_verifier", &verifier),
+        ],
+        config,
+    )
+    .await
+}
+
+// Waits for the browser to be redirected to us, and takes the code from the request.
+async fn authorization_code(listener: TcpListener, state: &str) -> Result<String, OAuthError> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut buf = vec![0u8; 4096];
+        let len = stream.read(&mut buf).await?;
+        let request = String::from_utf8_lossy(&buf[..len]);
+
+        // eg. "GET /login?code=...&state=... HTTP/1.1"
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let params: HashMap<String, String> = Url::parse("http://127.0.0.1")
+            .and_then(|base| base.join(path))
+            .map(|url| url.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+
+        if params.get("state").map(String::as_str) != Some(state) {
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            continue;
+        }
+
+        let body = "Login complete, you can close this window now.";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+
+        return match (params.get("code"), params.get("error")) {
+            (Some(code), _) => Ok(code.clone()),
+            (None, error) => Err(OAuthError::Denied {
+                error: error.cloned().unwrap_or_else(|| "invalid_request".into()),
+                description: "the authorization was not granted".into(),
+            }),
+        };
+    }
+}
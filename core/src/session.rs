@@ -2,28 +2,31 @@ use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::task::Context;
 use std::task::Poll;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use futures_core::TryStream;
-use futures_util::{future, ready, StreamExt, TryStreamExt};
+use futures_util::{future, StreamExt, TryStreamExt};
 use once_cell::sync::OnceCell;
 use thiserror::Error;
 use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::apresolve::apresolve;
+use crate::apresolve::{self, apresolve};
 use crate::audio_key::AudioKeyManager;
 use crate::authentication::Credentials;
 use crate::cache::Cache;
 use crate::channel::ChannelManager;
 use crate::config::SessionConfig;
 use crate::connection::{self, AuthenticationError};
+use crate::data_usage::{self, DataUsage, DataUsageCounter};
 use crate::mercury::MercuryManager;
+use crate::throttle::Throttle;
 
 #[derive(Debug, Error)]
 pub enum SessionError {
@@ -33,8 +36,31 @@ pub enum SessionError {
     IoError(#[from] io::Error),
 }
 
+// Access points to try before giving up, when connecting to one fails. With several ports to
+// choose from, this many are tried per port.
+const MAX_AP_ATTEMPTS: usize = 3;
+
+// With several ports to choose from, a port which doesn't connect within this time is assumed to
+// be blocked, and the next one is tried. `SessionConfig::network_timeout` takes precedence.
+const AP_PORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The access point pings at this interval. With `SessionConfig::network_timeout` set, a
+// connection which stays silent for longer than that in addition is considered lost.
+const AP_PING_INTERVAL: Duration = Duration::from_secs(120);
+
+impl SessionError {
+    /// Whether the credentials were rejected, rather than the connection failing.
+    pub fn is_login_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::AuthenticationError(AuthenticationError::LoginFailed(_))
+        )
+    }
+}
+
 struct SessionData {
     country: String,
+    filter_explicit_content: bool,
     time_delta: i64,
     canonical_username: String,
     invalid: bool,
@@ -51,11 +77,27 @@ struct SessionInternal {
     mercury: OnceCell<MercuryManager>,
     cache: Option<Arc<Cache>>,
 
+    data_usage: DataUsageCounter,
+    monthly_data_usage: Mutex<MonthlyDataUsage>,
+
+    download_throttle: Option<Throttle>,
+
     handle: tokio::runtime::Handle,
 
     session_id: usize,
 }
 
+// Don't write to the cache for every packet
+const DATA_USAGE_SAVE_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+// What the data cap is counted from: the first day of the month, the bytes received within it by
+// previous sessions, and those this session had received before it began.
+struct MonthlyDataUsage {
+    month: u64,
+    previous: u64,
+    before: u64,
+}
+
 static SESSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone)]
@@ -68,13 +110,94 @@ impl Session {
         cache: Option<Cache>,
         store_credentials: bool,
     ) -> Result<(Session, Credentials), SessionError> {
-        let ap = apresolve(config.proxy.as_ref(), config.ap_port).await;
+        let aps = match &config.ap_address {
+            Some(ap) => vec![ap.clone()],
+            None => {
+                apresolve(
+                    config.proxy.as_ref(),
+                    &config.ap_ports,
+                    config.force_tls,
+                    config.bind_address,
+                    &config.dns_resolver,
+                    cache.as_ref(),
+                )
+                .await
+            }
+        };
 
-        info!("Connecting to AP \"{}\"", ap);
-        let mut conn = connection::connect(ap, config.proxy.as_ref()).await?;
+        let several_ports = config.ap_ports.len() > 1;
+        let connect_timeout = config
+            .network_timeout
+            .or_else(|| Some(AP_PORT_TIMEOUT).filter(|_| several_ports));
+        let max_attempts = MAX_AP_ATTEMPTS * config.ap_ports.len().max(1);
+        let mut attempts = aps.iter().take(max_attempts).peekable();
+        let mut blocked_ports = Vec::new();
+        let (conn, reusable_credentials) = loop {
+            // panic safety: apresolve always returns at least the fallback
+            let ap = attempts.next().unwrap();
+            let port = apresolve::ap_port(ap);
+            if blocked_ports.contains(&port) && attempts.peek().is_some() {
+                debug!("Skipping AP \"{}\", its port timed out before", ap);
+                continue;
+            }
+
+            info!("Connecting to AP \"{}\"", ap);
+            let result = async {
+                let connect = connection::connect(
+                    ap.clone(),
+                    config.proxy.as_ref(),
+                    config.bind_address,
+                    &config.dns_resolver,
+                    config.keepalive_interval,
+                );
+                let mut conn = match connect_timeout {
+                    Some(duration) => match tokio::time::timeout(duration, connect).await {
+                        Ok(conn) => conn?,
+                        Err(_) => {
+                            if several_ports {
+                                blocked_ports.push(port);
+                            }
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "Timed out connecting to the access point",
+                            )
+                            .into());
+                        }
+                    },
+                    None => connect.await?,
+                };
+
+                let authenticate =
+                    connection::authenticate(&mut conn, credentials.clone(), &config.device_id);
+                let reusable_credentials = match config.network_timeout {
+                    Some(duration) => tokio::time::timeout(duration, authenticate)
+                        .await
+                        .map_err(|_| {
+                            io::Error::new(io::ErrorKind::TimedOut, "Timed out authenticating")
+                        })??,
+                    None => authenticate.await?,
+                };
+                Ok::<_, SessionError>((conn, reusable_credentials))
+            }
+            .await;
 
-        let reusable_credentials =
-            connection::authenticate(&mut conn, credentials, &config.device_id).await?;
+            match result {
+                Ok(result) => {
+                    apresolve::report_success(ap);
+                    break result;
+                }
+                Err(e) if !e.is_login_failure() && attempts.peek().is_some() => {
+                    warn!("Connecting to AP \"{}\" failed: {}", ap, e);
+                    apresolve::report_failure(ap);
+                }
+                Err(e) => {
+                    if !e.is_login_failure() {
+                        apresolve::report_failure(ap);
+                    }
+                    return Err(e);
+                }
+            }
+        };
         info!("Authenticated as \"{}\" !", reusable_credentials.username);
         if let Some(cache) = &cache {
             if store_credentials {
@@ -107,16 +230,32 @@ impl Session {
 
         debug!("new Session[{}]", session_id);
 
+        let today = data_usage::today();
+        let monthly_data_usage = MonthlyDataUsage {
+            month: data_usage::month_start(today),
+            previous: cache
+                .as_ref()
+                .and_then(Cache::data_usage)
+                .map_or(0, |history| history.month(today).total()),
+            before: 0,
+        };
+
+        let download_throttle = config.max_download_rate.map(Throttle::new);
+
         let session = Session(Arc::new(SessionInternal {
             config,
             data: RwLock::new(SessionData {
                 country: String::new(),
+                filter_explicit_content: false,
                 canonical_username: username,
                 invalid: false,
                 time_delta: 0,
             }),
             tx_connection: sender_tx,
             cache: cache.map(Arc::new),
+            data_usage: DataUsageCounter::default(),
+            monthly_data_usage: Mutex::new(monthly_data_usage),
+            download_throttle,
             audio_key: OnceCell::new(),
             channel: OnceCell::new(),
             mercury: OnceCell::new(),
@@ -127,7 +266,11 @@ impl Session {
         let sender_task = UnboundedReceiverStream::new(sender_rx)
             .map(Ok)
             .forward(sink);
-        let receiver_task = DispatchTask(stream, session.weak());
+        let silence_timeout = session
+            .config()
+            .network_timeout
+            .map(|timeout| AP_PING_INTERVAL + timeout);
+        let receiver_task = DispatchTask::new(stream, session.weak(), silence_timeout);
 
         tokio::spawn(async move {
             let result = future::try_join(sender_task, receiver_task).await;
@@ -170,6 +313,15 @@ impl Session {
         self.0.handle.spawn(task);
     }
 
+    /// Runs `task` on a thread where blocking, eg. on file I/O, doesn't hold up other tasks.
+    pub fn spawn_blocking<F, R>(&self, task: F) -> tokio::task::JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.0.handle.spawn_blocking(task)
+    }
+
     fn debug_info(&self) {
         debug!(
             "Session[{}] strong={} weak={}",
@@ -179,8 +331,59 @@ impl Session {
         );
     }
 
+    /// Bytes received by this session.
+    pub fn data_usage(&self) -> DataUsage {
+        self.0.data_usage.total()
+    }
+
+    /// Bytes received this calendar month (UTC), by this and previous sessions sharing the
+    /// cache. `SessionConfig::data_cap` applies to these.
+    pub fn monthly_data_usage(&self) -> u64 {
+        let month = data_usage::month_start(data_usage::today());
+        let total = self.data_usage().total();
+
+        let mut monthly = self.0.monthly_data_usage.lock().unwrap();
+        if monthly.month != month {
+            // the month changed during this session, which counts from here on
+            *monthly = MonthlyDataUsage {
+                month,
+                previous: 0,
+                before: total,
+            };
+        }
+        monthly.previous + total - monthly.before
+    }
+
+    /// Whether the data received this month exceeds `SessionConfig::data_cap`.
+    pub fn data_cap_exceeded(&self) -> bool {
+        match self.config().data_cap {
+            Some(cap) => self.monthly_data_usage() > cap,
+            None => false,
+        }
+    }
+
+    /// Limits how fast audio data is requested, if `SessionConfig::max_download_rate` is set.
+    pub fn download_throttle(&self) -> Option<&Throttle> {
+        self.0.download_throttle.as_ref()
+    }
+
     #[allow(clippy::match_same_arms)]
     fn dispatch(&self, cmd: u8, data: Bytes) {
+        match cmd {
+            0x9 | 0xa => self.0.data_usage.add_audio(data.len() as u64),
+            _ => self.0.data_usage.add_metadata(data.len() as u64),
+        }
+
+        if self.0.data_usage.unsaved() > DATA_USAGE_SAVE_THRESHOLD {
+            if let Some(cache) = self.cache().cloned() {
+                // don't hold up the connection while the cache is locked and written
+                let unsaved = self.0.data_usage.take_unsaved();
+                self.spawn_blocking(move || {
+                    cache.add_data_usage(data_usage::today(), unsaved);
+                });
+            }
+        }
+
         match cmd {
             0x4 => {
                 let server_timestamp = BigEndian::read_u32(data.as_ref()) as i64;
@@ -201,6 +404,13 @@ impl Session {
                 info!("Country: {:?}", country);
                 self.0.data.write().unwrap().country = country;
             }
+            0x50 => {
+                let product_info = String::from_utf8_lossy(data.as_ref());
+                let filter_explicit_content =
+                    product_info_value(&product_info, "filter-explicit-content") == Some("1");
+                debug!("Filter explicit content: {}", filter_explicit_content);
+                self.0.data.write().unwrap().filter_explicit_content = filter_explicit_content;
+            }
 
             0x9 | 0xa => self.channel().dispatch(cmd, data),
             0xd | 0xe => self.audio_key().dispatch(cmd, data),
@@ -217,7 +427,7 @@ impl Session {
         self.0.cache.as_ref()
     }
 
-    fn config(&self) -> &SessionConfig {
+    pub fn config(&self) -> &SessionConfig {
         &self.0.config
     }
 
@@ -229,6 +439,11 @@ impl Session {
         self.0.data.read().unwrap().country.clone()
     }
 
+    /// Whether the account is set up not to play explicit content.
+    pub fn filter_explicit_content(&self) -> bool {
+        self.0.data.read().unwrap().filter_explicit_content
+    }
+
     pub fn device_id(&self) -> &str {
         &self.config().device_id
     }
@@ -266,15 +481,49 @@ impl SessionWeak {
     }
 }
 
+fn save_data_usage(session: &SessionInternal) {
+    if let Some(cache) = &session.cache {
+        let unsaved = session.data_usage.take_unsaved();
+        cache.add_data_usage(data_usage::today(), unsaved);
+    }
+}
+
 impl Drop for SessionInternal {
     fn drop(&mut self) {
+        let usage = self.data_usage.total();
+        info!(
+            "Session[{}] received {} bytes of audio and {} bytes of metadata",
+            self.session_id, usage.audio, usage.metadata
+        );
+        save_data_usage(self);
+
         debug!("drop Session[{}]", self.session_id);
     }
 }
 
-struct DispatchTask<S>(S, SessionWeak)
+struct DispatchTask<S>
 where
-    S: TryStream<Ok = (u8, Bytes)> + Unpin;
+    S: TryStream<Ok = (u8, Bytes)> + Unpin,
+{
+    stream: S,
+    session: SessionWeak,
+    // how long the connection may stay silent, and until when
+    silence_timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<S> DispatchTask<S>
+where
+    S: TryStream<Ok = (u8, Bytes)> + Unpin,
+{
+    fn new(stream: S, session: SessionWeak, silence_timeout: Option<Duration>) -> Self {
+        Self {
+            stream,
+            session,
+            silence_timeout: silence_timeout
+                .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+        }
+    }
+}
 
 impl<S> Future for DispatchTask<S>
 where
@@ -284,13 +533,17 @@ where
     type Output = Result<(), S::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let session = match self.1.try_upgrade() {
+        let session = match self.session.try_upgrade() {
             Some(session) => session,
             None => return Poll::Ready(Ok(())),
         };
 
         loop {
-            let (cmd, data) = match ready!(self.0.try_poll_next_unpin(cx)) {
+            let next = match self.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(next) => next,
+                Poll::Pending => break,
+            };
+            let (cmd, data) = match next {
                 Some(Ok(t)) => t,
                 None => {
                     warn!("Connection to server closed.");
@@ -303,8 +556,26 @@ where
                 }
             };
 
+            if let Some((timeout, sleep)) = &mut self.silence_timeout {
+                let deadline = Instant::now() + *timeout;
+                sleep.as_mut().reset(deadline);
+            }
+
             session.dispatch(cmd, data);
         }
+
+        if let Some((timeout, sleep)) = &mut self.silence_timeout {
+            if sleep.as_mut().poll(cx).is_ready() {
+                warn!(
+                    "Nothing received from the server for {}s, assuming the connection is lost.",
+                    timeout.as_secs()
+                );
+                session.shutdown();
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        Poll::Pending
     }
 }
 
@@ -316,3 +587,29 @@ where
         debug!("drop Dispatch");
     }
 }
+
+// The product info is a flat XML document, a single value can be read without a parser.
+fn product_info_value<'a>(product_info: &'a str, name: &str) -> Option<&'a str> {
+    let start_tag = format!("<{}>", name);
+    let end_tag = format!("</{}>", name);
+    let value = &product_info[product_info.find(&start_tag)? + start_tag.len()..];
+    Some(value[..value.find(&end_tag)?].trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn product_info() {
+        let product_info = "<products><product><type>premium</type>\
+            <filter-explicit-content>1</filter-explicit-content></product></products>";
+
+        assert_eq!(product_info_value(product_info, "type"), Some("premium"));
+        assert_eq!(
+            product_info_value(product_info, "filter-explicit-content"),
+            Some("1")
+        );
+        assert_eq!(product_info_value(product_info, "catalogue"), None);
+    }
+}
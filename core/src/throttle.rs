@@ -0,0 +1,55 @@
+use std::cmp::max;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Holds back requests for audio data, so that what is received doesn't exceed
+/// `SessionConfig::max_download_rate` on average.
+pub struct Throttle {
+    bytes_per_second: usize,
+    // when the bytes received so far would have been received at the limit
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: usize) -> Self {
+        Self {
+            bytes_per_second: max(bytes_per_second, 1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second
+    }
+
+    /// Counts `length` bytes received, whether or not they were waited for.
+    pub fn received(&self, length: usize) {
+        let mut next = self.next.lock().unwrap();
+        *next = max(*next, Instant::now())
+            + Duration::from_secs_f64(length as f64 / self.bytes_per_second as f64);
+    }
+
+    /// Waits until what was received is back within the limit.
+    pub async fn wait(&self) {
+        let next = *self.next.lock().unwrap();
+        tokio::time::sleep_until(next.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now();
+
+        throttle.wait().await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        throttle.received(100);
+        throttle.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
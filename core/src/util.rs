@@ -27,3 +27,42 @@ impl<T: Seq> SeqGenerator<T> {
         mem::replace(&mut self.0, value)
     }
 }
+
+#[cfg(not(no_atomic_u64))]
+pub use std::sync::atomic::AtomicU64;
+
+/// Stand-in for targets without 64-bit atomics, eg. ARMv5 and 32-bit MIPS, with the subset of
+/// the API used here.
+#[cfg(no_atomic_u64)]
+#[derive(Debug, Default)]
+pub struct AtomicU64(std::sync::Mutex<u64>);
+
+#[cfg(no_atomic_u64)]
+impl AtomicU64 {
+    pub fn new(value: u64) -> Self {
+        Self(std::sync::Mutex::new(value))
+    }
+
+    fn value(&self) -> std::sync::MutexGuard<'_, u64> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn load(&self, _: std::sync::atomic::Ordering) -> u64 {
+        *self.value()
+    }
+
+    pub fn store(&self, value: u64, _: std::sync::atomic::Ordering) {
+        *self.value() = value;
+    }
+
+    pub fn swap(&self, value: u64, _: std::sync::atomic::Ordering) -> u64 {
+        mem::replace(&mut *self.value(), value)
+    }
+
+    pub fn fetch_add(&self, value: u64, _: std::sync::atomic::Ordering) -> u64 {
+        let mut current = self.value();
+        let previous = *current;
+        *current = previous.wrapping_add(value);
+        previous
+    }
+}
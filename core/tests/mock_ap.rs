@@ -0,0 +1,119 @@
+use std::fs;
+use std::time::Duration;
+
+use librespot_core::authentication::Credentials;
+use librespot_core::cache::Cache;
+use librespot_core::keymaster;
+use librespot_core::mock_ap::{MockAp, REUSABLE_CREDENTIALS};
+use librespot_core::session::Session;
+use librespot_protocol::authentication::AuthenticationType;
+use librespot_protocol::keyexchange::ErrorCode;
+
+use tokio::time::{sleep, timeout};
+
+fn credentials() -> Credentials {
+    Credentials::with_password("test", "test")
+}
+
+#[tokio::test]
+async fn test_login() {
+    let ap = MockAp::start().await.unwrap();
+
+    let (session, reusable_credentials) =
+        Session::connect(ap.session_config(), credentials(), None, false)
+            .await
+            .unwrap();
+
+    assert_eq!(session.username(), "test");
+    assert_eq!(reusable_credentials.auth_data, REUSABLE_CREDENTIALS);
+    assert_eq!(ap.logins(), 1);
+}
+
+#[tokio::test]
+async fn test_bad_credentials() {
+    let ap = MockAp::start().await.unwrap();
+    ap.reject_logins(Some(ErrorCode::BadCredentials));
+
+    match Session::connect(ap.session_config(), credentials(), None, false).await {
+        Ok(_) => panic!("Authentication succeeded despite of bad credentials."),
+        Err(e) => {
+            assert!(e.is_login_failure());
+            assert_eq!(e.to_string(), "Login failed with reason: Bad credentials");
+        }
+    }
+    assert_eq!(ap.logins(), 0);
+}
+
+#[tokio::test]
+async fn test_credentials_cached() {
+    let ap = MockAp::start().await.unwrap();
+    let location = std::env::temp_dir().join(format!("librespot-mock-ap-{}", std::process::id()));
+    let cache = Cache::new(Some(&location), None, None, None).unwrap();
+
+    Session::connect(
+        ap.session_config(),
+        credentials(),
+        Some(cache.clone()),
+        true,
+    )
+    .await
+    .unwrap();
+
+    let cached = cache.credentials();
+    let _ = fs::remove_dir_all(&location);
+
+    let cached = cached.expect("Credentials weren't cached");
+    assert_eq!(cached.username, "test");
+    assert_eq!(
+        cached.auth_type,
+        AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS
+    );
+    assert_eq!(cached.auth_data, REUSABLE_CREDENTIALS);
+}
+
+#[tokio::test]
+async fn test_token() {
+    let ap = MockAp::start().await.unwrap();
+    let token =
+        r#"{"accessToken":"token","expiresIn":3600,"tokenType":"Bearer","scope":["streaming"]}"#;
+    ap.respond("hm://keymaster/token/", token.as_bytes().to_vec());
+
+    let (session, _) = Session::connect(ap.session_config(), credentials(), None, false)
+        .await
+        .unwrap();
+    let token = keymaster::get_token(&session, "client", "streaming")
+        .await
+        .unwrap();
+
+    assert_eq!(token.access_token, "token");
+    assert_eq!(token.expires_in, 3600);
+    assert_eq!(
+        ap.requests(),
+        vec!["hm://keymaster/token/authenticated?client_id=client&scope=streaming"]
+    );
+
+    assert!(session
+        .mercury()
+        .get("hm://metadata/3/track/0")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_disconnect() {
+    let ap = MockAp::start().await.unwrap();
+
+    let (session, _) = Session::connect(ap.session_config(), credentials(), None, false)
+        .await
+        .unwrap();
+    assert!(!session.is_invalid());
+
+    ap.disconnect();
+    timeout(Duration::from_secs(5), async {
+        while !session.is_invalid() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The session is still valid after the connection was closed");
+}
@@ -0,0 +1,134 @@
+// The generated proxy for AddService takes one argument per D-Bus parameter.
+#![allow(clippy::too_many_arguments)]
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+use log::{debug, warn};
+use tokio::sync::oneshot;
+use zbus::dbus_proxy;
+use zbus::zvariant::OwnedObjectPath;
+
+// Any interface and protocol, see avahi-common/address.h
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Avahi.Server",
+    default_service = "org.freedesktop.Avahi",
+    default_path = "/"
+)]
+trait Server {
+    fn entry_group_new(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Avahi.EntryGroup",
+    default_service = "org.freedesktop.Avahi"
+)]
+trait EntryGroup {
+    fn add_service(
+        &self,
+        interface: i32,
+        protocol: i32,
+        flags: u32,
+        name: &str,
+        type_: &str,
+        domain: &str,
+        host: &str,
+        port: u16,
+        txt: Vec<Vec<u8>>,
+    ) -> zbus::Result<()>;
+
+    fn commit(&self) -> zbus::Result<()>;
+
+    fn free(&self) -> zbus::Result<()>;
+}
+
+async fn add_service(
+    name: &str,
+    service_type: &str,
+    port: u16,
+    txt: Vec<Vec<u8>>,
+) -> zbus::Result<EntryGroupProxy<'static>> {
+    let connection = zbus::Connection::system().await?;
+    let server = ServerProxy::new(&connection).await?;
+
+    let path = server.entry_group_new().await?;
+    let group = EntryGroupProxy::builder(&connection)
+        .path(path)?
+        .build()
+        .await?;
+
+    group
+        .add_service(
+            AVAHI_IF_UNSPEC,
+            AVAHI_PROTO_UNSPEC,
+            0,
+            name,
+            service_type,
+            "",
+            "",
+            port,
+            txt,
+        )
+        .await?;
+    group.commit().await?;
+
+    Ok(group)
+}
+
+/// Registers the service with avahi-daemon, or with the built-in responder on `ips` if that
+/// fails, eg. because the daemon isn't running. It stays registered until the returned sender
+/// is dropped.
+pub fn register(
+    name: String,
+    service_type: &'static str,
+    port: u16,
+    txt: &[&str],
+    ips: Vec<IpAddr>,
+) -> oneshot::Sender<Infallible> {
+    let txt_records: Vec<String> = txt.iter().map(|record| record.to_string()).collect();
+    let txt = txt
+        .iter()
+        .map(|record| record.as_bytes().to_vec())
+        .collect();
+    let (close_tx, close_rx) = oneshot::channel::<Infallible>();
+
+    tokio::spawn(async move {
+        match add_service(&name, service_type, port, txt).await {
+            Ok(group) => {
+                debug!("Registered {} with avahi", name);
+
+                close_rx.await.unwrap_err();
+                if let Err(e) = group.free().await {
+                    warn!("Unregistering from avahi failed: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Registering with avahi failed, using libmdns instead: {}",
+                    e
+                );
+
+                let handle = tokio::runtime::Handle::current();
+                let responder = if ips.is_empty() {
+                    libmdns::Responder::spawn(&handle)
+                } else {
+                    libmdns::Responder::spawn_with_ip_list(&handle, ips)
+                };
+                let txt_records: Vec<&str> = txt_records.iter().map(String::as_str).collect();
+                match responder {
+                    Ok(responder) => {
+                        let _svc =
+                            responder.register(service_type.to_owned(), name, port, &txt_records);
+                        close_rx.await.unwrap_err();
+                    }
+                    Err(e) => warn!("Starting libmdns failed: {}", e),
+                }
+            }
+        }
+    });
+
+    close_tx
+}
@@ -8,12 +8,17 @@
 //! and spawns an http server to answer requests of Spotify clients.
 
 #![warn(clippy::all, missing_docs, rust_2018_idioms)]
+#![deny(clippy::print_stdout)]
 
+#[cfg(feature = "with-avahi")]
+mod avahi;
 mod server;
 
 use std::borrow::Cow;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
 use std::pin::Pin;
+use std::str::FromStr;
 use std::task::{Context, Poll};
 
 use futures_core::Stream;
@@ -34,17 +39,70 @@ pub use crate::core::config::DeviceType;
 /// is selected in the list of available devices, it yields [`Credentials`].
 pub struct Discovery {
     server: DiscoveryServer,
+    _svc: Service,
+}
+
+/// The implementation used to announce the device via mDNS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MdnsBackend {
+    /// Built-in mDNS responder.
+    Libmdns,
+    /// Register the service with a running avahi-daemon over D-Bus.
+    Avahi,
+    /// Register the service through the system's DNS-SD library.
+    DnsSd,
+}
+
+impl MdnsBackend {
+    /// Whether support for this backend was enabled at build time.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Self::Libmdns => true,
+            Self::Avahi => cfg!(feature = "with-avahi"),
+            Self::DnsSd => cfg!(feature = "with-dns-sd"),
+        }
+    }
+}
+
+impl FromStr for MdnsBackend {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "libmdns" => Ok(Self::Libmdns),
+            "avahi" => Ok(Self::Avahi),
+            "dnssd" | "dns-sd" => Ok(Self::DnsSd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for MdnsBackend {
+    fn default() -> Self {
+        if cfg!(feature = "with-dns-sd") {
+            Self::DnsSd
+        } else {
+            Self::Libmdns
+        }
+    }
+}
 
-    #[cfg(not(feature = "with-dns-sd"))]
-    _svc: libmdns::Service,
+// Keeps the service registered until dropped.
+#[allow(dead_code)]
+enum Service {
+    Libmdns(libmdns::Service),
     #[cfg(feature = "with-dns-sd")]
-    _svc: dns_sd::DNSService,
+    DnsSd(dns_sd::DNSService),
+    #[cfg(feature = "with-avahi")]
+    Avahi(tokio::sync::oneshot::Sender<std::convert::Infallible>),
 }
 
 /// A builder for [`Discovery`].
 pub struct Builder {
     server_config: server::Config,
     port: u16,
+    interface: Option<String>,
+    txt_records: Vec<String>,
+    mdns_backend: MdnsBackend,
 }
 
 /// Errors that can occur while setting up a [`Discovery`] instance.
@@ -56,6 +114,15 @@ pub enum Error {
     /// Setting up the http server failed.
     #[error("Setting up the http server failed: {0}")]
     HttpServerError(#[from] hyper::Error),
+    /// The mDNS backend was not enabled at build time.
+    #[error("The {0:?} mDNS backend is not available")]
+    UnavailableBackend(MdnsBackend),
+    /// Listing the network interfaces failed.
+    #[error("Listing the network interfaces failed: {0}")]
+    InterfacesError(io::Error),
+    /// The interface to listen on does not exist.
+    #[error("Unknown network interface or address: {0}")]
+    UnknownInterface(String),
 }
 
 impl Builder {
@@ -66,8 +133,13 @@ impl Builder {
                 name: "Librespot".into(),
                 device_type: DeviceType::default(),
                 device_id: device_id.into(),
+                brand: "librespot".into(),
+                model: "librespot".into(),
             },
             port: 0,
+            interface: None,
+            txt_records: Vec::new(),
+            mdns_backend: MdnsBackend::default(),
         }
     }
 
@@ -83,6 +155,24 @@ impl Builder {
         self
     }
 
+    /// Sets the brand name shown in other Spotify clients. Default is `"librespot"`.
+    pub fn brand(mut self, brand: impl Into<Cow<'static, str>>) -> Self {
+        self.server_config.brand = brand.into();
+        self
+    }
+
+    /// Sets the model name shown in other Spotify clients. Default is `"librespot"`.
+    pub fn model(mut self, model: impl Into<Cow<'static, str>>) -> Self {
+        self.server_config.model = model.into();
+        self
+    }
+
+    /// Adds a `key=value` record to the TXT records of the announced service.
+    pub fn txt_record(mut self, record: impl Into<String>) -> Self {
+        self.txt_records.push(record.into());
+        self
+    }
+
     /// Sets the port on which it should listen to incoming connections.
     /// The default value `0` means any port.
     pub fn port(mut self, port: u16) -> Self {
@@ -90,6 +180,21 @@ impl Builder {
         self
     }
 
+    /// Only announces the device and accepts connections on a network interface, given by
+    /// its name or one of its addresses. Of an interface with several addresses only one is
+    /// used, IPv4 if it has one. By default all interfaces are used.
+    pub fn interface(mut self, interface: impl Into<String>) -> Self {
+        self.interface = Some(interface.into());
+        self
+    }
+
+    /// Sets the implementation used to announce the device. Default is [`MdnsBackend::DnsSd`]
+    /// if built with the `with-dns-sd` feature, [`MdnsBackend::Libmdns`] otherwise.
+    pub fn mdns_backend(mut self, mdns_backend: MdnsBackend) -> Self {
+        self.mdns_backend = mdns_backend;
+        self
+    }
+
     /// Sets up the [`Discovery`] instance.
     ///
     /// # Errors
@@ -97,31 +202,109 @@ impl Builder {
     pub fn launch(self) -> Result<Discovery, Error> {
         let mut port = self.port;
         let name = self.server_config.name.clone().into_owned();
-        let server = DiscoveryServer::new(self.server_config, &mut port)?;
-
-        #[cfg(feature = "with-dns-sd")]
-        let svc = dns_sd::DNSService::register(
-            Some(name.as_ref()),
-            "_spotify-connect._tcp",
-            None,
-            None,
-            port,
-            &["VERSION=1.0", "CPath=/"],
-        )
-        .map_err(|e| Error::DnsSdError(io::Error::new(io::ErrorKind::Unsupported, e)))?;
-
-        #[cfg(not(feature = "with-dns-sd"))]
-        let svc = libmdns::Responder::spawn(&tokio::runtime::Handle::current())?.register(
-            "_spotify-connect._tcp".to_owned(),
-            name,
-            port,
-            &["VERSION=1.0", "CPath=/"],
-        );
+
+        let ips = match &self.interface {
+            Some(interface) => interface_ips(interface)?,
+            None => Vec::new(),
+        };
+
+        // The http server can only listen on a single address, so prefer IPv4. Without an
+        // interface, listen on all IPv6 addresses, which includes IPv4 on dual stack hosts.
+        let ip = ips
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| ips.first())
+            .copied()
+            .unwrap_or_else(|| {
+                if TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok() {
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                } else {
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                }
+            });
+
+        let server = DiscoveryServer::new(self.server_config, ip, &mut port)?;
+
+        // only announce the address the server is listening on
+        let ips = if ips.is_empty() { ips } else { vec![ip] };
+
+        let mut txt_records = vec!["VERSION=1.0", "CPath=/"];
+        txt_records.extend(self.txt_records.iter().map(String::as_str));
+
+        if !self.mdns_backend.is_available() {
+            return Err(Error::UnavailableBackend(self.mdns_backend));
+        }
+
+        if self.interface.is_some() && self.mdns_backend != MdnsBackend::Libmdns {
+            log::warn!(
+                "{:?} announces the device on all interfaces",
+                self.mdns_backend
+            );
+        }
+
+        let svc = match self.mdns_backend {
+            MdnsBackend::Libmdns => {
+                let handle = tokio::runtime::Handle::current();
+                let responder = if ips.is_empty() {
+                    libmdns::Responder::spawn(&handle)?
+                } else {
+                    libmdns::Responder::spawn_with_ip_list(&handle, ips)?
+                };
+
+                Service::Libmdns(responder.register(
+                    "_spotify-connect._tcp".to_owned(),
+                    name,
+                    port,
+                    &txt_records,
+                ))
+            }
+            #[cfg(feature = "with-dns-sd")]
+            MdnsBackend::DnsSd => Service::DnsSd(
+                dns_sd::DNSService::register(
+                    Some(name.as_ref()),
+                    "_spotify-connect._tcp",
+                    None,
+                    None,
+                    port,
+                    &txt_records,
+                )
+                .map_err(|e| Error::DnsSdError(io::Error::new(io::ErrorKind::Unsupported, e)))?,
+            ),
+            #[cfg(feature = "with-avahi")]
+            MdnsBackend::Avahi => Service::Avahi(avahi::register(
+                name,
+                "_spotify-connect._tcp",
+                port,
+                &txt_records,
+                ips,
+            )),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("unavailable backends are rejected above"),
+        };
 
         Ok(Discovery { server, _svc: svc })
     }
 }
 
+fn interface_ips(interface: &str) -> Result<Vec<IpAddr>, Error> {
+    if let Ok(ip) = interface.parse() {
+        return Ok(vec![ip]);
+    }
+
+    let ips: Vec<IpAddr> = if_addrs::get_if_addrs()
+        .map_err(Error::InterfacesError)?
+        .into_iter()
+        .filter(|iface| iface.name == interface)
+        .map(|iface| iface.ip())
+        .collect();
+
+    if ips.is_empty() {
+        Err(Error::UnknownInterface(interface.to_string()))
+    } else {
+        Ok(ips)
+    }
+}
+
 impl Discovery {
     /// Starts a [`Builder`] with the provided device id.
     pub fn builder(device_id: impl Into<String>) -> Builder {
@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -28,6 +28,8 @@ pub struct Config {
     pub name: Cow<'static, str>,
     pub device_type: DeviceType,
     pub device_id: String,
+    pub brand: Cow<'static, str>,
+    pub model: Cow<'static, str>,
 }
 
 struct RequestHandler {
@@ -65,8 +67,8 @@ impl RequestHandler {
             "deviceType": (device_type),
             "libraryVersion": crate::core::version::SEMVER,
             "accountReq": "PREMIUM",
-            "brandDisplayName": "librespot",
-            "modelDisplayName": "librespot",
+            "brandDisplayName": (self.config.brand),
+            "modelDisplayName": (self.config.model),
             "resolverVersion": "0",
             "groupStatus": "NONE",
             "voiceSupport": "NO",
@@ -186,13 +188,13 @@ pub struct DiscoveryServer {
 }
 
 impl DiscoveryServer {
-    pub fn new(config: Config, port: &mut u16) -> hyper::Result<Self> {
+    pub fn new(config: Config, ip: IpAddr, port: &mut u16) -> hyper::Result<Self> {
         let (discovery, cred_rx) = RequestHandler::new(config);
         let discovery = Arc::new(discovery);
 
         let (close_tx, close_rx) = oneshot::channel();
 
-        let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port);
+        let address = SocketAddr::new(ip, *port);
 
         let make_service = make_service_fn(move |_| {
             let discovery = discovery.clone();
@@ -204,7 +206,7 @@ impl DiscoveryServer {
         let server = hyper::Server::try_bind(&address)?.serve(make_service);
 
         *port = server.local_addr().port();
-        debug!("Zeroconf server listening on 0.0.0.0:{}", *port);
+        debug!("Zeroconf server listening on {}", server.local_addr());
 
         tokio::spawn(async {
             let result = server
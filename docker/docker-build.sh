@@ -10,11 +10,14 @@ rm -f $DESTDIR/i386-linux/*
 mkdir -p $DESTDIR/arm-linux
 rm -f $DESTDIR/arm-linux/*
 
+mkdir -p $DESTDIR/mips-linux
+rm -f $DESTDIR/mips-linux/*
+
 function build {
 	echo Building for $1 to $3...
 
 	if [[ ! -f /build/$1/release/spotty ]]; then
-		cargo build --release --target $1
+		cargo ${TOOLCHAIN:+"+$TOOLCHAIN"} build --release --target $1
 	fi
 
 	$2 /build/$1/release/spotty \
@@ -23,5 +26,7 @@ function build {
 
 build arm-unknown-linux-gnueabihf arm-linux-gnueabihf-strip arm-linux/spotty-hf
 build aarch64-unknown-linux-gnu aarch64-linux-gnu-strip arm-linux/spotty-aarch64
+SPOTTY_RELEASE_ARCH=armv5 build armv5te-unknown-linux-gnueabi arm-linux-gnueabi-strip arm-linux/spotty-armv5
+TOOLCHAIN=$MIPS_TOOLCHAIN build mipsel-unknown-linux-gnu mipsel-linux-gnu-strip mips-linux/spotty-mipsel
 build x86_64-unknown-linux-musl strip i386-linux/spotty-x86_64
 build i686-unknown-linux-musl strip i386-linux/spotty
@@ -1,4 +1,5 @@
 #![allow(clippy::unused_io_amount)]
+#![deny(clippy::print_stdout)]
 
 #[macro_use]
 extern crate log;
@@ -8,6 +9,7 @@ extern crate async_trait;
 
 pub mod cover;
 use std::collections::HashMap;
+use std::fmt;
 use std::string::FromUtf8Error;
 
 use librespot_core::mercury::MercuryError;
@@ -63,6 +65,7 @@ pub struct AudioItem {
     pub duration: i32,
     pub available: bool,
     pub alternatives: Option<Vec<SpotifyId>>,
+    pub explicit: bool,
 }
 
 impl AudioItem {
@@ -98,6 +101,7 @@ impl AudioFiles for Track {
                     duration: item.duration,
                     available: item.available,
                     alternatives: Some(item.alternatives),
+                    explicit: item.explicit,
                 })
             }
         }
@@ -122,6 +126,7 @@ impl AudioFiles for Episode {
                     duration: item.duration,
                     available: item.available,
                     alternatives: None,
+                    explicit: item.explicit,
                 })
             }
         }
@@ -174,9 +179,13 @@ pub struct Track {
     pub duration: i32,
     pub album: SpotifyId,
     pub artists: Vec<SpotifyId>,
+    pub number: i32,
+    pub disc_number: i32,
     pub files: HashMap<FileFormat, FileId>,
     pub alternatives: Vec<SpotifyId>,
     pub available: bool,
+    pub explicit: bool,
+    pub popularity: i32,
 }
 
 #[derive(Debug, Clone)]
@@ -186,12 +195,54 @@ pub struct Album {
     pub artists: Vec<SpotifyId>,
     pub tracks: Vec<SpotifyId>,
     pub covers: Vec<FileId>,
+    pub label: String,
+    pub release_date: Option<ReleaseDate>,
+    pub popularity: i32,
+    pub genres: Vec<String>,
+}
+
+// Only the year is known for some albums, or the year and month
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReleaseDate {
+    pub year: i32,
+    pub month: Option<i32>,
+    pub day: Option<i32>,
+}
+
+impl ReleaseDate {
+    fn from_message(date: &protocol::metadata::Date) -> Option<Self> {
+        if !date.has_year() {
+            return None;
+        }
+
+        let month = Some(date.get_month()).filter(|_| date.has_month());
+        Some(ReleaseDate {
+            year: date.get_year(),
+            month,
+            day: Some(date.get_day()).filter(|_| month.is_some() && date.has_day()),
+        })
+    }
+}
+
+// As ISO 8601, eg. 1999, 1999-04 or 1999-04-07
+impl fmt::Display for ReleaseDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}", self.year)?;
+        if let Some(month) = self.month {
+            write!(f, "-{:02}", month)?;
+        }
+        if let Some(day) = self.day {
+            write!(f, "-{:02}", day)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Episode {
     pub id: SpotifyId,
     pub name: String,
+    pub description: String,
     pub external_url: String,
     pub duration: i32,
     pub language: String,
@@ -224,6 +275,8 @@ pub struct Artist {
     pub id: SpotifyId,
     pub name: String,
     pub top_tracks: Vec<SpotifyId>,
+    pub popularity: i32,
+    pub genres: Vec<String>,
 }
 
 impl Metadata for Track {
@@ -269,6 +322,8 @@ impl Metadata for Track {
             duration: msg.get_duration(),
             album: SpotifyId::from_raw(msg.get_album().get_gid())?,
             artists,
+            number: msg.get_number(),
+            disc_number: msg.get_disc_number(),
             files,
             alternatives: msg
                 .get_alternative()
@@ -276,6 +331,8 @@ impl Metadata for Track {
                 .filter_map(|alt| SpotifyId::from_raw(alt.get_gid()).ok())
                 .collect(),
             available: parse_restrictions(msg.get_restriction(), &country, "premium"),
+            explicit: msg.get_explicit(),
+            popularity: msg.get_popularity(),
         })
     }
 }
@@ -335,6 +392,10 @@ impl Metadata for Album {
             artists,
             tracks,
             covers,
+            label: msg.get_label().to_owned(),
+            release_date: ReleaseDate::from_message(msg.get_date()),
+            popularity: msg.get_popularity(),
+            genres: msg.get_genre().to_vec(),
         })
     }
 }
@@ -410,6 +471,8 @@ impl Metadata for Artist {
             id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             top_tracks,
+            popularity: msg.get_popularity(),
+            genres: msg.get_genre().to_vec(),
         })
     }
 }
@@ -452,6 +515,7 @@ impl Metadata for Episode {
         Ok(Episode {
             id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
             name: msg.get_name().to_owned(),
+            description: msg.get_description().to_owned(),
             external_url: msg.get_external_url().to_owned(),
             duration: msg.get_duration().to_owned(),
             language: msg.get_language().to_owned(),
@@ -102,6 +102,7 @@ pub struct AlsaSink {
     period_buffer: Vec<u8>,
 }
 
+#[allow(clippy::print_stdout)]
 fn list_compatible_devices() -> SinkResult<()> {
     let i = HintIter::new_str(None, "pcm").map_err(AlsaError::Parsing)?;
 
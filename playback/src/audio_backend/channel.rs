@@ -0,0 +1,50 @@
+use super::{Sink, SinkAsBytes, SinkResult};
+use crate::config::{AudioFormat, TrackMarker};
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+
+use tokio::sync::mpsc;
+
+/// What a [`ChannelSink`] hands to the receiver.
+#[derive(Debug)]
+pub enum ChannelData {
+    Audio(Vec<u8>),
+    /// The audio after this is that of the track `uri`.
+    TrackBoundary(String),
+}
+
+/// Hands the audio to an async receiver, eg. to stream it over HTTP. Writing blocks while the
+/// channel is full, and discards the audio once the receiver was dropped.
+pub struct ChannelSink {
+    sender: mpsc::Sender<ChannelData>,
+    format: AudioFormat,
+}
+
+impl ChannelSink {
+    pub fn new(sender: mpsc::Sender<ChannelData>, format: AudioFormat) -> Self {
+        info!("Using ChannelSink with format: {:?}", format);
+
+        Self { sender, format }
+    }
+}
+
+impl Sink for ChannelSink {
+    sink_as_bytes!();
+
+    fn track_boundary(&mut self, _marker: Option<&TrackMarker>, uri: &str) -> SinkResult<()> {
+        let _ = self
+            .sender
+            .blocking_send(ChannelData::TrackBoundary(uri.to_string()));
+
+        Ok(())
+    }
+}
+
+impl SinkAsBytes for ChannelSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        // not an error, the player must not exit when a listener goes away
+        let _ = self.sender.blocking_send(ChannelData::Audio(data.to_vec()));
+
+        Ok(())
+    }
+}
@@ -94,13 +94,13 @@ impl Open for GstreamerSink {
         bus.set_sync_handler(move |_bus, msg| {
             match msg.view() {
                 gst::MessageView::Eos(_) => {
-                    println!("gst signaled end of stream");
+                    info!("gst signaled end of stream");
 
                     let mut async_error_storage = async_error_clone.lock();
                     *async_error_storage = Some(String::from("gst signaled end of stream"));
                 }
                 gst::MessageView::Error(err) => {
-                    println!(
+                    error!(
                         "Error from {:?}: {} ({:?})",
                         err.src().map(|s| s.path_string()),
                         err.error(),
@@ -1,4 +1,4 @@
-use crate::config::AudioFormat;
+use crate::config::{AudioFormat, TrackMarker};
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
 use thiserror::Error;
@@ -29,6 +29,11 @@ pub trait Sink {
         Ok(())
     }
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()>;
+    /// Called between the last packet of a track and the first packet of the track `uri`, with
+    /// `PlayerConfig::track_marker` if one is set.
+    fn track_boundary(&mut self, _marker: Option<&TrackMarker>, _uri: &str) -> SinkResult<()> {
+        Ok(())
+    }
 }
 
 pub type SinkBuilder = fn(Option<String>, AudioFormat) -> Box<dyn Sink>;
@@ -118,6 +123,12 @@ use self::pipe::StdoutSink;
 mod subprocess;
 use self::subprocess::SubprocessSink;
 
+mod snapcast;
+use self::snapcast::SnapcastSink;
+
+mod channel;
+pub use self::channel::{ChannelData, ChannelSink};
+
 pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     #[cfg(feature = "rodio-backend")]
     (RodioSink::NAME, rodio::mk_rodio), // default goes first
@@ -137,6 +148,7 @@ pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     (SdlSink::NAME, mk_sink::<SdlSink>),
     (StdoutSink::NAME, mk_sink::<StdoutSink>),
     (SubprocessSink::NAME, mk_sink::<SubprocessSink>),
+    (SnapcastSink::NAME, mk_sink::<SnapcastSink>),
 ];
 
 pub fn find(name: Option<String>) -> Option<SinkBuilder> {
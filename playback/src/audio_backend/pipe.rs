@@ -1,9 +1,9 @@
 use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
-use crate::config::AudioFormat;
+use crate::config::{AudioFormat, TrackMarker};
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
 
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 use std::process::exit;
 use thiserror::Error;
@@ -21,6 +21,9 @@ enum StdoutError {
 
     #[error("<StdoutSink> The Output Stream is None")]
     NoOutput,
+
+    #[error("<StdoutSink> Failed to Write the Track Marker, {0}")]
+    MarkerFailure(std::io::Error),
 }
 
 impl From<StdoutError> for SinkError {
@@ -28,7 +31,7 @@ impl From<StdoutError> for SinkError {
         use StdoutError::*;
         let es = e.to_string();
         match e {
-            FlushFailure(_) | OnWrite(_) => SinkError::OnWrite(es),
+            FlushFailure(_) | OnWrite(_) | MarkerFailure(_) => SinkError::OnWrite(es),
             OpenFailure { .. } => SinkError::ConnectionRefused(es),
             NoOutput => SinkError::NotConnected(es),
         }
@@ -39,9 +42,28 @@ pub struct StdoutSink {
     output: Option<Box<dyn Write>>,
     file: Option<String>,
     format: AudioFormat,
+    // bytes written so far, for the track markers
+    position: u64,
+    marker_file: Option<File>,
+}
+
+#[cfg(unix)]
+fn marker_file(fd: i32) -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn marker_file(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "file descriptors are only supported on Unix",
+    ))
 }
 
 impl Open for StdoutSink {
+    #[allow(clippy::print_stdout)]
     fn open(file: Option<String>, format: AudioFormat) -> Self {
         if let Some("?") = file.as_deref() {
             println!("\nUsage:\n\nOutput to stdout:\n\n\t--backend pipe\n\nOutput to file:\n\n\t--backend pipe --device {{filename}}\n");
@@ -54,6 +76,8 @@ impl Open for StdoutSink {
             output: None,
             file,
             format,
+            position: 0,
+            marker_file: None,
         }
     }
 }
@@ -90,6 +114,26 @@ impl Sink for StdoutSink {
     }
 
     sink_as_bytes!();
+
+    fn track_boundary(&mut self, marker: Option<&TrackMarker>, uri: &str) -> SinkResult<()> {
+        match marker {
+            None => Ok(()),
+            Some(TrackMarker::Inline(bytes)) => self.write_bytes(bytes),
+            Some(TrackMarker::Fd(fd)) => {
+                if self.marker_file.is_none() {
+                    self.marker_file = Some(marker_file(*fd).map_err(StdoutError::MarkerFailure)?);
+                }
+
+                if let Some(file) = self.marker_file.as_mut() {
+                    writeln!(file, "{} {}", self.position, uri)
+                        .and_then(|_| file.flush())
+                        .map_err(StdoutError::MarkerFailure)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
 }
 
 impl SinkAsBytes for StdoutSink {
@@ -100,6 +144,8 @@ impl SinkAsBytes for StdoutSink {
             .write_all(data)
             .map_err(StdoutError::OnWrite)?;
 
+        self.position += data.len() as u64;
+
         Ok(())
     }
 }
@@ -32,6 +32,7 @@ fn output_devices() -> Box<dyn Iterator<Item = (DeviceIndex, DeviceInfo)>> {
     Box::new(devices)
 }
 
+#[allow(clippy::print_stdout)]
 fn list_outputs() {
     let default = get_default_output_index();
 
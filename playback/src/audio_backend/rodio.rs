@@ -96,6 +96,7 @@ fn list_formats(device: &rodio::Device) {
     }
 }
 
+#[allow(clippy::print_stdout)]
 fn list_outputs(host: &cpal::Host) -> Result<(), cpal::DevicesError> {
     let mut default_device_name = None;
 
@@ -0,0 +1,253 @@
+use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use crate::config::AudioFormat;
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// Streams to a Snapcast server, through the named pipe of a `pipe://` source, or to a `tcp://`
+// source in server mode. Snapcast reads raw PCM in the sample format of the source, which only
+// has integer formats with 24 bit samples stored in 32 bits.
+//
+// A pipe blocks while it's full, but a TCP source takes whatever it's sent, so the samples are
+// written at the rate they are played at, a little ahead of time.
+
+const DEFAULT_FIFO: &str = "/tmp/snapfifo";
+
+// how far ahead of the playback the samples are written
+const LEAD: Duration = Duration::from_millis(500);
+
+// falling behind by more than this, e.g. after a pause or a stall, starts the timing over
+const MAX_LAG: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+enum SnapcastError {
+    #[error("<SnapcastSink> {0}")]
+    OnWrite(io::Error),
+
+    #[error("<SnapcastSink> Pipe {file} Can Not be Opened, {e}")]
+    OpenFailure { file: String, e: io::Error },
+
+    #[error("<SnapcastSink> Can Not Connect to {address}, {e}")]
+    ConnectFailure { address: String, e: io::Error },
+
+    #[error("<SnapcastSink> Snapcast Can't Play Ogg, Passthrough is Not Supported")]
+    Passthrough,
+
+    #[error("<SnapcastSink> Failed to Flush the Output Stream, {0}")]
+    FlushFailure(io::Error),
+
+    #[error("<SnapcastSink> The Output Stream is None")]
+    NoOutput,
+}
+
+impl From<SnapcastError> for SinkError {
+    fn from(e: SnapcastError) -> SinkError {
+        use SnapcastError::*;
+        let es = e.to_string();
+        match e {
+            FlushFailure(_) | OnWrite(_) => SinkError::OnWrite(es),
+            OpenFailure { .. } | ConnectFailure { .. } => SinkError::ConnectionRefused(es),
+            Passthrough => SinkError::InvalidParams(es),
+            NoOutput => SinkError::NotConnected(es),
+        }
+    }
+}
+
+enum Output {
+    Pipe(File),
+    Tcp(TcpStream),
+}
+
+impl Output {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Self::Pipe(file) => file,
+            Self::Tcp(stream) => stream,
+        }
+    }
+}
+
+// the path of the pipe, or the host and port of a TCP source
+enum Target {
+    Pipe(String),
+    Tcp(String),
+}
+
+pub struct SnapcastSink {
+    output: Option<Output>,
+    target: Target,
+    format: AudioFormat,
+    // when the frames written since then are due to be played from
+    clock: Option<(Instant, u64)>,
+}
+
+impl Open for SnapcastSink {
+    #[allow(clippy::print_stdout)]
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        if let Some("?") = device.as_deref() {
+            println!("\nUsage:\n\nOutput to the pipe of a Snapcast source, e.g. pipe:///tmp/snapfifo?name=Spotify&sampleformat=44100:16:2:\n\n\t--backend snapcast [--device {{pipe}}]\n\nOutput to a Snapcast TCP source in server mode, e.g. tcp://0.0.0.0:4953?name=Spotify&mode=server:\n\n\t--backend snapcast --device tcp://{{host}}:{{port}}\n\nThe sample format of the source must match --format, which is S16 unless it's S24 or S32.\n");
+            exit(0);
+        }
+
+        let format = match format {
+            AudioFormat::S16 | AudioFormat::S24 | AudioFormat::S32 => format,
+            AudioFormat::S24_3 => {
+                warn!("Snapcast stores 24 bit samples in 32 bits, using S24 instead of S24_3");
+                AudioFormat::S24
+            }
+            _ => {
+                warn!("Snapcast doesn't support {:?}, using S16 instead", format);
+                AudioFormat::S16
+            }
+        };
+
+        let target = match device {
+            Some(device) => match device.strip_prefix("tcp://") {
+                Some(address) => Target::Tcp(address.to_string()),
+                None => Target::Pipe(device),
+            },
+            None => Target::Pipe(DEFAULT_FIFO.to_string()),
+        };
+
+        info!("Using SnapcastSink with format: {:?}", format);
+
+        Self {
+            output: None,
+            target,
+            format,
+            clock: None,
+        }
+    }
+}
+
+impl Sink for SnapcastSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.output.is_none() {
+            let output = match &self.target {
+                Target::Tcp(address) => {
+                    let stream =
+                        TcpStream::connect(address).map_err(|e| SnapcastError::ConnectFailure {
+                            address: address.to_string(),
+                            e,
+                        })?;
+                    // the chunks are paced, so there's nothing to gain from waiting for more
+                    let _ = stream.set_nodelay(true);
+                    Output::Tcp(stream)
+                }
+                Target::Pipe(file) => {
+                    let file = OpenOptions::new().write(true).open(file).map_err(|e| {
+                        SnapcastError::OpenFailure {
+                            file: file.to_string(),
+                            e,
+                        }
+                    })?;
+                    Output::Pipe(file)
+                }
+            };
+            self.output = Some(output);
+        }
+
+        self.clock = None;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.clock = None;
+
+        self.output
+            .take()
+            .ok_or(SnapcastError::NoOutput)?
+            .writer()
+            .flush()
+            .map_err(SnapcastError::FlushFailure)?;
+
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        use zerocopy::AsBytes;
+        match packet {
+            AudioPacket::Samples(samples) => match self.format {
+                AudioFormat::S32 => {
+                    let samples_s32: &[i32] = &converter.f64_to_s32(&samples);
+                    self.write_bytes(samples_s32.as_bytes())
+                }
+                AudioFormat::S24 => {
+                    let samples_s24: &[i32] = &converter.f64_to_s24(&samples);
+                    self.write_bytes(samples_s24.as_bytes())
+                }
+                _ => {
+                    let samples_s16: &[i16] = &converter.f64_to_s16(&samples);
+                    self.write_bytes(samples_s16.as_bytes())
+                }
+            },
+            AudioPacket::OggData(_) => Err(SnapcastError::Passthrough.into()),
+        }
+    }
+}
+
+impl SinkAsBytes for SnapcastSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        let result = self
+            .output
+            .as_mut()
+            .ok_or(SnapcastError::NoOutput)?
+            .writer()
+            .write_all(data);
+
+        // a TCP source that restarted gets one attempt to reconnect
+        if let Err(e) = result {
+            self.output = None;
+            if matches!(self.target, Target::Pipe(_)) || self.start().is_err() {
+                return Err(SnapcastError::OnWrite(e).into());
+            }
+            self.output
+                .as_mut()
+                .ok_or(SnapcastError::NoOutput)?
+                .writer()
+                .write_all(data)
+                .map_err(SnapcastError::OnWrite)?;
+        }
+
+        let frames = (data.len() / (self.format.size() * NUM_CHANNELS as usize)) as u64;
+        self.pace(frames);
+
+        Ok(())
+    }
+}
+
+impl SnapcastSink {
+    pub const NAME: &'static str = "snapcast";
+
+    // Waits until the `frames` just written are no more than `LEAD` ahead of the playback.
+    fn pace(&mut self, frames: u64) {
+        let now = Instant::now();
+        let (started, written) = match self.clock {
+            Some((started, written))
+                if duration_of(written) + MAX_LAG > now.duration_since(started) =>
+            {
+                (started, written + frames)
+            }
+            _ => (now, frames),
+        };
+        self.clock = Some((started, written));
+
+        let due = started + duration_of(written);
+        if let Some(ahead) = due.checked_duration_since(now + LEAD) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+fn duration_of(frames: u64) -> Duration {
+    Duration::from_micros(frames * 1_000_000 / u64::from(SAMPLE_RATE))
+}
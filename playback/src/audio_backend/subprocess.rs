@@ -66,6 +66,7 @@ pub struct SubprocessSink {
 }
 
 impl Open for SubprocessSink {
+    #[allow(clippy::print_stdout)]
     fn open(shell_command: Option<String>, format: AudioFormat) -> Self {
         if let Some("?") = shell_command.as_deref() {
             println!("\nUsage:\n\nOutput to a Subprocess:\n\n\t--backend subprocess --device {{shell_command}}\n");
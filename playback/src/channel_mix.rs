@@ -0,0 +1,105 @@
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicI8, Ordering};
+use std::sync::Arc;
+
+use crate::NUM_CHANNELS;
+
+// Balance and channel swap for rooms with asymmetric speaker placement, applied to the decoded
+// samples after normalisation and volume control.
+//
+// The balance attenuates the channel on the other side linearly, so +50 plays the left channel
+// at half the amplitude and +100 mutes it. It applies to the output channels, i.e. after
+// swapping.
+
+/// From -100 (left only) to +100 (right only).
+pub const BALANCE_RANGE: RangeInclusive<i8> = -100..=100;
+
+/// Clones share the settings, so they can be changed while playing.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelMix(Arc<(AtomicI8, AtomicBool)>);
+
+impl ChannelMix {
+    pub fn new(balance: i8, swap_channels: bool) -> Self {
+        let mix = Self::default();
+        mix.set_balance(balance);
+        mix.set_swap_channels(swap_channels);
+        mix
+    }
+
+    pub fn balance(&self) -> i8 {
+        (self.0).0.load(Ordering::Relaxed)
+    }
+
+    /// Clamped to `BALANCE_RANGE`.
+    pub fn set_balance(&self, balance: i8) {
+        let balance = balance.clamp(*BALANCE_RANGE.start(), *BALANCE_RANGE.end());
+        (self.0).0.store(balance, Ordering::Relaxed);
+    }
+
+    pub fn swap_channels(&self) -> bool {
+        (self.0).1.load(Ordering::Relaxed)
+    }
+
+    pub fn set_swap_channels(&self, swap_channels: bool) {
+        (self.0).1.store(swap_channels, Ordering::Relaxed);
+    }
+
+    /// Processes interleaved samples in place.
+    pub fn apply(&self, samples: &mut [f64]) {
+        let balance = self.balance();
+        let swap_channels = self.swap_channels();
+        if balance == 0 && !swap_channels {
+            return;
+        }
+
+        let left_gain = 1.0 - f64::from(balance.max(0)) / 100.0;
+        let right_gain = 1.0 + f64::from(balance.min(0)) / 100.0;
+
+        for frame in samples.chunks_exact_mut(NUM_CHANNELS as usize) {
+            if swap_channels {
+                frame.swap(0, 1);
+            }
+            frame[0] *= left_gain;
+            frame[1] *= right_gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn neutral() {
+        let mut samples = [0.5, -0.25, 1.0, 0.0];
+        ChannelMix::default().apply(&mut samples);
+        assert_eq!(samples, [0.5, -0.25, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn balance() {
+        let mix = ChannelMix::new(50, false);
+        let mut samples = [0.5, -0.25, 1.0, 1.0];
+        mix.apply(&mut samples);
+        assert_eq!(samples, [0.25, -0.25, 0.5, 1.0]);
+
+        mix.set_balance(-100);
+        let mut samples = [0.5, -0.25, 1.0, 1.0];
+        mix.apply(&mut samples);
+        assert_eq!(samples, [0.5, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn swap_channels_before_balance() {
+        let mix = ChannelMix::new(100, true);
+        let mut samples = [0.5, -0.25];
+        mix.apply(&mut samples);
+        assert_eq!(samples, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn balance_is_clamped() {
+        assert_eq!(ChannelMix::new(i8::MIN, false).balance(), -100);
+        assert_eq!(ChannelMix::new(i8::MAX, false).balance(), 100);
+    }
+}
@@ -1,6 +1,11 @@
 use std::{mem, str::FromStr, time::Duration};
 
+pub use crate::channel_mix::ChannelMix;
+pub use crate::decoder::{AudioCodec, DecoderBuilder};
 pub use crate::dither::{mk_ditherer, DithererBuilder, TriangularDitherer};
+pub use crate::equalizer::EqBand;
+pub use crate::output_profile::{ActiveProfile, OutputProfile};
+pub use crate::time_stretch::PlaybackSpeed;
 use crate::{convert::i24, player::duration_to_coefficient};
 
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
@@ -121,11 +126,25 @@ impl Default for NormalisationMethod {
     }
 }
 
+/// How the pipe backend signals where one track ends and the next one starts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackMarker {
+    /// Write these bytes into the audio stream.
+    Inline(Vec<u8>),
+    /// Write the position in the audio stream in bytes and the track's URI as a line to this
+    /// file descriptor.
+    Fd(i32),
+}
+
 #[derive(Clone)]
 pub struct PlayerConfig {
     pub bitrate: Bitrate,
     pub gapless: bool,
     pub passthrough: bool,
+    // add Spotify's normalisation data as ReplayGain tags to the passthrough stream
+    pub replaygain_tags: bool,
+    // add title, artists, album etc. as Vorbis comments to the passthrough stream
+    pub metadata_tags: bool,
 
     pub normalisation: bool,
     pub normalisation_type: NormalisationType,
@@ -140,6 +159,57 @@ pub struct PlayerConfig {
     // (thereby circumventing Send bounds that they might not satisfy)
     pub ditherer: Option<DithererBuilder>,
 
+    // used instead of the built-in decoder for the codec, see `decoder::DECODERS`
+    pub decoders: Vec<(AudioCodec, DecoderBuilder)>,
+
+    // applied to decoded samples only, has no effect in passthrough mode
+    pub equalizer: Vec<EqBand>,
+
+    // keep at least this much audio downloaded ahead of the playback position while
+    // streaming, and download the rest of each track as soon as it's loaded if either is set
+    pub prefetch_bytes: usize,
+    pub prefetch_duration: Duration,
+
+    // cap how much of a track is downloaded ahead of playback, as downloads are kept in a
+    // temporary file, which is in memory on many small hosts
+    pub max_buffer_bytes: Option<usize>,
+
+    // signal track boundaries in the output, only supported by the pipe backend
+    pub track_marker: Option<TrackMarker>,
+
+    // skip explicit tracks, even if the account doesn't filter explicit content
+    pub filter_explicit: bool,
+
+    // announce the change to the next track this long before the current one ends, see
+    // `PlayerEvent::TrackChanging`
+    pub track_change_lead: Option<Duration>,
+
+    // end each track at this position, to play a clip in single track mode
+    pub stop_position_ms: Option<u32>,
+
+    // gain in dB applied to all output, whether normalised or not, see `NormalisationData`
+    pub pre_gain_db: f64,
+
+    // limit the output with the dynamic normalisation limiter and its settings, whether
+    // normalisation is enabled or not
+    pub limiter: bool,
+
+    // balance and channel swap, shared with the running players to change them while playing
+    pub channel_mix: ChannelMix,
+
+    // the speed podcast episodes are played at, shared with the running players like `channel_mix`
+    pub speed: PlaybackSpeed,
+
+    // overrides `normalisation` and `equalizer` while set, shared like `channel_mix`
+    pub profile: ActiveProfile,
+
+    // measure the EBU R128 loudness of the played audio per track, see `loudness`
+    pub analyze_loudness: bool,
+
+    // remember the keys of played tracks, to play them from the audio cache when Spotify can't
+    // be reached
+    pub offline_fallback: bool,
+
     pub lms_connect_mode: bool,
 }
 
@@ -157,7 +227,25 @@ impl Default for PlayerConfig {
             normalisation_release_cf: duration_to_coefficient(Duration::from_millis(100)),
             normalisation_knee_db: 5.0,
             passthrough: false,
+            replaygain_tags: false,
+            metadata_tags: false,
             ditherer: Some(mk_ditherer::<TriangularDitherer>),
+            decoders: Vec::new(),
+            equalizer: Vec::new(),
+            prefetch_bytes: 0,
+            prefetch_duration: Duration::ZERO,
+            max_buffer_bytes: None,
+            track_marker: None,
+            filter_explicit: false,
+            track_change_lead: None,
+            stop_position_ms: None,
+            pre_gain_db: 0.0,
+            limiter: false,
+            channel_mix: ChannelMix::default(),
+            speed: PlaybackSpeed::default(),
+            profile: ActiveProfile::default(),
+            analyze_loudness: false,
+            offline_fallback: false,
             lms_connect_mode: false,
         }
     }
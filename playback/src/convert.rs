@@ -1,4 +1,5 @@
 use crate::dither::{Ditherer, DithererBuilder};
+use crate::dsp;
 use zerocopy::AsBytes;
 
 #[derive(AsBytes, Copy, Clone, Debug)]
@@ -89,7 +90,12 @@ impl Converter {
         samples.iter().map(|sample| *sample as f32).collect()
     }
 
+    // Without a ditherer every sample is converted on its own, which `dsp` vectorises.
+
     pub fn f64_to_s32(&mut self, samples: &[f64]) -> Vec<i32> {
+        if self.ditherer.is_none() {
+            return dsp::to_s32(samples, Self::SCALE_S32);
+        }
         samples
             .iter()
             .map(|sample| self.scale(*sample, Self::SCALE_S32) as i32)
@@ -98,6 +104,9 @@ impl Converter {
 
     // S24 is 24-bit PCM packed in an upper 32-bit word
     pub fn f64_to_s24(&mut self, samples: &[f64]) -> Vec<i32> {
+        if self.ditherer.is_none() {
+            return dsp::to_s32(samples, Self::SCALE_S24);
+        }
         samples
             .iter()
             .map(|sample| self.clamping_scale(*sample, Self::SCALE_S24) as i32)
@@ -106,6 +115,12 @@ impl Converter {
 
     // S24_3 is 24-bit PCM in a 3-byte array
     pub fn f64_to_s24_3(&mut self, samples: &[f64]) -> Vec<i24> {
+        if self.ditherer.is_none() {
+            return dsp::to_s32(samples, Self::SCALE_S24)
+                .into_iter()
+                .map(i24::from_s24)
+                .collect();
+        }
         samples
             .iter()
             .map(|sample| i24::from_s24(self.clamping_scale(*sample, Self::SCALE_S24) as i32))
@@ -113,6 +128,9 @@ impl Converter {
     }
 
     pub fn f64_to_s16(&mut self, samples: &[f64]) -> Vec<i16> {
+        if self.ditherer.is_none() {
+            return dsp::to_s16(samples, Self::SCALE_S16);
+        }
         samples
             .iter()
             .map(|sample| self.scale(*sample, Self::SCALE_S16) as i16)
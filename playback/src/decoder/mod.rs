@@ -1,5 +1,10 @@
+use std::io::{Read, Seek};
+
+use bytes::Bytes;
 use thiserror::Error;
 
+use crate::metadata::FileFormat;
+
 mod lewton_decoder;
 pub use lewton_decoder::VorbisDecoder;
 
@@ -12,6 +17,8 @@ pub enum DecoderError {
     LewtonDecoder(String),
     #[error("Passthrough Decoder Error: {0}")]
     PassthroughDecoder(String),
+    #[error("Decoder Error: {0}")]
+    Other(String),
 }
 
 pub type DecoderResult<T> = Result<T, DecoderError>;
@@ -28,7 +35,8 @@ pub type AudioPacketResult<T> = Result<T, AudioPacketError>;
 
 pub enum AudioPacket {
     Samples(Vec<f64>),
-    OggData(Vec<u8>),
+    // Ogg pages, shared rather than copied on their way to the sink
+    OggData(Bytes),
 }
 
 impl AudioPacket {
@@ -63,3 +71,46 @@ pub trait AudioDecoder {
     fn seek(&mut self, absgp: u64) -> DecoderResult<()>;
     fn next_packet(&mut self) -> DecoderResult<Option<AudioPacket>>;
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioCodec {
+    Vorbis,
+    Mp3,
+    Aac,
+}
+
+impl AudioCodec {
+    pub fn from_format(format: FileFormat) -> Option<Self> {
+        use FileFormat::*;
+        match format {
+            OGG_VORBIS_96 | OGG_VORBIS_160 | OGG_VORBIS_320 => Some(Self::Vorbis),
+            MP3_96 | MP3_160 | MP3_160_ENC | MP3_256 | MP3_320 => Some(Self::Mp3),
+            AAC_160 | AAC_320 | MP4_128 | MP4_128_DUAL => Some(Self::Aac),
+            OTHER3 | OTHER5 => None,
+        }
+    }
+}
+
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Creates a decoder for an (already decrypted) audio file. Decoders for other
+/// codecs or implementations can be plugged in with `PlayerConfig::decoders`.
+pub type DecoderBuilder = fn(Box<dyn ReadSeek>) -> DecoderResult<Box<dyn AudioDecoder + Send>>;
+
+fn mk_vorbis(input: Box<dyn ReadSeek>) -> DecoderResult<Box<dyn AudioDecoder + Send>> {
+    Ok(Box::new(VorbisDecoder::new(input)?))
+}
+
+pub const DECODERS: &[(&str, AudioCodec, DecoderBuilder)] = &[
+    ("lewton", AudioCodec::Vorbis, mk_vorbis), // default goes first
+];
+
+pub fn find(codec: AudioCodec, name: Option<&str>) -> Option<DecoderBuilder> {
+    DECODERS
+        .iter()
+        .filter(|decoder| decoder.1 == codec)
+        .find(|decoder| name.map_or(true, |name| name == decoder.0))
+        .map(|decoder| decoder.2)
+}
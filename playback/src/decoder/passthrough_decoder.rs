@@ -1,5 +1,7 @@
 // Passthrough decoder for librespot
 use super::{AudioDecoder, AudioPacket, DecoderError, DecoderResult};
+use bytes::buf::Writer;
+use bytes::{BufMut, BytesMut};
 use ogg::{OggReadError, Packet, PacketReader, PacketWriteEndInfo, PacketWriter};
 use std::io::{Read, Seek};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -22,9 +24,55 @@ where
     Ok(pck.data.into_boxed_slice())
 }
 
+// Appends user comments to a Vorbis comment header packet, see
+// https://xiph.org/vorbis/doc/v-comment.html
+fn append_comments(header: &[u8], comments: &[String]) -> DecoderResult<Box<[u8]>> {
+    // packet type byte followed by "vorbis"
+    const COMMENT_HEADER_PREFIX_LEN: usize = 7;
+
+    let invalid = || DecoderError::PassthroughDecoder("Invalid Comment Header".to_string());
+    let read_len = |pos: usize| {
+        header
+            .get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(invalid)
+    };
+
+    let vendor_len = read_len(COMMENT_HEADER_PREFIX_LEN)?;
+    let count_pos = COMMENT_HEADER_PREFIX_LEN + 4 + vendor_len;
+    let count = read_len(count_pos)?;
+
+    let mut end_pos = count_pos + 4;
+    for _ in 0..count {
+        end_pos += 4 + read_len(end_pos)?;
+    }
+
+    if end_pos > header.len() {
+        return Err(invalid());
+    }
+
+    let mut data =
+        Vec::with_capacity(header.len() + comments.iter().map(|c| c.len() + 4).sum::<usize>());
+    data.extend_from_slice(&header[..count_pos]);
+    data.extend_from_slice(&((count + comments.len()) as u32).to_le_bytes());
+    data.extend_from_slice(&header[count_pos + 4..end_pos]);
+    for comment in comments {
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    // framing bit
+    data.extend_from_slice(&header[end_pos..]);
+
+    Ok(data.into_boxed_slice())
+}
+
+// A little more than the largest Ogg page, 255 segments of 255 bytes plus the header
+const PAGE_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct PassthroughDecoder<R: Read + Seek> {
     rdr: PacketReader<R>,
-    wtr: PacketWriter<Vec<u8>>,
+    // the pages are split off without copying, reusing the buffer once the sink dropped them
+    wtr: PacketWriter<Writer<BytesMut>>,
     eos: bool,
     bos: bool,
     ofsgp_page: u64,
@@ -55,7 +103,7 @@ impl<R: Read + Seek> PassthroughDecoder<R> {
 
         Ok(PassthroughDecoder {
             rdr,
-            wtr: PacketWriter::new(Vec::new()),
+            wtr: PacketWriter::new(BytesMut::with_capacity(PAGE_BUFFER_SIZE).writer()),
             ofsgp_page: 0,
             stream_serial,
             ident,
@@ -65,6 +113,14 @@ impl<R: Read + Seek> PassthroughDecoder<R> {
             bos: false,
         })
     }
+
+    /// Adds `KEY=value` comments to the Vorbis comment header written to the output,
+    /// e.g. ReplayGain tags.
+    pub fn append_comments(&mut self, comments: &[String]) -> DecoderResult<()> {
+        self.comment = append_comments(&self.comment, comments)?;
+        debug!("Added comments {:?}", comments);
+        Ok(())
+    }
 }
 
 impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
@@ -181,10 +237,10 @@ impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
                 )
                 .map_err(|e| DecoderError::PassthroughDecoder(e.to_string()))?;
 
-            let data = self.wtr.inner_mut();
+            let data = self.wtr.inner_mut().get_mut();
 
             if !data.is_empty() {
-                let ogg_data = AudioPacket::OggData(std::mem::take(data));
+                let ogg_data = AudioPacket::OggData(data.split().freeze());
                 return Ok(Some(ogg_data));
             }
         }
@@ -0,0 +1,144 @@
+// The loops of the sample processing that work on each sample on its own, dispatched at runtime
+// to a version compiled for the vector extensions of the CPU it runs on: the volume and gain
+// scaling, and the conversion to integer samples without dithering. Release builds target the
+// baseline of each architecture, so they run on old NAS and router hardware, without giving up
+// the wider vectors of newer CPUs.
+//
+// The limiter, the equaliser and the ditherer carry state from one sample to the next, so they
+// can't be vectorised this way and always use the generic code.
+//
+// On ARM the vector extensions can't be detected at runtime on stable Rust: aarch64 always has
+// NEON, 32-bit ARM only uses it when built with `-C target-feature=+neon`. ARMv5, soft-float
+// and MIPS builds use the generic loops.
+
+/// The vector extensions the loops can use on this CPU, for the log and `--check`.
+pub fn cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            features.push("sse2");
+        }
+        if is_x86_feature_detected!("avx") {
+            features.push("avx");
+        }
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_feature = "neon"))]
+    features.push("neon");
+
+    features
+}
+
+/// Multiplies the samples by `factor` in place.
+pub fn scale(samples: &mut [f64], factor: f64) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // Safe, the CPU supports AVX.
+            return unsafe { scale_avx(samples, factor) };
+        }
+    }
+
+    scale_generic(samples, factor)
+}
+
+#[inline(always)]
+fn scale_generic(samples: &mut [f64], factor: f64) {
+    for sample in samples.iter_mut() {
+        *sample *= factor;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn scale_avx(samples: &mut [f64], factor: f64) {
+    scale_generic(samples, factor)
+}
+
+/// Rounds `samples * factor` to the nearest integer, saturating at the bounds of `i16`.
+pub fn to_s16(samples: &[f64], factor: f64) -> Vec<i16> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // Safe, the CPU supports AVX.
+            return unsafe { to_s16_avx(samples, factor) };
+        }
+    }
+
+    to_s16_generic(samples, factor)
+}
+
+#[inline(always)]
+fn to_s16_generic(samples: &[f64], factor: f64) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|sample| (sample * factor).round() as i16)
+        .collect()
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn to_s16_avx(samples: &[f64], factor: f64) -> Vec<i16> {
+    to_s16_generic(samples, factor)
+}
+
+/// Rounds `samples * factor` to the nearest integer, clamped to `-factor..=factor - 1`, which
+/// keeps the padding of 24 bit samples in 32 bits clear.
+pub fn to_s32(samples: &[f64], factor: f64) -> Vec<i32> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // Safe, the CPU supports AVX.
+            return unsafe { to_s32_avx(samples, factor) };
+        }
+    }
+
+    to_s32_generic(samples, factor)
+}
+
+#[inline(always)]
+fn to_s32_generic(samples: &[f64], factor: f64) -> Vec<i32> {
+    samples
+        .iter()
+        .map(|sample| (sample * factor).round().clamp(-factor, factor - 1.0) as i32)
+        .collect()
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn to_s32_avx(samples: &[f64], factor: f64) -> Vec<i32> {
+    to_s32_generic(samples, factor)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale() {
+        // not a multiple of the vector width
+        let mut samples: Vec<f64> = (0..37).map(|i| i as f64 / 37.0 - 0.5).collect();
+        let mut expected = samples.clone();
+        scale_generic(&mut expected, 0.25);
+
+        scale(&mut samples, 0.25);
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn test_to_integer() {
+        let samples = [-1.5, -1.0, -0.5, 0.0, 0.5, 1.0, 1.5];
+        assert_eq!(
+            to_s16(&samples, 32768.),
+            [-32768, -32768, -16384, 0, 16384, 32767, 32767]
+        );
+        assert_eq!(
+            to_s32(&samples, 8388608.),
+            [-8388608, -8388608, -4194304, 0, 4194304, 8388607, 8388607]
+        );
+        assert_eq!(to_s32(&[1.0], 2147483648.), [i32::MAX]);
+    }
+}
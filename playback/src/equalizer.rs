@@ -0,0 +1,254 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use crate::player::db_to_ratio;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+// A small parametric equalizer made of cascaded biquad filters, applied to the
+// decoded samples before normalisation and volume control. Useful for basic
+// room correction on Squeezebox Radios and cheap DACs.
+//
+// Filter coefficients taken from the Audio EQ Cookbook by Robert Bristow-Johnson:
+// https://www.w3.org/TR/audio-eq-cookbook/
+//
+// Bands are given as `FREQ:GAIN[:Q]`, e.g. `60:+3,1000:0,8000:-2:0.7`. Instead of
+// a frequency, `bass` and `treble` may be used for simple tone controls, which
+// map to shelving filters at fixed corner frequencies.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EqFilter {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    pub filter: EqFilter,
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+impl EqBand {
+    pub const DEFAULT_Q: f64 = 1.0;
+    pub const DEFAULT_SHELF_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+    pub const BASS_FREQUENCY: f64 = 100.0;
+    pub const TREBLE_FREQUENCY: f64 = 10000.0;
+    pub const MAX_GAIN_DB: f64 = 24.0;
+}
+
+impl FromStr for EqBand {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split(':').map(str::trim);
+
+        let (filter, frequency, default_q) = match parts.next().ok_or(())? {
+            "bass" => (
+                EqFilter::LowShelf,
+                Self::BASS_FREQUENCY,
+                Self::DEFAULT_SHELF_Q,
+            ),
+            "treble" => (
+                EqFilter::HighShelf,
+                Self::TREBLE_FREQUENCY,
+                Self::DEFAULT_SHELF_Q,
+            ),
+            frequency => (
+                EqFilter::Peaking,
+                frequency.parse::<f64>().map_err(|_| ())?,
+                Self::DEFAULT_Q,
+            ),
+        };
+
+        let gain_db = parts.next().ok_or(())?.parse::<f64>().map_err(|_| ())?;
+
+        let q = match parts.next() {
+            Some(q) => q.parse::<f64>().map_err(|_| ())?,
+            None => default_q,
+        };
+
+        if parts.next().is_some()
+            || !(frequency > 0.0 && frequency < SAMPLE_RATE as f64 / 2.0)
+            || !(gain_db.is_finite() && gain_db.abs() <= Self::MAX_GAIN_DB)
+            || !(q > 0.0 && q.is_finite())
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            filter,
+            frequency,
+            gain_db,
+            q,
+        })
+    }
+}
+
+/// Parses a list of bands separated by commas or newlines. Empty lines and
+/// lines starting with `#` are ignored, so the same syntax can be used in a file.
+/// On error the offending band is returned.
+pub fn parse_eq_bands(s: &str) -> Result<Vec<EqBand>, String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|band| !band.is_empty())
+        .map(|band| EqBand::from_str(band).map_err(|_| band.to_string()))
+        .collect()
+}
+
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    // x[n-1], x[n-2], y[n-1], y[n-2] per channel
+    state: [[f64; 4]; NUM_CHANNELS as usize],
+}
+
+impl Biquad {
+    fn new(band: &EqBand) -> Self {
+        let a = db_to_ratio(band.gain_db / 2.0);
+        let w0 = 2.0 * PI * band.frequency / SAMPLE_RATE as f64;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * band.q);
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter {
+            EqFilter::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            EqFilter::LowShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+            EqFilter::HighShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            state: [[0.0; 4]; NUM_CHANNELS as usize],
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, sample: f64, channel: usize) -> f64 {
+        let [x1, x2, y1, y2] = self.state[channel];
+        let y = self.b0 * sample + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+        self.state[channel] = [sample, x1, y, y1];
+        y
+    }
+
+    fn reset(&mut self) {
+        self.state = [[0.0; 4]; NUM_CHANNELS as usize];
+    }
+}
+
+pub struct Equalizer {
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(bands: &[EqBand]) -> Self {
+        for band in bands {
+            debug!("Equalizer band: {:?}", band);
+        }
+
+        Self {
+            filters: bands
+                .iter()
+                // bands without gain are a no-op, don't waste CPU cycles on them
+                .filter(|band| band.gain_db != 0.0)
+                .map(Biquad::new)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Filters interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f64]) {
+        if self.filters.is_empty() {
+            return;
+        }
+
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let channel = i % NUM_CHANNELS as usize;
+            for filter in self.filters.iter_mut() {
+                *sample = filter.process(*sample, channel);
+            }
+        }
+    }
+
+    /// Clears the filter history, e.g. after a seek or track change.
+    pub fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_bands() {
+        let bands = parse_eq_bands("60:+3, 1000:0,8000:-2:0.7").unwrap();
+        assert_eq!(bands.len(), 3);
+        assert_eq!(bands[0].frequency, 60.0);
+        assert_eq!(bands[0].gain_db, 3.0);
+        assert_eq!(bands[0].q, EqBand::DEFAULT_Q);
+        assert_eq!(bands[2].q, 0.7);
+
+        let bands = parse_eq_bands("# tone controls\nbass:+4\ntreble:-1.5\n").unwrap();
+        assert_eq!(bands[0].filter, EqFilter::LowShelf);
+        assert_eq!(bands[1].filter, EqFilter::HighShelf);
+
+        assert!(parse_eq_bands("60").is_err());
+        assert!(parse_eq_bands("0:3").is_err());
+        assert!(parse_eq_bands("30000:3").is_err());
+        assert!(parse_eq_bands("60:3:0").is_err());
+        assert!(parse_eq_bands("60:3:1:1").is_err());
+    }
+
+    #[test]
+    fn flat_band_passes_through() {
+        let mut eq = Equalizer::new(&parse_eq_bands("1000:0").unwrap());
+        assert!(eq.is_empty());
+
+        let mut samples = vec![0.5, -0.5, 0.25, -0.25];
+        eq.process(&mut samples);
+        assert_eq!(samples, vec![0.5, -0.5, 0.25, -0.25]);
+    }
+}
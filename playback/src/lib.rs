@@ -1,3 +1,6 @@
+// Audio may be written to stdout by the pipe backend, so nothing else must be.
+#![deny(clippy::print_stdout)]
+
 #[macro_use]
 extern crate log;
 
@@ -6,12 +9,18 @@ use librespot_core as core;
 use librespot_metadata as metadata;
 
 pub mod audio_backend;
+pub mod channel_mix;
 pub mod config;
 pub mod convert;
 pub mod decoder;
 pub mod dither;
+pub mod dsp;
+pub mod equalizer;
+pub mod loudness;
 pub mod mixer;
+pub mod output_profile;
 pub mod player;
+pub mod time_stretch;
 
 pub const SAMPLE_RATE: u32 = 44100;
 pub const NUM_CHANNELS: u8 = 2;
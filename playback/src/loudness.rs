@@ -0,0 +1,280 @@
+use std::f64::consts::PI;
+
+use crate::player::ratio_to_db;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+// Measures the loudness of the played audio according to EBU R128 / ITU-R BS.1770-4, to verify
+// the normalisation settings on a given output chain.
+//
+// The samples are K-weighted, their mean square is taken over blocks of 400 ms overlapping by
+// 75 %, and blocks quieter than -70 LUFS or 10 LU below the mean of the remaining ones are gated
+// out. The true peak is estimated by oversampling four times.
+
+// Sub-blocks of 100 ms, four of which make a gating block
+const SUB_BLOCK_FRAMES: usize = SAMPLE_RATE as usize / 10;
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+const OVERSAMPLING: usize = 4;
+const INTERPOLATION_TAPS: usize = 12;
+
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    // x[n-1], x[n-2], y[n-1], y[n-2] per channel
+    state: [[f64; 4]; NUM_CHANNELS as usize],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [[0.0; 4]; NUM_CHANNELS as usize],
+        }
+    }
+
+    // The two stages of the K-weighting filter, for any sample rate. The coefficients are
+    // derived from the 48 kHz ones in BS.1770, as done by libebur128.
+    fn k_weighting() -> [Self; 2] {
+        let rate = SAMPLE_RATE as f64;
+
+        // high shelf, modelling the acoustic effect of the head
+        let f0 = 1681.974450955533;
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        // high pass
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        [shelf, high_pass]
+    }
+
+    #[inline]
+    fn process(&mut self, sample: f64, channel: usize) -> f64 {
+        let [x1, x2, y1, y2] = self.state[channel];
+        let y =
+            self.b[0] * sample + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+        self.state[channel] = [sample, x1, y, y1];
+        y
+    }
+}
+
+// Hann windowed sinc, to interpolate between the middle two of `INTERPOLATION_TAPS` samples
+fn interpolation_coefficients() -> [[f64; INTERPOLATION_TAPS]; OVERSAMPLING - 1] {
+    let half = INTERPOLATION_TAPS as f64 / 2.0;
+    let mut coefficients = [[0.0; INTERPOLATION_TAPS]; OVERSAMPLING - 1];
+
+    for (phase, taps) in coefficients.iter_mut().enumerate() {
+        let position = half - 1.0 + (phase + 1) as f64 / OVERSAMPLING as f64;
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = position - i as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.5 * (1.0 + (PI * x / half).cos());
+            *tap = sinc * window;
+        }
+    }
+
+    coefficients
+}
+
+/// The loudness of a track, see `LoudnessMeter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// `None` if the audio was quieter than the absolute gate throughout.
+    pub integrated_lufs: Option<f64>,
+    pub true_peak_dbtp: f64,
+}
+
+pub struct LoudnessMeter {
+    filters: [Biquad; 2],
+    sub_block_power: f64,
+    sub_block_frames: usize,
+    // the mean squares of the last sub-blocks, to make the overlapping blocks from
+    recent_sub_blocks: Vec<f64>,
+    blocks: Vec<f64>,
+    interpolation: [[f64; INTERPOLATION_TAPS]; OVERSAMPLING - 1],
+    history: [[f64; INTERPOLATION_TAPS]; NUM_CHANNELS as usize],
+    peak: f64,
+}
+
+impl Default for LoudnessMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoudnessMeter {
+    pub fn new() -> Self {
+        Self {
+            filters: Biquad::k_weighting(),
+            sub_block_power: 0.0,
+            sub_block_frames: 0,
+            recent_sub_blocks: Vec::with_capacity(SUB_BLOCKS_PER_BLOCK),
+            blocks: Vec::new(),
+            interpolation: interpolation_coefficients(),
+            history: [[0.0; INTERPOLATION_TAPS]; NUM_CHANNELS as usize],
+            peak: 0.0,
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn process(&mut self, samples: &[f64]) {
+        for frame in samples.chunks_exact(NUM_CHANNELS as usize) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                let mut weighted = sample;
+                for filter in self.filters.iter_mut() {
+                    weighted = filter.process(weighted, channel);
+                }
+                // all channels are weighted equally for stereo
+                self.sub_block_power += weighted * weighted;
+
+                self.measure_peak(sample, channel);
+            }
+
+            self.sub_block_frames += 1;
+            if self.sub_block_frames == SUB_BLOCK_FRAMES {
+                self.end_sub_block();
+            }
+        }
+    }
+
+    fn measure_peak(&mut self, sample: f64, channel: usize) {
+        let history = &mut self.history[channel];
+        history.rotate_left(1);
+        history[INTERPOLATION_TAPS - 1] = sample;
+
+        let mut peak = sample.abs();
+        for taps in self.interpolation.iter() {
+            let interpolated: f64 = taps.iter().zip(history.iter()).map(|(t, x)| t * x).sum();
+            peak = peak.max(interpolated.abs());
+        }
+        self.peak = self.peak.max(peak);
+    }
+
+    fn end_sub_block(&mut self) {
+        if self.recent_sub_blocks.len() == SUB_BLOCKS_PER_BLOCK {
+            self.recent_sub_blocks.remove(0);
+        }
+        self.recent_sub_blocks
+            .push(self.sub_block_power / SUB_BLOCK_FRAMES as f64);
+        self.sub_block_power = 0.0;
+        self.sub_block_frames = 0;
+
+        if self.recent_sub_blocks.len() == SUB_BLOCKS_PER_BLOCK {
+            let power = self.recent_sub_blocks.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64;
+            self.blocks.push(power);
+        }
+    }
+
+    /// The loudness measured since the meter was created or reset. `None` if less than one
+    /// block of 400 ms was measured.
+    pub fn loudness(&self) -> Option<Loudness> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+
+        let gated_mean = |threshold_lufs: f64| {
+            let gated: Vec<f64> = self
+                .blocks
+                .iter()
+                .copied()
+                .filter(|&power| power_to_lufs(power) > threshold_lufs)
+                .collect();
+            Some(gated.iter().sum::<f64>() / gated.len() as f64).filter(|_| !gated.is_empty())
+        };
+
+        let integrated_lufs = gated_mean(ABSOLUTE_GATE_LUFS)
+            .map(|power| power_to_lufs(power) + RELATIVE_GATE_LU)
+            .and_then(|relative_gate| gated_mean(relative_gate.max(ABSOLUTE_GATE_LUFS)))
+            .map(power_to_lufs);
+
+        Some(Loudness {
+            integrated_lufs,
+            true_peak_dbtp: ratio_to_db(self.peak),
+        })
+    }
+
+    /// Starts measuring anew, e.g. for the next track.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, seconds: f64) -> Vec<f64> {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                let sample = amplitude * (2.0 * PI * frequency * t).sin();
+                vec![sample; NUM_CHANNELS as usize]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_scale_sine() {
+        // a 0 dBFS 1 kHz sine in both channels reads 0 LUFS by definition
+        let mut meter = LoudnessMeter::new();
+        meter.process(&sine(997.0, 1.0, 5.0));
+
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness.integrated_lufs.unwrap() - 0.0).abs() < 0.1);
+        assert!((loudness.true_peak_dbtp - 0.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn quiet_sine() {
+        let mut meter = LoudnessMeter::new();
+        meter.process(&sine(997.0, 0.1, 5.0));
+
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness.integrated_lufs.unwrap() + 20.0).abs() < 0.1);
+        assert!((loudness.true_peak_dbtp + 20.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        let mut meter = LoudnessMeter::new();
+        assert_eq!(meter.loudness(), None);
+
+        meter.process(&vec![0.0; 2 * SAMPLE_RATE as usize * NUM_CHANNELS as usize]);
+        assert_eq!(meter.loudness().unwrap().integrated_lufs, None);
+
+        meter.reset();
+        assert_eq!(meter.loudness(), None);
+    }
+}
@@ -12,6 +12,11 @@ pub trait MappedCtrl {
 
 impl MappedCtrl for VolumeCtrl {
     fn to_mapped(&self, volume: u16) -> f64 {
+        // Volume is locked at 100% and controlled downstream.
+        if let Self::Fixed = self {
+            return 1.0;
+        }
+
         // More than just an optimization, this ensures that zero volume is
         // really mute (both the log and cubic equations would otherwise not
         // reach zero).
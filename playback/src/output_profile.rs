@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::equalizer::EqBand;
+
+// Named presets of the output settings, e.g. a "night" profile with a volume cap and the bass
+// turned down, which can be switched while playing. Settings a profile leaves unset keep the
+// ones given on the command line.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputProfile {
+    /// In the range of the mixer volume, see `ConnectConfig::max_volume`.
+    pub max_volume: Option<u16>,
+    /// Changes the normalisation factor from the next track on.
+    pub normalisation: Option<bool>,
+    pub equalizer: Option<Vec<EqBand>>,
+}
+
+/// The profile the players apply, if any. Clones share it, so it can be switched while
+/// playing, like `ChannelMix`.
+#[derive(Clone, Debug, Default)]
+pub struct ActiveProfile(Arc<(AtomicUsize, Mutex<Option<OutputProfile>>)>);
+
+impl ActiveProfile {
+    pub fn get(&self) -> Option<OutputProfile> {
+        (self.0).1.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, profile: Option<OutputProfile>) {
+        *(self.0).1.lock().unwrap() = profile;
+        (self.0).0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes with every `set`, for the players to check cheaply whether to apply it.
+    pub fn generation(&self) -> usize {
+        (self.0).0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared() {
+        let active = ActiveProfile::default();
+        let shared = active.clone();
+        let generation = shared.generation();
+
+        let profile = OutputProfile {
+            normalisation: Some(true),
+            ..OutputProfile::default()
+        };
+        active.set(Some(profile.clone()));
+        assert_ne!(shared.generation(), generation);
+        assert_eq!(shared.get(), Some(profile));
+    }
+}
@@ -1,15 +1,18 @@
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::fmt;
 use std::future::Future;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::pin::Pin;
 use std::process::exit;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use std::{mem, thread, time};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use futures_util::stream::futures_unordered::FuturesUnordered;
-use futures_util::{future, StreamExt, TryFutureExt};
+use futures_util::{future, FutureExt, StreamExt};
 use tokio::sync::{mpsc, oneshot};
 
 use crate::audio::{AudioDecrypt, AudioFile, StreamLoaderController};
@@ -20,23 +23,76 @@ use crate::audio::{
 use crate::audio_backend::Sink;
 use crate::config::{Bitrate, NormalisationMethod, NormalisationType, PlayerConfig};
 use crate::convert::Converter;
+use crate::core::audio_key::AudioKey;
+use crate::core::cache::OfflineTrack;
+use crate::core::config::ControlSource;
 use crate::core::session::Session;
-use crate::core::spotify_id::SpotifyId;
+use crate::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
 use crate::core::util::SeqGenerator;
-use crate::decoder::{AudioDecoder, AudioPacket, DecoderError, PassthroughDecoder, VorbisDecoder};
-use crate::metadata::{AudioItem, FileFormat};
+use crate::decoder::{self, AudioCodec, AudioDecoder, AudioPacket, DecoderBuilder};
+use crate::decoder::{DecoderError, PassthroughDecoder};
+use crate::dsp;
+use crate::equalizer::Equalizer;
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::metadata::{Album, Artist, AudioItem, Episode, FileFormat, Metadata, Show, Track};
 use crate::mixer::VolumeGetter;
+use crate::output_profile::OutputProfile;
+use crate::time_stretch::TimeStretch;
 
-use crate::{MS_PER_PAGE, NUM_CHANNELS, PAGES_PER_MS, SAMPLES_PER_SECOND};
+use crate::{MS_PER_PAGE, NUM_CHANNELS, PAGES_PER_MS, SAMPLES_PER_SECOND, SAMPLE_RATE};
 
 const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
 pub const DB_VOLTAGE_RATIO: f64 = 20.0;
 pub const PCM_AT_0DBFS: f64 = 1.0;
 
+/// The process exit code when the audio output fails.
+pub const AUDIO_ERROR_EXIT_CODE: i32 = 7;
+
 pub struct Player {
     commands: Option<mpsc::UnboundedSender<PlayerCommand>>,
     thread_handle: Option<thread::JoinHandle<()>>,
     play_request_id_generator: SeqGenerator<u64>,
+    buffer_fill: BufferFill,
+    sink_stats: SinkStats,
+}
+
+/// How much of the current track is downloaded ahead of the playback position.
+#[derive(Clone, Debug, Default)]
+pub struct BufferFill(Arc<AtomicU32>);
+
+impl BufferFill {
+    /// The duration of the audio downloaded ahead, in milliseconds. `0` if nothing is playing.
+    pub fn ms(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, ms: u32) {
+        self.0.store(ms, Ordering::Relaxed);
+    }
+}
+
+/// How long writing to the sink blocked and how much audio it took, to tell whether the output
+/// keeps up. Both counters wrap around, so compare them with `wrapping_sub`.
+#[derive(Clone, Debug, Default)]
+pub struct SinkStats(Arc<(AtomicU32, AtomicU32)>);
+
+impl SinkStats {
+    /// The total time spent in `Sink::write`, in microseconds.
+    pub fn blocked_us(&self) -> u32 {
+        (self.0).0.load(Ordering::Relaxed)
+    }
+
+    /// The total number of samples written, for all channels.
+    pub fn samples(&self) -> u32 {
+        (self.0).1.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, blocked: Duration, samples: usize) {
+        (self.0)
+            .0
+            .fetch_add(blocked.as_micros() as u32, Ordering::Relaxed);
+        (self.0).1.fetch_add(samples as u32, Ordering::Relaxed);
+    }
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -61,11 +117,169 @@ struct PlayerInternal {
     volume_getter: Box<dyn VolumeGetter + Send>,
     event_senders: Vec<mpsc::UnboundedSender<PlayerEvent>>,
     converter: Converter,
+    equalizer: Option<Equalizer>,
+
+    // stretches episodes to `PlayerConfig::speed`, unless in passthrough mode
+    time_stretch: Option<TimeStretch>,
+    stretch_track: bool,
+    // the speed of the last `PlayerEvent::Playing`
+    reported_speed: f64,
+
+    // the normalisation and equalizer of the configuration, which `PlayerConfig::profile`
+    // overrides, and the generation of it applied last
+    configured_output: OutputProfile,
+    profile_generation: Option<usize>,
 
-    normalisation_integrator: f64,
-    normalisation_peak: f64,
+    normalisation_limiter: Limiter,
+    // with `PlayerConfig::limiter`, after all other processing
+    limiter: Option<Limiter>,
 
     auto_normalise_as_album: bool,
+
+    buffer_fill: BufferFill,
+    sink_stats: SinkStats,
+
+    // the track whose first packet is yet to be written, for `Sink::track_boundary`
+    track_boundary: Option<SpotifyId>,
+
+    fade_in: Option<FadeIn>,
+
+    // for the track that is playing, see `NormalisationData::get_pre_gain_factor`
+    pre_gain_factor: f64,
+
+    // with `PlayerConfig::analyze_loudness`, and the track it is measuring
+    loudness_meter: Option<LoudnessMeter>,
+    loudness_track: Option<(u64, SpotifyId)>,
+
+    // the play request whose track change was announced, see `PlayerConfig::track_change_lead`
+    announced_track_change: Option<u64>,
+}
+
+// The feedforward limiter of the dynamic normalisation method, also used on its own for
+// `PlayerConfig::limiter`.
+struct Limiter {
+    threshold_db: f64,
+    knee_db: f64,
+    attack_cf: f64,
+    release_cf: f64,
+    integrator: f64,
+    peak: f64,
+}
+
+impl Limiter {
+    fn new(config: &PlayerConfig) -> Self {
+        Self {
+            threshold_db: config.normalisation_threshold_dbfs,
+            knee_db: config.normalisation_knee_db,
+            attack_cf: config.normalisation_attack_cf,
+            release_cf: config.normalisation_release_cf,
+            integrator: 0.0,
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, mut sample: f64) -> f64 {
+        // Feedforward limiter in the log domain
+        // After: Giannoulis, D., Massberg, M., & Reiss, J.D. (2012). Digital Dynamic
+        // Range Compressor Design—A Tutorial and Analysis. Journal of The Audio
+        // Engineering Society, 60, 399-408.
+
+        // Some tracks have samples that are precisely 0.0. That's silence
+        // and we know we don't need to limit that, in which we can spare
+        // the CPU cycles.
+        //
+        // Also, calling `ratio_to_db(0.0)` returns `inf` and would get the
+        // peak detector stuck. Also catch the unlikely case where a sample
+        // is decoded as `NaN` or some other non-normal value.
+        let limiter_db = if sample.is_normal() {
+            // step 1-4: half-wave rectification and conversion into dB
+            // and gain computer with soft knee and subtractor
+            let bias_db = ratio_to_db(sample.abs()) - self.threshold_db;
+            let knee_boundary_db = bias_db * 2.0;
+
+            if knee_boundary_db < -self.knee_db {
+                0.0
+            } else if knee_boundary_db.abs() <= self.knee_db {
+                // The textbook equation:
+                // ratio_to_db(sample.abs()) - (ratio_to_db(sample.abs()) - (bias_db + knee_db / 2.0).powi(2) / (2.0 * knee_db))
+                // Simplifies to:
+                // ((2.0 * bias_db) + knee_db).powi(2) / (8.0 * knee_db)
+                // Which in our case further simplifies to:
+                // (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
+                // because knee_boundary_db is 2.0 * bias_db.
+                (knee_boundary_db + self.knee_db).powi(2) / (8.0 * self.knee_db)
+            } else {
+                // Textbook:
+                // ratio_to_db(sample.abs()) - threshold_db, which is already our bias_db.
+                bias_db
+            }
+        } else {
+            0.0
+        };
+
+        // Spare the CPU unless (1) the limiter is engaged, (2) we
+        // were in attack or (3) we were in release, and that attack/
+        // release wasn't finished yet.
+        if limiter_db > 0.0 || self.integrator > 0.0 || self.peak > 0.0 {
+            // step 5: smooth, decoupled peak detector
+            // Textbook:
+            // release_cf * self.integrator + (1.0 - release_cf) * limiter_db
+            // Simplifies to:
+            // release_cf * self.integrator - release_cf * limiter_db + limiter_db
+            self.integrator = f64::max(
+                limiter_db,
+                self.release_cf * self.integrator - self.release_cf * limiter_db + limiter_db,
+            );
+            // Textbook:
+            // attack_cf * self.peak + (1.0 - attack_cf) * self.integrator
+            // Simplifies to:
+            // attack_cf * self.peak - attack_cf * self.integrator + self.integrator
+            self.peak =
+                self.attack_cf * self.peak - self.attack_cf * self.integrator + self.integrator;
+
+            // step 6: make-up gain applied later (volume attenuation)
+            // Applying the standard normalisation factor here won't work,
+            // because there are tracks with peaks as high as 6 dB above
+            // the default threshold, so that would clip.
+
+            // steps 7-8: conversion into level and multiplication into gain stage
+            sample *= db_to_ratio(-self.peak);
+        }
+
+        sample
+    }
+}
+
+// A volume ramp from silence, applied to the decoded samples.
+struct FadeIn {
+    position: u64,
+    length: u64,
+}
+
+impl FadeIn {
+    fn new(duration: Duration) -> Self {
+        let length = (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        Self {
+            position: 0,
+            length: length.max(1),
+        }
+    }
+
+    fn apply(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_mut(NUM_CHANNELS as usize) {
+            let ramp = (self.position as f64 / self.length as f64).min(1.0);
+            // squared, as a linear ramp sounds loud too early
+            let gain = ramp * ramp;
+            for sample in frame {
+                *sample *= gain;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
 }
 
 enum PlayerCommand {
@@ -85,7 +299,17 @@ enum PlayerCommand {
     AddEventSender(mpsc::UnboundedSender<PlayerEvent>),
     SetSinkEventCallback(Option<SinkEventCallback>),
     EmitVolumeSetEvent(u16),
+    EmitPlaybackModeEvent {
+        shuffle: bool,
+        repeat: bool,
+    },
+    EmitCommandOverriddenEvent {
+        source: ControlSource,
+        command: &'static str,
+        overridden_by: ControlSource,
+    },
     SetAutoNormaliseAsAlbum(bool),
+    FadeIn(Duration),
 }
 
 #[derive(Debug, Clone)]
@@ -130,6 +354,14 @@ pub enum PlayerEvent {
         track_id: SpotifyId,
         position_ms: u32,
         duration_ms: u32,
+        // how fast the position advances, faster than real time for sped up podcast episodes
+        speed: f64,
+    },
+    // Playback fell behind, because the audio data or the decoder didn't keep up.
+    BufferUnderrun {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
     },
     // The player entered a paused state.
     Paused {
@@ -144,6 +376,14 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The track will end in `remaining_ms`, followed by the next one if it is preloaded. Sent
+    // `PlayerConfig::track_change_lead` before the end, and again if a seek moved back past it.
+    TrackChanging {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        next_track_id: Option<SpotifyId>,
+        remaining_ms: u32,
+    },
     // The player reached the end of a track.
     // This event is intended for use within spirc. Spirc will respond by issuing another command
     // which will trigger another event (e.g. Changed or Stopped)
@@ -151,15 +391,62 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The codec and bitrate of the track that is about to play. Sent whenever playback of a
+    // loaded track starts, before the "Playing" or "Paused" event.
+    FormatChanged {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        format: StreamFormat,
+    },
+    // The ReplayGain values of the track that is about to play, whether normalisation is enabled
+    // or not. Sent along with "FormatChanged".
+    Normalisation {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        data: NormalisationData,
+    },
+    // The loudness of the audio played for a track, measured until it ended or playback moved
+    // on. Only sent with `PlayerConfig::analyze_loudness`.
+    Loudness {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        loudness: Loudness,
+    },
+    // The track about to play couldn't be loaded from Spotify, it plays from the audio cache.
+    // Only with `PlayerConfig::offline_fallback`. Sent before "FormatChanged".
+    Offline {
+        play_request_id: u64,
+        track_id: SpotifyId,
+    },
     // The player was unable to load the requested track.
     Unavailable {
         play_request_id: u64,
         track_id: SpotifyId,
+        reason: UnavailableReason,
+    },
+    // Something went wrong, eg. a track couldn't be decoded. Recoverable errors don't interrupt
+    // playback for longer than skipping a track.
+    Error {
+        category: ErrorCategory,
+        message: String,
+        recoverable: bool,
     },
     // The mixer volume was set to a new level.
     VolumeSet {
         volume: u16,
     },
+    // Shuffle or repeat was switched on or off for the current context.
+    PlaybackModeChanged {
+        shuffle: bool,
+        repeat: bool,
+    },
+    // A command from one control source was ignored or superseded because of a
+    // command from the other source, according to the configured control policy.
+    CommandOverridden {
+        source: ControlSource,
+        command: &'static str,
+        overridden_by: ControlSource,
+    },
 }
 
 impl PlayerEvent {
@@ -172,15 +459,33 @@ impl PlayerEvent {
             | Unavailable {
                 play_request_id, ..
             }
+            | Offline {
+                play_request_id, ..
+            }
+            | FormatChanged {
+                play_request_id, ..
+            }
+            | Normalisation {
+                play_request_id, ..
+            }
+            | Loudness {
+                play_request_id, ..
+            }
             | Started {
                 play_request_id, ..
             }
             | Playing {
                 play_request_id, ..
             }
+            | BufferUnderrun {
+                play_request_id, ..
+            }
             | TimeToPreloadNextTrack {
                 play_request_id, ..
             }
+            | TrackChanging {
+                play_request_id, ..
+            }
             | EndOfTrack {
                 play_request_id, ..
             }
@@ -190,11 +495,140 @@ impl PlayerEvent {
             | Stopped {
                 play_request_id, ..
             } => Some(*play_request_id),
-            Changed { .. } | Preloading { .. } | VolumeSet { .. } => None,
+            Changed { .. }
+            | Preloading { .. }
+            | Error { .. }
+            | VolumeSet { .. }
+            | PlaybackModeChanged { .. }
+            | CommandOverridden { .. } => None,
+        }
+    }
+}
+
+/// Why a track couldn't be played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnavailableReason {
+    /// The track doesn't exist (anymore), or its metadata couldn't be loaded.
+    NotFound,
+    /// The track isn't available in the user's country, or not with their subscription.
+    Restricted,
+    /// The track is explicit, and explicit content is filtered.
+    Explicit,
+    /// There is no file in a format which can be played.
+    UnsupportedFormat,
+    /// The audio file or its key couldn't be downloaded.
+    LoadFailed,
+    /// The audio file couldn't be decoded.
+    DecodeFailed,
+}
+
+impl UnavailableReason {
+    /// A short code, eg. for scripts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Restricted => "restricted",
+            Self::Explicit => "explicit",
+            Self::UnsupportedFormat => "unsupported_format",
+            Self::LoadFailed => "load_failed",
+            Self::DecodeFailed => "decode_failed",
+        }
+    }
+}
+
+impl fmt::Display for UnavailableReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotFound => "track not found",
+            Self::Restricted => "not available in this country or with this subscription",
+            Self::Explicit => "skipped (explicit)",
+            Self::UnsupportedFormat => "no supported audio format",
+            Self::LoadFailed => "unable to download the audio file",
+            Self::DecodeFailed => "unable to decode the audio file",
+        })
+    }
+}
+
+/// What kind of error is reported with `PlayerEvent::Error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    Network,
+    Decode,
+    Auth,
+    Sink,
+}
+
+impl ErrorCategory {
+    /// A short code, eg. for scripts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Decode => "decode",
+            Self::Auth => "auth",
+            Self::Sink => "sink",
         }
     }
 }
 
+/// The format of the audio stream of a track, as fetched from Spotify.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub file_format: FileFormat,
+    /// Whether the stream is passed on without decoding.
+    pub passthrough: bool,
+}
+
+impl StreamFormat {
+    pub fn codec(&self) -> &'static str {
+        use FileFormat::*;
+        match self.file_format {
+            OGG_VORBIS_96 | OGG_VORBIS_160 | OGG_VORBIS_320 => "Ogg Vorbis",
+            MP3_96 | MP3_160 | MP3_160_ENC | MP3_256 | MP3_320 => "MP3",
+            AAC_160 | AAC_320 | MP4_128 | MP4_128_DUAL => "AAC",
+            OTHER3 | OTHER5 => "unknown",
+        }
+    }
+
+    /// The nominal bitrate in kbps, `0` if unknown.
+    pub fn bitrate(&self) -> u32 {
+        use FileFormat::*;
+        match self.file_format {
+            OGG_VORBIS_96 | MP3_96 => 96,
+            MP4_128 | MP4_128_DUAL => 128,
+            OGG_VORBIS_160 | MP3_160 | MP3_160_ENC | AAC_160 => 160,
+            MP3_256 => 256,
+            OGG_VORBIS_320 | MP3_320 | AAC_320 => 320,
+            OTHER3 | OTHER5 => 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    pub fn channels(&self) -> u8 {
+        NUM_CHANNELS
+    }
+}
+
+impl fmt::Display for StreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let channels = if self.channels() == 1 {
+            "mono"
+        } else {
+            "stereo"
+        };
+        write!(
+            f,
+            "{} {} kbps, {} kHz {}",
+            self.codec(),
+            self.bitrate(),
+            self.sample_rate() as f64 / 1000.0,
+            channels
+        )
+    }
+}
+
 pub type PlayerEventChannel = mpsc::UnboundedReceiver<PlayerEvent>;
 
 pub fn db_to_ratio(db: f64) -> f64 {
@@ -215,10 +649,10 @@ pub fn coefficient_to_duration(coefficient: f64) -> Duration {
 
 #[derive(Clone, Copy, Debug)]
 pub struct NormalisationData {
-    track_gain_db: f64,
-    track_peak: f64,
-    album_gain_db: f64,
-    album_peak: f64,
+    pub track_gain_db: f64,
+    pub track_peak: f64,
+    pub album_gain_db: f64,
+    pub album_peak: f64,
 }
 
 impl NormalisationData {
@@ -241,6 +675,16 @@ impl NormalisationData {
         Ok(r)
     }
 
+    // As per https://wiki.hydrogenaud.io/index.php?title=ReplayGain_2.0_specification#Metadata_format
+    fn replaygain_comments(&self) -> Vec<String> {
+        vec![
+            format!("REPLAYGAIN_TRACK_GAIN={:.2} dB", self.track_gain_db),
+            format!("REPLAYGAIN_TRACK_PEAK={:.6}", self.track_peak),
+            format!("REPLAYGAIN_ALBUM_GAIN={:.2} dB", self.album_gain_db),
+            format!("REPLAYGAIN_ALBUM_PEAK={:.6}", self.album_peak),
+        ]
+    }
+
     fn get_factor(config: &PlayerConfig, data: NormalisationData) -> f64 {
         if !config.normalisation {
             return 1.0;
@@ -313,6 +757,41 @@ impl NormalisationData {
 
         normalisation_factor
     }
+
+    // The factor for `PlayerConfig::pre_gain_db`. A boost is lowered for tracks whose peak would
+    // otherwise exceed dBFS, after normalisation by `normalisation_factor`.
+    fn get_pre_gain_factor(
+        config: &PlayerConfig,
+        data: NormalisationData,
+        normalisation_factor: f64,
+    ) -> f64 {
+        let factor = db_to_ratio(config.pre_gain_db);
+        if factor <= PCM_AT_0DBFS {
+            return factor;
+        }
+
+        let mut peak = if config.normalisation_type == NormalisationType::Album {
+            data.album_peak
+        } else {
+            data.track_peak
+        };
+        if config.normalisation {
+            peak *= normalisation_factor;
+            if config.normalisation_method == NormalisationMethod::Dynamic {
+                peak = peak.min(db_to_ratio(config.normalisation_threshold_dbfs));
+            }
+        }
+
+        let limited = f64::min(factor, PCM_AT_0DBFS / peak).max(PCM_AT_0DBFS);
+        if limited < factor {
+            info!(
+                "Lowering the pre-gain to {:.2} dB for the duration of this track to avoid exceeding dBFS.",
+                ratio_to_db(limited)
+            );
+        }
+
+        limited
+    }
 }
 
 impl Player {
@@ -327,6 +806,10 @@ impl Player {
     {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        let buffer_fill = BufferFill::default();
+        let internal_buffer_fill = buffer_fill.clone();
+        let sink_stats = SinkStats::default();
+        let internal_sink_stats = sink_stats.clone();
 
         if config.normalisation {
             debug!("Normalisation Type: {:?}", config.normalisation_type);
@@ -359,6 +842,24 @@ impl Player {
 
             let converter = Converter::new(config.ditherer);
 
+            let equalizer = Some(Equalizer::new(&config.equalizer))
+                .filter(|equalizer| !config.passthrough && !equalizer.is_empty());
+
+            let time_stretch =
+                Some(TimeStretch::new(config.speed.clone())).filter(|_| !config.passthrough);
+
+            let configured_output = OutputProfile {
+                max_volume: None,
+                normalisation: Some(config.normalisation),
+                equalizer: Some(config.equalizer.clone()),
+            };
+
+            let normalisation_limiter = Limiter::new(&config);
+            let limiter = Some(Limiter::new(&config)).filter(|_| config.limiter);
+
+            let loudness_meter = Some(LoudnessMeter::new())
+                .filter(|_| config.analyze_loudness && !config.passthrough);
+
             let internal = PlayerInternal {
                 session,
                 config,
@@ -372,11 +873,32 @@ impl Player {
                 volume_getter,
                 event_senders: [event_sender].to_vec(),
                 converter,
+                equalizer,
+
+                time_stretch,
+                stretch_track: false,
+                reported_speed: 1.0,
+
+                configured_output,
+                profile_generation: None,
 
-                normalisation_peak: 0.0,
-                normalisation_integrator: 0.0,
+                normalisation_limiter,
+                limiter,
 
                 auto_normalise_as_album: false,
+
+                buffer_fill: internal_buffer_fill,
+                sink_stats: internal_sink_stats,
+
+                track_boundary: None,
+
+                fade_in: None,
+
+                pre_gain_factor: 1.0,
+
+                loudness_meter,
+                loudness_track: None,
+                announced_track_change: None,
             };
 
             // While PlayerInternal is written as a future, it still contains blocking code.
@@ -390,6 +912,8 @@ impl Player {
                 commands: Some(cmd_tx),
                 thread_handle: Some(handle),
                 play_request_id_generator: SeqGenerator::new(0),
+                buffer_fill,
+                sink_stats,
             },
             event_receiver,
         )
@@ -453,6 +977,16 @@ impl Player {
         }
     }
 
+    /// A handle to read how much of the current track is buffered, eg. for monitoring.
+    pub fn buffer_fill(&self) -> BufferFill {
+        self.buffer_fill.clone()
+    }
+
+    /// A handle to read how the sink keeps up with the decoded audio.
+    pub fn sink_stats(&self) -> SinkStats {
+        self.sink_stats.clone()
+    }
+
     pub fn set_sink_event_callback(&self, callback: Option<SinkEventCallback>) {
         self.command(PlayerCommand::SetSinkEventCallback(callback));
     }
@@ -461,9 +995,31 @@ impl Player {
         self.command(PlayerCommand::EmitVolumeSetEvent(volume));
     }
 
+    pub fn emit_playback_mode_event(&self, shuffle: bool, repeat: bool) {
+        self.command(PlayerCommand::EmitPlaybackModeEvent { shuffle, repeat });
+    }
+
+    pub fn emit_command_overridden_event(
+        &self,
+        source: ControlSource,
+        command: &'static str,
+        overridden_by: ControlSource,
+    ) {
+        self.command(PlayerCommand::EmitCommandOverriddenEvent {
+            source,
+            command,
+            overridden_by,
+        });
+    }
+
     pub fn set_auto_normalise_as_album(&self, setting: bool) {
         self.command(PlayerCommand::SetAutoNormaliseAsAlbum(setting));
     }
+
+    /// Fades in what is played next over `duration`. Has no effect in passthrough mode.
+    pub fn fade_in(&self, duration: Duration) {
+        self.command(PlayerCommand::FadeIn(duration));
+    }
 }
 
 impl Drop for Player {
@@ -486,13 +1042,17 @@ struct PlayerLoadedTrackData {
     bytes_per_second: usize,
     duration_ms: u32,
     stream_position_pcm: u64,
+    format: StreamFormat,
+    // played from the audio cache, because Spotify couldn't be reached
+    offline: bool,
 }
 
 enum PlayerPreload {
     None,
     Loading {
         track_id: SpotifyId,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader:
+            Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, UnavailableReason>> + Send>>,
     },
     Ready {
         track_id: SpotifyId,
@@ -508,7 +1068,8 @@ enum PlayerState {
         track_id: SpotifyId,
         play_request_id: u64,
         start_playback: bool,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader:
+            Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, UnavailableReason>> + Send>>,
     },
     Paused {
         track_id: SpotifyId,
@@ -520,6 +1081,7 @@ enum PlayerState {
         bytes_per_second: usize,
         duration_ms: u32,
         stream_position_pcm: u64,
+        format: StreamFormat,
         suggested_to_preload_next_track: bool,
     },
     Playing {
@@ -532,6 +1094,7 @@ enum PlayerState {
         bytes_per_second: usize,
         duration_ms: u32,
         stream_position_pcm: u64,
+        format: StreamFormat,
         reported_nominal_start_time: Option<Instant>,
         suggested_to_preload_next_track: bool,
     },
@@ -615,6 +1178,7 @@ impl PlayerState {
                 normalisation_data,
                 stream_loader_controller,
                 stream_position_pcm,
+                format,
                 ..
             } => {
                 *self = EndOfTrack {
@@ -627,6 +1191,8 @@ impl PlayerState {
                         bytes_per_second,
                         duration_ms,
                         stream_position_pcm,
+                        format,
+                        offline: false,
                     },
                 };
             }
@@ -650,6 +1216,7 @@ impl PlayerState {
                 duration_ms,
                 bytes_per_second,
                 stream_position_pcm,
+                format,
                 suggested_to_preload_next_track,
             } => {
                 *self = Playing {
@@ -662,6 +1229,7 @@ impl PlayerState {
                     duration_ms,
                     bytes_per_second,
                     stream_position_pcm,
+                    format,
                     reported_nominal_start_time: None,
                     suggested_to_preload_next_track,
                 };
@@ -686,6 +1254,7 @@ impl PlayerState {
                 duration_ms,
                 bytes_per_second,
                 stream_position_pcm,
+                format,
                 reported_nominal_start_time: _,
                 suggested_to_preload_next_track,
             } => {
@@ -699,83 +1268,505 @@ impl PlayerState {
                     duration_ms,
                     bytes_per_second,
                     stream_position_pcm,
+                    format,
                     suggested_to_preload_next_track,
                 };
             }
-            _ => {
-                error!("PlayerState playing_to_paused: invalid state");
-                exit(1);
+            _ => {
+                error!("PlayerState playing_to_paused: invalid state");
+                exit(1);
+            }
+        }
+    }
+}
+
+struct PlayerTrackLoader {
+    session: Session,
+    config: PlayerConfig,
+}
+
+// When playback at `speed` would have started to be at `position_ms` now.
+fn nominal_start_time(position_ms: u32, speed: f64) -> Instant {
+    Instant::now() - Duration::from_millis((f64::from(position_ms) / speed) as u64)
+}
+
+/// What would be played for a given track with the current configuration, without
+/// actually loading it.
+#[derive(Clone, Debug)]
+pub struct TrackInfo {
+    pub name: String,
+    pub uri: String,
+    pub duration_ms: u32,
+    pub format: FileFormat,
+    pub normalisation_data: Option<NormalisationData>,
+}
+
+/// Looks up the file which would be played for `spotify_id` and reads its
+/// normalisation data.
+pub async fn get_track_info(
+    session: Session,
+    config: PlayerConfig,
+    spotify_id: SpotifyId,
+) -> Option<TrackInfo> {
+    let loader = PlayerTrackLoader { session, config };
+
+    let (result_tx, result_rx) = oneshot::channel();
+
+    // seek() on the audio stream is blocking, see `PlayerInternal::load_track()`
+    std::thread::spawn(move || {
+        let data = futures_executor::block_on(loader.load_track_info(spotify_id));
+        let _ = result_tx.send(data);
+    });
+
+    result_rx.await.ok().flatten()
+}
+
+/// Downloads the file which would be played for `spotify_id` into the cache.
+/// Returns `false` if that failed.
+pub async fn prefetch_track(session: Session, config: PlayerConfig, spotify_id: SpotifyId) -> bool {
+    PlayerTrackLoader { session, config }
+        .prefetch_track(spotify_id)
+        .await
+}
+
+/// Writes the file which would be played for `spotify_id` to `output` as an Ogg stream,
+/// with `comments` added to its Vorbis comment header. Returns `false` if that failed.
+pub async fn export_track<W: Write + Send + 'static>(
+    session: Session,
+    config: PlayerConfig,
+    spotify_id: SpotifyId,
+    comments: Vec<String>,
+    output: W,
+) -> bool {
+    let loader = PlayerTrackLoader { session, config };
+
+    let (result_tx, result_rx) = oneshot::channel();
+
+    // reading the audio stream is blocking, see `PlayerInternal::load_track()`
+    std::thread::spawn(move || {
+        let result = futures_executor::block_on(loader.export_track(spotify_id, comments, output));
+        let _ = result_tx.send(result);
+    });
+
+    result_rx.await.unwrap_or(false)
+}
+
+impl PlayerTrackLoader {
+    async fn find_available_alternative(&self, audio: AudioItem) -> Option<AudioItem> {
+        if audio.available {
+            Some(audio)
+        } else if let Some(alternatives) = &audio.alternatives {
+            let alternatives: FuturesUnordered<_> = alternatives
+                .iter()
+                .map(|alt_id| AudioItem::get_audio_item(&self.session, *alt_id))
+                .collect();
+
+            alternatives
+                .filter_map(|x| future::ready(x.ok()))
+                .filter(|x| future::ready(x.available))
+                .next()
+                .await
+        } else {
+            None
+        }
+    }
+
+    async fn find_audio_item(&self, spotify_id: SpotifyId) -> Result<AudioItem, UnavailableReason> {
+        match AudioItem::get_audio_item(&self.session, spotify_id).await {
+            Ok(audio) => match self.find_available_alternative(audio).await {
+                Some(audio) => Ok(audio),
+                None => {
+                    warn!(
+                        "<{}> is not available",
+                        spotify_id.to_uri().unwrap_or_default()
+                    );
+                    Err(UnavailableReason::Restricted)
+                }
+            },
+            Err(e) => {
+                error!("Unable to load audio item: {:?}", e);
+                Err(UnavailableReason::NotFound)
+            }
+        }
+    }
+
+    // The file to play for `audio`, of a format which can be decoded, or passed through.
+    fn find_file(&self, audio: &AudioItem) -> Option<(FileFormat, FileId)> {
+        self.find_file_of(audio, |codec| {
+            if self.config.passthrough {
+                codec == AudioCodec::Vorbis
+            } else {
+                self.find_decoder(codec).is_some()
+            }
+        })
+    }
+
+    fn find_file_of<F>(&self, audio: &AudioItem, playable: F) -> Option<(FileFormat, FileId)>
+    where
+        F: Fn(AudioCodec) -> bool,
+    {
+        let bitrate = if self.session.data_cap_exceeded() {
+            warn!("Data cap exceeded, using the lowest bitrate");
+            Bitrate::Bitrate96
+        } else {
+            self.config.bitrate
+        };
+
+        let file = preferred_formats(bitrate)
+            .filter(|&format| AudioCodec::from_format(format).map_or(false, &playable))
+            .find_map(|format| match audio.files.get(&format) {
+                Some(&file_id) => Some((format, file_id)),
+                _ => None,
+            });
+
+        if file.is_none() {
+            warn!("<{}> is not available in any supported format", audio.name);
+        }
+
+        file
+    }
+
+    // Vorbis comments describing `audio`, see https://xiph.org/vorbis/doc/v-comment.html
+    async fn metadata_comments(&self, audio: &AudioItem) -> Vec<String> {
+        let mut comments = vec![format!("TITLE={}", audio.name)];
+
+        match audio.id.audio_type {
+            SpotifyAudioType::Track => {
+                let track = match Track::get(&self.session, audio.id).await {
+                    Ok(track) => track,
+                    Err(e) => {
+                        warn!("Unable to load track metadata: {:?}", e);
+                        return comments;
+                    }
+                };
+
+                for artist in track.artists {
+                    if let Ok(artist) = Artist::get(&self.session, artist).await {
+                        comments.push(format!("ARTIST={}", artist.name));
+                    }
+                }
+                if let Ok(album) = Album::get(&self.session, track.album).await {
+                    comments.push(format!("ALBUM={}", album.name));
+                }
+                if track.number > 0 {
+                    comments.push(format!("TRACKNUMBER={}", track.number));
+                }
+                if track.disc_number > 0 {
+                    comments.push(format!("DISCNUMBER={}", track.disc_number));
+                }
+            }
+            // The episode metadata doesn't include chapters, so there are none to add.
+            SpotifyAudioType::Podcast => {
+                let episode = match Episode::get(&self.session, audio.id).await {
+                    Ok(episode) => episode,
+                    Err(e) => {
+                        warn!("Unable to load episode metadata: {:?}", e);
+                        return comments;
+                    }
+                };
+
+                if let Ok(show) = Show::get(&self.session, episode.show).await {
+                    comments.push(format!("ALBUM={}", show.name));
+                    comments.push(format!("ARTIST={}", show.publisher));
+                }
+            }
+            SpotifyAudioType::NonPlayable => (),
+        }
+
+        comments
+    }
+
+    fn find_decoder(&self, codec: AudioCodec) -> Option<DecoderBuilder> {
+        self.config
+            .decoders
+            .iter()
+            .find(|decoder| decoder.0 == codec)
+            .map(|decoder| decoder.1)
+            .or_else(|| decoder::find(codec, None))
+    }
+
+    fn stream_data_rate(&self, format: FileFormat) -> usize {
+        match format {
+            FileFormat::OGG_VORBIS_96 => 12 * 1024,
+            FileFormat::OGG_VORBIS_160 => 20 * 1024,
+            FileFormat::OGG_VORBIS_320 => 40 * 1024,
+            FileFormat::MP3_256 => 32 * 1024,
+            FileFormat::MP3_320 => 40 * 1024,
+            FileFormat::MP3_160 => 20 * 1024,
+            FileFormat::MP3_96 => 12 * 1024,
+            FileFormat::MP3_160_ENC => 20 * 1024,
+            FileFormat::MP4_128_DUAL => 16 * 1024,
+            FileFormat::OTHER3 => 40 * 1024, // better some high guess than nothing
+            FileFormat::AAC_160 => 20 * 1024,
+            FileFormat::AAC_320 => 40 * 1024,
+            FileFormat::MP4_128 => 16 * 1024,
+            FileFormat::OTHER5 => 40 * 1024, // better some high guess than nothing
+        }
+    }
+
+    async fn load_track_info(&self, spotify_id: SpotifyId) -> Option<TrackInfo> {
+        let audio = self.find_audio_item(spotify_id).await.ok()?;
+        let (format, file_id) = self.find_file(&audio)?;
+
+        // Only the header is needed, don't download the whole file.
+        let bytes_per_second = self.stream_data_rate(format);
+        let encrypted_file = AudioFile::open(&self.session, file_id, bytes_per_second, false);
+
+        let encrypted_file = match encrypted_file.await {
+            Ok(encrypted_file) => encrypted_file,
+            Err(e) => {
+                error!("Unable to load encrypted file: {:?}", e);
+                return None;
+            }
+        };
+        encrypted_file
+            .get_stream_loader_controller()
+            .set_random_access_mode();
+
+        let key = self.session.audio_key().request(spotify_id, file_id);
+
+        let normalisation_data = match key.await {
+            Ok(_) if !has_spotify_header(format) => None,
+            Ok(key) => {
+                let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
+                NormalisationData::parse_from_file(&mut decrypted_file).ok()
+            }
+            Err(e) => {
+                warn!("Unable to load decryption key: {:?}", e);
+                None
+            }
+        };
+
+        Some(TrackInfo {
+            name: audio.name,
+            uri: audio.uri,
+            duration_ms: max(audio.duration, 0) as u32,
+            format,
+            normalisation_data,
+        })
+    }
+
+    async fn prefetch_track(&self, spotify_id: SpotifyId) -> bool {
+        let audio = match self.find_audio_item(spotify_id).await {
+            Ok(audio) => audio,
+            Err(_) => return false,
+        };
+
+        let (format, file_id) = match self.find_file(&audio) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        info!(
+            "Prefetching <{}> with Spotify URI <{}>",
+            audio.name, audio.uri
+        );
+
+        let bytes_per_second = self.stream_data_rate(format);
+        match AudioFile::prefetch(&self.session, file_id, bytes_per_second).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Unable to prefetch <{}>: {:?}", audio.uri, e);
+                false
+            }
+        }
+    }
+
+    async fn export_track<W: Write>(
+        &self,
+        spotify_id: SpotifyId,
+        mut comments: Vec<String>,
+        mut output: W,
+    ) -> bool {
+        let audio = match self.find_audio_item(spotify_id).await {
+            Ok(audio) => audio,
+            Err(_) => return false,
+        };
+
+        // the Ogg stream is exported as it is
+        let (format, file_id) = match self.find_file_of(&audio, |codec| codec == AudioCodec::Vorbis)
+        {
+            Some(file) => file,
+            None => return false,
+        };
+
+        info!(
+            "Exporting <{}> with Spotify URI <{}>",
+            audio.name, audio.uri
+        );
+
+        let bytes_per_second = self.stream_data_rate(format);
+        let encrypted_file = AudioFile::open(&self.session, file_id, bytes_per_second, true);
+
+        let encrypted_file = match encrypted_file.await {
+            Ok(encrypted_file) => encrypted_file,
+            Err(e) => {
+                error!("Unable to load encrypted file: {:?}", e);
+                return false;
+            }
+        };
+        encrypted_file
+            .get_stream_loader_controller()
+            .set_stream_mode();
+
+        let key = match self.session.audio_key().request(spotify_id, file_id).await {
+            Ok(key) => key,
+            Err(e) => {
+                error!("Unable to load decryption key: {:?}", e);
+                return false;
+            }
+        };
+
+        let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
+
+        if self.config.metadata_tags {
+            comments.extend(self.metadata_comments(&audio).await);
+        }
+        if self.config.replaygain_tags {
+            if let Ok(data) = NormalisationData::parse_from_file(&mut decrypted_file) {
+                comments.extend(data.replaygain_comments());
+            }
+        }
+
+        let mut decoder =
+            match PassthroughDecoder::new(Subfile::new(decrypted_file, SPOTIFY_OGG_HEADER_SIZE)) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    error!("Unable to read audio file: {}", e);
+                    return false;
+                }
+            };
+
+        if let Err(e) = decoder.append_comments(&comments) {
+            warn!("Unable to add tags: {}", e);
+        }
+
+        loop {
+            let data = match decoder.next_packet() {
+                Ok(Some(AudioPacket::OggData(data))) => data,
+                Ok(Some(AudioPacket::Samples(_))) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Unable to read audio file: {}", e);
+                    return false;
+                }
+            };
+
+            if let Err(e) = output.write_all(&data) {
+                error!("Unable to write <{}>: {}", audio.uri, e);
+                return false;
+            }
+        }
+
+        match output.flush() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Unable to write <{}>: {}", audio.uri, e);
+                false
             }
         }
     }
-}
 
-struct PlayerTrackLoader {
-    session: Session,
-    config: PlayerConfig,
-}
+    // The track as saved with `PlayerConfig::offline_fallback`, if its file is still cached.
+    fn offline_track(&self, spotify_id: SpotifyId) -> Option<OfflineTrack> {
+        if !self.config.offline_fallback {
+            return None;
+        }
+        self.session.cache()?.offline_track(spotify_id)
+    }
 
-impl PlayerTrackLoader {
-    async fn find_available_alternative(&self, audio: AudioItem) -> Option<AudioItem> {
-        if audio.available {
-            Some(audio)
-        } else if let Some(alternatives) = &audio.alternatives {
-            let alternatives: FuturesUnordered<_> = alternatives
-                .iter()
-                .map(|alt_id| AudioItem::get_audio_item(&self.session, *alt_id))
-                .collect();
+    fn save_offline_track(
+        &self,
+        spotify_id: SpotifyId,
+        audio: &AudioItem,
+        format: FileFormat,
+        file_id: FileId,
+        key: AudioKey,
+        saved: &Option<OfflineTrack>,
+    ) {
+        let cache = match self.session.cache() {
+            Some(cache) if self.config.offline_fallback => cache,
+            _ => return,
+        };
 
-            alternatives
-                .filter_map(|x| future::ready(x.ok()))
-                .filter(|x| future::ready(x.available))
-                .next()
-                .await
-        } else {
-            None
+        let offline_track = OfflineTrack {
+            name: audio.name.clone(),
+            duration_ms: audio.duration as u32,
+            explicit: audio.explicit,
+            file_format: format as i32,
+            file_id: file_id.0,
+            key: key.0,
+        };
+        if saved.as_ref() != Some(&offline_track) {
+            cache.save_offline_track(spotify_id, &offline_track);
         }
     }
 
-    fn stream_data_rate(&self, format: FileFormat) -> usize {
-        match format {
-            FileFormat::OGG_VORBIS_96 => 12 * 1024,
-            FileFormat::OGG_VORBIS_160 => 20 * 1024,
-            FileFormat::OGG_VORBIS_320 => 40 * 1024,
-            FileFormat::MP3_256 => 32 * 1024,
-            FileFormat::MP3_320 => 40 * 1024,
-            FileFormat::MP3_160 => 20 * 1024,
-            FileFormat::MP3_96 => 12 * 1024,
-            FileFormat::MP3_160_ENC => 20 * 1024,
-            FileFormat::MP4_128_DUAL => 16 * 1024,
-            FileFormat::OTHER3 => 40 * 1024, // better some high guess than nothing
-            FileFormat::AAC_160 => 20 * 1024,
-            FileFormat::AAC_320 => 40 * 1024,
-            FileFormat::MP4_128 => 16 * 1024,
-            FileFormat::OTHER5 => 40 * 1024, // better some high guess than nothing
+    // An audio item for the one cached file of an offline track. Only Vorbis files are played.
+    fn offline_audio_item(spotify_id: SpotifyId, track: &OfflineTrack) -> AudioItem {
+        let files = [
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_320,
+        ]
+        .iter()
+        .filter(|&&format| format as i32 == track.file_format)
+        .map(|&format| (format, FileId(track.file_id)))
+        .collect();
+
+        AudioItem {
+            id: spotify_id,
+            uri: spotify_id.to_uri().unwrap_or_default(),
+            files,
+            name: track.name.clone(),
+            duration: track.duration_ms as i32,
+            available: true,
+            alternatives: None,
+            explicit: track.explicit,
         }
     }
 
+    fn filter_explicit(&self) -> bool {
+        self.config.filter_explicit || self.session.filter_explicit_content()
+    }
+
     async fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> Option<PlayerLoadedTrackData> {
-        let audio = match AudioItem::get_audio_item(&self.session, spotify_id).await {
-            Ok(audio) => match self.find_available_alternative(audio).await {
-                Some(audio) => audio,
-                None => {
-                    warn!(
-                        "<{}> is not available",
-                        spotify_id.to_uri().unwrap_or_default()
-                    );
-                    return None;
+    ) -> Result<PlayerLoadedTrackData, UnavailableReason> {
+        let offline_track = self.offline_track(spotify_id);
+        let mut offline = false;
+
+        let audio = match offline_track {
+            // don't wait for requests over a connection which is gone
+            Some(ref track) if self.session.is_invalid() => {
+                offline = true;
+                Self::offline_audio_item(spotify_id, track)
+            }
+            _ => match self.find_audio_item(spotify_id).await {
+                Ok(audio) => audio,
+                Err(UnavailableReason::NotFound) if offline_track.is_some() => {
+                    offline = true;
+                    // panic safety: checked by the match guard
+                    Self::offline_audio_item(spotify_id, offline_track.as_ref().unwrap())
                 }
+                Err(reason) => return Err(reason),
             },
-            Err(e) => {
-                error!("Unable to load audio item: {:?}", e);
-                return None;
-            }
         };
 
+        if offline {
+            info!(
+                "Spotify can't be reached, playing <{}> from the cache",
+                audio.uri
+            );
+        }
+
+        if audio.explicit && self.filter_explicit() {
+            warn!("<{}> is explicit, skipping it", audio.uri);
+            return Err(UnavailableReason::Explicit);
+        }
+
         info!("Loading <{}> with Spotify URI <{}>", audio.name, audio.uri);
 
         if audio.duration < 0 {
@@ -784,46 +1775,27 @@ impl PlayerTrackLoader {
                 spotify_id.to_uri().unwrap_or_default(),
                 audio.duration
             );
-            return None;
+            return Err(UnavailableReason::NotFound);
         }
         let duration_ms = audio.duration as u32;
+        // a position taken over from another device may have run past the end
+        let position_ms = position_ms.min(duration_ms);
 
-        // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
-        let formats = match self.config.bitrate {
-            Bitrate::Bitrate96 => [
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate160 => [
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate320 => [
-                FileFormat::OGG_VORBIS_320,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-            ],
-        };
-
-        let (format, file_id) =
-            match formats
-                .iter()
-                .find_map(|format| match audio.files.get(format) {
-                    Some(&file_id) => Some((*format, file_id)),
-                    _ => None,
-                }) {
-                Some(t) => t,
-                None => {
-                    warn!("<{}> is not available in any supported format", audio.name);
-                    return None;
-                }
-            };
+        let (format, file_id) = self
+            .find_file(&audio)
+            .ok_or(UnavailableReason::UnsupportedFormat)?;
 
         let bytes_per_second = self.stream_data_rate(format);
         let play_from_beginning = position_ms == 0;
 
+        // the metadata of other items can't be loaded offline
+        let tag_metadata = self.config.passthrough && self.config.metadata_tags && !offline;
+        let metadata_comments = if tag_metadata {
+            self.metadata_comments(&audio).await
+        } else {
+            Vec::new()
+        };
+
         // This is only a loop to be able to reload the file if an error occurred
         // while opening a cached file.
         loop {
@@ -833,18 +1805,35 @@ impl PlayerTrackLoader {
                 bytes_per_second,
                 play_from_beginning,
             );
+            // request the key while the file is opened rather than after, saving a roundtrip
+            let key = match offline_track {
+                Some(ref track) if offline => future::Either::Left(future::ok(AudioKey(track.key))),
+                _ => future::Either::Right(self.session.audio_key().request(spotify_id, file_id)),
+            };
+
+            let (encrypted_file, key) = future::join(encrypted_file, key).await;
 
-            let encrypted_file = match encrypted_file.await {
+            let encrypted_file = match encrypted_file {
                 Ok(encrypted_file) => encrypted_file,
                 Err(e) => {
                     error!("Unable to load encrypted file: {:?}", e);
-                    return None;
+                    return Err(UnavailableReason::LoadFailed);
                 }
             };
             let is_cached = encrypted_file.is_cached();
 
             let stream_loader_controller = encrypted_file.get_stream_loader_controller();
 
+            let read_ahead = max(
+                self.config.prefetch_bytes,
+                (self.config.prefetch_duration.as_secs_f32() * bytes_per_second as f32) as usize,
+            );
+            stream_loader_controller.set_read_ahead(
+                self.config
+                    .max_buffer_bytes
+                    .map_or(read_ahead, |limit| min(read_ahead, limit)),
+            );
+
             if play_from_beginning {
                 // No need to seek -> we stream from the beginning
                 stream_loader_controller.set_stream_mode();
@@ -853,17 +1842,51 @@ impl PlayerTrackLoader {
                 stream_loader_controller.set_random_access_mode();
             }
 
-            let key = match self.session.audio_key().request(spotify_id, file_id).await {
-                Ok(key) => key,
-                Err(e) => {
+            let cached_key = offline_track
+                .as_ref()
+                .filter(|track| track.file_id == file_id.0)
+                .map(|track| AudioKey(track.key));
+
+            let key = match (key, cached_key) {
+                (Ok(key), _) => key,
+                (Err(e), Some(cached_key)) => {
+                    warn!(
+                        "Unable to load decryption key: {:?}, using the cached one",
+                        e
+                    );
+                    offline = true;
+                    cached_key
+                }
+                (Err(e), None) => {
                     error!("Unable to load decryption key: {:?}", e);
-                    return None;
+                    return Err(UnavailableReason::LoadFailed);
                 }
             };
 
+            if !offline {
+                self.save_offline_track(spotify_id, &audio, format, file_id, key, &offline_track);
+            }
+
             let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
 
-            let normalisation_data = match NormalisationData::parse_from_file(&mut decrypted_file) {
+            let normalisation_data = if has_spotify_header(format) {
+                NormalisationData::parse_from_file(&mut decrypted_file)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Only Spotify's Ogg Vorbis files have normalisation data",
+                ))
+            };
+
+            // only tag the stream if there actually is normalisation data
+            let replaygain_comments = match normalisation_data {
+                Ok(ref data) if self.config.passthrough && self.config.replaygain_tags => {
+                    data.replaygain_comments()
+                }
+                _ => Vec::new(),
+            };
+
+            let normalisation_data = match normalisation_data {
                 Ok(data) => data,
                 Err(_) => {
                     warn!("Unable to extract normalisation data, using default value.");
@@ -876,17 +1899,37 @@ impl PlayerTrackLoader {
                 }
             };
 
-            let audio_file = Subfile::new(decrypted_file, 0xa7);
+            let header_size = if has_spotify_header(format) {
+                SPOTIFY_OGG_HEADER_SIZE
+            } else {
+                0
+            };
+            let audio_file = Subfile::new(decrypted_file, header_size);
 
             let result = if self.config.passthrough {
                 match PassthroughDecoder::new(audio_file) {
-                    Ok(result) => Ok(Box::new(result) as Decoder),
+                    Ok(mut result) => {
+                        if !metadata_comments.is_empty() {
+                            if let Err(e) = result.append_comments(&metadata_comments) {
+                                warn!("Unable to add tags: {}", e);
+                            }
+                        }
+                        if !replaygain_comments.is_empty() {
+                            if let Err(e) = result.append_comments(&replaygain_comments) {
+                                warn!("Unable to add ReplayGain tags: {}", e);
+                            }
+                        }
+                        Ok(Box::new(result) as Decoder)
+                    }
                     Err(e) => Err(DecoderError::PassthroughDecoder(e.to_string())),
                 }
             } else {
-                match VorbisDecoder::new(audio_file) {
-                    Ok(result) => Ok(Box::new(result) as Decoder),
-                    Err(e) => Err(DecoderError::LewtonDecoder(e.to_string())),
+                match AudioCodec::from_format(format).and_then(|codec| self.find_decoder(codec)) {
+                    Some(decoder) => decoder(Box::new(audio_file)),
+                    None => {
+                        error!("No decoder available for {:?}", format);
+                        return Err(UnavailableReason::UnsupportedFormat);
+                    }
                 }
             };
 
@@ -902,12 +1945,12 @@ impl PlayerTrackLoader {
                         Some(cache) => {
                             if cache.remove_file(file_id).is_err() {
                                 error!("Error removing file from cache");
-                                return None;
+                                return Err(UnavailableReason::DecodeFailed);
                             }
                         }
                         None => {
                             error!("If the audio file is cached, a cache should exist");
-                            return None;
+                            return Err(UnavailableReason::DecodeFailed);
                         }
                     }
 
@@ -916,7 +1959,7 @@ impl PlayerTrackLoader {
                 }
                 Err(e) => {
                     error!("Unable to read audio file: {}", e);
-                    return None;
+                    return Err(UnavailableReason::DecodeFailed);
                 }
             };
 
@@ -931,13 +1974,18 @@ impl PlayerTrackLoader {
             let stream_position_pcm = position_pcm;
             info!("<{}> ({} ms) loaded", audio.name, audio.duration);
 
-            return Some(PlayerLoadedTrackData {
+            return Ok(PlayerLoadedTrackData {
                 decoder,
                 normalisation_data,
                 stream_loader_controller,
                 bytes_per_second,
                 duration_ms,
                 stream_position_pcm,
+                format: StreamFormat {
+                    file_format: format,
+                    passthrough: self.config.passthrough,
+                },
+                offline,
             });
         }
     }
@@ -950,6 +1998,7 @@ impl Future for PlayerInternal {
         // While this is written as a future, it still contains blocking code.
         // It must be run on its own thread.
         let passthrough = self.config.passthrough;
+        let stop_position_ms = self.config.stop_position_ms;
 
         loop {
             let mut all_futures_completed_or_not_ready = true;
@@ -989,12 +2038,17 @@ impl Future for PlayerInternal {
                             exit(1);
                         }
                     }
-                    Poll::Ready(Err(e)) => {
+                    Poll::Ready(Err(reason)) => {
                         warn!(
-                            "Skipping to next track, unable to load track <{:?}>: {:?}",
-                            track_id, e
+                            "Skipping to next track, unable to load track <{:?}>: {}",
+                            track_id, reason
                         );
                         debug_assert!(self.state.is_loading());
+                        self.send_event(PlayerEvent::Unavailable {
+                            track_id,
+                            play_request_id,
+                            reason,
+                        });
                         self.send_event(PlayerEvent::EndOfTrack {
                             track_id,
                             play_request_id,
@@ -1018,8 +2072,8 @@ impl Future for PlayerInternal {
                             loaded_track: Box::new(loaded_track),
                         };
                     }
-                    Poll::Ready(Err(_)) => {
-                        debug!("Unable to preload {:?}", track_id);
+                    Poll::Ready(Err(reason)) => {
+                        debug!("Unable to preload {:?}: {}", track_id, reason);
                         self.preload = PlayerPreload::None;
                         // Let Spirc know that the track was unavailable.
                         if let PlayerState::Playing {
@@ -1032,6 +2086,7 @@ impl Future for PlayerInternal {
                             self.send_event(PlayerEvent::Unavailable {
                                 track_id,
                                 play_request_id,
+                                reason,
                             });
                         }
                     }
@@ -1041,6 +2096,8 @@ impl Future for PlayerInternal {
 
             if self.state.is_playing() {
                 self.ensure_sink_running();
+                let speed = self.position_speed();
+                let speed_changed = speed != self.reported_speed;
 
                 if let PlayerState::Playing {
                     track_id,
@@ -1055,6 +2112,7 @@ impl Future for PlayerInternal {
                 {
                     match decoder.next_packet() {
                         Ok(packet) => {
+                            let mut past_stop_position = false;
                             if !passthrough {
                                 if let Some(ref packet) = packet {
                                     match packet.samples() {
@@ -1063,38 +2121,59 @@ impl Future for PlayerInternal {
                                                 (samples.len() / NUM_CHANNELS as usize) as u64;
                                             let stream_position_millis =
                                                 Self::position_pcm_to_ms(*stream_position_pcm);
+                                            past_stop_position = stop_position_ms
+                                                .map_or(false, |stop| {
+                                                    stream_position_millis >= stop
+                                                });
 
-                                            let notify_about_position =
-                                                match *reported_nominal_start_time {
-                                                    None => true,
-                                                    Some(reported_nominal_start_time) => {
-                                                        // only notify if we're behind. If we're ahead it's probably due to a buffer of the backend and we're actually in time.
-                                                        let lag = (Instant::now()
-                                                            - reported_nominal_start_time)
-                                                            .as_millis()
-                                                            as i64
-                                                            - stream_position_millis as i64;
-                                                        lag > Duration::from_secs(1).as_millis()
-                                                            as i64
-                                                    }
-                                                };
+                                            let lagging = match *reported_nominal_start_time {
+                                                None => false,
+                                                Some(reported_nominal_start_time) => {
+                                                    // only notify if we're behind. If we're ahead it's probably due to a buffer of the backend and we're actually in time.
+                                                    let lag = ((Instant::now()
+                                                        - reported_nominal_start_time)
+                                                        .as_millis()
+                                                        as f64
+                                                        * speed)
+                                                        as i64
+                                                        - stream_position_millis as i64;
+                                                    lag > Duration::from_secs(1).as_millis() as i64
+                                                }
+                                            };
+                                            let notify_about_position = lagging
+                                                || speed_changed
+                                                || reported_nominal_start_time.is_none();
                                             if notify_about_position {
-                                                *reported_nominal_start_time = Some(
-                                                    Instant::now()
-                                                        - Duration::from_millis(
-                                                            stream_position_millis as u64,
-                                                        ),
-                                                );
+                                                *reported_nominal_start_time =
+                                                    Some(nominal_start_time(
+                                                        stream_position_millis,
+                                                        speed,
+                                                    ));
+                                                self.reported_speed = speed;
+                                                // behind the position reported at the start of playback
+                                                if lagging {
+                                                    self.send_event(PlayerEvent::BufferUnderrun {
+                                                        track_id,
+                                                        play_request_id,
+                                                        position_ms: stream_position_millis,
+                                                    });
+                                                }
                                                 self.send_event(PlayerEvent::Playing {
                                                     track_id,
                                                     play_request_id,
                                                     position_ms: stream_position_millis as u32,
                                                     duration_ms,
+                                                    speed,
                                                 });
                                             }
                                         }
                                         Err(e) => {
                                             warn!("Skipping to next track, unable to decode samples for track <{:?}>: {:?}", track_id, e);
+                                            self.send_event(PlayerEvent::Error {
+                                                category: ErrorCategory::Decode,
+                                                message: format!("Unable to decode samples: {}", e),
+                                                recoverable: true,
+                                            });
                                             self.send_event(PlayerEvent::EndOfTrack {
                                                 track_id,
                                                 play_request_id,
@@ -1107,10 +2186,20 @@ impl Future for PlayerInternal {
                                 *stream_position_pcm = duration_ms.into();
                             }
 
-                            self.handle_packet(packet, normalisation_factor);
+                            if past_stop_position {
+                                // end the track as if it was over
+                                self.handle_packet(None, normalisation_factor);
+                            } else {
+                                self.handle_packet(packet, normalisation_factor);
+                            }
                         }
                         Err(e) => {
                             warn!("Skipping to next track, unable to get next packet for track <{:?}>: {:?}", track_id, e);
+                            self.send_event(PlayerEvent::Error {
+                                category: ErrorCategory::Decode,
+                                message: format!("Unable to get next packet: {}", e),
+                                recoverable: true,
+                            });
                             self.send_event(PlayerEvent::EndOfTrack {
                                 track_id,
                                 play_request_id,
@@ -1123,6 +2212,8 @@ impl Future for PlayerInternal {
                 };
             }
 
+            // set once the state is no longer borrowed, unknown while the file size is
+            let mut buffered_ms = Some(0);
             if let PlayerState::Playing {
                 track_id,
                 play_request_id,
@@ -1142,6 +2233,12 @@ impl Future for PlayerInternal {
                 ..
             } = self.state
             {
+                let file_size = stream_loader_controller.len() as u64;
+                buffered_ms = Some(file_size).filter(|size| *size > 0).map(|file_size| {
+                    let ahead = stream_loader_controller.downloaded_ahead() as u64;
+                    (ahead * duration_ms as u64 / file_size) as u32
+                });
+
                 if (!*suggested_to_preload_next_track)
                     && ((duration_ms as i64 - Self::position_pcm_to_ms(stream_position_pcm) as i64)
                         < PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS as i64)
@@ -1154,6 +2251,11 @@ impl Future for PlayerInternal {
                     });
                 }
             }
+            if let Some(buffered_ms) = buffered_ms {
+                self.buffer_fill.set(buffered_ms);
+            }
+
+            self.announce_track_change();
 
             if self.session.is_invalid() {
                 return Poll::Ready(());
@@ -1167,6 +2269,78 @@ impl Future for PlayerInternal {
 }
 
 impl PlayerInternal {
+    // Switches the normalisation and equalizer to `PlayerConfig::profile` if it changed.
+    fn apply_profile(&mut self) {
+        let generation = self.config.profile.generation();
+        if self.profile_generation == Some(generation) {
+            return;
+        }
+        self.profile_generation = Some(generation);
+
+        let profile = self.config.profile.get().unwrap_or_default();
+        let configured = &self.configured_output;
+        self.config.normalisation = profile
+            .normalisation
+            .or(configured.normalisation)
+            .unwrap_or_default();
+        self.config.equalizer = profile
+            .equalizer
+            .or_else(|| configured.equalizer.clone())
+            .unwrap_or_default();
+
+        let passthrough = self.config.passthrough;
+        self.equalizer = Some(Equalizer::new(&self.config.equalizer))
+            .filter(|equalizer| !passthrough && !equalizer.is_empty());
+    }
+
+    fn announce_track_change(&mut self) {
+        let lead_ms = match self.config.track_change_lead {
+            Some(lead) => lead.as_millis() as u32,
+            None => return,
+        };
+
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            duration_ms,
+            stream_position_pcm,
+            ..
+        } = self.state
+        {
+            let remaining_ms =
+                duration_ms.saturating_sub(Self::position_pcm_to_ms(stream_position_pcm));
+            let announced = self.announced_track_change == Some(play_request_id);
+
+            if remaining_ms > lead_ms {
+                if announced {
+                    self.announced_track_change = None;
+                }
+            } else if !announced {
+                self.announced_track_change = Some(play_request_id);
+
+                let next_track_id = match self.preload {
+                    PlayerPreload::Loading { track_id, .. }
+                    | PlayerPreload::Ready { track_id, .. } => Some(track_id),
+                    PlayerPreload::None => None,
+                };
+                self.send_event(PlayerEvent::TrackChanging {
+                    play_request_id,
+                    track_id,
+                    next_track_id,
+                    remaining_ms,
+                });
+            }
+        }
+    }
+
+    // How fast the position of the current track advances.
+    fn position_speed(&self) -> f64 {
+        match self.time_stretch {
+            Some(_) if self.stretch_track => self.config.speed.speed(),
+            _ => 1.0,
+        }
+    }
+
     fn position_pcm_to_ms(position_pcm: u64) -> u32 {
         (position_pcm as f64 * MS_PER_PAGE) as u32
     }
@@ -1185,7 +2359,7 @@ impl PlayerInternal {
                 Ok(()) => self.sink_status = SinkStatus::Running,
                 Err(e) => {
                     error!("{}", e);
-                    exit(1);
+                    exit(AUDIO_ERROR_EXIT_CODE);
                 }
             }
         }
@@ -1247,6 +2421,7 @@ impl PlayerInternal {
                 ..
             } => {
                 self.ensure_sink_stopped(false);
+                self.report_loudness();
                 self.send_event(PlayerEvent::Stopped {
                     track_id,
                     play_request_id,
@@ -1273,11 +2448,14 @@ impl PlayerInternal {
             self.state.paused_to_playing();
 
             let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+            let speed = self.position_speed();
+            self.reported_speed = speed;
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
                 position_ms,
                 duration_ms,
+                speed,
             });
             self.ensure_sink_running();
         } else {
@@ -1312,8 +2490,24 @@ impl PlayerInternal {
     fn handle_packet(&mut self, packet: Option<AudioPacket>, normalisation_factor: f64) {
         match packet {
             Some(mut packet) => {
+                self.apply_profile();
+
+                // Time stretching goes before anything that depends on the timing of the
+                // samples, and may leave nothing to play while it fills its window.
+                if let (true, Some(time_stretch), AudioPacket::Samples(data)) =
+                    (self.stretch_track, self.time_stretch.as_mut(), &mut packet)
+                {
+                    time_stretch.apply(data);
+                }
+
                 if !packet.is_empty() {
                     if let AudioPacket::Samples(ref mut data) = packet {
+                        // Tone shaping goes first, so that any boost is subject
+                        // to normalisation and limiting like the rest of the signal.
+                        if let Some(ref mut equalizer) = self.equalizer {
+                            equalizer.process(data);
+                        }
+
                         // Get the volume for the packet.
                         // In the case of hardware volume control this will
                         // always be 1.0 (no change).
@@ -1324,111 +2518,79 @@ impl PlayerInternal {
                         // dynamic method, there may still be peaks that we want to shave off.
                         // No matter the case we apply volume attenuation last if there is any.
                         if !self.config.normalisation && volume < 1.0 {
-                            for sample in data.iter_mut() {
-                                *sample *= volume;
-                            }
+                            dsp::scale(data, volume);
                         } else if self.config.normalisation_method == NormalisationMethod::Basic
                             && (normalisation_factor < 1.0 || volume < 1.0)
                         {
+                            dsp::scale(data, normalisation_factor * volume);
+                        } else if self.config.normalisation_method == NormalisationMethod::Dynamic {
                             for sample in data.iter_mut() {
-                                *sample *= normalisation_factor * volume;
+                                *sample = self
+                                    .normalisation_limiter
+                                    .process(*sample * normalisation_factor)
+                                    * volume;
                             }
-                        } else if self.config.normalisation_method == NormalisationMethod::Dynamic {
-                            // zero-cost shorthands
-                            let threshold_db = self.config.normalisation_threshold_dbfs;
-                            let knee_db = self.config.normalisation_knee_db;
-                            let attack_cf = self.config.normalisation_attack_cf;
-                            let release_cf = self.config.normalisation_release_cf;
+                        }
 
+                        if self.pre_gain_factor != 1.0 {
+                            dsp::scale(data, self.pre_gain_factor);
+                        }
+
+                        self.config.channel_mix.apply(data);
+
+                        if let Some(ref mut limiter) = self.limiter {
                             for sample in data.iter_mut() {
-                                *sample *= normalisation_factor;
-
-                                // Feedforward limiter in the log domain
-                                // After: Giannoulis, D., Massberg, M., & Reiss, J.D. (2012). Digital Dynamic
-                                // Range Compressor Design—A Tutorial and Analysis. Journal of The Audio
-                                // Engineering Society, 60, 399-408.
-
-                                // Some tracks have samples that are precisely 0.0. That's silence
-                                // and we know we don't need to limit that, in which we can spare
-                                // the CPU cycles.
-                                //
-                                // Also, calling `ratio_to_db(0.0)` returns `inf` and would get the
-                                // peak detector stuck. Also catch the unlikely case where a sample
-                                // is decoded as `NaN` or some other non-normal value.
-                                let limiter_db = if sample.is_normal() {
-                                    // step 1-4: half-wave rectification and conversion into dB
-                                    // and gain computer with soft knee and subtractor
-                                    let bias_db = ratio_to_db(sample.abs()) - threshold_db;
-                                    let knee_boundary_db = bias_db * 2.0;
-
-                                    if knee_boundary_db < -knee_db {
-                                        0.0
-                                    } else if knee_boundary_db.abs() <= knee_db {
-                                        // The textbook equation:
-                                        // ratio_to_db(sample.abs()) - (ratio_to_db(sample.abs()) - (bias_db + knee_db / 2.0).powi(2) / (2.0 * knee_db))
-                                        // Simplifies to:
-                                        // ((2.0 * bias_db) + knee_db).powi(2) / (8.0 * knee_db)
-                                        // Which in our case further simplifies to:
-                                        // (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
-                                        // because knee_boundary_db is 2.0 * bias_db.
-                                        (knee_boundary_db + knee_db).powi(2) / (8.0 * knee_db)
-                                    } else {
-                                        // Textbook:
-                                        // ratio_to_db(sample.abs()) - threshold_db, which is already our bias_db.
-                                        bias_db
-                                    }
-                                } else {
-                                    0.0
-                                };
-
-                                // Spare the CPU unless (1) the limiter is engaged, (2) we
-                                // were in attack or (3) we were in release, and that attack/
-                                // release wasn't finished yet.
-                                if limiter_db > 0.0
-                                    || self.normalisation_integrator > 0.0
-                                    || self.normalisation_peak > 0.0
-                                {
-                                    // step 5: smooth, decoupled peak detector
-                                    // Textbook:
-                                    // release_cf * self.normalisation_integrator + (1.0 - release_cf) * limiter_db
-                                    // Simplifies to:
-                                    // release_cf * self.normalisation_integrator - release_cf * limiter_db + limiter_db
-                                    self.normalisation_integrator = f64::max(
-                                        limiter_db,
-                                        release_cf * self.normalisation_integrator
-                                            - release_cf * limiter_db
-                                            + limiter_db,
-                                    );
-                                    // Textbook:
-                                    // attack_cf * self.normalisation_peak + (1.0 - attack_cf) * self.normalisation_integrator
-                                    // Simplifies to:
-                                    // attack_cf * self.normalisation_peak - attack_cf * self.normalisation_integrator + self.normalisation_integrator
-                                    self.normalisation_peak = attack_cf * self.normalisation_peak
-                                        - attack_cf * self.normalisation_integrator
-                                        + self.normalisation_integrator;
-
-                                    // step 6: make-up gain applied later (volume attenuation)
-                                    // Applying the standard normalisation factor here won't work,
-                                    // because there are tracks with peaks as high as 6 dB above
-                                    // the default threshold, so that would clip.
-
-                                    // steps 7-8: conversion into level and multiplication into gain stage
-                                    *sample *= db_to_ratio(-self.normalisation_peak);
-                                }
+                                *sample = limiter.process(*sample);
+                            }
+                        }
 
-                                *sample *= volume;
+                        if let Some(ref mut fade_in) = self.fade_in {
+                            fade_in.apply(data);
+                            if fade_in.is_done() {
+                                self.fade_in = None;
                             }
                         }
+
+                        if let Some(ref mut meter) = self.loudness_meter {
+                            meter.process(data);
+                        }
+                    }
+
+                    if let Some(track_id) = self.track_boundary.take() {
+                        let uri = track_id.to_uri().unwrap_or_default();
+                        let marker = self.config.track_marker.as_ref();
+                        if let Err(e) = self.sink.track_boundary(marker, &uri) {
+                            warn!("{}", e);
+                            self.send_event(PlayerEvent::Error {
+                                category: ErrorCategory::Sink,
+                                message: e.to_string(),
+                                recoverable: true,
+                            });
+                        }
                     }
 
+                    let samples = packet.samples().map_or(0, |samples| samples.len());
+                    let started = Instant::now();
                     if let Err(_e) = self.sink.write(packet, &mut self.converter) {
                         // error!("{}", e);
-                        exit(1);
+                        exit(AUDIO_ERROR_EXIT_CODE);
                     }
+                    self.sink_stats.add(started.elapsed(), samples);
                 }
             }
 
             None => {
+                // Play out what the time stretching still holds, without stretching it again.
+                let flushed = match self.time_stretch {
+                    Some(ref mut time_stretch) if self.stretch_track => time_stretch.flush(),
+                    _ => Vec::new(),
+                };
+                if !flushed.is_empty() {
+                    self.stretch_track = false;
+                    self.handle_packet(Some(AudioPacket::Samples(flushed)), normalisation_factor);
+                    self.stretch_track = true;
+                }
+
                 if self.config.lms_connect_mode {
                     // info!("In LMS Connect mode - ignore end of track");
 
@@ -1440,6 +2602,8 @@ impl PlayerInternal {
                     return;
                 }
 
+                self.report_loudness();
+
                 self.state.playing_to_end_of_track();
                 if let PlayerState::EndOfTrack {
                     track_id,
@@ -1459,6 +2623,43 @@ impl PlayerInternal {
         }
     }
 
+    fn report_loudness(&mut self) {
+        let (play_request_id, track_id) = match self.loudness_track.take() {
+            Some(track) => track,
+            None => return,
+        };
+        let loudness = match self.loudness_meter {
+            Some(ref mut meter) => {
+                let loudness = meter.loudness();
+                meter.reset();
+                loudness
+            }
+            None => return,
+        };
+
+        // less than 400 ms were played
+        let loudness = match loudness {
+            Some(loudness) => loudness,
+            None => return,
+        };
+
+        let integrated = loudness
+            .integrated_lufs
+            .map_or_else(|| "silent".to_string(), |lufs| format!("{:.1} LUFS", lufs));
+        info!(
+            "Loudness of <{}>: {} integrated, {:.1} dBTP true peak",
+            track_id.to_uri().unwrap_or_default(),
+            integrated,
+            loudness.true_peak_dbtp
+        );
+
+        self.send_event(PlayerEvent::Loudness {
+            play_request_id,
+            track_id,
+            loudness,
+        });
+    }
+
     fn start_playback(
         &mut self,
         track_id: SpotifyId,
@@ -1468,6 +2669,7 @@ impl PlayerInternal {
     ) {
         let position_ms = Self::position_pcm_to_ms(loaded_track.stream_position_pcm);
 
+        self.apply_profile();
         let mut config = self.config.clone();
         if config.normalisation_type == NormalisationType::Auto {
             if self.auto_normalise_as_album {
@@ -1478,15 +2680,63 @@ impl PlayerInternal {
         };
         let normalisation_factor =
             NormalisationData::get_factor(&config, loaded_track.normalisation_data);
+        self.pre_gain_factor = NormalisationData::get_pre_gain_factor(
+            &config,
+            loaded_track.normalisation_data,
+            normalisation_factor,
+        );
+
+        self.track_boundary = Some(track_id);
+        self.stretch_track = track_id.audio_type == SpotifyAudioType::Podcast;
+        if let Some(ref mut time_stretch) = self.time_stretch {
+            time_stretch.reset();
+        }
+
+        if self.loudness_meter.is_some() {
+            self.report_loudness();
+            self.loudness_track = Some((play_request_id, track_id));
+        }
+
+        let prefetch = self.config.prefetch_bytes > 0 || !self.config.prefetch_duration.is_zero();
+        let len = loaded_track.stream_loader_controller.len();
+        let fits_buffer = self
+            .config
+            .max_buffer_bytes
+            .map_or(true, |limit| len <= limit);
+        if prefetch && fits_buffer {
+            loaded_track.stream_loader_controller.fetch_remainder();
+        }
+
+        if loaded_track.offline {
+            self.send_event(PlayerEvent::Offline {
+                play_request_id,
+                track_id,
+            });
+        }
+
+        self.send_event(PlayerEvent::FormatChanged {
+            play_request_id,
+            track_id,
+            format: loaded_track.format,
+        });
+
+        self.send_event(PlayerEvent::Normalisation {
+            play_request_id,
+            track_id,
+            data: loaded_track.normalisation_data,
+        });
 
         if start_playback {
             self.ensure_sink_running();
 
+            let speed = self.position_speed();
+            self.reported_speed = speed;
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
                 position_ms,
                 duration_ms: loaded_track.duration_ms,
+                speed,
             });
 
             self.state = PlayerState::Playing {
@@ -1499,9 +2749,8 @@ impl PlayerInternal {
                 duration_ms: loaded_track.duration_ms,
                 bytes_per_second: loaded_track.bytes_per_second,
                 stream_position_pcm: loaded_track.stream_position_pcm,
-                reported_nominal_start_time: Some(
-                    Instant::now() - Duration::from_millis(position_ms as u64),
-                ),
+                format: loaded_track.format,
+                reported_nominal_start_time: Some(nominal_start_time(position_ms, speed)),
                 suggested_to_preload_next_track: false,
             };
         } else {
@@ -1517,6 +2766,7 @@ impl PlayerInternal {
                 duration_ms: loaded_track.duration_ms,
                 bytes_per_second: loaded_track.bytes_per_second,
                 stream_position_pcm: loaded_track.stream_position_pcm,
+                format: loaded_track.format,
                 suggested_to_preload_next_track: false,
             };
 
@@ -1654,6 +2904,7 @@ impl PlayerInternal {
                     bytes_per_second,
                     duration_ms,
                     normalisation_data,
+                    format,
                     ..
                 }
                 | PlayerState::Paused {
@@ -1663,6 +2914,7 @@ impl PlayerInternal {
                     bytes_per_second,
                     duration_ms,
                     normalisation_data,
+                    format,
                     ..
                 } = old_state
                 {
@@ -1673,6 +2925,9 @@ impl PlayerInternal {
                         bytes_per_second,
                         duration_ms,
                         stream_position_pcm,
+                        format,
+                        // reported when it was loaded
+                        offline: false,
                     };
 
                     self.preload = PlayerPreload::None;
@@ -1830,6 +3085,13 @@ impl PlayerInternal {
 
             match decoder.seek(position_pcm) {
                 Ok(_) => {
+                    if let Some(ref mut equalizer) = self.equalizer {
+                        equalizer.reset();
+                    }
+                    if let Some(ref mut time_stretch) = self.time_stretch {
+                        time_stretch.reset();
+                    }
+
                     if let PlayerState::Playing {
                         ref mut stream_position_pcm,
                         ..
@@ -1856,6 +3118,7 @@ impl PlayerInternal {
         // ensure we have a bit of a buffer of downloaded data
         self.preload_data_before_playback();
 
+        let speed = self.position_speed();
         if let PlayerState::Playing {
             track_id,
             play_request_id,
@@ -1864,13 +3127,14 @@ impl PlayerInternal {
             ..
         } = self.state
         {
-            *reported_nominal_start_time =
-                Some(Instant::now() - Duration::from_millis(position_ms as u64));
+            *reported_nominal_start_time = Some(nominal_start_time(position_ms, speed));
+            self.reported_speed = speed;
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
                 position_ms,
                 duration_ms,
+                speed,
             });
         }
         if let PlayerState::Paused {
@@ -1917,9 +3181,25 @@ impl PlayerInternal {
                 self.send_event(PlayerEvent::VolumeSet { volume })
             }
 
+            PlayerCommand::EmitPlaybackModeEvent { shuffle, repeat } => {
+                self.send_event(PlayerEvent::PlaybackModeChanged { shuffle, repeat })
+            }
+
+            PlayerCommand::EmitCommandOverriddenEvent {
+                source,
+                command,
+                overridden_by,
+            } => self.send_event(PlayerEvent::CommandOverridden {
+                source,
+                command,
+                overridden_by,
+            }),
+
             PlayerCommand::SetAutoNormaliseAsAlbum(setting) => {
                 self.auto_normalise_as_album = setting
             }
+
+            PlayerCommand::FadeIn(duration) => self.fade_in = Some(FadeIn::new(duration)),
         }
     }
 
@@ -1932,7 +3212,8 @@ impl PlayerInternal {
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> impl Future<Output = Result<PlayerLoadedTrackData, ()>> + Send + 'static {
+    ) -> impl Future<Output = Result<PlayerLoadedTrackData, UnavailableReason>> + Send + 'static
+    {
         // This method creates a future that returns the loaded stream and associated info.
         // Ideally all work should be done using asynchronous code. However, seek() on the
         // audio stream is implemented in a blocking fashion. Thus, we can't turn it into future
@@ -1947,13 +3228,12 @@ impl PlayerInternal {
         let (result_tx, result_rx) = oneshot::channel();
 
         std::thread::spawn(move || {
-            let data = futures_executor::block_on(loader.load_track(spotify_id, position_ms));
-            if let Some(data) = data {
-                let _ = result_tx.send(data);
-            }
+            let result = futures_executor::block_on(loader.load_track(spotify_id, position_ms));
+            let _ = result_tx.send(result);
         });
 
-        result_rx.map_err(|_| ())
+        // the thread only goes away without a result if it panicked
+        result_rx.map(|result| result.unwrap_or(Err(UnavailableReason::LoadFailed)))
     }
 
     fn preload_data_before_playback(&mut self) {
@@ -2018,10 +3298,26 @@ impl ::std::fmt::Debug for PlayerCommand {
             PlayerCommand::EmitVolumeSetEvent(volume) => {
                 f.debug_tuple("VolumeSet").field(&volume).finish()
             }
+            PlayerCommand::EmitPlaybackModeEvent { shuffle, repeat } => f
+                .debug_tuple("PlaybackMode")
+                .field(&shuffle)
+                .field(&repeat)
+                .finish(),
+            PlayerCommand::EmitCommandOverriddenEvent {
+                source,
+                command,
+                overridden_by,
+            } => f
+                .debug_tuple("CommandOverridden")
+                .field(&source)
+                .field(&command)
+                .field(&overridden_by)
+                .finish(),
             PlayerCommand::SetAutoNormaliseAsAlbum(setting) => f
                 .debug_tuple("SetAutoNormaliseAsAlbum")
                 .field(&setting)
                 .finish(),
+            PlayerCommand::FadeIn(duration) => f.debug_tuple("FadeIn").field(&duration).finish(),
         }
     }
 }
@@ -2071,6 +3367,41 @@ impl ::std::fmt::Debug for PlayerState {
         }
     }
 }
+// The formats of each bitrate, Vorbis first as it's always built in. MP3_160_ENC is left out, as
+// it's encrypted differently.
+const FORMATS_96: &[FileFormat] = &[FileFormat::OGG_VORBIS_96, FileFormat::MP3_96];
+const FORMATS_160: &[FileFormat] = &[
+    FileFormat::OGG_VORBIS_160,
+    FileFormat::MP3_160,
+    FileFormat::AAC_160,
+    FileFormat::MP4_128,
+    FileFormat::MP4_128_DUAL,
+];
+const FORMATS_320: &[FileFormat] = &[
+    FileFormat::OGG_VORBIS_320,
+    FileFormat::MP3_320,
+    FileFormat::MP3_256,
+    FileFormat::AAC_320,
+];
+
+// The file formats to look for, best first: those of `bitrate`, then lower and then higher ones.
+// (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it.
+fn preferred_formats(bitrate: Bitrate) -> impl Iterator<Item = FileFormat> {
+    let bitrates = match bitrate {
+        Bitrate::Bitrate96 => [FORMATS_96, FORMATS_160, FORMATS_320],
+        Bitrate::Bitrate160 => [FORMATS_160, FORMATS_96, FORMATS_320],
+        Bitrate::Bitrate320 => [FORMATS_320, FORMATS_160, FORMATS_96],
+    };
+    IntoIterator::into_iter(bitrates).flatten().copied()
+}
+
+// Spotify's Ogg Vorbis files start with a header of their own, which holds the normalisation data.
+const SPOTIFY_OGG_HEADER_SIZE: u64 = 0xa7;
+
+fn has_spotify_header(format: FileFormat) -> bool {
+    AudioCodec::from_format(format) == Some(AudioCodec::Vorbis)
+}
+
 struct Subfile<T: Read + Seek> {
     stream: T,
     offset: u64,
@@ -2103,3 +3434,48 @@ impl<T: Read + Seek> Seek for Subfile<T> {
         Ok(newpos.saturating_sub(self.offset))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preferred_formats() {
+        let formats: Vec<_> = preferred_formats(Bitrate::Bitrate160).collect();
+        assert_eq!(
+            &formats[..3],
+            &[
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::MP3_160,
+                FileFormat::AAC_160
+            ]
+        );
+        // then the lower bitrate, before the higher one
+        let position = |format| formats.iter().position(|&f| f == format).unwrap();
+        assert!(position(FileFormat::MP4_128_DUAL) < position(FileFormat::OGG_VORBIS_96));
+        assert!(position(FileFormat::MP3_96) < position(FileFormat::OGG_VORBIS_320));
+        assert!(!formats.contains(&FileFormat::MP3_160_ENC));
+
+        assert_eq!(
+            preferred_formats(Bitrate::Bitrate96).next(),
+            Some(FileFormat::OGG_VORBIS_96)
+        );
+        assert_eq!(
+            preferred_formats(Bitrate::Bitrate320).last(),
+            Some(FileFormat::MP3_96)
+        );
+
+        // every known format once, but the one which can't be decrypted
+        let mut formats = preferred_formats(Bitrate::Bitrate320).collect::<Vec<_>>();
+        formats.sort_by_key(|&format| format as i32);
+        formats.dedup();
+        assert_eq!(formats.len(), 11);
+    }
+
+    #[test]
+    fn test_has_spotify_header() {
+        assert!(has_spotify_header(FileFormat::OGG_VORBIS_320));
+        assert!(!has_spotify_header(FileFormat::MP3_320));
+        assert!(!has_spotify_header(FileFormat::AAC_160));
+    }
+}
@@ -0,0 +1,1141 @@
+//! Control endpoint, for LMS to drive Spotify Connect.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::connect::spirc::{PlayQueue, QueueError, QueueTrack, Spirc};
+use crate::core::cache::Cache;
+use crate::core::config::ConnectConfig;
+use crate::core::session::Session;
+use crate::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use crate::metadata::{Episode, Metadata};
+use crate::output_profiles::OutputProfiles;
+use crate::playback::channel_mix::BALANCE_RANGE;
+use crate::playback::config::{ChannelMix, PlaybackSpeed, PlayerConfig};
+use crate::playback::time_stretch::SPEED_RANGE;
+use crate::spotty::{context_tracks, get_spotify_id};
+use crate::status::SharedStatus;
+use crate::web_api::{library_command, search_uri, WebApi};
+
+/// Where to start playing a context, see `ControlCommand::Load`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadOffset {
+    Index(usize),
+    Track(SpotifyId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Prev,
+    VolumeUp,
+    VolumeDown,
+    Seek(u32),
+    SeekBy(i64),
+    SkipChapter(bool),
+    SetShuffle(bool),
+    SetRepeat(bool),
+    Queue,
+    AddToQueue(SpotifyId),
+    RemoveFromQueue(usize),
+    MoveInQueue(usize, usize),
+    TakeOver,
+    SetBalance(i8),
+    SetSwapChannels(bool),
+    // in hundredths
+    SetSpeed(u16),
+    // `None` for the configured settings, see `OutputProfiles`
+    SetProfile(Option<String>),
+    SetLiked(bool),
+    SetFollowed(bool),
+    Playlists,
+    AddToPlaylist(SpotifyId),
+    Load {
+        uri: String,
+        shuffle: Option<bool>,
+        offset: Option<LoadOffset>,
+    },
+    // plays the best match of a search, see `ControlCommand::from_intent`
+    PlaySearch {
+        kind: &'static str,
+        query: String,
+        shuffle: Option<bool>,
+    },
+}
+
+// What a "play" intent can ask for, the most specific first
+const INTENT_KINDS: [&str; 6] = ["track", "episode", "album", "show", "playlist", "artist"];
+
+impl ControlCommand {
+    /// Parses an intent of a voice assistant, an object with a single action. "play" searches
+    /// for the most specific of `INTENT_KINDS` it's given, narrowed down by the artist and album,
+    /// e.g. `{"play": {"album": "Kind of Blue", "artist": "Miles Davis", "shuffle": true}}`.
+    /// Any other action is run as a command, with the value as its argument, e.g.
+    /// `{"next": null}`, `{"shuffle": true}` or `{"seek": "+30s"}`.
+    pub fn from_intent(intent: &Value) -> Result<Self, String> {
+        let (action, value) = intent
+            .as_object()
+            .filter(|actions| actions.len() == 1)
+            .and_then(|actions| actions.iter().next())
+            .ok_or_else(|| {
+                "Expected a single action, like {\"play\": {\"track\": ...}}".to_string()
+            })?;
+
+        let fields = match (action.as_str(), value) {
+            ("play", Value::Object(fields)) if !fields.is_empty() => fields,
+            ("play", Value::String(query)) => {
+                return Ok(Self::PlaySearch {
+                    kind: "track",
+                    query: query.to_string(),
+                    shuffle: None,
+                })
+            }
+            (_, Value::Null) => return action.parse(),
+            (_, Value::Object(args)) if args.is_empty() => return action.parse(),
+            (_, Value::Bool(true)) => return format!("{} on", action).parse(),
+            (_, Value::Bool(false)) => return format!("{} off", action).parse(),
+            (_, Value::String(arg)) => return format!("{} {}", action, arg).parse(),
+            (_, Value::Number(arg)) => return format!("{} {}", action, arg).parse(),
+            _ => return Err(format!("Invalid value for \"{}\"", action)),
+        };
+
+        if let Some(key) = fields
+            .keys()
+            .find(|key| *key != "shuffle" && !INTENT_KINDS.contains(&key.as_str()))
+        {
+            return Err(format!("Unknown field \"{}\"", key));
+        }
+        let text = |key: &str| {
+            fields
+                .get(key)
+                .and_then(Value::as_str)
+                .filter(|text| !text.is_empty())
+        };
+
+        let kind = *INTENT_KINDS
+            .iter()
+            .find(|kind| fields.contains_key(**kind))
+            .ok_or_else(|| "Nothing to play".to_string())?;
+        let mut query = text(kind)
+            .ok_or_else(|| format!("Invalid {}", kind))?
+            .to_string();
+        if kind == "track" || kind == "album" {
+            for filter in ["album", "artist"].iter().filter(|filter| **filter != kind) {
+                if let Some(name) = text(filter) {
+                    query = format!("{} {}:{}", query, filter, name);
+                }
+            }
+        }
+
+        Ok(Self::PlaySearch {
+            kind,
+            query,
+            shuffle: fields.get("shuffle").and_then(Value::as_bool),
+        })
+    }
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let command = words
+            .first()
+            .map(|word| word.to_lowercase())
+            .unwrap_or_default();
+        let args = words.get(1..).unwrap_or_default();
+
+        let switch = |value: &str| match value.to_lowercase().as_ref() {
+            "on" | "true" | "1" => Ok(true),
+            "off" | "false" | "0" => Ok(false),
+            _ => Err(format!("Invalid value \"{}\", expected on or off", value)),
+        };
+        let position = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid queue position \"{}\"", value))
+        };
+
+        match (command.as_ref(), args) {
+            ("play", []) => Ok(Self::Play),
+            ("pause", []) => Ok(Self::Pause),
+            ("playpause", []) => Ok(Self::PlayPause),
+            ("next", []) => Ok(Self::Next),
+            ("prev", []) => Ok(Self::Prev),
+            ("volumeup", []) => Ok(Self::VolumeUp),
+            ("volumedown", []) => Ok(Self::VolumeDown),
+            ("seek", [value]) => {
+                let (sign, time) = match (value.strip_prefix('+'), value.strip_prefix('-')) {
+                    (Some(time), _) => (1, time),
+                    (_, Some(time)) => (-1, time),
+                    _ => (0, *value),
+                };
+                let position_ms = parse_time_ms(time).ok_or_else(|| {
+                    format!(
+                        "Invalid position \"{}\", expected eg. 90, 1:30 or +30s",
+                        value
+                    )
+                })?;
+                match sign {
+                    0 => Ok(Self::Seek(position_ms)),
+                    _ => Ok(Self::SeekBy(sign * i64::from(position_ms))),
+                }
+            }
+            ("chapter", ["next"]) => Ok(Self::SkipChapter(true)),
+            ("chapter", ["prev"]) => Ok(Self::SkipChapter(false)),
+            ("shuffle", [value]) => switch(value).map(Self::SetShuffle),
+            ("repeat", [value]) => switch(value).map(Self::SetRepeat),
+            ("takeover", []) => Ok(Self::TakeOver),
+            ("queue", []) => Ok(Self::Queue),
+            ("queue", ["add", uri]) => SpotifyId::from_uri(uri)
+                .map(Self::AddToQueue)
+                .map_err(|_| format!("Invalid Spotify URI \"{}\"", uri)),
+            ("queue", ["remove", index]) => position(index).map(Self::RemoveFromQueue),
+            ("queue", ["move", from, to]) => Ok(Self::MoveInQueue(position(from)?, position(to)?)),
+            ("balance", [value]) => value
+                .parse::<i8>()
+                .ok()
+                .filter(|balance| BALANCE_RANGE.contains(balance))
+                .map(Self::SetBalance)
+                .ok_or_else(|| format!("Invalid balance \"{}\", expected -100 - 100", value)),
+            ("swapchannels", [value]) => switch(value).map(Self::SetSwapChannels),
+            ("speed", [value]) => value
+                .trim_end_matches('x')
+                .parse::<f64>()
+                .ok()
+                .filter(|speed| SPEED_RANGE.contains(speed))
+                .map(|speed| Self::SetSpeed((speed * 100.0).round() as u16))
+                .ok_or_else(|| format!("Invalid speed \"{}\", expected 1.0 - 3.0", value)),
+            ("profile", ["none"]) => Ok(Self::SetProfile(None)),
+            ("profile", [name]) => Ok(Self::SetProfile(Some(name.to_string()))),
+            ("like", []) => Ok(Self::SetLiked(true)),
+            ("unlike", []) => Ok(Self::SetLiked(false)),
+            ("follow", []) => Ok(Self::SetFollowed(true)),
+            ("unfollow", []) => Ok(Self::SetFollowed(false)),
+            ("playlists", []) => Ok(Self::Playlists),
+            ("playlist", ["add", uri]) => SpotifyId::from_uri(uri)
+                .ok()
+                .filter(|_| uri.contains(":playlist:"))
+                .map(Self::AddToPlaylist)
+                .ok_or_else(|| format!("Invalid playlist URI \"{}\"", uri)),
+            ("load", [uri, options @ ..]) => {
+                SpotifyId::from_uri(uri).map_err(|_| format!("Invalid Spotify URI \"{}\"", uri))?;
+
+                let (mut shuffle, mut offset) = (None, None);
+                for option in options.chunks(2) {
+                    match *option {
+                        ["shuffle", value] => shuffle = Some(switch(value)?),
+                        ["offset", value] => {
+                            offset = match value.parse() {
+                                Ok(index) => Some(LoadOffset::Index(index)),
+                                Err(_) => SpotifyId::from_uri(value)
+                                    .map(|track_id| Some(LoadOffset::Track(track_id)))
+                                    .map_err(|_| format!("Invalid offset \"{}\"", value))?,
+                            }
+                        }
+                        _ => return Err(format!("Invalid option \"{}\"", option.join(" "))),
+                    }
+                }
+
+                Ok(Self::Load {
+                    uri: uri.to_string(),
+                    shuffle,
+                    offset,
+                })
+            }
+            _ => Err(format!("Unknown command \"{}\"", s.trim())),
+        }
+    }
+}
+
+/// A control command, and where to send the response to.
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    response: oneshot::Sender<Response<Body>>,
+}
+
+impl ControlRequest {
+    pub fn run(
+        self,
+        spirc: &Spirc,
+        session: &Session,
+        web_api: Option<&WebApi>,
+        status: &SharedStatus,
+    ) {
+        let response = self.response;
+        match self.command {
+            ControlCommand::Play => spirc.play(),
+            ControlCommand::Pause => spirc.pause(),
+            ControlCommand::PlayPause => spirc.play_pause(),
+            ControlCommand::Next => spirc.next(),
+            ControlCommand::Prev => spirc.prev(),
+            ControlCommand::VolumeUp => spirc.volume_up(),
+            ControlCommand::VolumeDown => spirc.volume_down(),
+            ControlCommand::Seek(position_ms) => spirc.seek(position_ms),
+            ControlCommand::SeekBy(_) | ControlCommand::SkipChapter(_) => {
+                let (track_id, position_ms, duration_ms) =
+                    match status.lock().unwrap().track_position() {
+                        Some(track_position) => track_position,
+                        None => {
+                            let _ = response.send(json_response(
+                                StatusCode::CONFLICT,
+                                json!({ "error": "Not playing." }),
+                            ));
+                            return;
+                        }
+                    };
+
+                if let ControlCommand::SeekBy(offset_ms) = self.command {
+                    let position_ms = i64::from(position_ms) + offset_ms;
+                    if position_ms >= i64::from(duration_ms) {
+                        spirc.next();
+                    } else {
+                        spirc.seek(position_ms.max(0) as u32);
+                    }
+                } else if let ControlCommand::SkipChapter(forward) = self.command {
+                    let (spirc, session) = (spirc.clone(), session.clone());
+                    tokio::spawn(async move {
+                        let result =
+                            skip_chapter(&session, &spirc, track_id, position_ms, forward).await;
+                        let _ = response.send(result);
+                    });
+                    return;
+                }
+            }
+            ControlCommand::SetShuffle(shuffle) => spirc.set_shuffle(shuffle),
+            ControlCommand::SetRepeat(repeat) => spirc.set_repeat(repeat),
+            ControlCommand::Queue => {
+                // the queue is only known once spirc got around to it
+                let queue = spirc.queue();
+                tokio::spawn(async move {
+                    let _ = response.send(match queue.await {
+                        Ok(Some(queue)) => json_response(StatusCode::OK, queue_json(&queue)),
+                        _ => json_response(
+                            StatusCode::CONFLICT,
+                            json!({ "error": "Not the active device." }),
+                        ),
+                    });
+                });
+                return;
+            }
+            ControlCommand::AddToQueue(track_id) => spirc.add_to_queue(track_id),
+            ControlCommand::RemoveFromQueue(_) | ControlCommand::MoveInQueue(..) => {
+                let result = match self.command {
+                    ControlCommand::RemoveFromQueue(index) => spirc.remove_from_queue(index),
+                    ControlCommand::MoveInQueue(from, to) => spirc.move_in_queue(from, to),
+                    _ => unreachable!(),
+                };
+                tokio::spawn(async move {
+                    let _ = response.send(match result.await {
+                        Ok(Ok(())) => json_response(StatusCode::OK, json!({ "ok": true })),
+                        Ok(Err(QueueError::NoTrack(index))) => {
+                            let error = format!("No track at position {} of the queue.", index);
+                            json_response(StatusCode::NOT_FOUND, json!({ "error": error }))
+                        }
+                        _ => json_response(
+                            StatusCode::CONFLICT,
+                            json!({ "error": "Not the active device." }),
+                        ),
+                    });
+                });
+                return;
+            }
+            ControlCommand::TakeOver => spirc.take_over(),
+            ControlCommand::Load {
+                uri,
+                shuffle,
+                offset,
+            } => {
+                let (spirc, session) = (spirc.clone(), session.clone());
+                tokio::spawn(async move {
+                    let result = load_context(&session, &spirc, uri, shuffle, offset).await;
+                    let _ = response.send(result);
+                });
+                return;
+            }
+            ControlCommand::PlaySearch {
+                kind,
+                query,
+                shuffle,
+            } => {
+                let web_api = match web_api {
+                    Some(web_api) => web_api.clone(),
+                    None => {
+                        let _ = response.send(json_response(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            json!({ "error": "Searching needs a client ID." }),
+                        ));
+                        return;
+                    }
+                };
+                let (spirc, session) = (spirc.clone(), session.clone());
+                tokio::spawn(async move {
+                    let result = match search_uri(&web_api, kind, &query).await {
+                        Ok(Some(uri)) => load_context(&session, &spirc, uri, shuffle, None).await,
+                        Ok(None) => json_response(
+                            StatusCode::NOT_FOUND,
+                            json!({ "error": format!("No {} found for \"{}\".", kind, query) }),
+                        ),
+                        Err(e) => {
+                            warn!("Failed to search for {} \"{}\": {}", kind, query, e);
+                            json_response(
+                                StatusCode::BAD_GATEWAY,
+                                json!({ "error": e.to_string() }),
+                            )
+                        }
+                    };
+                    let _ = response.send(result);
+                });
+                return;
+            }
+            // see `run_channel_mix`, `run_speed`, `run_profile` and `run_library`
+            ControlCommand::SetBalance(_)
+            | ControlCommand::SetSwapChannels(_)
+            | ControlCommand::SetSpeed(_)
+            | ControlCommand::SetProfile(_)
+            | ControlCommand::SetLiked(_)
+            | ControlCommand::SetFollowed(_)
+            | ControlCommand::Playlists
+            | ControlCommand::AddToPlaylist(_) => (),
+        }
+
+        let _ = response.send(json_response(StatusCode::OK, json!({ "ok": true })));
+    }
+
+    /// Runs the commands for the balance and channel swap, which don't need a Connect session,
+    /// and remembers the result for the device. Returns any other request.
+    pub fn run_channel_mix(
+        self,
+        channel_mix: &ChannelMix,
+        cache: Option<&Cache>,
+        device_name: &str,
+    ) -> Result<(), Self> {
+        match self.command {
+            ControlCommand::SetBalance(balance) => channel_mix.set_balance(balance),
+            ControlCommand::SetSwapChannels(swap) => channel_mix.set_swap_channels(swap),
+            _ => return Err(self),
+        }
+
+        if let Some(cache) = cache {
+            cache.save_channel_mix(
+                device_name,
+                channel_mix.balance(),
+                channel_mix.swap_channels(),
+            );
+        }
+
+        let _ = self.response.send(json_response(
+            StatusCode::OK,
+            json!({
+                "balance": channel_mix.balance(),
+                "swapChannels": channel_mix.swap_channels(),
+            }),
+        ));
+        Ok(())
+    }
+
+    /// Runs the command for the speed podcast episodes are played at, which doesn't need a
+    /// Connect session. Returns any other request.
+    pub fn run_speed(self, speed: &PlaybackSpeed) -> Result<(), Self> {
+        match self.command {
+            ControlCommand::SetSpeed(hundredths) => speed.set_speed(f64::from(hundredths) / 100.0),
+            _ => return Err(self),
+        }
+
+        let _ = self.response.send(json_response(
+            StatusCode::OK,
+            json!({ "speed": speed.speed() }),
+        ));
+        Ok(())
+    }
+
+    /// Runs the command which switches the output profile, which doesn't need a Connect
+    /// session. Returns any other request.
+    pub fn run_profile(
+        self,
+        profiles: &mut OutputProfiles,
+        player_config: &PlayerConfig,
+        connect_config: &mut ConnectConfig,
+        spirc: Option<&Spirc>,
+    ) -> Result<(), Self> {
+        let name = match self.command {
+            ControlCommand::SetProfile(ref name) => name.as_deref(),
+            _ => return Err(self),
+        };
+
+        let response = match profiles.switch(name, player_config, connect_config, spirc) {
+            Ok(()) => json_response(StatusCode::OK, json!({ "profile": profiles.active() })),
+            Err(e) => json_response(StatusCode::NOT_FOUND, json!({ "error": e })),
+        };
+        let _ = self.response.send(response);
+        Ok(())
+    }
+
+    /// Runs the commands which save `track_id`, the current track, to the library, follow its
+    /// artists or add it to a playlist, and list the playlists. Returns any other request.
+    pub fn run_library(
+        self,
+        web_api: Option<&WebApi>,
+        track_id: Option<SpotifyId>,
+    ) -> Result<(), Self> {
+        match self.command {
+            ControlCommand::SetLiked(_)
+            | ControlCommand::SetFollowed(_)
+            | ControlCommand::Playlists
+            | ControlCommand::AddToPlaylist(_) => (),
+            _ => return Err(self),
+        }
+
+        let web_api = match web_api {
+            Some(web_api) => web_api.clone(),
+            None => {
+                self.reject("Not connected, or without a client ID.");
+                return Ok(());
+            }
+        };
+
+        let track_id = track_id.filter(|track_id| track_id.audio_type == SpotifyAudioType::Track);
+        if track_id.is_none() && self.command != ControlCommand::Playlists {
+            let _ = self.response.send(json_response(
+                StatusCode::CONFLICT,
+                json!({ "error": "Not playing a track." }),
+            ));
+            return Ok(());
+        }
+
+        let (command, response) = (self.command, self.response);
+        tokio::spawn(async move {
+            let result = library_command(&web_api, command, track_id).await;
+            let _ = response.send(match result {
+                Ok(body) => json_response(StatusCode::OK, body),
+                Err(e) => {
+                    warn!("Failed to update the library: {}", e);
+                    json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() }))
+                }
+            });
+        });
+        Ok(())
+    }
+
+    // For commands that were handled without spirc.
+    pub fn accept(self) {
+        let _ = self
+            .response
+            .send(json_response(StatusCode::OK, json!({ "ok": true })));
+    }
+
+    pub fn reject(self, error: &str) {
+        let _ = self.response.send(json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": error }),
+        ));
+    }
+}
+
+// going back to the previous chapter within this long after the start of the current one,
+// otherwise to its start, like with tracks
+const CHAPTER_RESTART_MS: u32 = 3000;
+
+// A time like "90", "90s", "1:30" or "1:01:30" in ms
+fn parse_time_ms(time: &str) -> Option<u32> {
+    let time = time.strip_suffix('s').unwrap_or(time);
+    let mut parts = time.rsplit(':');
+    // a sign is only taken before the whole time, see `ControlCommand::Seek`
+    let mut seconds = parts
+        .next()
+        .filter(|seconds| !seconds.starts_with(&['-', '+'][..]))?
+        .parse::<f64>()
+        .ok()?;
+    for (unit, part) in [60.0, 3600.0].iter().zip(&mut parts) {
+        seconds += unit * f64::from(part.parse::<u32>().ok()?);
+    }
+
+    if parts.next().is_some() || !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    Some((seconds * 1000.0).round() as u32)
+}
+
+// The start of each chapter of a podcast episode in ms, from the lines of its description
+// which start with a timestamp, eg. "12:34 Topic" or "(1:02:03) Topic". Spotify gets the
+// chapters it shows from the description, too.
+fn episode_chapters(description: &str) -> Vec<u32> {
+    let mut chapters = description
+        .lines()
+        .filter_map(|line| {
+            let time = line
+                .split_whitespace()
+                .next()?
+                .trim_matches(|c: char| !c.is_ascii_digit());
+            if time.contains(':') {
+                parse_time_ms(time)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    chapters.sort_unstable();
+    chapters.dedup();
+    chapters
+}
+
+// Where the next chapter starts, or the current or previous one going back, of those starting at
+// `chapters`, sorted
+fn chapter_start(chapters: &[u32], position_ms: u32, forward: bool) -> Option<u32> {
+    let current = chapters
+        .iter()
+        .rposition(|start_ms| *start_ms <= position_ms);
+    match (forward, current) {
+        (true, _) => chapters
+            .iter()
+            .copied()
+            .find(|start_ms| *start_ms > position_ms),
+        (false, Some(index))
+            if index == 0 || position_ms - chapters[index] > CHAPTER_RESTART_MS =>
+        {
+            Some(chapters[index])
+        }
+        (false, Some(index)) => Some(chapters[index - 1]),
+        (false, None) => None,
+    }
+}
+
+// Seek to the next chapter of `episode_id`, or back to the start of the current or previous one
+async fn skip_chapter(
+    session: &Session,
+    spirc: &Spirc,
+    episode_id: SpotifyId,
+    position_ms: u32,
+    forward: bool,
+) -> Response<Body> {
+    if episode_id.audio_type != SpotifyAudioType::Podcast {
+        return json_response(
+            StatusCode::CONFLICT,
+            json!({ "error": "Not playing a podcast episode." }),
+        );
+    }
+
+    let chapters = match Episode::get(session, episode_id).await {
+        Ok(episode) => episode_chapters(&episode.description),
+        Err(error) => {
+            warn!(
+                "Failed to get the chapters of <{}>: {:?}",
+                episode_id.to_uri().unwrap_or_default(),
+                error
+            );
+            return json_response(
+                StatusCode::BAD_GATEWAY,
+                json!({ "error": "Failed to get the episode." }),
+            );
+        }
+    };
+
+    match chapter_start(&chapters, position_ms, forward) {
+        Some(start_ms) => {
+            spirc.seek(start_ms);
+            json_response(
+                StatusCode::OK,
+                json!({ "ok": true, "position": start_ms, "chapters": chapters.len() }),
+            )
+        }
+        None => json_response(
+            StatusCode::CONFLICT,
+            json!({ "error": "No chapter to skip to." }),
+        ),
+    }
+}
+
+// Play `uri` on this device from `offset`, or a random track when shuffling
+async fn load_context(
+    session: &Session,
+    spirc: &Spirc,
+    uri: String,
+    shuffle: Option<bool>,
+    offset: Option<LoadOffset>,
+) -> Response<Body> {
+    let id = match get_spotify_id(&uri) {
+        Some(id) => id,
+        None => return json_response(StatusCode::BAD_REQUEST, json!({ "error": "Invalid URI." })),
+    };
+
+    let tracks = match context_tracks(session, &uri, id).await {
+        Ok(tracks) if !tracks.is_empty() => tracks,
+        Ok(_) => {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                json!({ "error": "Nothing to play." }),
+            )
+        }
+        Err(error) => {
+            warn!("Failed to get tracks for {}: {:?}", uri, error);
+            return json_response(
+                StatusCode::BAD_GATEWAY,
+                json!({ "error": "Failed to get the tracks." }),
+            );
+        }
+    };
+
+    let index = match offset {
+        Some(LoadOffset::Index(index)) if index < tracks.len() => Some(index),
+        Some(LoadOffset::Index(_)) => None,
+        Some(LoadOffset::Track(track_id)) => tracks.iter().position(|id| *id == track_id),
+        None if shuffle == Some(true) => Some(rand::thread_rng().gen_range(0..tracks.len())),
+        None => Some(0),
+    };
+    let index = match index {
+        Some(index) => index,
+        None => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "The offset isn't part of the context." }),
+            )
+        }
+    };
+
+    let count = tracks.len();
+    spirc.load_context(uri, tracks, index as u32, shuffle, None);
+    json_response(
+        StatusCode::OK,
+        json!({ "ok": true, "tracks": count, "index": index }),
+    )
+}
+
+fn queue_json(queue: &PlayQueue) -> Value {
+    let track_json = |track: &QueueTrack| {
+        json!({
+            "uri": track.uri,
+            "queued": track.queued,
+        })
+    };
+
+    json!({
+        "context": queue.context_uri,
+        "current": queue.current_track.as_ref().map(&track_json),
+        "next": queue.next_tracks.iter().map(&track_json).collect::<Vec<_>>(),
+    })
+}
+
+pub(crate) fn json_response(code: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// A random token for the control endpoint, saved to the cache for LMS to read.
+pub fn new_control_token(cache: Option<&Cache>) -> String {
+    use rand::RngCore;
+
+    let mut secret = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let token = hex::encode(secret);
+
+    match cache.map(|cache| cache.save_control_token(&token)) {
+        Some(Ok(location)) => info!("Saved the control token to {:?}", location),
+        Some(Err(e)) => warn!("Cannot save the control token: {}", e),
+        None => warn!("Without a cache the control token can't be saved"),
+    }
+
+    token
+}
+
+// Why a request to the control endpoint is refused: browsers send an Origin with requests from
+// other sites, and can't send a JSON body to another site without asking it first, which this
+// endpoint never allows.
+fn control_request_error(
+    request: &Request<Body>,
+    token: &str,
+) -> Option<(StatusCode, &'static str)> {
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    if request.method() != Method::POST {
+        return Some((StatusCode::METHOD_NOT_ALLOWED, "Commands must be POSTed."));
+    }
+
+    if let Some(origin) = header("origin") {
+        if Some(origin.trim_start_matches("http://")) != header("host") {
+            return Some((
+                StatusCode::FORBIDDEN,
+                "Cross-site requests are not allowed.",
+            ));
+        }
+    }
+
+    let given_token = header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+    if !given_token.map_or(false, |given| {
+        tokens_match(given.as_bytes(), token.as_bytes())
+    }) {
+        return Some((StatusCode::UNAUTHORIZED, "Missing or invalid token."));
+    }
+
+    let content_type = header("content-type").and_then(|value| value.split(';').next());
+    if content_type.map(str::trim) != Some("application/json") {
+        return Some((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Commands must be sent as application/json.",
+        ));
+    }
+
+    None
+}
+
+// Takes as long wherever the tokens differ, so the token can't be guessed byte by byte from
+// how fast requests are rejected.
+fn tokens_match(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (given, token)| diff | (given ^ token))
+            == 0
+}
+
+async fn control_response(
+    request: Request<Body>,
+    token: &str,
+    requests: &mpsc::UnboundedSender<ControlRequest>,
+) -> Response<Body> {
+    if let Some((code, error)) = control_request_error(&request, token) {
+        return json_response(code, json!({ "error": error }));
+    }
+
+    let intent = request.uri().path() == "/intent";
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+
+    let command = serde_json::from_slice::<Value>(&body)
+        .map_err(|_| "Invalid JSON".to_string())
+        .and_then(|body| {
+            if intent {
+                ControlCommand::from_intent(&body)
+            } else {
+                match body["command"].as_str() {
+                    Some(command) => command.parse::<ControlCommand>(),
+                    None => Err("Expected {\"command\": \"...\"}".to_string()),
+                }
+            }
+        });
+    let command = match command {
+        Ok(command) => command,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e })),
+    };
+
+    debug!("Control command: {:?}", command);
+
+    let (response_tx, response_rx) = oneshot::channel();
+    let request = ControlRequest {
+        command,
+        response: response_tx,
+    };
+
+    if requests.send(request).is_err() {
+        return json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": "Shutting down." }),
+        );
+    }
+
+    response_rx.await.unwrap_or_else(|_| {
+        json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": "Shutting down." }),
+        )
+    })
+}
+
+// Accept one command per request, like {"command": "next"} or {"command": "shuffle on"}, POSTed
+// as JSON, or an intent of a voice assistant POSTed to /intent. Requests need the token as
+// `Authorization: Bearer <token>`.
+pub fn serve_control(
+    address: SocketAddr,
+    token: String,
+    requests: mpsc::UnboundedSender<ControlRequest>,
+) -> Result<(), hyper::Error> {
+    let token = Arc::new(token);
+
+    let make_service = make_service_fn(move |_| {
+        let (token, requests) = (token.clone(), requests.clone());
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                let (token, requests) = (token.clone(), requests.clone());
+                async move {
+                    let response = control_response(request, &token, &requests).await;
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&address)?.serve(make_service);
+
+    info!("Control endpoint listening on {}", server.local_addr());
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("Control endpoint failed: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_control_request_error() {
+        let request = |method: Method, headers: &[(&str, &str)]| {
+            let mut builder = Request::builder()
+                .method(method)
+                .header("host", "localhost:24880");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let code = |request: Request<Body>| control_request_error(&request, "s3cr3t").map(|e| e.0);
+
+        let valid = [
+            ("authorization", "Bearer s3cr3t"),
+            ("content-type", "application/json; charset=utf-8"),
+        ];
+        assert_eq!(code(request(Method::POST, &valid)), None);
+        assert_eq!(
+            code(request(Method::GET, &valid)),
+            Some(StatusCode::METHOD_NOT_ALLOWED)
+        );
+
+        let same_origin = [valid[0], valid[1], ("origin", "http://localhost:24880")];
+        assert_eq!(code(request(Method::POST, &same_origin)), None);
+        let foreign_origin = [valid[0], valid[1], ("origin", "https://example.com")];
+        assert_eq!(
+            code(request(Method::POST, &foreign_origin)),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        let wrong_token = [("authorization", "Bearer guess"), valid[1]];
+        assert_eq!(
+            code(request(Method::POST, &wrong_token)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        let same_length = [("authorization", "Bearer s3cr3T"), valid[1]];
+        assert_eq!(
+            code(request(Method::POST, &same_length)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            code(request(Method::POST, &valid[1..])),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+
+        // a form or text/plain can be POSTed by any web page without asking first
+        let text = [valid[0], ("content-type", "text/plain")];
+        assert_eq!(
+            code(request(Method::POST, &text)),
+            Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_control_command_from_intent() {
+        let intent = |intent: Value| ControlCommand::from_intent(&intent);
+
+        assert_eq!(
+            intent(json!({
+                "play": { "album": "Kind of Blue", "artist": "Miles Davis", "shuffle": true }
+            })),
+            Ok(ControlCommand::PlaySearch {
+                kind: "album",
+                query: "Kind of Blue artist:Miles Davis".to_string(),
+                shuffle: Some(true),
+            })
+        );
+        assert_eq!(
+            intent(json!({ "play": { "artist": "Miles Davis" } })),
+            Ok(ControlCommand::PlaySearch {
+                kind: "artist",
+                query: "Miles Davis".to_string(),
+                shuffle: None,
+            })
+        );
+        assert_eq!(
+            intent(json!({ "play": "So What" })),
+            Ok(ControlCommand::PlaySearch {
+                kind: "track",
+                query: "So What".to_string(),
+                shuffle: None,
+            })
+        );
+
+        assert_eq!(intent(json!({ "next": null })), Ok(ControlCommand::Next));
+        assert_eq!(intent(json!({ "play": {} })), Ok(ControlCommand::Play));
+        assert_eq!(intent(json!({ "pause": {} })), Ok(ControlCommand::Pause));
+        assert_eq!(
+            intent(json!({ "shuffle": true })),
+            Ok(ControlCommand::SetShuffle(true))
+        );
+        assert_eq!(
+            intent(json!({ "seek": "+30s" })),
+            Ok(ControlCommand::SeekBy(30_000))
+        );
+
+        assert!(intent(json!({ "play": { "artist": "" } })).is_err());
+        assert!(intent(json!({ "play": { "genre": "jazz" } })).is_err());
+        assert!(intent(json!({ "play": { "shuffle": true } })).is_err());
+        assert!(intent(json!({ "next": null, "pause": null })).is_err());
+        assert!(intent(json!(["next"])).is_err());
+        assert!(intent(json!({ "explode": null })).is_err());
+    }
+
+    #[test]
+    fn test_parse_time_ms() {
+        assert_eq!(parse_time_ms("90"), Some(90_000));
+        assert_eq!(parse_time_ms("90s"), Some(90_000));
+        assert_eq!(parse_time_ms("1.5"), Some(1_500));
+        assert_eq!(parse_time_ms("1:30"), Some(90_000));
+        assert_eq!(parse_time_ms("1:01:30"), Some(3_690_000));
+        assert_eq!(parse_time_ms("0:05.25"), Some(5_250));
+
+        assert_eq!(parse_time_ms(""), None);
+        assert_eq!(parse_time_ms("-5"), None);
+        assert_eq!(parse_time_ms("1:-30"), None);
+        assert_eq!(parse_time_ms("+5"), None);
+        assert_eq!(parse_time_ms("1.5:30"), None);
+        assert_eq!(parse_time_ms("1:00:00:00"), None);
+        assert_eq!(parse_time_ms("inf"), None);
+        assert_eq!(parse_time_ms("soon"), None);
+    }
+
+    #[test]
+    fn test_control_command_from_str() {
+        let command = |command: &str| command.parse::<ControlCommand>();
+        let track = SpotifyId::from_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC").unwrap();
+
+        assert_eq!(command("PLAY"), Ok(ControlCommand::Play));
+        assert_eq!(command("  next "), Ok(ControlCommand::Next));
+        assert_eq!(command("seek 1:30"), Ok(ControlCommand::Seek(90_000)));
+        assert_eq!(command("seek +30s"), Ok(ControlCommand::SeekBy(30_000)));
+        assert_eq!(command("seek -1:00"), Ok(ControlCommand::SeekBy(-60_000)));
+        assert_eq!(
+            command("chapter next"),
+            Ok(ControlCommand::SkipChapter(true))
+        );
+        assert_eq!(
+            command("chapter prev"),
+            Ok(ControlCommand::SkipChapter(false))
+        );
+        assert_eq!(command("shuffle on"), Ok(ControlCommand::SetShuffle(true)));
+        assert_eq!(command("repeat 0"), Ok(ControlCommand::SetRepeat(false)));
+
+        assert_eq!(command("queue"), Ok(ControlCommand::Queue));
+        assert_eq!(
+            command("queue add spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+            Ok(ControlCommand::AddToQueue(track))
+        );
+        assert_eq!(
+            command("queue remove 2"),
+            Ok(ControlCommand::RemoveFromQueue(2))
+        );
+        assert_eq!(
+            command("queue move 3 1"),
+            Ok(ControlCommand::MoveInQueue(3, 1))
+        );
+
+        assert_eq!(
+            command("load spotify:album:1weenld61qoidwYuZ1GESA"),
+            Ok(ControlCommand::Load {
+                uri: "spotify:album:1weenld61qoidwYuZ1GESA".to_string(),
+                shuffle: None,
+                offset: None,
+            })
+        );
+        assert_eq!(
+            command("load spotify:album:1weenld61qoidwYuZ1GESA shuffle on offset 3"),
+            Ok(ControlCommand::Load {
+                uri: "spotify:album:1weenld61qoidwYuZ1GESA".to_string(),
+                shuffle: Some(true),
+                offset: Some(LoadOffset::Index(3)),
+            })
+        );
+        assert_eq!(
+            command("load spotify:album:1weenld61qoidwYuZ1GESA offset spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+            Ok(ControlCommand::Load {
+                uri: "spotify:album:1weenld61qoidwYuZ1GESA".to_string(),
+                shuffle: None,
+                offset: Some(LoadOffset::Track(track)),
+            })
+        );
+
+        for invalid in &[
+            "",
+            "explode",
+            "play now",
+            "seek",
+            "seek soon",
+            "seek +-5",
+            "chapter 2",
+            "shuffle maybe",
+            "queue add spotify:nothing",
+            "queue remove -1",
+            "queue move 1",
+            "load",
+            "load nothing",
+            "load spotify:album:1weenld61qoidwYuZ1GESA shuffle",
+            "load spotify:album:1weenld61qoidwYuZ1GESA offset first",
+            "load spotify:album:1weenld61qoidwYuZ1GESA repeat on",
+        ] {
+            assert!(command(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_episode_chapters() {
+        let description = "An episode about things.\n\
+                           00:00 Intro\n\
+                           (12:34) Things\n\
+                           1:02:03 - More things\n\
+                           12:34 Things, again\n\
+                           2 things to say";
+        assert_eq!(episode_chapters(description), [0, 754_000, 3_723_000]);
+        assert!(episode_chapters("No chapters").is_empty());
+    }
+
+    #[test]
+    fn test_chapter_start() {
+        let chapters = [0, 60_000, 120_000];
+
+        assert_eq!(chapter_start(&chapters, 30_000, true), Some(60_000));
+        assert_eq!(chapter_start(&chapters, 60_000, true), Some(120_000));
+        assert_eq!(chapter_start(&chapters, 150_000, true), None);
+
+        // back to the start of the current chapter, or the previous one right after it started
+        assert_eq!(chapter_start(&chapters, 90_000, false), Some(60_000));
+        assert_eq!(chapter_start(&chapters, 61_000, false), Some(0));
+        assert_eq!(chapter_start(&chapters, 1_000, false), Some(0));
+        assert_eq!(chapter_start(&[60_000], 30_000, false), None);
+        assert_eq!(chapter_start(&[], 30_000, true), None);
+    }
+}
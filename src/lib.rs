@@ -1,4 +1,11 @@
 #![crate_name = "librespot"]
+// stdout carries the audio or a command's response, which only the binary writes
+#![deny(clippy::print_stdout)]
+// `json!` expands recursively, once per key of the capabilities in `spotty::capabilities`
+#![recursion_limit = "512"]
+
+#[macro_use]
+extern crate serde_json;
 
 pub use librespot_audio as audio;
 pub use librespot_connect as connect;
@@ -7,3 +14,13 @@ pub use librespot_discovery as discovery;
 pub use librespot_metadata as metadata;
 pub use librespot_playback as playback;
 pub use librespot_protocol as protocol;
+
+pub mod control;
+pub mod output_profiles;
+pub mod preview;
+pub mod runtime;
+pub mod self_update;
+pub mod setup;
+pub mod spotty;
+pub mod status;
+pub mod web_api;
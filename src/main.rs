@@ -1,388 +1,168 @@
 #[macro_use]
 extern crate serde_json;
 
-use futures_util::{future, FutureExt, StreamExt};
-use librespot_playback::player::PlayerEvent;
-use log::{error, info, trace, warn};
-use sha1::{Digest, Sha1};
-use tokio::sync::mpsc::UnboundedReceiver;
-use url::Url;
+use log::{error, warn};
+use serde_json::Value;
 
-use librespot::connect::spirc::Spirc;
-use librespot::core::authentication::Credentials;
-use librespot::core::cache::Cache;
-use librespot::core::config::{ConnectConfig, DeviceType, SessionConfig};
-use librespot::core::session::Session;
-use librespot::core::version;
-use librespot::playback::audio_backend::{self, SinkBuilder};
-use librespot::playback::config::{
-    AudioFormat, Bitrate, NormalisationMethod, NormalisationType, PlayerConfig, VolumeCtrl,
-};
-use librespot::playback::mixer::softmixer::SoftMixer;
-use librespot::playback::mixer::{self, MixerConfig, MixerFn};
-use librespot::playback::player::Player;
-
-mod spotty;
-use spotty::LMS;
+use librespot::preview;
+use librespot::runtime::Runtime;
+use librespot::self_update::self_update;
+use librespot::setup::{self, Args, Command, Setup};
+use librespot::spotty::{self, ExitCode};
 
 use std::env;
-use std::ops::RangeInclusive;
+use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
-use std::pin::Pin;
 use std::process::exit;
-use std::str::FromStr;
-use std::time::Duration;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
 
-const VERSION: &'static str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
+// Write the reason as a JSON object to stderr before exiting with an error
+fn enable_json_errors() {
+    JSON_ERRORS.store(true, Ordering::Relaxed);
+}
 
-#[cfg(target_os = "windows")]
-const NULLDEVICE: &'static str = "NUL";
-#[cfg(not(target_os = "windows"))]
-const NULLDEVICE: &'static str = "/dev/null";
+// Exit with `code`, after the error was logged
+fn exit_with(code: ExitCode, error: &str) -> ! {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let json = json!({
+            "error": error,
+            "code": code.name(),
+            "exitCode": code as i32,
+        });
+        eprintln!("{}", json);
+    }
 
-fn device_id(name: &str) -> String {
-    hex::encode(Sha1::digest(name.as_bytes()))
+    exit(code as i32);
 }
 
-fn usage(program: &str, opts: &getopts::Options) -> String {
-    let repo_home = env!("CARGO_PKG_REPOSITORY");
-    let desc = env!("CARGO_PKG_DESCRIPTION");
-    let version = get_version_string();
-    let brief = format!(
-        "{}\n\n{}\n\n{}\n\nUsage: {} [<Options>]",
-        version, desc, repo_home, program
-    );
-    opts.usage(&brief)
+fn fatal(code: ExitCode, error: &str) -> ! {
+    error!("{}", error);
+    exit_with(code, error);
+}
+
+fn write_response(json_token: Value, save_token: Option<String>) {
+    if let Some(save_token) = save_token {
+        fs::write(save_token, json_token.to_string()).expect("Can't write token file");
+    } else {
+        println!("{}", json_token);
+    }
 }
 
-#[cfg(debug_assertions)]
-fn setup_logging(quiet: bool, verbose: bool) {
-    let mut builder = env_logger::Builder::new();
-    match env::var("RUST_LOG") {
-        Ok(config) => {
-            builder.parse_filters(&config);
-            builder.init();
+// Answer a command like --cache-stats with the error, and exit with its code
+fn exit_with_response(error: spotty::Error) -> ! {
+    write_response(json!({ "error": error.message }), None);
+    exit_with(error.code, &error.message);
+}
 
-            if verbose {
-                warn!("`--verbose` flag overidden by `RUST_LOG` environment variable");
-            } else if quiet {
-                warn!("`--quiet` flag overidden by `RUST_LOG` environment variable");
-            }
+// Answer a command like --cache-stats with its result, or the error
+fn respond(result: Result<Value, spotty::Error>) -> ! {
+    match result {
+        Ok(response) => {
+            write_response(response, None);
+            exit(0);
         }
-        Err(_) => {
-            if verbose {
-                builder.parse_filters("libmdns=info,librespot=trace,spotty=trace");
-            } else if quiet {
-                builder.parse_filters("libmdns=warn,librespot=warn,spotty=warn");
-            } else {
-                builder.parse_filters("libmdns=info,librespot=info,spotty=info");
-            }
-            builder.init();
+        Err(error) => exit_with_response(error),
+    }
+}
 
-            if verbose && quiet {
-                warn!("`--verbose` and `--quiet` are mutually exclusive. Logging can not be both verbose and quiet. Using verbose mode.");
+// The PID in `pid_file`, if that process is still running
+#[cfg(unix)]
+fn running_pid(pid_file: &Path) -> Option<libc::pid_t> {
+    let pid = fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    // signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+// Detach from the terminal and continue in a child process, which writes its PID to `pid_file`.
+// Must be called before any threads are started.
+#[cfg(unix)]
+fn daemonize(pid_file: &Path) {
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(pid) = running_pid(pid_file) {
+        let error = format!("spotty is already running with PID {}", pid);
+        fatal(ExitCode::Error, &error);
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            let error = format!("Failed to fork: {}", io::Error::last_os_error());
+            fatal(ExitCode::Error, &error);
+        }
+        0 => (),
+        _ => exit(0),
+    }
+
+    unsafe { libc::setsid() };
+
+    // Nothing may be written to the terminal we've left, logs go to --log-file if any
+    match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+    {
+        Ok(null) => {
+            for fd in 0..=2 {
+                unsafe { libc::dup2(null.as_raw_fd(), fd) };
             }
         }
+        Err(e) => warn!("Failed to open /dev/null: {}", e),
+    }
+
+    if let Err(e) = fs::write(pid_file, format!("{}\n", std::process::id())) {
+        let error = format!("Failed to write PID file {}: {}", pid_file.display(), e);
+        fatal(ExitCode::Error, &error);
     }
 }
 
-fn get_version_string() -> String {
-    #[cfg(debug_assertions)]
-    const BUILD_PROFILE: &str = "debug";
-    #[cfg(not(debug_assertions))]
-    const BUILD_PROFILE: &str = "release";
+#[cfg(not(unix))]
+fn daemonize(_pid_file: &Path) {}
+
+// Ask the instance in `pid_file` to shut down, and wait for it to do so
+#[cfg(unix)]
+fn kill(pid_file: &Path) {
+    let pid = match running_pid(pid_file) {
+        Some(pid) => pid,
+        None => {
+            warn!("spotty is not running");
+            let _ = fs::remove_file(pid_file);
+            exit(0);
+        }
+    };
 
-    format!(
-        "{spottyvers} - using librespot {semver} {sha} (Built on {build_date}, Build ID: {build_id}, Profile: {build_profile})",
-        spottyvers = VERSION,
-        semver = version::SEMVER,
-        sha = version::SHA_SHORT,
-        build_date = version::BUILD_DATE,
-        build_id = version::BUILD_ID,
-        build_profile = BUILD_PROFILE
-    )
-}
+    unsafe { libc::kill(pid, libc::SIGTERM) };
 
-struct Setup {
-    format: AudioFormat,
-    backend: SinkBuilder,
-    mixer: MixerFn,
-    cache: Option<Cache>,
-    player_config: PlayerConfig,
-    session_config: SessionConfig,
-    connect_config: ConnectConfig,
-    mixer_config: MixerConfig,
-    credentials: Option<Credentials>,
-    enable_discovery: bool,
-    zeroconf_port: u16,
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if unsafe { libc::kill(pid, 0) } != 0 {
+            exit(0);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
 
-    // spotty
-    authenticate: bool,
-    single_track: Option<String>,
-    start_position: u32,
-    client_id: Option<String>,
-    scopes: Option<String>,
-    get_token: bool,
-    save_token: Option<String>,
-    lms: LMS,
+    let error = format!("spotty with PID {} did not stop", pid);
+    fatal(ExitCode::Error, &error);
 }
 
-fn get_setup() -> Setup {
-    const VALID_INITIAL_VOLUME_RANGE: RangeInclusive<u16> = 0..=100;
-    const AP_PORT: &str = "ap-port";
-    const AUTHENTICATE: &str = "authenticate";
-    const AUTOPLAY: &str = "autoplay";
-    const BITRATE: &str = "bitrate";
-    const CACHE: &str = "cache";
-    const CHECK: &str = "check";
-    const CLIENT_ID: &str = "client-id";
-    const DISABLE_AUDIO_CACHE: &str = "disable-audio-cache";
-    const DISABLE_DISCOVERY: &str = "disable-discovery";
-    const DISABLE_GAPLESS: &str = "disable-gapless";
-    const ENABLE_AUDIO_CACHE: &str = "enable-audio-cache";
-    const ENABLE_VOLUME_NORMALISATION: &str = "enable-volume-normalisation";
-    const GET_TOKEN: &str = "get-token";
-    const HELP: &str = "help";
-    const INITIAL_VOLUME: &str = "initial-volume";
-    const LMS_AUTH: &str = "lms-auth";
-    const LOGITECH_MEDIA_SERVER: &str = "lms";
-    const NAME: &str = "name";
-    const NORMALISATION_GAIN_TYPE: &str = "normalisation-gain-type";
-    const PASSTHROUGH: &str = "passthrough";
-    const PASS_THROUGH: &str = "pass-through";
-    const PASSWORD: &str = "password";
-    const PLAYER_MAC: &str = "player-mac";
-    const PROXY: &str = "proxy";
-    const SAVE_TOKEN: &str = "save-token";
-    const SCOPE: &str = "scope";
-    const SINGLE_TRACK: &str = "single-track";
-    const START_POSITION: &str = "start-position";
-    const QUIET: &str = "quiet";
-    const USERNAME: &str = "username";
-    const VERBOSE: &str = "verbose";
-    const VERSION: &str = "version";
-    const ZEROCONF_PORT: &str = "zeroconf-port";
+#[cfg(not(unix))]
+fn kill(_pid_file: &Path) {}
 
-    // Mostly arbitrary.
-    const AUTHENTICATE_SHORT: &str = "a";
-    const AUTOPLAY_SHORT: &str = "A";
-    const AP_PORT_SHORT: &str = "";
-    const BITRATE_SHORT: &str = "b";
-    const CACHE_SHORT: &str = "c";
-    const DISABLE_AUDIO_CACHE_SHORT: &str = "G";
-    const ENABLE_AUDIO_CACHE_SHORT: &str = "";
-    const DISABLE_GAPLESS_SHORT: &str = "g";
-    const HELP_SHORT: &str = "h";
-    const CLIENT_ID_SHORT: &str = "i";
-    const ENABLE_VOLUME_NORMALISATION_SHORT: &str = "N";
-    const NAME_SHORT: &str = "n";
-    const DISABLE_DISCOVERY_SHORT: &str = "O";
-    const PASSTHROUGH_SHORT: &str = "P";
-    const PASSWORD_SHORT: &str = "p";
-    const QUIET_SHORT: &str = "q";
-    const INITIAL_VOLUME_SHORT: &str = "R";
-    const GET_TOKEN_SHORT: &str = "t";
-    const SAVE_TOKEN_SHORT: &str = "T";
-    const USERNAME_SHORT: &str = "u";
-    const VERSION_SHORT: &str = "V";
-    const VERBOSE_SHORT: &str = "v";
-    const NORMALISATION_GAIN_TYPE_SHORT: &str = "W";
-    const CHECK_SHORT: &str = "x";
-    const PROXY_SHORT: &str = "";
-    const ZEROCONF_PORT_SHORT: &str = "z";
-
-    // Options that have different desc's
-    // depending on what backends were enabled at build time.
-    const INITIAL_VOLUME_DESC: &str = "Initial volume in % from 0 - 100. Defaults to 50.";
+fn main() {
+    const RUST_BACKTRACE: &str = "RUST_BACKTRACE";
 
-    let mut opts = getopts::Options::new();
-    opts.optflag(
-        HELP_SHORT,
-        HELP,
-        "Print this help menu.",
-    )
-    .optflag(
-        VERSION_SHORT,
-        VERSION,
-        "Display librespot version string.",
-    )
-    .optflag(
-        VERBOSE_SHORT,
-        VERBOSE,
-        "Enable verbose log output.",
-    )
-    .optflag(
-        QUIET_SHORT,
-        QUIET,
-        "Only log warning and error messages.",
-    )
-    .optflag(
-        DISABLE_AUDIO_CACHE_SHORT,
-        DISABLE_AUDIO_CACHE,
-        "(Only here fore compatibility with librespot - audio cache is disabled by default).",
-    )
-    .optflag(
-        ENABLE_AUDIO_CACHE_SHORT,
-        ENABLE_AUDIO_CACHE,
-        "Enable caching of the audio data."
-    )
-    .optflag(
-        DISABLE_DISCOVERY_SHORT,
-        DISABLE_DISCOVERY,
-        "Disable zeroconf discovery mode.",
-    )
-    .optflag(
-        DISABLE_GAPLESS_SHORT,
-        DISABLE_GAPLESS,
-        "Disable gapless playback.",
-    )
-    .optflag(
-        AUTOPLAY_SHORT,
-        AUTOPLAY,
-        "Automatically play similar songs when your music ends.",
-    )
-    .optflag(
-        PASSTHROUGH_SHORT,
-        PASSTHROUGH,
-        "Pass a raw stream to the output. Only works with the pipe and subprocess backends.",
-    )
-    .optflag(
-        ENABLE_VOLUME_NORMALISATION_SHORT,
-        ENABLE_VOLUME_NORMALISATION,
-        "Play all tracks at approximately the same apparent volume.",
-    )
-    .optopt(
-        NAME_SHORT,
-        NAME,
-        "Device name. Defaults to Spotty.",
-        "NAME",
-    )
-    .optopt(
-        BITRATE_SHORT,
-        BITRATE,
-        "Bitrate (kbps) {96|160|320}. Defaults to 160.",
-        "BITRATE",
-    )
-    .optopt(
-        CACHE_SHORT,
-        CACHE,
-        "Path to a directory where files will be cached.",
-        "PATH",
-    )
-    .optopt(
-        USERNAME_SHORT,
-        USERNAME,
-        "Username used to sign in with.",
-        "USERNAME",
-    )
-    .optopt(
-        PASSWORD_SHORT,
-        PASSWORD,
-        "Password used to sign in with.",
-        "PASSWORD",
-    )
-    .optopt(
-        INITIAL_VOLUME_SHORT,
-        INITIAL_VOLUME,
-        INITIAL_VOLUME_DESC,
-        "VOLUME",
-    )
-    .optopt(
-        NORMALISATION_GAIN_TYPE_SHORT,
-        NORMALISATION_GAIN_TYPE,
-        "Specify the normalisation gain type to use {track|album|auto}. Defaults to auto.",
-        "TYPE",
-    )
-    .optopt(
-        ZEROCONF_PORT_SHORT,
-        ZEROCONF_PORT,
-        "The port the internal server advertises over zeroconf 1 - 65535. Ports <= 1024 may require root privileges.",
-        "PORT",
-    )
-    .optopt(
-        PROXY_SHORT,
-        PROXY,
-        "HTTP proxy to use when connecting.",
-        "URL",
-    )
-    .optopt(
-        AP_PORT_SHORT,
-        AP_PORT,
-        "Connect to an AP with a specified port 1 - 65535. If no AP with that port is present a fallback AP will be used. Available ports are usually 80, 443 and 4070.",
-        "PORT",
-    )
-    // spotty
-    .optflag(
-        AUTHENTICATE_SHORT,
-        AUTHENTICATE,
-        "Authenticate given username and password. Make sure you define a cache folder to store credentials."
-    )
-    .optopt(
-        "",
-        SINGLE_TRACK,
-        "Play a single track ID and exit.",
-        "ID"
-    )
-    .optopt(
-        "",
-        START_POSITION,
-        "Position (in seconds) where playback should be started. Only valid with the --single-track option.",
-        "STARTPOSITION"
-    )
-    .optflag(
-        CHECK_SHORT,
-        CHECK,
-        "Run quick internal check"
-    )
-    .optopt(
-        CLIENT_ID_SHORT,
-        CLIENT_ID,
-        "A Spotify client_id to be used to get the oauth token. Required with the --get-token request.",
-        "CLIENT_ID"
-    )
-    .optopt(
-        "",
-        SCOPE,
-        "The scopes you want to have access to with the oauth token.",
-        "SCOPE"
-    )
-    .optflag(
-        GET_TOKEN_SHORT,
-        GET_TOKEN,
-        "Get oauth token to be used with the web API etc. and print it to the console."
-    )
-    .optopt(
-        SAVE_TOKEN_SHORT,
-        SAVE_TOKEN,
-        "Get oauth token to be used with the web API etc. and store it in the given file.",
-        "TOKENFILE"
-    )
-    .optflag(
-        "",
-        PASS_THROUGH,
-        "Pass raw stream to output, only works for \"pipe\"."
-    )
-    .optopt(
-        "",
-        LOGITECH_MEDIA_SERVER,
-        "hostname and port of Logitech Media Server instance (eg. localhost:9000)",
-        "LMS"
-    )
-    .optopt(
-        "",
-        LMS_AUTH,
-        "Authentication data to access Logitech Media Server",
-        "LMSAUTH"
-    )
-    .optopt(
-        "",
-        PLAYER_MAC,
-        "MAC address of the Squeezebox to be controlled",
-        "MAC"
-    );
+    if env::var(RUST_BACKTRACE).is_err() {
+        env::set_var(RUST_BACKTRACE, "full")
+    }
 
-    let args: Vec<_> = std::env::args_os()
+    let argv: Vec<_> = env::args_os()
         .filter_map(|s| match s.into_string() {
             Ok(valid) => Some(valid),
             Err(s) => {
@@ -395,685 +175,296 @@ fn get_setup() -> Setup {
         })
         .collect();
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            eprintln!("Error parsing command line options: {}", e);
-            println!("\n{}", usage(&args[0], &opts));
-            exit(1);
+    let program = argv.first().cloned().unwrap_or_default();
+    let json_errors = argv.iter().any(|arg| arg == "--json-errors");
+    let args = Args::parse(argv).unwrap_or_else(|e| {
+        eprintln!("Error parsing command line options: {}", e);
+        println!("\n{}", setup::usage(&program));
+        if json_errors {
+            enable_json_errors();
         }
-    };
-
-    let stripped_env_key = |k: &str| {
-        k.trim_start_matches("LIBRESPOT_")
-            .replace('_', "-")
-            .to_lowercase()
-    };
-
-    let env_vars: Vec<_> = env::vars_os().filter_map(|(k, v)| match k.into_string() {
-        Ok(key) if key.starts_with("LIBRESPOT_") => {
-            let stripped_key = stripped_env_key(&key);
-            // We only care about long option/flag names.
-            if stripped_key.chars().count() > 1 && matches.opt_defined(&stripped_key) {
-                match v.into_string() {
-                    Ok(value) => Some((key, value)),
-                    Err(s) => {
-                        eprintln!("Environment variable was not valid Unicode and will not be evaluated: {}={:?}", key, s);
-                        None
-                    }
-                }
-            } else {
-                None
-            }
-        },
-        _ => None
-    })
-    .collect();
+        exit_with(e.code, &e.message);
+    });
 
-    let opt_present =
-        |opt| matches.opt_present(opt) || env_vars.iter().any(|(k, _)| stripped_env_key(k) == opt);
-
-    let opt_str = |opt| {
-        if matches.opt_present(opt) {
-            matches.opt_str(opt)
-        } else {
-            env_vars
-                .iter()
-                .find(|(k, _)| stripped_env_key(k) == opt)
-                .map(|(_, v)| v.to_string())
-        }
-    };
+    if args.json_errors() {
+        enable_json_errors();
+    }
 
-    if opt_present(HELP) {
-        println!("{}", usage(&args[0], &opts));
+    if args.help() {
+        println!("{}", setup::usage(args.program()));
         exit(0);
     }
 
-    if opt_present(VERSION) {
-        println!("{}", get_version_string());
+    if args.version() {
+        println!("{}", setup::version_string());
         exit(0);
     }
 
-    if opt_present(CHECK) {
-        spotty::check(get_version_string());
+    // the first line for older plugins, followed by JSON
+    if args.check() {
+        println!("ok {}", setup::version_string());
+        println!("{}", spotty::capabilities(setup::option_names()));
+        exit(0);
     }
 
-    #[cfg(debug_assertions)]
-    setup_logging(opt_present(QUIET), opt_present(VERBOSE));
-
-    info!("{}", get_version_string());
+    // the error is logged already
+    let setup = setup::get_setup(&args).unwrap_or_else(|e| exit_with(e.code, &e.message));
 
-    if !env_vars.is_empty() {
-        trace!("Environment variable(s):");
-
-        for (k, v) in &env_vars {
-            if matches!(k.as_str(), "LIBRESPOT_PASSWORD" | "LIBRESPOT_USERNAME") {
-                trace!("\t\t{}=\"XXXXXXXX\"", k);
-            } else if v.is_empty() {
-                trace!("\t\t{}=", k);
-            } else {
-                trace!("\t\t{}=\"{}\"", k, v);
-            }
+    if let Some(ref pid_file) = setup.pid_file {
+        if setup.kill {
+            kill(pid_file);
         }
-    }
-
-    let args_len = args.len();
 
-    if args_len > 1 {
-        trace!("Command line argument(s):");
-
-        for (index, key) in args.iter().enumerate() {
-            let opt = {
-                let key = key.trim_start_matches('-');
-
-                if let Some((s, _)) = key.split_once('=') {
-                    s
-                } else {
-                    key
-                }
-            };
-
-            if index > 0
-                && key.starts_with('-')
-                && &args[index - 1] != key
-                && matches.opt_defined(opt)
-                && matches.opt_present(opt)
-            {
-                if matches!(opt, PASSWORD | PASSWORD_SHORT | USERNAME | USERNAME_SHORT) {
-                    // Don't log creds.
-                    trace!("\t\t{} \"XXXXXXXX\"", opt);
-                } else {
-                    let value = matches.opt_str(opt).unwrap_or_else(|| "".to_string());
-                    if value.is_empty() {
-                        trace!("\t\t{}", opt);
-                    } else {
-                        trace!("\t\t{} \"{}\"", opt, value);
-                    }
-                }
-            }
+        // fork before the runtime starts any threads
+        if setup.daemon {
+            daemonize(pid_file);
         }
     }
 
-    let invalid_error_msg =
-        |long: &str, short: &str, invalid: &str, valid_values: &str, default_value: &str| {
-            error!("Invalid `--{}` / `-{}`: \"{}\"", long, short, invalid);
-
-            if !valid_values.is_empty() {
-                println!("Valid `--{}` / `-{}` values: {}", long, short, valid_values);
-            }
-
-            if !default_value.is_empty() {
-                println!("Default: {}", default_value);
-            }
-        };
-
-    let empty_string_error_msg = |long: &str, short: &str| {
-        error!("`--{}` / `-{}` can not be an empty string", long, short);
-        exit(1);
+    // the async tasks only, the player decodes and processes the samples on a thread of its own
+    let mut builder = if setup.runtime_threads > 1 {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(setup.runtime_threads);
+        builder
+    } else {
+        tokio::runtime::Builder::new_current_thread()
     };
 
-    let mixer = mixer::find(Some(SoftMixer::NAME).as_deref()).expect("Invalid mixer");
-    let mixer_type: Option<String> = None;
-
-    let mixer_config = {
-        let mixer_default_config = MixerConfig::default();
-
-        let device = mixer_default_config.device;
-
-        let index = mixer_default_config.index;
-
-        let control = mixer_default_config.control;
-
-        let volume_ctrl = VolumeCtrl::Linear;
+    builder
+        .enable_all()
+        .build()
+        .expect("Failed to create the runtime")
+        .block_on(run(setup));
+}
 
-        MixerConfig {
-            device,
-            control,
-            index,
-            volume_ctrl,
+async fn run(setup: Setup) {
+    let last_credentials = setup.credentials.clone();
+
+    match setup.command {
+        Command::Connect => (),
+        Command::CacheStats => respond(spotty::cache_stats(
+            setup.cache.as_ref(),
+            setup.cache_size_limit,
+        )),
+        Command::TokenInfo(token_file) => respond(spotty::token_info(&token_file)),
+        Command::ListAccounts => respond(spotty::list_accounts(setup.cache.as_ref())),
+        Command::ExportCredentials(path) => respond(spotty::export_credentials(
+            setup.cache.as_ref(),
+            setup.credentials.as_ref(),
+            &path,
+        )),
+        Command::ImportCredentials(path) => {
+            respond(spotty::import_credentials(setup.cache.as_ref(), &path))
         }
-    };
-
-    let cache = {
-        let volume_dir = opt_str(CACHE).map(|p| p.into());
-
-        let cred_dir = volume_dir.clone();
-
-        let audio_dir = if opt_present(DISABLE_AUDIO_CACHE) {
-            None
-        } else {
-            opt_str(CACHE)
-                .as_ref()
-                .map(|p| AsRef::<Path>::as_ref(p).join("files"))
-        };
-
-        let limit = None;
+        Command::DataUsage => respond(spotty::stats(
+            setup.cache.as_ref(),
+            setup.session_config.data_cap,
+        )),
+        Command::DryRun { check_config } => {
+            let report = spotty::dry_run(
+                &setup.lms,
+                setup.cache_dir.as_deref(),
+                || (setup.backend)(setup.device.clone(), setup.format),
+                setup.credentials.clone(),
+                check_config.then(|| setup.session_config.clone()),
+                setup.enable_discovery,
+            )
+            .await;
 
-        match Cache::new(cred_dir, volume_dir, audio_dir, limit) {
-            Ok(cache) => Some(cache),
-            Err(e) => {
-                warn!("Cannot create cache: {}", e);
-                None
+            println!("{}", report);
+            if report["ok"] != json!(true) {
+                exit_with(ExitCode::Error, "The setup is not valid.");
             }
+            exit(0);
         }
-    };
-
-    let credentials = {
-        let cached_creds = cache.as_ref().and_then(Cache::credentials);
-
-        if let Some(username) = opt_str(USERNAME) {
-            if username.is_empty() {
-                empty_string_error_msg(USERNAME, USERNAME_SHORT);
-            }
-            if let Some(password) = opt_str(PASSWORD) {
-                if password.is_empty() {
-                    empty_string_error_msg(PASSWORD, PASSWORD_SHORT);
-                }
-                Some(Credentials::with_password(username, password))
-            } else {
-                match cached_creds {
-                    Some(creds) if username == creds.username => Some(creds),
-                    _ => {
-                        let prompt = &format!("Password for {}: ", username);
-                        match rpassword::prompt_password(prompt) {
-                            Ok(password) => {
-                                if !password.is_empty() {
-                                    Some(Credentials::with_password(username, password))
-                                } else {
-                                    trace!("Password was empty.");
-                                    if cached_creds.is_some() {
-                                        trace!("Using cached credentials.");
-                                    }
-                                    cached_creds
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Cannot parse password: {}", e);
-                                if cached_creds.is_some() {
-                                    trace!("Using cached credentials.");
-                                }
-                                cached_creds
-                            }
-                        }
-                    }
-                }
-            }
-        } else {
-            if cached_creds.is_some() {
-                trace!("Using cached credentials.");
+        Command::SelfUpdate => respond(self_update(setup.session_config).await),
+        Command::Pair => {
+            let show_code = |code| write_response(code, None);
+            respond(spotty::pair(setup.cache, setup.session_config, show_code).await)
+        }
+        Command::LoginOAuth => {
+            let show_url = |url: &str| write_response(json!({ "loginUrl": url }), None);
+            respond(spotty::login_oauth(setup.cache, setup.session_config, show_url).await)
+        }
+        Command::Play(track_id) => {
+            let result = spotty::play_track(
+                track_id,
+                setup.start_position,
+                last_credentials,
+                setup.cache,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await;
+            if let Err(e) = result {
+                fatal(e.code, &e.message);
             }
-            cached_creds
+            exit(0);
         }
-    };
-
-    // don't enable discovery while fetching tracks or tokens
-    let enable_discovery = !opt_present(DISABLE_DISCOVERY)
-        && !opt_present(SINGLE_TRACK)
-        && !opt_present(SAVE_TOKEN)
-        && !opt_present(GET_TOKEN);
-
-    if credentials.is_none() && !enable_discovery {
-        error!("Credentials are required if discovery is disabled.");
-        exit(1);
-    }
-
-    if !enable_discovery && opt_present(ZEROCONF_PORT) {
-        warn!(
-            "With the `--{}` / `-{}` flag set `--{}` / `-{}` has no effect.",
-            DISABLE_DISCOVERY, DISABLE_DISCOVERY_SHORT, ZEROCONF_PORT, ZEROCONF_PORT_SHORT
-        );
-    }
-
-    let zeroconf_port = if enable_discovery {
-        opt_str(ZEROCONF_PORT)
-            .map(|port| match port.parse::<u16>() {
-                Ok(value) if value != 0 => value,
-                _ => {
-                    let valid_values = &format!("1 - {}", u16::MAX);
-                    invalid_error_msg(ZEROCONF_PORT, ZEROCONF_PORT_SHORT, &port, valid_values, "");
-
-                    exit(1);
-                }
-            })
-            .unwrap_or(0)
-    } else {
-        0
-    };
-
-    let connect_config = {
-        let connect_default_config = ConnectConfig::default();
-
-        let name = opt_str(NAME).unwrap_or_else(|| connect_default_config.name.clone());
-
-        if name.is_empty() {
-            empty_string_error_msg(NAME, NAME_SHORT);
-            exit(1);
+        Command::PlayerServer(port) => {
+            let result = spotty::serve_player(
+                port,
+                last_credentials,
+                setup.cache,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await;
+            if let Err(e) = result {
+                fatal(e.code, &e.message);
+            }
+            exit(0);
         }
-
-        let initial_volume = opt_str(INITIAL_VOLUME)
-            .map(|initial_volume| {
-                let volume = match initial_volume.parse::<u16>() {
-                    Ok(value) if (VALID_INITIAL_VOLUME_RANGE).contains(&value) => value,
-                    _ => {
-                        let valid_values = &format!(
-                            "{} - {}",
-                            VALID_INITIAL_VOLUME_RANGE.start(),
-                            VALID_INITIAL_VOLUME_RANGE.end()
-                        );
-
-                        let default_value = &connect_default_config
-                            .initial_volume
-                            .unwrap_or_default()
-                            .to_string();
-
-                        invalid_error_msg(
-                            INITIAL_VOLUME,
-                            INITIAL_VOLUME_SHORT,
-                            &initial_volume,
-                            valid_values,
-                            default_value,
-                        );
-
-                        exit(1);
-                    }
-                };
-
-                (volume as f32 / 100.0 * VolumeCtrl::MAX_VOLUME as f32) as u16
-            })
-            .or_else(|| match mixer_type.as_deref() {
-                _ => cache.as_ref().and_then(Cache::volume),
-            });
-
-        let device_type = DeviceType::default();
-        let has_volume_ctrl = !matches!(mixer_config.volume_ctrl, VolumeCtrl::Fixed);
-        let autoplay = opt_present(AUTOPLAY);
-
-        ConnectConfig {
-            name,
-            device_type,
-            initial_volume,
-            has_volume_ctrl,
-            autoplay,
+        Command::Preview(track_id) => {
+            let preview = preview::preview(
+                track_id,
+                last_credentials,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await;
+            let data = preview.unwrap_or_else(|e| fatal(e.code, &e.message));
+
+            let mut stdout = io::stdout();
+            if let Err(e) = stdout.write_all(&data).and_then(|_| stdout.flush()) {
+                fatal(
+                    ExitCode::Error,
+                    &format!("Unable to write the preview: {}", e),
+                );
+            }
+            exit(0);
         }
-    };
-
-    let session_config = SessionConfig {
-        user_agent: version::VERSION_STRING.to_string(),
-        device_id: device_id(&connect_config.name),
-        proxy: opt_str(PROXY).or_else(|| std::env::var("http_proxy").ok()).map(
-            |s| {
-                match Url::parse(&s) {
-                    Ok(url) => {
-                        if url.host().is_none() || url.port_or_known_default().is_none() {
-                            error!("Invalid proxy url, only URLs on the format \"http://host:port\" are allowed");
-                            exit(1);
-                        }
-
-                        if url.scheme() != "http" {
-                            error!("Only unsecure http:// proxies are supported");
-                            exit(1);
-                        }
-
-                        url
-                    },
-                    Err(e) => {
-                        error!("Invalid proxy URL: \"{}\", only URLs in the format \"http://host:port\" are allowed", e);
-                        exit(1);
-                    }
-                }
-            },
+        Command::Metadata(track_id) => respond(
+            spotty::get_metadata(
+                track_id,
+                last_credentials,
+                setup.cache,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await,
         ),
-        ap_port: opt_str(AP_PORT).map(|port| match port.parse::<u16>() {
-            Ok(value) if value != 0 => value,
-            _ => {
-                let valid_values = &format!("1 - {}", u16::MAX);
-                invalid_error_msg(AP_PORT, AP_PORT_SHORT, &port, valid_values, "");
-
-                exit(1);
-            }
-        }),
-    };
-
-    let player_config = {
-        let player_default_config = PlayerConfig::default();
-
-        let bitrate = opt_str(BITRATE)
-            .as_deref()
-            .map(|bitrate| {
-                Bitrate::from_str(bitrate).unwrap_or_else(|_| {
-                    invalid_error_msg(BITRATE, BITRATE_SHORT, bitrate, "96, 160, 320", "160");
-                    exit(1);
-                })
-            })
-            .unwrap_or(player_default_config.bitrate);
-
-        let gapless = !opt_present(DISABLE_GAPLESS);
-
-        let normalisation = opt_present(ENABLE_VOLUME_NORMALISATION);
-
-        let normalisation_type;
+        Command::Prefetch(uri) => respond(
+            spotty::prefetch(
+                uri,
+                last_credentials,
+                setup.cache,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await,
+        ),
+        Command::Download(uri, output_dir) => respond(
+            spotty::download(
+                uri,
+                output_dir,
+                last_credentials,
+                setup.cache,
+                setup.player_config,
+                setup.session_config,
+            )
+            .await,
+        ),
+        Command::Token => {
+            let token = spotty::get_token(
+                setup.client_ids,
+                setup.scopes,
+                last_credentials,
+                setup.session_config,
+            )
+            .await;
 
-        if !normalisation {
-            for a in &[NORMALISATION_GAIN_TYPE] {
-                if opt_present(a) {
-                    warn!(
-                        "Without the `--{}` / `-{}` flag normalisation options have no effect.",
-                        ENABLE_VOLUME_NORMALISATION, ENABLE_VOLUME_NORMALISATION_SHORT,
-                    );
-                    break;
+            // a token file gets the error too, so LMS doesn't keep reading an old token
+            match token {
+                Ok(token) => write_response(token, setup.save_token),
+                Err(e) => {
+                    write_response(json!({ "error": e.message }), setup.save_token);
+                    exit_with(e.code, &e.message);
                 }
             }
-
-            normalisation_type = player_default_config.normalisation_type;
-        } else {
-            normalisation_type = opt_str(NORMALISATION_GAIN_TYPE)
-                .as_deref()
-                .map(|gain_type| {
-                    NormalisationType::from_str(gain_type).unwrap_or_else(|_| {
-                        invalid_error_msg(
-                            NORMALISATION_GAIN_TYPE,
-                            NORMALISATION_GAIN_TYPE_SHORT,
-                            gain_type,
-                            "track, album, auto",
-                            &format!("{:?}", player_default_config.normalisation_type),
-                        );
-
-                        exit(1);
-                    })
-                })
-                .unwrap_or(player_default_config.normalisation_type);
+            exit(0);
         }
-
-        let ditherer = PlayerConfig::default().ditherer;
-        let passthrough = opt_present(PASSTHROUGH) || opt_present(PASS_THROUGH);
-
-        PlayerConfig {
-            bitrate,
-            gapless,
-            passthrough,
-            normalisation,
-            normalisation_type,
-            normalisation_method: NormalisationMethod::Basic,
-            normalisation_pregain_db: player_default_config.normalisation_pregain_db,
-            normalisation_threshold_dbfs: player_default_config.normalisation_threshold_dbfs,
-            normalisation_attack_cf: player_default_config.normalisation_attack_cf,
-            normalisation_release_cf: player_default_config.normalisation_release_cf,
-            normalisation_knee_db: player_default_config.normalisation_knee_db,
-            ditherer,
-            lms_connect_mode: !opt_present(SINGLE_TRACK),
-        }
-    };
-
-    let authenticate = opt_present(AUTHENTICATE);
-    let start_position = opt_str(START_POSITION)
-        .unwrap_or("0".to_string())
-        .parse::<f32>()
-        .unwrap_or(0.0);
-
-    let save_token = opt_str(SAVE_TOKEN).unwrap_or("".to_string());
-    let client_id = opt_str(CLIENT_ID).unwrap_or(format!("{}", include_str!("client_id.txt")));
-
-    let lms = LMS::new(
-        opt_str(LOGITECH_MEDIA_SERVER),
-        opt_str(PLAYER_MAC),
-        opt_str(LMS_AUTH),
-    );
-
-    Setup {
-        format: AudioFormat::default(),
-        backend: audio_backend::find(None).unwrap(),
-        mixer,
-        cache,
-        player_config,
-        session_config,
-        connect_config,
-        mixer_config,
-        credentials,
-        enable_discovery,
-        zeroconf_port,
-        // spotty
-        authenticate,
-        single_track: opt_str(SINGLE_TRACK),
-        start_position: (start_position * 1000.0) as u32,
-        get_token: opt_present(GET_TOKEN) || save_token.as_str().len() != 0,
-        save_token: if save_token.as_str().len() == 0 {
-            None
-        } else {
-            Some(save_token)
-        },
-        client_id: if client_id.as_str().len() == 0 {
-            None
-        } else {
-            Some(client_id)
-        },
-        scopes: opt_str(SCOPE),
-        lms,
     }
-}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    const RUST_BACKTRACE: &str = "RUST_BACKTRACE";
-    const RECONNECT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(600);
-    const RECONNECT_RATE_LIMIT: usize = 5;
-
-    if env::var(RUST_BACKTRACE).is_err() {
-        env::set_var(RUST_BACKTRACE, "full")
-    }
-
-    let setup = get_setup();
-
-    let mut last_credentials = None;
-    let mut spirc: Option<Spirc> = None;
-    let mut spirc_task: Option<Pin<_>> = None;
-    let mut player_event_channel: Option<UnboundedReceiver<PlayerEvent>> = None;
-    let mut auto_connect_times: Vec<Instant> = vec![];
-    let mut discovery = None;
-    let mut connecting: Pin<Box<dyn future::FusedFuture<Output = _>>> = Box::pin(future::pending());
+    let mut builder = Runtime::builder(
+        setup.session_config.clone(),
+        setup.player_config,
+        setup.connect_config.clone(),
+    )
+    .backend(setup.backend, setup.device, setup.format)
+    .mixer(setup.mixer, setup.mixer_config)
+    .cache(setup.cache)
+    .credentials(setup.credentials)
+    .lms(setup.lms)
+    .reconnect(setup.reconnect)
+    .status_address(setup.status_address)
+    .control_address(setup.control_address)
+    .control_token(setup.control_token)
+    .buffer_debug(setup.buffer_debug)
+    .volume_debounce(setup.volume_debounce)
+    .idle_timeout(setup.idle_timeout)
+    .web_api(setup.client_ids.for_web_api().map(String::from))
+    .lyrics(setup.lyrics)
+    .canvas(setup.canvas)
+    .color_palette(setup.color_palette)
+    .snapcast_metadata(setup.snapcast_metadata)
+    .take_over(setup.take_over)
+    .resume_on_start(setup.resume_on_start)
+    .alarm(setup.alarm)
+    .output_profiles(setup.output_profiles)
+    .authenticate(setup.authenticate);
 
     if setup.enable_discovery {
         let device_id = setup.session_config.device_id.clone();
-        match librespot::discovery::Discovery::builder(device_id)
+        let mut discovery = librespot::discovery::Discovery::builder(device_id)
             .name(setup.connect_config.name.clone())
             .device_type(setup.connect_config.device_type)
             .port(setup.zeroconf_port)
-            .launch()
-        {
-            Ok(d) => discovery = Some(d),
-            Err(err) => warn!("Could not initialise discovery: {}.", err),
-        };
-    }
+            .mdns_backend(setup.mdns_backend);
 
-    if let Some(credentials) = setup.credentials {
-        last_credentials = Some(credentials.clone());
-        connecting = Box::pin(
-            Session::connect(
-                setup.session_config.clone(),
-                credentials,
-                setup.cache.clone(),
-                true,
-            )
-            .fuse(),
-        );
-    } else if discovery.is_none() {
-        error!(
-            "Discovery is unavailable and no credentials provided. Authentication is not possible."
-        );
-        exit(1);
-    }
-
-    if let Some(ref track_id) = setup.single_track {
-        spotty::play_track(
-            track_id.to_string(),
-            setup.start_position,
-            last_credentials,
-            setup.player_config,
-            setup.session_config,
-        )
-        .await;
-        exit(0);
-    } else if setup.get_token {
-        spotty::get_token(
-            setup.client_id,
-            setup.scopes,
-            setup.save_token,
-            last_credentials,
-            setup.session_config,
-        )
-        .await;
-        exit(0);
-    }
-
-    loop {
-        tokio::select! {
-            credentials = async {
-                match discovery.as_mut() {
-                    Some(d) => d.next().await,
-                    _ => None
-                }
-            }, if discovery.is_some() => {
-                match credentials {
-                    Some(credentials) => {
-                        last_credentials = Some(credentials.clone());
-                        auto_connect_times.clear();
-
-                        if let Some(spirc) = spirc.take() {
-                            spirc.shutdown();
-                        }
-                        if let Some(spirc_task) = spirc_task.take() {
-                            // Continue shutdown in its own task
-                            tokio::spawn(spirc_task);
-                        }
-
-                        connecting = Box::pin(Session::connect(
-                            setup.session_config.clone(),
-                            credentials,
-                            setup.cache.clone(),
-                            true,
-                        ).fuse());
-                    },
-                    None => {
-                        error!("Discovery stopped unexpectedly");
-                        exit(1);
-                    }
-                }
-            },
-            session = &mut connecting, if !connecting.is_terminated() => match session {
-                Ok((session,_)) => {
-                    // Spotty auth mode: exit after saving credentials
-                    if setup.authenticate {
-                        println!("authorized");
-                        break;
-                    }
+        if let Some(interface) = setup.zeroconf_interface {
+            discovery = discovery.interface(interface);
+        }
 
-                    let mixer_config = setup.mixer_config.clone();
-                    let mixer = (setup.mixer)(mixer_config);
-                    let player_config = setup.player_config.clone();
-                    let connect_config = setup.connect_config.clone();
+        if let Some(brand) = setup.device_brand {
+            discovery = discovery.brand(brand);
+        }
 
-                    let soft_volume = mixer.get_soft_volume();
-                    let format = setup.format;
-                    let backend = setup.backend;
-                    let device = Some(NULLDEVICE.to_string());
-                    let (player, event_channel) =
-                        Player::new(player_config, session.clone(), soft_volume, move || {
-                            (backend)(device, format)
-                        });
+        if let Some(model) = setup.device_model {
+            discovery = discovery.model(model);
+        }
 
-                    let (spirc_, spirc_task_) = Spirc::new(connect_config, session, player, mixer);
+        for record in setup.zeroconf_txt {
+            discovery = discovery.txt_record(record);
+        }
 
-                    spirc = Some(spirc_);
-                    spirc_task = Some(Box::pin(spirc_task_));
-                    player_event_channel = Some(event_channel);
-                },
-                Err(e) => {
-                    error!("Connection failed: {}", e);
-                    exit(1);
-                }
-            },
-            _ = async {
-                if let Some(task) = spirc_task.as_mut() {
-                    task.await;
-                }
-            }, if spirc_task.is_some() => {
-                spirc_task = None;
+        builder = builder.discovery(discovery);
+    }
 
-                warn!("Spirc shut down unexpectedly");
+    let runtime = builder.build();
+    let status = runtime.status();
 
-                let mut reconnect_exceeds_rate_limit = || {
-                    auto_connect_times.retain(|&t| t.elapsed() < RECONNECT_RATE_LIMIT_WINDOW);
-                    auto_connect_times.len() > RECONNECT_RATE_LIMIT
-                };
+    if let Err(e) = runtime.run().await {
+        fatal(e.code, &e.message);
+    }
 
-                match last_credentials.clone() {
-                    Some(credentials) if !reconnect_exceeds_rate_limit() => {
-                        auto_connect_times.push(Instant::now());
+    // auth mode stops once the credentials are cached
+    if setup.authenticate {
+        println!("authorized");
+    }
 
-                        connecting = Box::pin(Session::connect(
-                            setup.session_config.clone(),
-                            credentials,
-                            setup.cache.clone(),
-                            true
-                        ).fuse());
-                    },
-                    _ => {
-                        error!("Spirc shut down too often. Not reconnecting automatically.");
-                        exit(1);
-                    },
-                }
-            },
-            event = async {
-                match player_event_channel.as_mut() {
-                    Some(p) => p.recv().await,
-                    _ => None
-                }
-            }, if player_event_channel.is_some() => match event {
-                Some(event) => {
-                    setup.lms.signal_event(event).await;
-                },
-                None => {
-                    player_event_channel = None;
-                }
-            },
-            _ = tokio::signal::ctrl_c() => {
-                break;
-            },
-            else => break,
+    if let Some(stats_file) = setup.stats_file {
+        if let Err(e) = status.lock().unwrap().stats().write(&stats_file) {
+            error!(
+                "Unable to write playback statistics to {:?}: {}",
+                stats_file, e
+            );
         }
     }
 
-    info!("Gracefully shutting down");
-
-    // Shutdown spirc if necessary
-    if let Some(spirc) = spirc {
-        spirc.shutdown();
-
-        if let Some(mut spirc_task) = spirc_task {
-            tokio::select! {
-                _ = tokio::signal::ctrl_c() => (),
-                _ = spirc_task.as_mut() => (),
-                else => (),
-            }
+    if setup.daemon {
+        if let Some(pid_file) = setup.pid_file {
+            let _ = fs::remove_file(pid_file);
         }
     }
 }
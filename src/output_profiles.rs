@@ -0,0 +1,178 @@
+//! Named output profiles, switched by LMS through the control endpoint.
+
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::connect::spirc::Spirc;
+use crate::core::config::ConnectConfig;
+use crate::playback::config::{OutputProfile, PlayerConfig, VolumeCtrl};
+use crate::playback::equalizer::parse_eq_bands;
+
+/// Named presets of the volume cap, normalisation and equalizer, see `OutputProfile`.
+#[derive(Clone, Debug)]
+pub struct OutputProfiles {
+    profiles: BTreeMap<String, OutputProfile>,
+    active: Option<String>,
+    // `ConnectConfig::max_volume` as configured, while no profile caps it
+    max_volume: u16,
+}
+
+impl OutputProfiles {
+    /// No profiles, with `max_volume` the configured `ConnectConfig::max_volume`.
+    pub fn new(max_volume: u16) -> Self {
+        Self {
+            profiles: BTreeMap::new(),
+            active: None,
+            max_volume,
+        }
+    }
+
+    /// Parses a JSON object of the profiles by name, e.g.
+    /// `{"night": {"maxVolume": 40, "normalisation": true, "eq": "bass:-6"}}`, with the volume
+    /// cap in % and the equalizer bands like `--eq`.
+    pub fn parse(json: &str, max_volume: u16) -> Result<Self, String> {
+        let value = serde_json::from_str::<Value>(json).map_err(|e| e.to_string())?;
+        let object = value
+            .as_object()
+            .ok_or_else(|| "Expected an object of the profiles by name".to_string())?;
+
+        let mut profiles = BTreeMap::new();
+        for (name, settings) in object {
+            if name == "none" {
+                return Err("\"none\" can't be used as the name of a profile".to_string());
+            }
+            let settings = settings
+                .as_object()
+                .ok_or_else(|| format!("Expected an object for the profile \"{}\"", name))?;
+
+            let mut profile = OutputProfile::default();
+            for (key, value) in settings {
+                match key.as_str() {
+                    "maxVolume" => {
+                        let percent = value
+                            .as_u64()
+                            .filter(|percent| *percent <= 100)
+                            .ok_or_else(|| {
+                                format!("Invalid maxVolume in \"{}\", expected 0 - 100", name)
+                            })?;
+                        profile.max_volume =
+                            Some((percent as f32 / 100.0 * VolumeCtrl::MAX_VOLUME as f32) as u16);
+                    }
+                    "normalisation" => {
+                        profile.normalisation = Some(value.as_bool().ok_or_else(|| {
+                            format!("Invalid normalisation in \"{}\", expected a boolean", name)
+                        })?);
+                    }
+                    "eq" => {
+                        let bands = value.as_str().ok_or_else(|| {
+                            format!("Invalid eq in \"{}\", expected bands like --eq", name)
+                        })?;
+                        profile.equalizer = Some(parse_eq_bands(bands).map_err(|band| {
+                            format!("Invalid equalizer band \"{}\" in \"{}\"", band, name)
+                        })?);
+                    }
+                    _ => return Err(format!("Unknown setting \"{}\" in \"{}\"", key, name)),
+                }
+            }
+            profiles.insert(name.to_string(), profile);
+        }
+
+        Ok(Self {
+            profiles,
+            ..Self::new(max_volume)
+        })
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Applies the profile `name`, or the configured settings for `None`, to the players through
+    /// `player_config` and to Spirc. `connect_config` keeps the volume cap for later sessions.
+    pub fn switch(
+        &mut self,
+        name: Option<&str>,
+        player_config: &PlayerConfig,
+        connect_config: &mut ConnectConfig,
+        spirc: Option<&Spirc>,
+    ) -> Result<(), String> {
+        let profile = match name {
+            Some(name) => Some(
+                self.profiles
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown profile \"{}\"", name))?
+                    .clone(),
+            ),
+            None => None,
+        };
+
+        connect_config.max_volume = profile
+            .as_ref()
+            .and_then(|profile| profile.max_volume)
+            .unwrap_or(self.max_volume);
+        if let Some(spirc) = spirc {
+            spirc.set_max_volume(connect_config.max_volume);
+        }
+        player_config.profile.set(profile);
+
+        info!("Output profile: {}", name.unwrap_or("none"));
+        self.active = name.map(String::from);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::ControlCommand;
+
+    #[test]
+    fn test_output_profiles() {
+        let json = r#"{
+            "night": { "maxVolume": 40, "normalisation": true, "eq": "bass:-6" },
+            "party": { "normalisation": false }
+        }"#;
+        let mut profiles = OutputProfiles::parse(json, 60000).unwrap();
+        let player_config = PlayerConfig::default();
+        let mut connect_config = ConnectConfig::default();
+
+        profiles
+            .switch(Some("night"), &player_config, &mut connect_config, None)
+            .unwrap();
+        assert_eq!(profiles.active(), Some("night"));
+        assert_eq!(connect_config.max_volume, 26214);
+        let night = player_config.profile.get().unwrap();
+        assert_eq!(night.normalisation, Some(true));
+        assert_eq!(night.equalizer.map(|bands| bands.len()), Some(1));
+
+        // the volume cap of the command line applies again
+        profiles
+            .switch(Some("party"), &player_config, &mut connect_config, None)
+            .unwrap();
+        assert_eq!(connect_config.max_volume, 60000);
+        assert!(profiles
+            .switch(Some("quiet"), &player_config, &mut connect_config, None)
+            .is_err());
+        assert_eq!(profiles.active(), Some("party"));
+
+        profiles
+            .switch(None, &player_config, &mut connect_config, None)
+            .unwrap();
+        assert_eq!(profiles.active(), None);
+        assert_eq!(player_config.profile.get(), None);
+
+        assert!(OutputProfiles::parse(r#"{"night": {"maxVolume": 101}}"#, 0).is_err());
+        assert!(OutputProfiles::parse(r#"{"night": {"eq": "bass"}}"#, 0).is_err());
+        assert!(OutputProfiles::parse(r#"{"night": {"crossfade": 5}}"#, 0).is_err());
+        assert!(OutputProfiles::parse(r#"{"none": {}}"#, 0).is_err());
+
+        assert_eq!(
+            "profile night".parse(),
+            Ok(ControlCommand::SetProfile(Some("night".to_string())))
+        );
+        assert_eq!("profile none".parse(), Ok(ControlCommand::SetProfile(None)));
+    }
+}
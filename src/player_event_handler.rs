@@ -118,10 +118,121 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
                 env_vars.insert("TRACK_ID", id);
             }
         },
+        PlayerEvent::FormatChanged {
+            track_id, format, ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::FormatChanged: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "format_changed".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("CODEC", format.codec().to_string());
+                env_vars.insert("BITRATE", format.bitrate().to_string());
+                env_vars.insert("SAMPLE_RATE", format.sample_rate().to_string());
+                env_vars.insert("CHANNELS", format.channels().to_string());
+                env_vars.insert("FORMAT", format.to_string());
+            }
+        },
+        PlayerEvent::Normalisation { track_id, data, .. } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::Normalisation: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "normalisation".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("TRACK_GAIN_DB", data.track_gain_db.to_string());
+                env_vars.insert("TRACK_PEAK", data.track_peak.to_string());
+                env_vars.insert("ALBUM_GAIN_DB", data.album_gain_db.to_string());
+                env_vars.insert("ALBUM_PEAK", data.album_peak.to_string());
+            }
+        },
+        PlayerEvent::Loudness {
+            track_id, loudness, ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::Loudness: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "loudness".to_string());
+                env_vars.insert("TRACK_ID", id);
+                // empty if the track was silent
+                env_vars.insert(
+                    "INTEGRATED_LUFS",
+                    loudness
+                        .integrated_lufs
+                        .map(|lufs| lufs.to_string())
+                        .unwrap_or_default(),
+                );
+                env_vars.insert("TRUE_PEAK_DBTP", loudness.true_peak_dbtp.to_string());
+            }
+        },
+        PlayerEvent::BufferUnderrun {
+            track_id,
+            position_ms,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::BufferUnderrun: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "buffer_underrun".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("POSITION_MS", position_ms.to_string());
+            }
+        },
+        PlayerEvent::Unavailable {
+            track_id, reason, ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::Unavailable: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "unavailable".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("REASON", reason.code().to_string());
+                env_vars.insert("REASON_MESSAGE", reason.to_string());
+            }
+        },
         PlayerEvent::VolumeSet { volume } => {
             env_vars.insert("PLAYER_EVENT", "volume_set".to_string());
             env_vars.insert("VOLUME", volume.to_string());
         }
+        PlayerEvent::PlaybackModeChanged { shuffle, repeat } => {
+            env_vars.insert("PLAYER_EVENT", "playback_mode".to_string());
+            env_vars.insert("SHUFFLE", shuffle.to_string());
+            env_vars.insert("REPEAT", repeat.to_string());
+        }
         _ => return None,
     }
 
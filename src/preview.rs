@@ -0,0 +1,383 @@
+//! The 30 second MP3 previews of tracks, played at the loudness of the normalised tracks.
+
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use crate::core::authentication::Credentials;
+use crate::core::config::SessionConfig;
+use crate::core::http;
+use crate::core::session::Session;
+use crate::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use crate::playback::config::PlayerConfig;
+use crate::playback::player::get_track_info;
+use crate::spotty::{get_spotify_id, Error, ExitCode};
+
+// The player Spotify embeds in web pages, which links the preview of a track
+const EMBED_URL: &str = "https://open.spotify.com/embed/track/";
+const PREVIEW_URL: &str = "https://p.scdn.co/mp3-preview/";
+
+// The global gain of an MP3 granule is a power of 2^(1/4), about 1.5 dB
+const GAIN_STEP_DB: f64 = 1.5;
+
+/// Downloads the preview of a track, which needs neither a session nor Premium. With
+/// credentials the track's normalisation gain is applied to it, so previews of different
+/// tracks play at the same loudness as the tracks themselves.
+pub async fn preview(
+    track_id: String,
+    last_credentials: Option<Credentials>,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Result<Vec<u8>, Error> {
+    let track = match get_spotify_id(&track_id) {
+        Some(track) if track.audio_type == SpotifyAudioType::Track => track,
+        _ => return Err(Error::new(ExitCode::InvalidArguments, "Invalid track ID.")),
+    };
+    // panic safety: the ID was parsed from base62
+    let base62 = track.to_base62().unwrap();
+
+    let embed = match http::get(&format!("{}{}", EMBED_URL, base62), &session_config).await {
+        Ok(embed) => String::from_utf8_lossy(&embed).into_owned(),
+        Err(error) => {
+            let error = format!("Failed to look up the preview of {}: {}", track_id, error);
+            return Err(Error::new(ExitCode::NetworkError, error));
+        }
+    };
+
+    let url = match preview_url(&embed) {
+        Some(url) => url,
+        None => {
+            let error = format!("There is no preview for {}", track_id);
+            return Err(Error::new(ExitCode::Unavailable, error));
+        }
+    };
+
+    debug!("Downloading preview from {}", url);
+    let mut data = match http::get(&url, &session_config).await {
+        Ok(data) => data.to_vec(),
+        Err(error) => {
+            let error = format!("Failed to download the preview for {}: {}", track_id, error);
+            return Err(Error::new(ExitCode::NetworkError, error));
+        }
+    };
+
+    let gain_db = match last_credentials {
+        Some(credentials) => {
+            normalisation_gain(track, credentials, player_config, session_config).await
+        }
+        None => {
+            warn!("Without credentials the preview plays at its own loudness.");
+            None
+        }
+    };
+
+    if let Some(gain_db) = gain_db {
+        let steps = (gain_db / GAIN_STEP_DB).round() as i32;
+        debug!(
+            "Applying {:.2} dB to the preview",
+            steps as f64 * GAIN_STEP_DB
+        );
+        apply_gain(&mut data, steps);
+    }
+
+    Ok(data)
+}
+
+// The gain the player would apply to the track, lowered if the track's peak would clip
+async fn normalisation_gain(
+    track: SpotifyId,
+    credentials: Credentials,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Option<f64> {
+    let session = match Session::connect(session_config, credentials, None, true).await {
+        Ok((session, _)) => session,
+        Err(error) => {
+            warn!(
+                "Failed to create session, the preview plays at its own loudness: {}",
+                error
+            );
+            return None;
+        }
+    };
+
+    let pregain_db = player_config.normalisation_pregain_db;
+    let data = get_track_info(session, player_config, track)
+        .await
+        .and_then(|info| info.normalisation_data);
+
+    match data {
+        Some(data) => {
+            let gain_db = data.track_gain_db + pregain_db;
+            let headroom_db = if data.track_peak > 0.0 {
+                -20.0 * data.track_peak.log10()
+            } else {
+                gain_db
+            };
+            Some(gain_db.min(headroom_db))
+        }
+        None => {
+            warn!("No normalisation data, the preview plays at its own loudness.");
+            None
+        }
+    }
+}
+
+// eg. "https://p.scdn.co/mp3-preview/3eb16018c2a700240e9dfb8817b6f2d041f15eb1?cid=..."
+fn preview_url(embed: &str) -> Option<String> {
+    let start = embed.find(PREVIEW_URL)?;
+    let file = &embed[start + PREVIEW_URL.len()..];
+    let file: String = file.chars().take_while(char::is_ascii_hexdigit).collect();
+    if file.is_empty() {
+        return None;
+    }
+    Some(format!("{}{}", PREVIEW_URL, file))
+}
+
+// Changes the global gain of every granule of every frame by `steps` of 1.5 dB, like mp3gain
+// does, which changes the loudness without decoding and encoding the MP3 again.
+fn apply_gain(data: &mut [u8], steps: i32) {
+    if steps == 0 {
+        return;
+    }
+
+    let mut position = id3v2_size(data);
+    while position + 4 <= data.len() {
+        let frame = match FrameHeader::parse(&data[position..]) {
+            Some(frame) if position + frame.length <= data.len() => frame,
+            // not in sync, eg. an ID3v1 tag at the end
+            _ => {
+                position += 1;
+                continue;
+            }
+        };
+
+        let side_info_start = position + if frame.crc { 6 } else { 4 };
+        let side_info_end = side_info_start + frame.side_info_length();
+        let side_info = &mut data[side_info_start..side_info_end];
+        for offset in frame.global_gain_offsets() {
+            let gain = read_bits(side_info, offset, 8) as i32;
+            write_bits(side_info, offset, 8, (gain + steps).clamp(0, 255) as u32);
+        }
+
+        if frame.crc {
+            let mut protected = data[position + 2..position + 4].to_vec();
+            protected.extend_from_slice(&data[side_info_start..side_info_end]);
+            let crc = crc16(&protected).to_be_bytes();
+            data[position + 4..position + 6].copy_from_slice(&crc);
+        }
+
+        position += frame.length;
+    }
+}
+
+fn id3v2_size(data: &[u8]) -> usize {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return 0;
+    }
+    // a synchsafe integer, 7 bits per byte
+    let size = data[6..10]
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+struct FrameHeader {
+    mpeg1: bool,
+    mono: bool,
+    crc: bool,
+    length: usize,
+}
+
+impl FrameHeader {
+    // Layer III frames only, as the previews are MP3
+    fn parse(data: &[u8]) -> Option<Self> {
+        const MPEG1_BITRATES: [usize; 15] = [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ];
+        const MPEG2_BITRATES: [usize; 15] =
+            [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+        const SAMPLE_RATES: [usize; 3] = [44100, 48000, 32000];
+
+        if data.len() < 4 || data[0] != 0xff || data[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        // 3 is MPEG-1, 2 MPEG-2 and 0 MPEG-2.5
+        let version = (data[1] >> 3) & 0x03;
+        let layer = (data[1] >> 1) & 0x03;
+        if version == 1 || layer != 1 {
+            return None;
+        }
+        let mpeg1 = version == 3;
+
+        let bitrate_index = (data[2] >> 4) as usize;
+        let sample_rate_index = ((data[2] >> 2) & 0x03) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            return None;
+        }
+
+        let (bitrate, sample_rate, samples) = match version {
+            3 => (
+                MPEG1_BITRATES[bitrate_index],
+                SAMPLE_RATES[sample_rate_index],
+                144,
+            ),
+            2 => (
+                MPEG2_BITRATES[bitrate_index],
+                SAMPLE_RATES[sample_rate_index] / 2,
+                72,
+            ),
+            _ => (
+                MPEG2_BITRATES[bitrate_index],
+                SAMPLE_RATES[sample_rate_index] / 4,
+                72,
+            ),
+        };
+        let padding = ((data[2] >> 1) & 0x01) as usize;
+
+        Some(Self {
+            mpeg1,
+            mono: data[3] >> 6 == 3,
+            crc: data[1] & 0x01 == 0,
+            length: samples * bitrate * 1000 / sample_rate + padding,
+        })
+    }
+
+    fn side_info_length(&self) -> usize {
+        match (self.mpeg1, self.mono) {
+            (true, true) => 17,
+            (true, false) => 32,
+            (false, true) => 9,
+            (false, false) => 17,
+        }
+    }
+
+    // The bit offsets of the global gains in the side information, two granules per channel in
+    // MPEG-1, one in MPEG-2
+    fn global_gain_offsets(&self) -> Vec<usize> {
+        let channels = if self.mono { 1 } else { 2 };
+        let (granules, start, granule_bits) = if self.mpeg1 {
+            // main_data_begin, private bits and the scale factor selection of each channel
+            let private_bits = if self.mono { 5 } else { 3 };
+            (2, 9 + private_bits + 4 * channels, 59)
+        } else {
+            let private_bits = if self.mono { 1 } else { 2 };
+            (1, 8 + private_bits, 63)
+        };
+
+        // after part2_3_length and big_values
+        (0..granules * channels)
+            .map(|granule| start + granule * granule_bits + 21)
+            .collect()
+    }
+}
+
+fn read_bits(data: &[u8], offset: usize, count: usize) -> u32 {
+    (offset..offset + count).fold(0, |value, bit| {
+        (value << 1) | ((data[bit / 8] >> (7 - bit % 8)) & 0x01) as u32
+    })
+}
+
+fn write_bits(data: &mut [u8], offset: usize, count: usize, value: u32) {
+    for (i, bit) in (offset..offset + count).enumerate() {
+        let mask = 0x80 >> (bit % 8);
+        if (value >> (count - 1 - i)) & 0x01 != 0 {
+            data[bit / 8] |= mask;
+        } else {
+            data[bit / 8] &= !mask;
+        }
+    }
+}
+
+// The CRC-16 of MPEG audio frames, over the last two bytes of the header and the side information
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // An MPEG-1 Layer III frame at 128 kbit/s and 44.1 kHz, with the given global gains
+    fn frame(mono: bool, crc: bool, gains: &[u32]) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[0] = 0xff;
+        frame[1] = if crc { 0xfa } else { 0xfb };
+        frame[2] = 0x90;
+        frame[3] = if mono { 0xc0 } else { 0x00 };
+
+        let header = FrameHeader::parse(&frame).unwrap();
+        let start = if crc { 6 } else { 4 };
+        let side_info = &mut frame[start..start + header.side_info_length()];
+        for (offset, gain) in header.global_gain_offsets().into_iter().zip(gains) {
+            write_bits(side_info, offset, 8, *gain);
+        }
+        frame
+    }
+
+    fn gains(frame: &[u8], crc: bool) -> Vec<u32> {
+        let header = FrameHeader::parse(frame).unwrap();
+        let start = if crc { 6 } else { 4 };
+        let side_info = &frame[start..start + header.side_info_length()];
+        header
+            .global_gain_offsets()
+            .into_iter()
+            .map(|offset| read_bits(side_info, offset, 8))
+            .collect()
+    }
+
+    #[test]
+    fn test_preview_url() {
+        let embed =
+            r#"{"audioPreview":{"url":"https://p.scdn.co/mp3-preview/3eb16018c2a7?cid=1"}}"#;
+        assert_eq!(
+            preview_url(embed).unwrap(),
+            "https://p.scdn.co/mp3-preview/3eb16018c2a7"
+        );
+        assert!(preview_url("<html></html>").is_none());
+    }
+
+    #[test]
+    fn test_frame_header() {
+        let header = FrameHeader::parse(&frame(false, false, &[])).unwrap();
+        assert!(header.mpeg1);
+        assert_eq!(header.length, 417);
+        assert_eq!(header.global_gain_offsets(), [41, 100, 159, 218]);
+
+        assert!(FrameHeader::parse(b"ID3\x04").is_none());
+    }
+
+    #[test]
+    fn test_apply_gain() {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        data.extend(frame(false, false, &[150, 160, 10, 250]));
+        data.extend(frame(true, false, &[100, 100]));
+
+        apply_gain(&mut data, 4);
+        assert_eq!(gains(&data[12..], false), [154, 164, 14, 254]);
+        assert_eq!(gains(&data[12 + 417..], false), [104, 104]);
+
+        apply_gain(&mut data, -20);
+        assert_eq!(gains(&data[12..], false), [134, 144, 0, 234]);
+    }
+
+    #[test]
+    fn test_apply_gain_crc() {
+        let mut data = frame(false, true, &[150, 160, 170, 180]);
+        apply_gain(&mut data, 2);
+        assert_eq!(gains(&data, true), [152, 162, 172, 182]);
+
+        let mut protected = data[2..4].to_vec();
+        protected.extend_from_slice(&data[6..38]);
+        assert_eq!(data[4..6], crc16(&protected).to_be_bytes());
+    }
+}
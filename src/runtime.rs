@@ -0,0 +1,896 @@
+//! Runs spotty as a Spotify Connect device, so other programs can embed it instead of running
+//! the binary.
+//!
+//! ```no_run
+//! # use librespot::core::config::{ConnectConfig, SessionConfig};
+//! # use librespot::playback::config::PlayerConfig;
+//! # use librespot::runtime::Runtime;
+//! # async fn run() {
+//! let runtime = Runtime::builder(
+//!     SessionConfig::default(),
+//!     PlayerConfig::default(),
+//!     ConnectConfig::default(),
+//! )
+//! .build();
+//!
+//! if let Err(e) = runtime.run().await {
+//!     eprintln!("{}", e);
+//! }
+//! # }
+//! ```
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::{future, FutureExt, StreamExt};
+use log::{error, info, warn};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::connect::spirc::Spirc;
+use crate::control::{self, ControlCommand};
+use crate::core::authentication::Credentials;
+use crate::core::cache::Cache;
+use crate::core::config::{ConnectConfig, SessionConfig};
+use crate::core::session::{Session, SessionError};
+use crate::core::spotify_id::SpotifyAudioType;
+use crate::discovery;
+use crate::output_profiles::OutputProfiles;
+use crate::playback::audio_backend::{self, SinkBuilder};
+use crate::playback::config::{AudioFormat, PlayerConfig};
+use crate::playback::mixer::{self, MixerConfig, MixerFn};
+use crate::playback::player::{ErrorCategory, Player, PlayerEvent};
+use crate::spotty::{
+    self, Alarm, ExitCode, OnPlay, Reconnect, ReconnectPolicy, SnapcastMetadata, LMS,
+};
+use crate::status::{self, SharedStatus, Status};
+use crate::web_api::{self, WebApi};
+
+pub use crate::spotty::Error;
+
+pub struct Builder {
+    session_config: SessionConfig,
+    player_config: PlayerConfig,
+    connect_config: ConnectConfig,
+    mixer: MixerFn,
+    mixer_config: MixerConfig,
+    backend: SinkBuilder,
+    device: Option<String>,
+    format: AudioFormat,
+    cache: Option<Cache>,
+    credentials: Option<Credentials>,
+    discovery: Option<discovery::Builder>,
+    lms: LMS,
+    reconnect: Reconnect,
+    status_address: Option<SocketAddr>,
+    control_address: Option<SocketAddr>,
+    control_token: Option<String>,
+    buffer_debug: Option<Duration>,
+    volume_debounce: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    web_api: Option<String>,
+    lyrics: bool,
+    canvas: bool,
+    color_palette: bool,
+    snapcast_metadata: Option<SnapcastMetadata>,
+    take_over: bool,
+    resume_on_start: bool,
+    alarm: Option<Alarm>,
+    output_profiles: OutputProfiles,
+    authenticate: bool,
+}
+
+impl Builder {
+    /// The audio is written to `device` by `backend`, the pipe backend to stdout by default.
+    pub fn backend(
+        mut self,
+        backend: SinkBuilder,
+        device: Option<String>,
+        format: AudioFormat,
+    ) -> Self {
+        self.backend = backend;
+        self.device = device;
+        self.format = format;
+        self
+    }
+
+    pub fn mixer(mut self, mixer: MixerFn, mixer_config: MixerConfig) -> Self {
+        self.mixer = mixer;
+        self.mixer_config = mixer_config;
+        self
+    }
+
+    pub fn cache(mut self, cache: Option<Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Log in with these credentials on start, otherwise wait for a client to connect through
+    /// discovery.
+    pub fn credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Announce the device over zeroconf, so clients can log in to it.
+    pub fn discovery(mut self, discovery: discovery::Builder) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Report player events to this LMS player.
+    pub fn lms(mut self, lms: LMS) -> Self {
+        self.lms = lms;
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: Reconnect) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Serve the status as JSON over HTTP on this address.
+    pub fn status_address(mut self, address: Option<SocketAddr>) -> Self {
+        self.status_address = address;
+        self
+    }
+
+    /// Accept playback commands over HTTP on this address.
+    pub fn control_address(mut self, address: Option<SocketAddr>) -> Self {
+        self.control_address = address;
+        self
+    }
+
+    /// The token control requests need, a random one saved to the cache if `None`.
+    pub fn control_token(mut self, token: Option<String>) -> Self {
+        self.control_token = token;
+        self
+    }
+
+    /// Write the buffer fill and throughput to stderr as a JSON line at this interval, see
+    /// `Status::buffer_debug`.
+    pub fn buffer_debug(mut self, interval: Option<Duration>) -> Self {
+        self.buffer_debug = interval;
+        self
+    }
+
+    /// Tell LMS about at most one volume change per interval, the latest one once it's over, as
+    /// dragging the volume slider in a Spotify app sends many.
+    pub fn volume_debounce(mut self, interval: Option<Duration>) -> Self {
+        self.volume_debounce = interval;
+        self
+    }
+
+    /// Disconnect after being paused or stopped for this long, to save power. Discovery stays
+    /// active, and a play command through the control endpoint reconnects and resumes.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Get Web API tokens for this client ID, which `lyrics`, `canvas` and the control
+    /// commands for the library need.
+    pub fn web_api(mut self, client_id: Option<String>) -> Self {
+        self.web_api = client_id;
+        self
+    }
+
+    /// Get the lyrics of each track, for the status endpoint and LMS.
+    pub fn lyrics(mut self, lyrics: bool) -> Self {
+        self.lyrics = lyrics;
+        self
+    }
+
+    /// Add the URL of the track's Canvas, a short looping video, to the details sent to LMS.
+    pub fn canvas(mut self, canvas: bool) -> Self {
+        self.canvas = canvas;
+        self
+    }
+
+    /// Add the dominant colours of the album's cover to the details sent to LMS.
+    pub fn color_palette(mut self, color_palette: bool) -> Self {
+        self.color_palette = color_palette;
+        self
+    }
+
+    /// Append the metadata and playback status to `path`, for a Snapcast stream control script,
+    /// see `SnapcastMetadata`.
+    pub fn snapcast_metadata(mut self, path: Option<PathBuf>) -> Self {
+        self.snapcast_metadata = path.map(SnapcastMetadata::new);
+        self
+    }
+
+    /// Continue the playback of the active device on this one once connected.
+    pub fn take_over(mut self, take_over: bool) -> Self {
+        self.take_over = take_over;
+        self
+    }
+
+    /// Resume what this device played last once connected, see `Spirc::resume`.
+    pub fn resume_on_start(mut self, resume_on_start: bool) -> Self {
+        self.resume_on_start = resume_on_start;
+        self
+    }
+
+    pub fn alarm(mut self, alarm: Option<Alarm>) -> Self {
+        self.alarm = alarm;
+        self
+    }
+
+    /// The output profiles the control endpoint can switch between. Any profile active already
+    /// should have been applied to the player and Connect configuration.
+    pub fn output_profiles(mut self, output_profiles: OutputProfiles) -> Self {
+        self.output_profiles = output_profiles;
+        self
+    }
+
+    /// Stop once logged in, after the credentials were cached.
+    pub fn authenticate(mut self, authenticate: bool) -> Self {
+        self.authenticate = authenticate;
+        self
+    }
+
+    pub fn build(self) -> Runtime {
+        Runtime {
+            setup: self,
+            status: Status::new(),
+        }
+    }
+}
+
+pub struct Runtime {
+    setup: Builder,
+    status: SharedStatus,
+}
+
+async fn reconnect(
+    delay: std::time::Duration,
+    session_config: SessionConfig,
+    credentials: Credentials,
+    cache: Option<Cache>,
+) -> Result<(Session, Credentials), SessionError> {
+    tokio::time::sleep(delay).await;
+    Session::connect(session_config, credentials, cache, true).await
+}
+
+// how often to check which line of the lyrics is sung
+const LYRICS_LINE_INTERVAL: Duration = Duration::from_millis(250);
+
+type SpircTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Starts the player and Spirc, to be controlled as a Connect device through `session`
+fn start_spirc(
+    setup: &mut Builder,
+    session: Session,
+    status: &SharedStatus,
+) -> (Spirc, SpircTask, UnboundedReceiver<PlayerEvent>) {
+    let mixer_config = setup.mixer_config.clone();
+    let mixer = (setup.mixer)(mixer_config);
+    let player_config = setup.player_config.clone();
+    let connect_config = setup.connect_config.clone();
+
+    let soft_volume = mixer.get_soft_volume();
+    let format = setup.format;
+    let backend = setup.backend;
+    let device = setup.device.clone();
+    let (player, event_channel) =
+        Player::new(player_config, session.clone(), soft_volume, move || {
+            (backend)(device, format)
+        });
+
+    status.lock().unwrap().connected(&session, &player);
+
+    let (spirc, spirc_task) = Spirc::new(connect_config, session, player, mixer);
+
+    if setup.take_over {
+        spirc.take_over();
+    } else if setup.resume_on_start {
+        // only when starting, not after reconnecting
+        setup.resume_on_start = false;
+        spirc.resume();
+    }
+
+    (spirc, Box::pin(spirc_task), event_channel)
+}
+
+// Applies `OnPlay` as Connect playback starts or resumes.
+async fn check_power(lms: &LMS, status: &SharedStatus, spirc: Option<&Spirc>) {
+    if lms.on_play() == OnPlay::Ignore || !lms.is_configured() {
+        return;
+    }
+
+    let powered = lms.is_powered().await;
+    status.lock().unwrap().squeezebox_powered(powered);
+    if powered != Some(false) {
+        return;
+    }
+
+    match (lms.on_play(), spirc) {
+        (OnPlay::PowerOn, _) => {
+            info!("The Squeezebox is switched off, powering it on");
+            lms.power_on().await;
+            status.lock().unwrap().squeezebox_powered(Some(true));
+        }
+        (OnPlay::Block, Some(spirc)) => {
+            warn!("The Squeezebox is switched off, pausing playback");
+            spirc.pause();
+        }
+        _ => (),
+    }
+}
+
+fn stop_spirc(spirc: &mut Option<Spirc>, spirc_task: &mut Option<SpircTask>) {
+    if let Some(spirc) = spirc.take() {
+        spirc.shutdown();
+    }
+    if let Some(spirc_task) = spirc_task.take() {
+        // Continue shutdown in its own task
+        tokio::spawn(spirc_task);
+    }
+}
+
+impl Runtime {
+    pub fn builder(
+        session_config: SessionConfig,
+        player_config: PlayerConfig,
+        connect_config: ConnectConfig,
+    ) -> Builder {
+        let max_volume = connect_config.max_volume;
+        Builder {
+            session_config,
+            player_config,
+            connect_config,
+            mixer: mixer::find(None).unwrap(),
+            mixer_config: MixerConfig::default(),
+            backend: audio_backend::find(Some(spotty::BACKEND.to_string())).unwrap(),
+            device: None,
+            format: AudioFormat::default(),
+            cache: None,
+            credentials: None,
+            discovery: None,
+            lms: LMS::new(None, None, None, false, OnPlay::default()),
+            reconnect: Reconnect::new(
+                ReconnectPolicy::default(),
+                Reconnect::DEFAULT_DELAY,
+                Reconnect::DEFAULT_MAX_DELAY,
+            ),
+            status_address: None,
+            control_address: None,
+            control_token: None,
+            buffer_debug: None,
+            volume_debounce: None,
+            idle_timeout: None,
+            web_api: None,
+            lyrics: false,
+            canvas: false,
+            color_palette: false,
+            snapcast_metadata: None,
+            take_over: false,
+            resume_on_start: false,
+            alarm: None,
+            output_profiles: OutputProfiles::new(max_volume),
+            authenticate: false,
+        }
+    }
+
+    /// The connection and playback status, which is kept up to date while running.
+    pub fn status(&self) -> SharedStatus {
+        self.status.clone()
+    }
+
+    /// Runs until Ctrl-C or SIGTERM.
+    pub async fn run(self) -> Result<(), Error> {
+        self.run_until(future::pending()).await
+    }
+
+    /// Runs until `shutdown` completes, Ctrl-C or SIGTERM.
+    // tokio::select! expands to poll_fn, which is newer than the MSRV
+    #[allow(clippy::incompatible_msrv)]
+    pub async fn run_until<F>(self, shutdown: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+        let Runtime { mut setup, status } = self;
+
+        let mut last_credentials = None;
+        // those of the current session, to keep it when logging in with new ones fails
+        let mut current_credentials = None;
+        let mut spirc: Option<Spirc> = None;
+        let mut current_session: Option<Session> = None;
+        // logged in with new credentials, waiting for the current track to end
+        let mut pending_session: Option<Session> = None;
+        let mut track_ended = false;
+        let mut spirc_task: Option<SpircTask> = None;
+        let mut player_event_channel: Option<UnboundedReceiver<PlayerEvent>> = None;
+        let mut discovery = None;
+        let mut connecting: Pin<Box<dyn future::FusedFuture<Output = _>>> =
+            Box::pin(future::pending());
+
+        if let Some(builder) = setup.discovery.take() {
+            match builder.launch() {
+                Ok(d) => discovery = Some(d),
+                Err(err) => warn!("Could not initialise discovery: {}.", err),
+            };
+        }
+
+        if let Some(credentials) = setup.credentials.take() {
+            last_credentials = Some(credentials.clone());
+            status.lock().unwrap().connecting();
+            connecting = Box::pin(
+                Session::connect(
+                    setup.session_config.clone(),
+                    credentials,
+                    setup.cache.clone(),
+                    true,
+                )
+                .fuse(),
+            );
+        } else if discovery.is_none() {
+            let error =
+                "Discovery is unavailable and no credentials provided. Authentication is not possible.";
+            return Err(Error::new(ExitCode::AuthFailed, error));
+        }
+
+        // Keep the device ID once running as a Connect device, so it stays the same device
+        let name = &setup.connect_config.name;
+        match setup.cache {
+            Some(ref cache) if !setup.authenticate && cache.device_id(name).is_none() => {
+                cache.save_device_id(name, &setup.session_config.device_id);
+            }
+            _ => (),
+        }
+
+        if let Some(address) = setup.status_address {
+            status::serve_status(
+                address,
+                status.clone(),
+                setup.lms.clone(),
+                setup.cache.clone(),
+            )
+            .map_err(|e| {
+                let error = format!("Could not start the status endpoint on {}: {}", address, e);
+                Error::new(ExitCode::Error, error)
+            })?;
+        }
+
+        let mut control_requests = match setup.control_address {
+            Some(address) => {
+                let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+                let token = setup
+                    .control_token
+                    .clone()
+                    .unwrap_or_else(|| control::new_control_token(setup.cache.as_ref()));
+                control::serve_control(address, token, requests_tx).map_err(|e| {
+                    let error =
+                        format!("Could not start the control endpoint on {}: {}", address, e);
+                    Error::new(ExitCode::Error, error)
+                })?;
+                Some(requests_rx)
+            }
+            None => None,
+        };
+
+        let next_alarm = |alarm: &Alarm| alarm.next_in().map(|d| Box::pin(tokio::time::sleep(d)));
+        let mut alarm_timer = setup.alarm.as_ref().and_then(next_alarm);
+
+        let mut buffer_debug = setup.buffer_debug.map(|interval| {
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+        });
+
+        // the latest volume change LMS wasn't told about yet, see `volume_debounce`
+        let mut pending_volume: Option<PlayerEvent> = None;
+        let mut volume_timer: Option<Pin<Box<tokio::time::Sleep>>> = None;
+
+        // see `idle_timeout`
+        let mut idle_timer: Option<Pin<Box<tokio::time::Sleep>>> = None;
+        let mut parked = false;
+
+        // see `lyrics`, the track whose lyrics were requested last
+        let mut web_api: Option<WebApi> = None;
+        let mut lyrics_track = None;
+        let mut lyrics_interval = setup
+            .web_api
+            .as_ref()
+            .filter(|_| setup.lyrics)
+            .map(|_| tokio::time::interval(LYRICS_LINE_INTERVAL));
+
+        // the track whose album, artists and genres were sent to LMS last
+        let mut details_track = None;
+
+        tokio::pin!(shutdown);
+
+        loop {
+            // Switch to a new session between tracks rather than cutting off the current one
+            let playing = spirc_task.is_some() && status.lock().unwrap().is_playing();
+            if pending_session.is_some() && (!playing || track_ended) {
+                let session = pending_session.take().unwrap();
+                track_ended = false;
+
+                stop_spirc(&mut spirc, &mut spirc_task);
+                let (spirc_, spirc_task_, event_channel) =
+                    start_spirc(&mut setup, session.clone(), &status);
+
+                web_api = setup
+                    .web_api
+                    .as_deref()
+                    .map(|client_id| WebApi::new(session.clone(), client_id));
+                lyrics_track = None;
+                current_session = Some(session);
+                spirc = Some(spirc_);
+                spirc_task = Some(spirc_task_);
+                player_event_channel = Some(event_channel);
+                parked = false;
+
+                setup.lms.signal_connection("connected", None).await;
+            }
+
+            if let Some(timeout) = setup.idle_timeout {
+                if spirc.is_none() || status.lock().unwrap().is_playing() {
+                    idle_timer = None;
+                } else if idle_timer.is_none() {
+                    idle_timer = Some(Box::pin(tokio::time::sleep(timeout)));
+                }
+            }
+
+            tokio::select! {
+                credentials = async {
+                    match discovery.as_mut() {
+                        Some(d) => d.next().await,
+                        _ => None
+                    }
+                }, if discovery.is_some() => {
+                    match credentials {
+                        Some(credentials) => {
+                            last_credentials = Some(credentials.clone());
+                            setup.reconnect.reset();
+
+                            // Keep playing until the new session is ready
+                            if let Some(session) = pending_session.take() {
+                                session.shutdown();
+                            }
+                            if spirc.is_none() {
+                                status.lock().unwrap().connecting();
+                            }
+
+                            connecting = Box::pin(Session::connect(
+                                setup.session_config.clone(),
+                                credentials,
+                                setup.cache.clone(),
+                                true,
+                            ).fuse());
+                        },
+                        None => {
+                            return Err(Error::new(ExitCode::Error, "Discovery stopped unexpectedly"));
+                        }
+                    }
+                },
+                session = &mut connecting, if !connecting.is_terminated() => match session {
+                    Ok((session,_)) => {
+                        // Spotty auth mode: stop after saving credentials
+                        if setup.authenticate {
+                            break;
+                        }
+
+                        setup.reconnect.connected();
+                        current_credentials = last_credentials.clone();
+
+                        if spirc.is_some() {
+                            info!("Switching to the new session after the current track");
+                        }
+                        pending_session = Some(session);
+                    },
+                    Err(e) if spirc.is_some() && e.is_login_failure() => {
+                        error!("Logging in with the new credentials failed: {}", e);
+                        info!("Keeping the current session");
+                        last_credentials = current_credentials.clone();
+                    },
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        let reason = e.to_string();
+                        if spirc.is_none() {
+                            status.lock().unwrap().disconnected();
+                        }
+
+                        let delay = setup.reconnect.next_delay();
+                        let category = if e.is_login_failure() {
+                            ErrorCategory::Auth
+                        } else {
+                            ErrorCategory::Network
+                        };
+                        let recoverable =
+                            last_credentials.is_some() && delay.is_some() && !e.is_login_failure();
+                        setup.lms.signal_error(category, &reason, recoverable).await;
+
+                        match (last_credentials.clone(), delay) {
+                            (Some(credentials), Some(delay)) if !e.is_login_failure() => {
+                                info!("Reconnecting in {:.1}s", delay.as_secs_f32());
+                                if spirc.is_none() {
+                                    status.lock().unwrap().connecting();
+                                    let state = "reconnecting";
+                                    setup.lms.signal_connection(state, Some(&reason)).await;
+                                }
+                                connecting = Box::pin(reconnect(
+                                    delay,
+                                    setup.session_config.clone(),
+                                    credentials,
+                                    setup.cache.clone(),
+                                ).fuse());
+                            },
+                            _ => {
+                                setup.lms.signal_connection("disconnected", Some(&reason)).await;
+                                let code = if e.is_login_failure() {
+                                    ExitCode::AuthFailed
+                                } else {
+                                    ExitCode::NetworkError
+                                };
+                                return Err(Error::new(code, reason));
+                            },
+                        }
+                    }
+                },
+                _ = async {
+                    if let Some(task) = spirc_task.as_mut() {
+                        task.await;
+                    }
+                }, if spirc_task.is_some() => {
+                    spirc = None;
+                    spirc_task = None;
+                    status.lock().unwrap().disconnected();
+
+                    warn!("Spirc shut down unexpectedly");
+
+                    // a new session is ready to take over
+                    if pending_session.is_some() {
+                        continue;
+                    }
+
+                    let reason = "The connection to Spotify was closed.";
+                    setup.lms.signal_connection("lost", Some(reason)).await;
+
+                    match (last_credentials.clone(), setup.reconnect.next_delay()) {
+                        (Some(credentials), Some(delay)) => {
+                            status.lock().unwrap().connecting();
+                            setup.lms.signal_connection("reconnecting", Some(reason)).await;
+                            connecting = Box::pin(reconnect(
+                                delay,
+                                setup.session_config.clone(),
+                                credentials,
+                                setup.cache.clone(),
+                            ).fuse());
+                        },
+                        _ => {
+                            let error = "Not reconnecting automatically, see --reconnect.";
+                            setup.lms.signal_connection("disconnected", Some(error)).await;
+                            return Err(Error::new(ExitCode::NetworkError, error));
+                        },
+                    }
+                },
+                event = async {
+                    match player_event_channel.as_mut() {
+                        Some(p) => p.recv().await,
+                        _ => None
+                    }
+                }, if player_event_channel.is_some() => match event {
+                    Some(event) => {
+                        if let PlayerEvent::EndOfTrack { .. } = event {
+                            track_ended = pending_session.is_some();
+                        }
+                        status.lock().unwrap().player_event(&event);
+                        if let Some(ref snapcast_metadata) = setup.snapcast_metadata {
+                            snapcast_metadata.player_event(&event);
+                            if let (PlayerEvent::Loading { track_id, .. }, Some(session)) =
+                                (&event, current_session.as_ref())
+                            {
+                                tokio::spawn(
+                                    snapcast_metadata
+                                        .clone()
+                                        .track_loading(session.clone(), *track_id),
+                                );
+                            }
+                        }
+                        if let PlayerEvent::Playing { .. } = event {
+                            check_power(&setup.lms, &status, spirc.as_ref()).await;
+                        }
+                        match (&event, web_api.as_ref()) {
+                            (PlayerEvent::Loading { track_id, .. }, Some(web_api))
+                                if setup.lyrics && lyrics_track != Some(*track_id) =>
+                            {
+                                lyrics_track = Some(*track_id);
+                                tokio::spawn(web_api::fetch_lyrics(
+                                    web_api.clone(),
+                                    *track_id,
+                                    status.clone(),
+                                    setup.lms.clone(),
+                                ));
+                            }
+                            _ => (),
+                        }
+                        match (&event, current_session.as_ref()) {
+                            (PlayerEvent::Loading { track_id, .. }, Some(session))
+                                if track_id.audio_type == SpotifyAudioType::Track
+                                    && details_track != Some(*track_id) =>
+                            {
+                                details_track = Some(*track_id);
+                                tokio::spawn(web_api::fetch_track_details(
+                                    session.clone(),
+                                    web_api.clone().filter(|_| setup.canvas),
+                                    setup.color_palette,
+                                    *track_id,
+                                    setup.lms.clone(),
+                                ));
+                            }
+                            _ => (),
+                        }
+                        match (&event, setup.volume_debounce) {
+                            (PlayerEvent::VolumeSet { .. }, Some(interval)) => {
+                                pending_volume = Some(event);
+                                if volume_timer.is_none() {
+                                    volume_timer = Some(Box::pin(tokio::time::sleep(interval)));
+                                }
+                            }
+                            _ => setup.lms.signal_event(event).await,
+                        }
+                    },
+                    None => {
+                        player_event_channel = None;
+                    }
+                },
+                request = async {
+                    match control_requests.as_mut() {
+                        Some(r) => r.recv().await,
+                        _ => None
+                    }
+                }, if control_requests.is_some() => match request {
+                    Some(request) => {
+                        let request = request
+                            .run_channel_mix(
+                                &setup.player_config.channel_mix,
+                                setup.cache.as_ref(),
+                                &setup.connect_config.name,
+                            )
+                            .or_else(|request| request.run_speed(&setup.player_config.speed))
+                            .or_else(|request| {
+                                request.run_profile(
+                                    &mut setup.output_profiles,
+                                    &setup.player_config,
+                                    &mut setup.connect_config,
+                                    spirc.as_ref(),
+                                )
+                            })
+                            .or_else(|request| {
+                                let track_id = status.lock().unwrap().track();
+                                request.run_library(web_api.as_ref(), track_id)
+                            });
+                        match (request, spirc.as_ref().zip(current_session.as_ref())) {
+                            (Ok(()), _) => (),
+                            (Err(request), Some((spirc, session))) => {
+                                request.run(spirc, session, web_api.as_ref(), &status)
+                            }
+                            (Err(request), None)
+                                if parked
+                                    && matches!(
+                                        request.command,
+                                        ControlCommand::Play | ControlCommand::PlayPause
+                                    ) =>
+                            {
+                                match last_credentials.clone() {
+                                    Some(credentials) => {
+                                        info!("Reconnecting to resume playback");
+                                        parked = false;
+                                        setup.resume_on_start = true;
+                                        status.lock().unwrap().connecting();
+                                        connecting = Box::pin(Session::connect(
+                                            setup.session_config.clone(),
+                                            credentials,
+                                            setup.cache.clone(),
+                                            true,
+                                        ).fuse());
+                                        request.accept();
+                                    }
+                                    None => request.reject("Not connected."),
+                                }
+                            }
+                            (Err(request), None) => {
+                                warn!("Not connected, ignoring {:?}", request.command);
+                                request.reject("Not connected.");
+                            }
+                        }
+                    }
+                    None => {
+                        control_requests = None;
+                    }
+                },
+                _ = async {
+                    if let Some(timer) = alarm_timer.as_mut() {
+                        timer.await;
+                    }
+                }, if alarm_timer.is_some() => {
+                    let alarm = setup.alarm.as_ref().unwrap();
+                    match (current_session.clone(), spirc.clone()) {
+                        (Some(session), Some(spirc)) => {
+                            tokio::spawn(alarm.clone().start(session, spirc, setup.lms.clone()));
+                        },
+                        _ => warn!("Not connected, skipping the alarm"),
+                    }
+                    alarm_timer = next_alarm(alarm);
+                },
+                _ = async {
+                    if let Some(timer) = volume_timer.as_mut() {
+                        timer.await;
+                    }
+                }, if volume_timer.is_some() => {
+                    volume_timer = None;
+                    if let Some(event) = pending_volume.take() {
+                        setup.lms.signal_event(event).await;
+                    }
+                },
+                _ = async {
+                    if let Some(timer) = idle_timer.as_mut() {
+                        timer.await;
+                    }
+                }, if idle_timer.is_some() => {
+                    idle_timer = None;
+                    info!("Idle for a while, disconnecting until the next play command");
+                    stop_spirc(&mut spirc, &mut spirc_task);
+                    if let Some(session) = current_session.take() {
+                        session.shutdown();
+                    }
+                    player_event_channel = None;
+                    web_api = None;
+                    parked = true;
+                    status.lock().unwrap().parked();
+                    setup.lms.signal_connection("parked", None).await;
+                },
+                _ = async {
+                    if let Some(interval) = lyrics_interval.as_mut() {
+                        interval.tick().await;
+                    }
+                }, if lyrics_interval.is_some() => {
+                    let line = status.lock().unwrap().next_lyrics_line();
+                    if let Some((track_id, line, words)) = line {
+                        setup.lms.signal_lyrics_line(track_id, line, &words).await;
+                    }
+                },
+                _ = async {
+                    if let Some(interval) = buffer_debug.as_mut() {
+                        interval.tick().await;
+                    }
+                }, if buffer_debug.is_some() => {
+                    let data_usage = current_session.as_ref().map(Session::data_usage);
+                    let report = status.lock().unwrap().buffer_debug(data_usage.unwrap_or_default());
+                    // stdout carries the audio
+                    eprintln!("{}", report);
+                },
+                _ = &mut shutdown => {
+                    break;
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    break;
+                },
+                _ = spotty::terminated() => {
+                    break;
+                },
+                else => break,
+            }
+        }
+
+        info!("Gracefully shutting down");
+
+        // Shutdown spirc if necessary
+        if let Some(spirc) = spirc {
+            spirc.shutdown();
+
+            if let Some(mut spirc_task) = spirc_task {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = spirc_task.as_mut() => (),
+                    else => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+//! Self update from the GitHub releases.
+
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use minisign_verify::{PublicKey, Signature};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::core::config::SessionConfig;
+use crate::core::http;
+use crate::spotty::{Error, ExitCode};
+
+// The GitHub repository ("owner/name") spotty was released from, set when the release workflow
+// builds spotty, so forks update from their own releases.
+const RELEASE_REPOSITORY: &str = match option_env!("SPOTTY_RELEASE_REPOSITORY") {
+    Some(repository) => repository,
+    None => "michaelherger/librespot",
+};
+// lists the SHA-256 of every binary of a release, in the format of `sha256sum`
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+// the minisign signature of `CHECKSUMS_ASSET`
+const SIGNATURE_ASSET: &str = "SHA256SUMS.minisig";
+
+// The minisign public key releases are signed with, set when the release workflow builds spotty.
+// Other builds can't check where an update comes from, so they don't update themselves.
+const UPDATE_PUBLIC_KEY: Option<&str> = option_env!("SPOTTY_UPDATE_PUBLIC_KEY");
+
+// Set by builds which need a binary of their own although they share the architecture of
+// another, eg. "armv5" for the soft-float ARM build.
+const RELEASE_ARCH: Option<&str> = option_env!("SPOTTY_RELEASE_ARCH");
+
+// eg. "spotty-linux-aarch64", or "spotty-windows-x86_64.exe"
+fn release_asset_name() -> String {
+    format!(
+        "spotty-{}-{}{}",
+        std::env::consts::OS,
+        RELEASE_ARCH.unwrap_or(std::env::consts::ARCH),
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+fn release_asset_url<'a>(release: &'a Value, name: &str) -> Option<&'a str> {
+    release["assets"]
+        .as_array()?
+        .iter()
+        .find(|asset| asset["name"].as_str() == Some(name))
+        .and_then(|asset| asset["browser_download_url"].as_str())
+}
+
+// The trusted comment is signed too, the release workflow puts the version of the release in it,
+// eg. "spotty v1.3.2". That keeps an older release from being served as the latest one.
+fn verify_signature(
+    data: &[u8],
+    signature: &[u8],
+    public_key: &str,
+    version: &str,
+) -> Result<(), String> {
+    let public_key =
+        PublicKey::from_base64(public_key).map_err(|e| format!("Invalid public key: {}", e))?;
+    let signature = Signature::decode(&String::from_utf8_lossy(signature))
+        .map_err(|e| format!("Invalid signature of {}: {}", CHECKSUMS_ASSET, e))?;
+    public_key
+        .verify(data, &signature, false)
+        .map_err(|_| format!("The signature of {} doesn't match", CHECKSUMS_ASSET))?;
+
+    let signed_version = signature
+        .trusted_comment()
+        .strip_prefix("spotty ")
+        .map(parse_version);
+    if signed_version != Some(parse_version(version)) {
+        return Err(format!(
+            "{} was signed for \"{}\", not version {}",
+            CHECKSUMS_ASSET,
+            signature.trusted_comment(),
+            version
+        ));
+    }
+
+    Ok(())
+}
+
+// Write the new binary next to the running one, then move it into place.
+fn replace_executable(binary: &[u8]) -> io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let new_exe = exe.with_extension("new");
+
+    fs::write(&new_exe, binary)?;
+    fs::set_permissions(&new_exe, fs::metadata(&exe)?.permissions())?;
+
+    // Windows can't replace a running executable, but it can rename it. The one left by the
+    // last update is in the way then, unless it's still running.
+    if cfg!(windows) {
+        let old_exe = exe.with_extension("old");
+        match fs::remove_file(&old_exe) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        fs::rename(&exe, old_exe)?;
+    }
+    fs::rename(&new_exe, &exe)?;
+
+    Ok(exe)
+}
+
+async fn download_update(
+    release: &Value,
+    version: &str,
+    session_config: &SessionConfig,
+) -> Result<PathBuf, String> {
+    let public_key =
+        UPDATE_PUBLIC_KEY.ok_or("This build can't verify updates, please update it manually")?;
+    let name = release_asset_name();
+    let binary_url = release_asset_url(release, &name)
+        .ok_or_else(|| format!("The release has no binary {}", name))?;
+    let checksums_url = release_asset_url(release, CHECKSUMS_ASSET)
+        .ok_or_else(|| format!("The release has no {}", CHECKSUMS_ASSET))?;
+    let signature_url = release_asset_url(release, SIGNATURE_ASSET)
+        .ok_or_else(|| format!("The release has no {}", SIGNATURE_ASSET))?;
+
+    let checksums = http::get(checksums_url, session_config)
+        .await
+        .map_err(|e| format!("Unable to download {}: {}", CHECKSUMS_ASSET, e))?;
+    let signature = http::get(signature_url, session_config)
+        .await
+        .map_err(|e| format!("Unable to download {}: {}", SIGNATURE_ASSET, e))?;
+    verify_signature(&checksums, &signature, public_key, version)?;
+    let checksum = String::from_utf8_lossy(&checksums)
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+        .map(|(checksum, _)| checksum.to_lowercase())
+        .ok_or_else(|| format!("{} has no checksum for {}", CHECKSUMS_ASSET, name))?;
+
+    let binary = http::get(binary_url, session_config)
+        .await
+        .map_err(|e| format!("Unable to download {}: {}", name, e))?;
+    if hex::encode(Sha256::digest(&binary)) != checksum {
+        return Err(format!("The checksum of {} doesn't match", name));
+    }
+
+    replace_executable(&binary).map_err(|e| format!("Unable to replace the executable: {}", e))
+}
+
+pub async fn self_update(session_config: SessionConfig) -> Result<Value, Error> {
+    let current = env!("CARGO_PKG_VERSION");
+
+    let releases_url = format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        RELEASE_REPOSITORY
+    );
+    let release = match http::get(&releases_url, &session_config).await {
+        Ok(body) => serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null),
+        Err(e) => {
+            let error = format!("Unable to check for updates: {}", e);
+            return Err(Error::new(ExitCode::NetworkError, error));
+        }
+    };
+
+    let latest = match release["tag_name"].as_str() {
+        Some(tag) => tag.trim_start_matches('v').to_string(),
+        None => return Err(Error::new(ExitCode::Unavailable, "No release found.")),
+    };
+
+    if parse_version(&latest) <= parse_version(current) {
+        return Ok(json!({
+            "current": current,
+            "latest": latest,
+            "updated": false,
+        }));
+    }
+
+    info!("Updating spotty from {} to {}", current, latest);
+
+    match download_update(&release, &latest, &session_config).await {
+        Ok(path) => Ok(json!({
+            "current": current,
+            "latest": latest,
+            "updated": true,
+            "path": path.to_string_lossy(),
+        })),
+        Err(e) => Err(Error::new(ExitCode::Error, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_signature() {
+        let public_key = "RWRERUxFliRKtAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4";
+        let checksums = format!("{}  spotty-linux-x86_64\n", "0".repeat(64));
+        let signature = "untrusted comment: signature from minisign secret key
+RURERUxFliRKtEC+bke28JSpW8zxOrvQY3CKPvg/eJBs87ldS+1CEIuAuaRcsvppqq3kHpV5Gx6n/F6JcZRTBhkRf3CTK7EpHgA=
+trusted comment: spotty v1.4.0
+jOcuK7TStP9LhjbKdr3jdCkYcfTKx0h5snUIZ9kEH6MJ2LZWY6WP0Rh2GODvfoqji7owlPyIWrtyOphl5QZJBQ==
+";
+        let verify = |checksums: &str, signature: &str, version| {
+            verify_signature(
+                checksums.as_bytes(),
+                signature.as_bytes(),
+                public_key,
+                version,
+            )
+        };
+
+        assert!(verify(&checksums, signature, "1.4.0").is_ok());
+
+        // an older release can't pass as the latest one
+        assert!(verify(&checksums, signature, "1.4.1").is_err());
+        let forged = signature.replace("spotty v1.4.0", "spotty v1.4.1");
+        assert!(verify(&checksums, &forged, "1.4.1").is_err());
+
+        let tampered = checksums.replace("x86_64", "aarch64");
+        assert!(verify(&tampered, signature, "1.4.0").is_err());
+        assert!(verify(&checksums, "", "1.4.0").is_err());
+    }
+}
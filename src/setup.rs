@@ -0,0 +1,3529 @@
+//! The configuration spotty runs with, parsed from the command line and the `LIBRESPOT_*`
+//! environment variables.
+//!
+//! ```no_run
+//! # use librespot::setup::{self, Args};
+//! let args = Args::parse(std::env::args().collect()).unwrap();
+//! let setup = setup::get_setup(&args).unwrap();
+//! ```
+
+use log::{debug, error, info, trace, warn};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use url::Url;
+
+use crate::core::authentication::Credentials;
+use crate::core::cache::Cache;
+use crate::core::config::{ConnectConfig, ControlPolicy, DeviceType, SessionConfig};
+use crate::core::dns::DnsResolver;
+use crate::core::version;
+use crate::discovery::MdnsBackend;
+use crate::playback::audio_backend::{self, SinkBuilder};
+use crate::playback::channel_mix::BALANCE_RANGE;
+use crate::playback::config::{
+    AudioFormat, Bitrate, ChannelMix, NormalisationMethod, NormalisationType, PlaybackSpeed,
+    PlayerConfig, TrackMarker, VolumeCtrl,
+};
+use crate::playback::dsp;
+use crate::playback::equalizer::parse_eq_bands;
+#[cfg(feature = "alsa-backend")]
+use crate::playback::mixer::alsamixer::AlsaMixer;
+use crate::playback::mixer::softmixer::SoftMixer;
+use crate::playback::mixer::{self, MixerConfig, MixerFn};
+use crate::playback::player::{coefficient_to_duration, duration_to_coefficient};
+use crate::playback::time_stretch::SPEED_RANGE;
+
+use crate::output_profiles::OutputProfiles;
+use crate::spotty::{
+    self, Alarm, ClientIds, Error, ExitCode, LogFormat, OnPlay, Reconnect, ReconnectPolicy,
+    RotatingFile, LMS,
+};
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(target_os = "windows")]
+const NULLDEVICE: &str = "NUL";
+#[cfg(not(target_os = "windows"))]
+const NULLDEVICE: &str = "/dev/null";
+
+const VALID_INITIAL_VOLUME_RANGE: RangeInclusive<u16> = 0..=100;
+const VALID_VOLUME_RANGE: RangeInclusive<f64> = 0.0..=100.0;
+const VALID_PRE_GAIN_RANGE: RangeInclusive<f64> = -30.0..=10.0;
+const VALID_NORMALISATION_KNEE_RANGE: RangeInclusive<f64> = 0.0..=10.0;
+const VALID_NORMALISATION_ATTACK_RANGE: RangeInclusive<u64> = 1..=500;
+const VALID_NORMALISATION_RELEASE_RANGE: RangeInclusive<u64> = 1..=1000;
+const ALSA_MIXER_CONTROL: &str = "alsa-mixer-control";
+const ALSA_MIXER_DEVICE: &str = "alsa-mixer-device";
+const ALSA_MIXER_INDEX: &str = "alsa-mixer-index";
+const AP_PORT: &str = "ap-port";
+const AP_ADDRESS: &str = "ap-address";
+const FORCE_TLS: &str = "force-tls";
+const BIND_ADDRESS: &str = "bind-address";
+const DNS_SERVER: &str = "dns-server";
+const DNS_OVER_HTTPS: &str = "dns-over-https";
+const NETWORK_TIMEOUT: &str = "network-timeout";
+const KEEPALIVE_INTERVAL: &str = "keepalive-interval";
+const AUTHENTICATE: &str = "authenticate";
+const AUTOPLAY: &str = "autoplay";
+const BITRATE: &str = "bitrate";
+const CACHE: &str = "cache";
+const CACHE_SIZE_LIMIT: &str = "cache-size-limit";
+const CACHE_STATS: &str = "cache-stats";
+const CREDENTIALS_PASSPHRASE_FILE: &str = "credentials-passphrase-file";
+const CREDENTIALS_KEYRING: &str = "credentials-keyring";
+const ACCOUNT: &str = "account";
+const LIST_ACCOUNTS: &str = "list-accounts";
+const JSON_ERRORS: &str = "json-errors";
+const EXPORT_CREDENTIALS: &str = "export-credentials";
+const IMPORT_CREDENTIALS: &str = "import-credentials";
+const CHECK: &str = "check";
+const CLIENT_ID: &str = "client-id";
+const CONTROL_POLICY: &str = "control-policy";
+const NO_EXPLICIT: &str = "no-explicit";
+const MAX_CONSECUTIVE_SKIPS: &str = "max-consecutive-skips";
+const VOLUME_STEPS: &str = "volume-steps";
+const MAX_VOLUME: &str = "max-volume";
+const QUANTIZE_VOLUME: &str = "quantize-volume";
+const VOLUME_DEBOUNCE: &str = "volume-debounce";
+const IDLE_TIMEOUT: &str = "idle-timeout";
+const RUNTIME_THREADS: &str = "runtime-threads";
+const LYRICS: &str = "lyrics";
+const CANVAS: &str = "canvas";
+const COLOR_PALETTE: &str = "color-palette";
+const SNAPCAST: &str = "snapcast";
+const SNAPCAST_METADATA: &str = "snapcast-metadata";
+const NO_EPISODES: &str = "no-episodes";
+const DISABLE_AUDIO_CACHE: &str = "disable-audio-cache";
+const DISABLE_DISCOVERY: &str = "disable-discovery";
+const DISABLE_GAPLESS: &str = "disable-gapless";
+const ENABLE_AUDIO_CACHE: &str = "enable-audio-cache";
+const ENABLE_VOLUME_NORMALISATION: &str = "enable-volume-normalisation";
+const EQ: &str = "eq";
+const PRE_GAIN: &str = "pre-gain";
+const LIMITER: &str = "limiter";
+const BALANCE: &str = "balance";
+const SWAP_CHANNELS: &str = "swap-channels";
+const SPEED: &str = "speed";
+const PROFILES: &str = "profiles";
+const PROFILE: &str = "profile";
+const DATA_CAP: &str = "data-cap";
+const MAX_DOWNLOAD_RATE: &str = "max-download-rate";
+const DRY_RUN: &str = "dry-run";
+const CHECK_CONFIG: &str = "check-config";
+const DOWNLOAD: &str = "download";
+const DAEMON: &str = "daemon";
+const KILL: &str = "kill";
+const PID_FILE: &str = "pid-file";
+const STATS_FILE: &str = "stats-file";
+const GET_METADATA: &str = "get-metadata";
+const GET_TOKEN: &str = "get-token";
+const HELP: &str = "help";
+const INITIAL_VOLUME: &str = "initial-volume";
+const LMS_AUTH: &str = "lms-auth";
+const LOGITECH_MEDIA_SERVER: &str = "lms";
+const MIXER_TYPE: &str = "mixer";
+const NAME: &str = "name";
+const NORMALISATION_GAIN_TYPE: &str = "normalisation-gain-type";
+const NORMALISATION_METHOD: &str = "normalisation-method";
+const NORMALISATION_ATTACK: &str = "normalisation-attack";
+const NORMALISATION_RELEASE: &str = "normalisation-release";
+const NORMALISATION_KNEE: &str = "normalisation-knee";
+const PASSTHROUGH: &str = "passthrough";
+const PASS_THROUGH: &str = "pass-through";
+const PASSWORD: &str = "password";
+const PASSWORD_FD: &str = "password-fd";
+const PLAYER_MAC: &str = "player-mac";
+const LMS_GROUP_VOLUME: &str = "lms-group-volume";
+const ON_PLAY: &str = "on-play";
+const PREFETCH: &str = "prefetch";
+const OUTPUT_DIR: &str = "output-dir";
+const PROXY: &str = "proxy";
+const SAVE_TOKEN: &str = "save-token";
+const TOKEN_INFO: &str = "token-info";
+const SCOPE: &str = "scope";
+const SINGLE_TRACK: &str = "single-track";
+const PLAYER_SERVER: &str = "player-server";
+const START_POSITION: &str = "start-position";
+const PREVIEW: &str = "preview";
+const STOP_POSITION: &str = "stop-position";
+const DURATION: &str = "duration";
+const STATS: &str = "stats";
+const QUIET: &str = "quiet";
+const REPLAYGAIN_TAGS: &str = "replaygain-tags";
+const METADATA_TAGS: &str = "metadata-tags";
+const TRACK_MARKER: &str = "track-marker";
+const TRACK_CHANGE_LEAD: &str = "track-change-lead";
+const PREFETCH_BYTES: &str = "prefetch-bytes";
+const PREFETCH_SECONDS: &str = "prefetch-seconds";
+const MAX_BUFFER_MB: &str = "max-buffer-mb";
+const TRACK_MARKER_FD: &str = "track-marker-fd";
+const USERNAME: &str = "username";
+const VERBOSE: &str = "verbose";
+const VERSION: &str = "version";
+const VOLUME_CTRL: &str = "volume-ctrl";
+const VOLUME_RANGE: &str = "volume-range";
+const ZEROCONF_PORT: &str = "zeroconf-port";
+const ZEROCONF_TXT: &str = "zeroconf-txt";
+const ZEROCONF_INTERFACE: &str = "zeroconf-interface";
+const MDNS_BACKEND: &str = "mdns-backend";
+const PAIR: &str = "pair";
+const SELF_UPDATE: &str = "self-update";
+const LOGIN_OAUTH: &str = "login-oauth";
+const RECONNECT: &str = "reconnect";
+const RECONNECT_DELAY: &str = "reconnect-delay";
+const RECONNECT_MAX_DELAY: &str = "reconnect-max-delay";
+const DEVICE_TYPE: &str = "device-type";
+const DEVICE_ID: &str = "device-id";
+const DEVICE_BRAND: &str = "device-brand";
+const DEVICE_MODEL: &str = "device-model";
+const STATUS_PORT: &str = "status-port";
+const CONTROL_PORT: &str = "control-port";
+const CONTROL_TOKEN: &str = "control-token";
+const BUFFER_DEBUG: &str = "buffer-debug";
+const ANALYZE_LOUDNESS: &str = "analyze-loudness";
+const OFFLINE_FALLBACK: &str = "offline-fallback";
+const TAKE_OVER: &str = "take-over";
+const RESUME_ON_START: &str = "resume-on-start";
+const PLAY_AT: &str = "play-at";
+const PLAY_AT_URI: &str = "uri";
+const PLAY_AT_FADE_IN: &str = "play-at-fade-in";
+const LOG_FORMAT: &str = "log-format";
+const NO_LOG: &str = "no-log";
+const LOG_FD: &str = "log-fd";
+const LOG_FILE: &str = "log-file";
+const LOG_FILE_SIZE: &str = "log-file-size";
+const LOG_FILE_COUNT: &str = "log-file-count";
+
+// Mostly arbitrary.
+const ALSA_MIXER_CONTROL_SHORT: &str = "";
+const ALSA_MIXER_DEVICE_SHORT: &str = "";
+const ALSA_MIXER_INDEX_SHORT: &str = "";
+const AUTHENTICATE_SHORT: &str = "a";
+const AUTOPLAY_SHORT: &str = "A";
+const AP_PORT_SHORT: &str = "";
+const BITRATE_SHORT: &str = "b";
+const CACHE_SHORT: &str = "c";
+const CACHE_SIZE_LIMIT_SHORT: &str = "M";
+const DISABLE_AUDIO_CACHE_SHORT: &str = "G";
+const ENABLE_AUDIO_CACHE_SHORT: &str = "";
+const DISABLE_GAPLESS_SHORT: &str = "g";
+const HELP_SHORT: &str = "h";
+const CLIENT_ID_SHORT: &str = "i";
+const CONTROL_POLICY_SHORT: &str = "";
+const DATA_CAP_SHORT: &str = "";
+const ENABLE_VOLUME_NORMALISATION_SHORT: &str = "N";
+const EQ_SHORT: &str = "";
+const MIXER_TYPE_SHORT: &str = "m";
+const NAME_SHORT: &str = "n";
+const DISABLE_DISCOVERY_SHORT: &str = "O";
+const PASSTHROUGH_SHORT: &str = "P";
+const PASSWORD_SHORT: &str = "p";
+const QUIET_SHORT: &str = "q";
+const REPLAYGAIN_TAGS_SHORT: &str = "";
+const INITIAL_VOLUME_SHORT: &str = "R";
+const GET_TOKEN_SHORT: &str = "t";
+const SAVE_TOKEN_SHORT: &str = "T";
+const USERNAME_SHORT: &str = "u";
+const VERSION_SHORT: &str = "V";
+const VERBOSE_SHORT: &str = "v";
+const NORMALISATION_GAIN_TYPE_SHORT: &str = "W";
+const CHECK_SHORT: &str = "x";
+const VOLUME_CTRL_SHORT: &str = "";
+const VOLUME_RANGE_SHORT: &str = "";
+const PROXY_SHORT: &str = "";
+const ZEROCONF_PORT_SHORT: &str = "z";
+
+// Options that have different desc's
+// depending on what backends were enabled at build time.
+#[cfg(feature = "alsa-backend")]
+const VOLUME_RANGE_DESC: &str = "Range of the volume control (dB) from 0.0 to 100.0. Only used with the cubic and log scales. Default for softvol: 60.0. For the alsa mixer: what the control supports.";
+#[cfg(not(feature = "alsa-backend"))]
+const VOLUME_RANGE_DESC: &str = "Range of the volume control (dB) from 0.0 to 100.0. Only used with the cubic and log scales. Defaults to 60.0.";
+#[cfg(feature = "alsa-backend")]
+const INITIAL_VOLUME_DESC: &str = "Initial volume in % from 0 - 100. Default for softvol: 50. For the alsa mixer: the current volume.";
+#[cfg(not(feature = "alsa-backend"))]
+const INITIAL_VOLUME_DESC: &str = "Initial volume in % from 0 - 100. Defaults to 50.";
+
+fn options() -> getopts::Options {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        HELP_SHORT,
+        HELP,
+        "Print this help menu.",
+    )
+    .optflag(
+        VERSION_SHORT,
+        VERSION,
+        "Display librespot version string.",
+    )
+    .optflag(
+        VERBOSE_SHORT,
+        VERBOSE,
+        "Enable verbose log output.",
+    )
+    .optflag(
+        QUIET_SHORT,
+        QUIET,
+        "Only log warning and error messages.",
+    )
+    .optflag(
+        "",
+        NO_LOG,
+        "Disable logging entirely.",
+    )
+    .optopt(
+        "",
+        LOG_FORMAT,
+        "Log format {text|json}. Defaults to text.",
+        "FORMAT",
+    )
+    .optopt(
+        "",
+        LOG_FD,
+        "Write the log to this already opened file descriptor instead of stderr (Unix only). Can't be stdout.",
+        "FD",
+    )
+    .optopt(
+        "",
+        LOG_FILE,
+        "Write the log to this file instead of stderr.",
+        "PATH",
+    )
+    .optopt(
+        "",
+        LOG_FILE_SIZE,
+        "Start a new log file once it reaches this size, eg. 500K or 10M. Defaults to 10M.",
+        "SIZE",
+    )
+    .optopt(
+        "",
+        LOG_FILE_COUNT,
+        "Number of old log files to keep, as PATH.1 to PATH.COUNT. Defaults to 3.",
+        "COUNT",
+    )
+    .optflag(
+        DISABLE_AUDIO_CACHE_SHORT,
+        DISABLE_AUDIO_CACHE,
+        "(Only here fore compatibility with librespot - audio cache is disabled by default).",
+    )
+    .optflag(
+        ENABLE_AUDIO_CACHE_SHORT,
+        ENABLE_AUDIO_CACHE,
+        "Enable caching of the audio data."
+    )
+    .optflag(
+        DISABLE_DISCOVERY_SHORT,
+        DISABLE_DISCOVERY,
+        "Disable zeroconf discovery mode.",
+    )
+    .optflag(
+        DISABLE_GAPLESS_SHORT,
+        DISABLE_GAPLESS,
+        "Disable gapless playback.",
+    )
+    .optflag(
+        AUTOPLAY_SHORT,
+        AUTOPLAY,
+        "Automatically play similar songs when your music ends.",
+    )
+    .optflag(
+        PASSTHROUGH_SHORT,
+        PASSTHROUGH,
+        "Pass a raw stream to the output. Only works with the pipe and subprocess backends.",
+    )
+    .optflag(
+        ENABLE_VOLUME_NORMALISATION_SHORT,
+        ENABLE_VOLUME_NORMALISATION,
+        "Play all tracks at approximately the same apparent volume.",
+    )
+    .optflag(
+        REPLAYGAIN_TAGS_SHORT,
+        REPLAYGAIN_TAGS,
+        "Add the track's normalisation data as ReplayGain tags to the passthrough stream.",
+    )
+    .optflag(
+        "",
+        METADATA_TAGS,
+        "Add title, artists, album, track and disc number as tags to the passthrough stream.",
+    )
+    .optopt(
+        "",
+        PREFETCH_BYTES,
+        "Keep at least this much audio downloaded ahead of playback, eg. 512K or 2M, and download the rest of the track once it plays.",
+        "SIZE",
+    )
+    .optopt(
+        "",
+        PREFETCH_SECONDS,
+        "Keep at least this many seconds of audio downloaded ahead of playback, and download the rest of the track once it plays.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        MAX_BUFFER_MB,
+        "Download at most this many MB of a track ahead of playback, as downloads are held in the system's temporary directory, which may be in memory. With a `--cache` they're kept in it instead.",
+        "MB",
+    )
+    .optopt(
+        "",
+        TRACK_MARKER,
+        "Write these hex encoded bytes to the output where a new track starts.",
+        "HEX",
+    )
+    .optopt(
+        "",
+        TRACK_CHANGE_LEAD,
+        "Tell LMS this many seconds before a track ends that the next one is about to start, eg. to fetch its artwork in time. Measured at the decoder, ahead of LMS' buffer.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        TRACK_MARKER_FD,
+        "Write the byte position in the output where a new track starts and its URI as a line to this file descriptor. Unix only.",
+        "FD",
+    )
+    .optopt(
+        NAME_SHORT,
+        NAME,
+        "Device name. Defaults to Spotty.",
+        "NAME",
+    )
+    .optopt(
+        BITRATE_SHORT,
+        BITRATE,
+        "Bitrate (kbps) {96|160|320}. Defaults to 160.",
+        "BITRATE",
+    )
+    .optopt(
+        CACHE_SHORT,
+        CACHE,
+        "Path to a directory where files will be cached.",
+        "PATH",
+    )
+    .optopt(
+        CACHE_SIZE_LIMIT_SHORT,
+        CACHE_SIZE_LIMIT,
+        "Limits the size of the audio file cache, eg. 500M or 2G. The least recently used files are removed first.",
+        "SIZE",
+    )
+    .optopt(
+        USERNAME_SHORT,
+        USERNAME,
+        "Username used to sign in with.",
+        "USERNAME",
+    )
+    .optopt(
+        PASSWORD_SHORT,
+        PASSWORD,
+        "Password used to sign in with.",
+        "PASSWORD",
+    )
+    .optopt(
+        "",
+        PASSWORD_FD,
+        "Read the password used to sign in with from a file descriptor, so it doesn't show in the process list. Defaults to the systemd credential spotty-password, if any.",
+        "FD",
+    )
+    .optopt(
+        INITIAL_VOLUME_SHORT,
+        INITIAL_VOLUME,
+        INITIAL_VOLUME_DESC,
+        "VOLUME",
+    )
+    .optopt(
+        VOLUME_CTRL_SHORT,
+        VOLUME_CTRL,
+        "Volume control scale type {cubic|fixed|linear|log}. Use fixed to keep the volume at 100% and control it downstream. Defaults to linear.",
+        "VOLUME_CTRL",
+    )
+    .optopt(
+        VOLUME_RANGE_SHORT,
+        VOLUME_RANGE,
+        VOLUME_RANGE_DESC,
+        "RANGE",
+    )
+    .optopt(
+        MIXER_TYPE_SHORT,
+        MIXER_TYPE,
+        "Mixer to use {alsa|softvol}. Defaults to softvol.",
+        "MIXER",
+    )
+    .optopt(
+        ALSA_MIXER_CONTROL_SHORT,
+        ALSA_MIXER_CONTROL,
+        "Alsa mixer control, e.g. PCM, Master or similar. Defaults to PCM.",
+        "NAME",
+    )
+    .optopt(
+        ALSA_MIXER_DEVICE_SHORT,
+        ALSA_MIXER_DEVICE,
+        "Alsa mixer device, e.g. hw:0 or similar from `aplay -l`. Defaults to default.",
+        "DEVICE",
+    )
+    .optopt(
+        ALSA_MIXER_INDEX_SHORT,
+        ALSA_MIXER_INDEX,
+        "Alsa index of the cards mixer. Defaults to 0.",
+        "NUMBER",
+    )
+    .optopt(
+        NORMALISATION_GAIN_TYPE_SHORT,
+        NORMALISATION_GAIN_TYPE,
+        "Specify the normalisation gain type to use {track|album|auto}. Defaults to auto.",
+        "TYPE",
+    )
+    .optopt(
+        "",
+        NORMALISATION_METHOD,
+        "Specify the normalisation method to use {basic|dynamic}. Dynamic raises quiet tracks further and limits their peaks instead. Defaults to basic.",
+        "METHOD",
+    )
+    .optopt(
+        "",
+        NORMALISATION_ATTACK,
+        "Attack time in ms in which the dynamic limiter reduces gain, from 1 to 500. Also used by the limiter. Defaults to 5.",
+        "TIME",
+    )
+    .optopt(
+        "",
+        NORMALISATION_RELEASE,
+        "Release or decay time in ms in which the dynamic limiter restores gain, from 1 to 1000. Also used by the limiter. Defaults to 100.",
+        "TIME",
+    )
+    .optopt(
+        "",
+        NORMALISATION_KNEE,
+        "Knee width in dB of the dynamic limiter from 0.0 to 10.0. Also used by the limiter. Defaults to 5.0.",
+        "KNEE",
+    )
+    .optopt(
+        EQ_SHORT,
+        EQ,
+        "Equalizer bands as FREQ:GAIN[:Q] in Hz and dB, eg. \"60:+3,1000:0,8000:-2\". Use bass:GAIN and treble:GAIN for simple tone controls. May also be the path to a file with one band per line. Has no effect in passthrough mode.",
+        "BANDS",
+    )
+    .optopt(
+        "",
+        PRE_GAIN,
+        "Gain in dB from -30 to +10 applied to all output, with or without normalisation, eg. -6 to avoid overloading a DAC's inputs. A boost is lowered for tracks that would clip. Defaults to 0. Has no effect in passthrough mode.",
+        "DB",
+    )
+    .optflag(
+        "",
+        LIMITER,
+        "Soft limit the output at -2 dBFS, with or without normalisation, to protect the amplifier from the peaks of positive EQ or pre-gain. Has no effect in passthrough mode.",
+    )
+    .optopt(
+        "",
+        BALANCE,
+        "Balance between the left and the right speaker from -100 (left only) to +100 (right only). Defaults to 0, or the balance last set through the control endpoint. Has no effect in passthrough mode.",
+        "BALANCE",
+    )
+    .optflag(
+        "",
+        SWAP_CHANNELS,
+        "Swap the left and the right channel. Has no effect in passthrough mode.",
+    )
+    .optopt(
+        "",
+        SPEED,
+        "Play podcast episodes faster without raising the pitch, from 1.0 to 3.0. Defaults to 1.0. Has no effect in passthrough mode.",
+        "SPEED",
+    )
+    .optopt(
+        "",
+        PROFILES,
+        "Path to a JSON file of output profiles to switch between through the control endpoint, eg. {\"night\": {\"maxVolume\": 40, \"normalisation\": true, \"eq\": \"bass:-6\"}}. Settings a profile leaves out keep the ones given here.",
+        "FILE",
+    )
+    .optopt(
+        "",
+        PROFILE,
+        "Output profile of --profiles to start with.",
+        "NAME",
+    )
+    .optopt(
+        ZEROCONF_PORT_SHORT,
+        ZEROCONF_PORT,
+        "The port the internal server advertises over zeroconf 1 - 65535. Ports <= 1024 may require root privileges.",
+        "PORT",
+    )
+    .optopt(
+        "",
+        ZEROCONF_INTERFACE,
+        "Only announce the device and listen for zeroconf connections on this network interface, given by name or address. Defaults to all interfaces.",
+        "IP|IFACE",
+    )
+    .optflag(
+        "",
+        PAIR,
+        "Link this device to an account by entering a code on another device, for networks where zeroconf does not work. Prints the code as JSON and exits once linked.",
+    )
+    .optflag(
+        "",
+        LOGIN_OAUTH,
+        "Log in through a browser on this machine instead of with a password. Prints the URL to open as JSON and exits once logged in.",
+    )
+    .optflag(
+        "",
+        SELF_UPDATE,
+        "Replace this binary with the latest release for this platform, if there is a newer one. Prints the result as JSON and exits.",
+    )
+    .optopt(
+        "",
+        RECONNECT,
+        "When to reconnect after losing the connection {never|limited|forever}. limited gives up after 5 attempts within 10 minutes. Defaults to limited.",
+        "POLICY",
+    )
+    .optopt(
+        "",
+        RECONNECT_DELAY,
+        "Seconds to wait before the first reconnection attempt, doubled for every further one, up to a day. Defaults to 1.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        RECONNECT_MAX_DELAY,
+        "Maximum number of seconds to wait between reconnection attempts, up to a day. Defaults to 300.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        STATUS_PORT,
+        "Serve the session, playback, cache and LMS status as JSON on this port, and the data usage for Prometheus on /metrics, eg. for monitoring. Only on localhost, unless preceded by the IP address to listen on, like 0.0.0.0:8080.",
+        "[IP:]PORT",
+    )
+    .optopt(
+        "",
+        CONTROL_PORT,
+        "Accept playback commands like {\"command\": \"next\"}, \"shuffle on\" or \"like\", POSTed as application/json to this port, on localhost unless preceded by the IP address to listen on, with the token of `--control-token` as `Authorization: Bearer TOKEN`. \"like\", \"follow\" and \"playlist add URI\" save the current track, follow its artists or add it to a playlist, \"playlists\" lists those it can be added to. They need a client ID. \"load URI [shuffle on|off] [offset N|URI]\" plays an album, playlist, artist, show or track. \"seek 1:30\", \"seek +30s\" and \"chapter next|prev\" move within the current track or podcast episode, \"speed 1.5\" sets the speed of podcast episodes. Intents of voice assistants, like {\"play\": {\"artist\": \"Miles Davis\"}}, are POSTed to /intent, and play the best match of a search, which needs a client ID.",
+        "[IP:]PORT",
+    )
+    .optopt(
+        "",
+        CONTROL_TOKEN,
+        "Token the requests to `--control-port` need. Defaults to a random one, saved to control_token in the cache folder.",
+        "TOKEN",
+    )
+    .optopt(
+        "",
+        BUFFER_DEBUG,
+        "Write the buffer fill, sink backpressure and network rate to stderr as a JSON line every SECONDS, to tune the prefetch and LMS buffer sizes.",
+        "SECONDS",
+    )
+    .optflag(
+        "",
+        ANALYZE_LOUDNESS,
+        "Measure the EBU R128 integrated loudness and true peak of each played track, as heard after normalisation and volume, and log it. Has no effect in passthrough mode.",
+    )
+    .optflag(
+        "",
+        OFFLINE_FALLBACK,
+        "Play tracks from the audio cache when Spotify can't be reached. Requires a cache with the audio cache enabled; tracks are only available offline once they were played online.",
+    )
+    .optflag(
+        "",
+        TAKE_OVER,
+        "Continue the playback of the currently active Spotify Connect device on this one once connected.",
+    )
+    .optflag(
+        "",
+        RESUME_ON_START,
+        "Resume what this device played last once connected, unless another device is playing. Requires --cache.",
+    )
+    .optmulti(
+        "",
+        PLAY_AT,
+        "Start playing --uri on this device at a time like 7:30, 7:30 mon-fri or a cron expression like \"30 7 * * 1-5\". Can be given several times.",
+        "SCHEDULE",
+    )
+    .optopt(
+        "",
+        PLAY_AT_URI,
+        "Album, playlist or track to play with --play-at.",
+        "URI",
+    )
+    .optopt(
+        "",
+        PLAY_AT_FADE_IN,
+        "Seconds to fade in the playback started by --play-at, 0 to disable. Defaults to 30.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        MDNS_BACKEND,
+        "Implementation used to announce the device over zeroconf {libmdns|avahi|dnssd}. Defaults to dnssd if available, libmdns otherwise.",
+        "BACKEND",
+    )
+    .optmulti(
+        "",
+        ZEROCONF_TXT,
+        "Additional TXT record advertised over zeroconf. Can be given several times.",
+        "KEY=VALUE",
+    )
+    .optopt(
+        "",
+        DEVICE_TYPE,
+        "Displayed device type {speaker|avr|stb|computer|tv|audiodongle|...}. Defaults to speaker.",
+        "TYPE",
+    )
+    .optopt(
+        "",
+        DEVICE_ID,
+        "Device ID announced to Spotify. Defaults to the ID used before with the same --name and --cache, or one derived from --name.",
+        "ID",
+    )
+    .optopt(
+        "",
+        DEVICE_BRAND,
+        "Brand name shown for this device in Spotify clients. Defaults to librespot.",
+        "BRAND",
+    )
+    .optopt(
+        "",
+        DEVICE_MODEL,
+        "Model name shown for this device in Spotify clients. Defaults to librespot.",
+        "MODEL",
+    )
+    .optopt(
+        CONTROL_POLICY_SHORT,
+        CONTROL_POLICY,
+        "Who wins when LMS and Spotify Connect commands arrive at the same time {last|lms|connect}. Defaults to last.",
+        "POLICY",
+    )
+    .optflag(
+        "",
+        NO_EXPLICIT,
+        "Skip explicit tracks, even if the account allows explicit content.",
+    )
+    .optopt(
+        "",
+        MAX_CONSECUTIVE_SKIPS,
+        "Stop playback after skipping this many unplayable tracks in a row. Defaults to 10.",
+        "NUMBER",
+    )
+    .optopt(
+        "",
+        VOLUME_STEPS,
+        "Volume steps announced to Spotify clients, 0 for a fixed volume eg. with digital outputs. Defaults to 64, or 0 with --volume-ctrl fixed.",
+        "STEPS",
+    )
+    .optopt(
+        "",
+        MAX_VOLUME,
+        "Highest volume in % from 0 - 100 Spotify clients can set, eg. to protect small speakers. Defaults to 100.",
+        "VOLUME",
+    )
+    .optflag(
+        "",
+        QUANTIZE_VOLUME,
+        "Round the volume set by Spotify clients to the volume steps, so dragging the volume slider changes it less often.",
+    )
+    .optopt(
+        "",
+        VOLUME_DEBOUNCE,
+        "Tell LMS about at most one volume change per MS milliseconds while the volume slider is dragged in a Spotify app, the latest one. Defaults to 0, every change.",
+        "MS",
+    )
+    .optopt(
+        "",
+        IDLE_TIMEOUT,
+        "Disconnect after being paused or stopped for this many minutes, eg. to save the battery. Spotify clients can connect again through discovery, and \"play\" through the control port reconnects and resumes.",
+        "MINUTES",
+    )
+    .optflag(
+        "",
+        LYRICS,
+        "Get the lyrics of each track, for LMS and the status endpoint's /lyrics. Synced lyrics are sent to LMS line by line as they're sung.",
+    )
+    .optflag(
+        "",
+        CANVAS,
+        "Send the URL of each track's Canvas, the short looping video shown by the Spotify apps, to LMS along with the track's details.",
+    )
+    .optflag(
+        "",
+        COLOR_PALETTE,
+        "Send the dominant colours of each track's cover art to LMS along with the track's details, eg. for displays to match their background like the Spotify apps.",
+    )
+    .optflagopt(
+        "",
+        SNAPCAST,
+        "Play in Connect mode rather than leave the playback to LMS, and stream to a Snapcast server through the named pipe of a pipe source, /tmp/snapfifo by default, or to a TCP source in server mode at tcp://HOST:PORT. The sample format of the source must be 44100:16:2.",
+        "PIPE",
+    )
+    .optopt(
+        "",
+        SNAPCAST_METADATA,
+        "Append the metadata and playback status of the current track to this file or named pipe, as the JSON-RPC notifications of a Snapcast stream control script, one per line.",
+        "PATH",
+    )
+    .optopt(
+        "",
+        RUNTIME_THREADS,
+        "Run the network, decryption and LMS communication on this many threads instead of a single one, for busy hosts. It doesn't spread the decoding and sample processing, eg. the equalizer and time stretching, they run on the player's own thread either way. Defaults to 1.",
+        "N",
+    )
+    .optflag(
+        "",
+        NO_EPISODES,
+        "Don't announce support for podcast episodes to Spotify clients.",
+    )
+    .optopt(
+        PROXY_SHORT,
+        PROXY,
+        "HTTP(S) proxy to use when connecting, optionally with user:password. Defaults to HTTPS_PROXY or HTTP_PROXY.",
+        "URL",
+    )
+    .optopt(
+        DATA_CAP_SHORT,
+        DATA_CAP,
+        "Soft limit of the data (in MB) downloaded per calendar month (UTC). Once exceeded the lowest bitrate is used. Requires a cache.",
+        "MB",
+    )
+    .optopt(
+        "",
+        MAX_DOWNLOAD_RATE,
+        "Limit the average rate audio data is downloaded at (in kbit/s).",
+        "KBPS",
+    )
+    .optopt(
+        AP_PORT_SHORT,
+        AP_PORT,
+        "Connect to an AP with a specified port 1 - 65535. If no AP with that port is present a fallback AP will be used. Available ports are usually 80, 443 and 4070. Several ports can be given in order of preference, eg. 443,4070,80, each of them is given 10 seconds or `--network-timeout` to connect before the next one is tried.",
+        "PORT[,PORT...]",
+    )
+    .optopt(
+        "",
+        AP_ADDRESS,
+        "Always connect to this AP instead of resolving one, eg. ap-gae2.spotify.com:4070.",
+        "HOST:PORT",
+    )
+    .optflag(
+        "",
+        FORCE_TLS,
+        "Only connect to APs on port 443 and resolve them over HTTPS, never falling back to other ports. A proxy has to be an https:// one.",
+    )
+    .optopt(
+        "",
+        BIND_ADDRESS,
+        "Local IPv4 or IPv6 address to make connections to Spotify and the proxy from.",
+        "IP",
+    )
+    .optopt(
+        "",
+        DNS_SERVER,
+        "Resolve the addresses of Spotify's servers and the proxy with this DNS server instead of the system's resolver.",
+        "IP[:PORT]",
+    )
+    .optopt(
+        "",
+        DNS_OVER_HTTPS,
+        "Resolve the addresses of Spotify's servers and the proxy with this DNS-over-HTTPS endpoint, eg. https://cloudflare-dns.com/dns-query.",
+        "URL",
+    )
+    .optopt(
+        "",
+        NETWORK_TIMEOUT,
+        "Give up connecting to an AP after this many seconds 1 - 300, and consider the connection lost when the AP stays silent for this long beyond its 2 minute ping interval. Defaults to waiting for the operating system.",
+        "SECONDS",
+    )
+    .optopt(
+        "",
+        KEEPALIVE_INTERVAL,
+        "Probe the AP connection with TCP keepalives after this many seconds 1 - 3600 without traffic, and at this interval until it responds.",
+        "SECONDS",
+    )
+    // spotty
+    .optflag(
+        AUTHENTICATE_SHORT,
+        AUTHENTICATE,
+        "Authenticate given username and password. Make sure you define a cache folder to store credentials."
+    )
+    .optopt(
+        "",
+        SINGLE_TRACK,
+        "Play a single track ID and exit.",
+        "ID"
+    )
+    .optopt(
+        "",
+        PLAYER_SERVER,
+        "Keep a session and a player open and stream the track requested with GET /play/ID?start=MS&next=ID to the caller, on this port on localhost, instead of starting a process per track. The player goes on to the optional next track without a gap, which is to be requested next.",
+        "PORT"
+    )
+    .optopt(
+        "",
+        PREVIEW,
+        "Write the 30 second MP3 preview of a track ID to stdout and exit. Needs no Premium, with credentials it plays at the loudness of the normalised track.",
+        "ID"
+    )
+    .optopt(
+        "",
+        START_POSITION,
+        "Position where playback should be started, eg. 90 or 90s (seconds), 90000ms, 1:30 or 00:01:30.500. Only valid with the --single-track option.",
+        "STARTPOSITION"
+    )
+    .optopt(
+        "",
+        STOP_POSITION,
+        "Position where playback should end, in the same format as --start-position. Only valid with the --single-track option.",
+        "STOPPOSITION"
+    )
+    .optopt(
+        "",
+        DURATION,
+        "How long to play from --start-position, eg. 30s for a preview clip. Only valid with the --single-track option.",
+        "DURATION"
+    )
+    .optflag(
+        CHECK_SHORT,
+        CHECK,
+        "Run quick internal check"
+    )
+    .optopt(
+        "",
+        PREFETCH,
+        "Download a track, album or playlist into the audio cache without playing it and exit.",
+        "URI"
+    )
+    .optopt(
+        "",
+        DOWNLOAD,
+        "Save the Ogg files of a track, album or playlist with their tags and exit.",
+        "URI"
+    )
+    .optopt(
+        "",
+        OUTPUT_DIR,
+        "Directory to save files to with --download.",
+        "DIR"
+    )
+    .optflag(
+        "",
+        CACHE_STATS,
+        "Print the size of the audio file cache as JSON and exit."
+    )
+    .optopt(
+        "",
+        CREDENTIALS_PASSPHRASE_FILE,
+        "Encrypt the cached credentials with a key derived from the passphrase in this file. The passphrase can also be set in the LIBRESPOT_CREDENTIALS_PASSPHRASE environment variable.",
+        "FILE"
+    )
+    .optflag(
+        "",
+        CREDENTIALS_KEYRING,
+        "Encrypt the cached credentials with a passphrase kept in the OS keyring."
+    )
+    .optopt(
+        "",
+        ACCOUNT,
+        "Use the cached credentials of this account instead of the last used ones.",
+        "USERNAME"
+    )
+    .optflag(
+        "",
+        LIST_ACCOUNTS,
+        "Print the accounts with cached credentials as JSON and exit."
+    )
+    .optflag(
+        "",
+        JSON_ERRORS,
+        "Write a JSON object with the reason to stderr when exiting with an error.",
+    )
+    .optopt(
+        "",
+        EXPORT_CREDENTIALS,
+        "Write the cached credentials, or those of --account, to a file and exit. Encrypted if a credentials passphrase is set. Anyone with the file can use your account.",
+        "FILE"
+    )
+    .optopt(
+        "",
+        IMPORT_CREDENTIALS,
+        "Cache the credentials from a file written by --export-credentials and exit. Requires the same credentials passphrase if it was encrypted.",
+        "FILE"
+    )
+    .optflag(
+        "",
+        DAEMON,
+        "Run in the background and write the PID file (Unix only). Use --log-file to keep the log.",
+    )
+    .optflag(
+        "",
+        KILL,
+        "Stop the instance running in the background, as found in the PID file, and exit (Unix only).",
+    )
+    .optopt(
+        "",
+        PID_FILE,
+        "PID file for --daemon and --kill. Defaults to spotty.pid in the cache directory.",
+        "PATH",
+    )
+    .optopt(
+        "",
+        STATS_FILE,
+        "Write playback statistics as JSON to this file on exit, eg. to find out why tracks stutter.",
+        "PATH",
+    )
+    .optflag(
+        "",
+        STATS,
+        "Print the data usage stored in the cache as JSON and exit."
+    )
+    .optflag(
+        "",
+        DRY_RUN,
+        "Validate the configuration, LMS connection, cache and backend, print a JSON report and exit."
+    )
+    .optflag(
+        "",
+        CHECK_CONFIG,
+        "Like --dry-run, but also verify the credentials by logging in to Spotify."
+    )
+    .optopt(
+        "",
+        GET_METADATA,
+        "Print codec and normalisation data of a track ID as JSON and exit.",
+        "ID"
+    )
+    .optmulti(
+        CLIENT_ID_SHORT,
+        CLIENT_ID,
+        "A Spotify client_id to be used to get the oauth token. Required with the --get-token request. Can be given several times as CLIENT_ID:SCOPE1,SCOPE2 to use a client_id for the scopes it is registered for, and the built-in one for all others.",
+        "CLIENT_ID[:SCOPES]"
+    )
+    .optopt(
+        "",
+        SCOPE,
+        "The scopes you want to have access to with the oauth token.",
+        "SCOPE"
+    )
+    .optflag(
+        GET_TOKEN_SHORT,
+        GET_TOKEN,
+        "Get oauth token to be used with the web API etc. and print it to the console."
+    )
+    .optopt(
+        SAVE_TOKEN_SHORT,
+        SAVE_TOKEN,
+        "Get oauth token to be used with the web API etc. and store it in the given file.",
+        "TOKENFILE"
+    )
+    .optopt(
+        "",
+        TOKEN_INFO,
+        "Print the client ID, scopes and remaining validity of a token saved with --save-token as JSON and exit.",
+        "FILE",
+    )
+    .optflag(
+        "",
+        PASS_THROUGH,
+        "Pass raw stream to output, only works for \"pipe\"."
+    )
+    .optopt(
+        "",
+        LOGITECH_MEDIA_SERVER,
+        "hostname and port of Logitech Media Server instance (eg. localhost:9000)",
+        "LMS"
+    )
+    .optopt(
+        "",
+        LMS_AUTH,
+        "Authentication data to access Logitech Media Server",
+        "LMSAUTH"
+    )
+    .optopt(
+        "",
+        PLAYER_MAC,
+        "MAC address of the Squeezebox to be controlled",
+        "MAC"
+    )
+    .optflag(
+        "",
+        LMS_GROUP_VOLUME,
+        "Apply volume changes to all players synced with the Squeezebox."
+    )
+    .optopt(
+        "",
+        ON_PLAY,
+        "What to do when Connect playback starts while the Squeezebox is switched off {power-on|ignore|block}. Block pauses playback. Defaults to ignore.",
+        "ACTION"
+    );
+
+    opts
+}
+
+fn device_id(name: &str) -> String {
+    hex::encode(Sha1::digest(name.as_bytes()))
+}
+
+// Logging is set up by the time the options are checked, and the binary only reports the error
+// it exits with, so what's valid is logged here
+fn invalid_error(
+    long: &str,
+    short: &str,
+    invalid: &str,
+    valid_values: &str,
+    default_value: &str,
+) -> Error {
+    error!("Invalid `--{}` / `-{}`: \"{}\"", long, short, invalid);
+
+    if !valid_values.is_empty() {
+        error!("Valid `--{}` / `-{}` values: {}", long, short, valid_values);
+    }
+
+    if !default_value.is_empty() {
+        error!("Default: {}", default_value);
+    }
+
+    let error = format!("Invalid `--{}`: \"{}\"", long, invalid);
+    Error::new(ExitCode::InvalidArguments, error)
+}
+
+fn empty_string_error(long: &str, short: &str) -> Error {
+    error!("`--{}` / `-{}` can not be an empty string", long, short);
+
+    let error = format!("`--{}` can not be an empty string", long);
+    Error::new(ExitCode::InvalidArguments, error)
+}
+
+fn setup_error(code: ExitCode, error: impl Into<String>) -> Error {
+    let error = error.into();
+    error!("{}", error);
+    Error::new(code, error)
+}
+
+// Logging isn't set up yet when the command is parsed, the binary shows the error
+fn command_error(error: impl Into<String>) -> Error {
+    Error::new(ExitCode::InvalidArguments, error)
+}
+
+// The flags from before there were subcommands, each standing for a command. When several are
+// set the first one wins, in the order they were checked in back then.
+const COMMAND_FLAGS: [&str; 18] = [
+    CACHE_STATS,
+    TOKEN_INFO,
+    LIST_ACCOUNTS,
+    EXPORT_CREDENTIALS,
+    IMPORT_CREDENTIALS,
+    STATS,
+    DRY_RUN,
+    CHECK_CONFIG,
+    SELF_UPDATE,
+    PAIR,
+    LOGIN_OAUTH,
+    SINGLE_TRACK,
+    PLAYER_SERVER,
+    PREVIEW,
+    GET_METADATA,
+    PREFETCH,
+    DOWNLOAD,
+    GET_TOKEN,
+];
+
+/// What to do, chosen with a subcommand or one of the flags from before there were
+/// subcommands.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    Play(String),
+    PlayerServer(u16),
+    Preview(String),
+    Metadata(String),
+    Prefetch(String),
+    Download(String, PathBuf),
+    Token,
+    TokenInfo(String),
+    CacheStats,
+    ListAccounts,
+    ExportCredentials(PathBuf),
+    ImportCredentials(PathBuf),
+    DataUsage,
+    DryRun { check_config: bool },
+    Pair,
+    LoginOAuth,
+    SelfUpdate,
+}
+
+impl Command {
+    // Fetching tracks or tokens, or logging in, is done without discovery
+    fn uses_discovery(&self) -> bool {
+        !matches!(
+            self,
+            Command::Play(_)
+                | Command::PlayerServer(_)
+                | Command::Preview(_)
+                | Command::Metadata(_)
+                | Command::Prefetch(_)
+                | Command::Download(..)
+                | Command::Token
+                | Command::Pair
+                | Command::LoginOAuth
+                | Command::SelfUpdate
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseFileSizeError {
+    #[error("empty argument")]
+    EmptyInput,
+    #[error("invalid suffix")]
+    InvalidSuffix,
+    #[error("invalid number: {0}")]
+    InvalidNumber(#[from] std::num::ParseFloatError),
+    #[error("non-finite number specified")]
+    NotFinite(f64),
+    #[error("negative size")]
+    Negative,
+}
+
+// Sizes like 500M, 2G or 1.5GiB. SI suffixes are powers of 1000, IEC suffixes powers of 1024.
+pub fn parse_file_size(input: &str) -> Result<u64, ParseFileSizeError> {
+    let input = input.trim();
+    // "B" for bytes is optional
+    let input = input.strip_suffix(&['B', 'b'][..]).unwrap_or(input);
+    let mut iter = input.chars();
+    let mut suffix = iter.next_back().ok_or(ParseFileSizeError::EmptyInput)?;
+    let mut suffix_len = 0;
+
+    let iec = matches!(suffix, 'i' | 'I');
+
+    if iec {
+        suffix_len += 1;
+        suffix = iter.next_back().ok_or(ParseFileSizeError::InvalidSuffix)?;
+    }
+
+    let base: u64 = if iec { 1024 } else { 1000 };
+
+    suffix_len += 1;
+    let exponent = match suffix.to_ascii_uppercase() {
+        '0'..='9' if !iec => {
+            suffix_len -= 1;
+            0
+        }
+        'K' => 1,
+        'M' => 2,
+        'G' => 3,
+        'T' => 4,
+        _ => return Err(ParseFileSizeError::InvalidSuffix),
+    };
+
+    let num = &input[..input.len() - suffix_len];
+    if num.is_empty() {
+        return Err(ParseFileSizeError::EmptyInput);
+    }
+
+    let num = num.parse::<f64>()?;
+    if !num.is_finite() {
+        return Err(ParseFileSizeError::NotFinite(num));
+    }
+    if num < 0.0 {
+        return Err(ParseFileSizeError::Negative);
+    }
+
+    Ok((num * base.pow(exponent) as f64) as u64)
+}
+
+#[derive(Debug, Error)]
+pub enum ParsePositionError {
+    #[error("empty argument")]
+    EmptyInput,
+    #[error("invalid number: {0}")]
+    InvalidNumber(#[from] std::num::ParseFloatError),
+    #[error("too many fields, expected at most HH:MM:SS")]
+    TooManyFields,
+    #[error("negative, non-finite or too large position")]
+    OutOfRange,
+}
+
+// Positions like 90 or 90s (seconds), 90000ms, 1:30 or 00:01:30.500. Returns milliseconds.
+pub fn parse_position(input: &str) -> Result<u32, ParsePositionError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(ParsePositionError::EmptyInput);
+    }
+
+    let seconds = if let Some(ms) = input.strip_suffix("ms") {
+        ms.trim().parse::<f64>()? / 1000.0
+    } else if input.contains(':') {
+        let fields: Vec<&str> = input.split(':').collect();
+        if fields.len() > 3 {
+            return Err(ParsePositionError::TooManyFields);
+        }
+        let mut seconds = 0.0;
+        for field in fields {
+            let value = field.trim().parse::<f64>()?;
+            // "-0:30" would otherwise be 30 seconds
+            if value.is_sign_negative() {
+                return Err(ParsePositionError::OutOfRange);
+            }
+            seconds = seconds * 60.0 + value;
+        }
+        seconds
+    } else {
+        let seconds = input.strip_suffix('s').unwrap_or(input);
+        seconds.trim().parse::<f64>()?
+    };
+
+    let ms = (seconds * 1000.0).round();
+    if !ms.is_finite() || ms < 0.0 || ms > u32::MAX as f64 {
+        return Err(ParsePositionError::OutOfRange);
+    }
+
+    Ok(ms as u32)
+}
+
+/// The help text.
+pub fn usage(program: &str) -> String {
+    let repo_home = env!("CARGO_PKG_REPOSITORY");
+    let desc = env!("CARGO_PKG_DESCRIPTION");
+    let version = version_string();
+    let brief = format!(
+        "{}\n\n{}\n\n{}\n\nUsage: {} [<Command>] [<Options>]\n\n{}",
+        version,
+        desc,
+        repo_home,
+        program,
+        "Commands:
+    connect             Run as a Spotify Connect device. The default.
+    play URI            Play a track to stdout, like --single-track.
+    serve PORT          Stream tracks to LMS over HTTP, like --player-server.
+    token               Get an access token, like --get-token.
+    cache stats         Show the audio cache usage, like --cache-stats.
+    cache accounts      List the cached accounts, like --list-accounts.
+    cache usage         Show the data usage, like --stats.
+    cache prefetch URI  Cache the audio of a track, album or playlist, like --prefetch.
+    cache export FILE   Export the cached credentials, like --export-credentials.
+    cache import FILE   Import credentials into the cache, like --import-credentials."
+    );
+    options().usage(&brief)
+}
+
+/// The long names of all options, as getopts doesn't tell them otherwise.
+pub fn option_names() -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for line in options().usage("").lines() {
+        // eg. "    -n, --name NAME     Device name."
+        let name = line
+            .split_whitespace()
+            .take(2)
+            .find_map(|word| word.strip_prefix("--"))
+            .map(|name| name.trim_end_matches(','));
+        if let Some(name) = name {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+fn setup_logging(
+    quiet: bool,
+    verbose: bool,
+    format: LogFormat,
+    target: Option<Box<dyn Write + Send>>,
+) {
+    let mut builder = env_logger::Builder::new();
+
+    // Never log to stdout, it may carry the audio
+    match target {
+        Some(target) => {
+            builder
+                .target(env_logger::Target::Pipe(target))
+                .write_style(env_logger::WriteStyle::Never);
+        }
+        None => {
+            builder.target(env_logger::Target::Stderr);
+        }
+    }
+
+    if format == LogFormat::Json {
+        // one object per line, eg. for journald or Loki
+        builder.format(|buf, record| {
+            let entry = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "fields": {
+                    "module": record.module_path(),
+                    "file": record.file(),
+                    "line": record.line(),
+                },
+            });
+            writeln!(buf, "{}", entry)
+        });
+    }
+
+    match env::var("RUST_LOG") {
+        Ok(config) => {
+            builder.parse_filters(&config);
+            builder.init();
+
+            if verbose {
+                warn!("`--verbose` flag overidden by `RUST_LOG` environment variable");
+            } else if quiet {
+                warn!("`--quiet` flag overidden by `RUST_LOG` environment variable");
+            }
+        }
+        Err(_) => {
+            if verbose {
+                builder.parse_filters("libmdns=info,librespot=trace,spotty=trace");
+            } else if quiet {
+                builder.parse_filters("libmdns=warn,librespot=warn,spotty=warn");
+            } else {
+                builder.parse_filters("libmdns=info,librespot=info,spotty=info");
+            }
+            builder.init();
+
+            if verbose && quiet {
+                warn!("`--verbose` and `--quiet` are mutually exclusive. Logging can not be both verbose and quiet. Using verbose mode.");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn log_fd_writer(fd: i32) -> Option<Box<dyn Write + Send>> {
+    use std::os::unix::io::FromRawFd;
+
+    // Safety: the descriptor is handed to us by whoever started us, for us to own
+    Some(Box::new(unsafe { fs::File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn log_fd_writer(_fd: i32) -> Option<Box<dyn Write + Send>> {
+    None
+}
+
+/// The version of spotty and the librespot it's built on.
+pub fn version_string() -> String {
+    // shadowed by the `--version` option at the module level
+    const VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
+
+    #[cfg(debug_assertions)]
+    const BUILD_PROFILE: &str = "debug";
+    #[cfg(not(debug_assertions))]
+    const BUILD_PROFILE: &str = "release";
+
+    format!(
+        "{spottyvers} - using librespot {semver} {sha} (Built on {build_date}, Build ID: {build_id}, Profile: {build_profile})",
+        spottyvers = VERSION,
+        semver = version::SEMVER,
+        sha = version::SHA_SHORT,
+        build_date = version::BUILD_DATE,
+        build_id = version::BUILD_ID,
+        build_profile = BUILD_PROFILE
+    )
+}
+
+/// What spotty runs with.
+pub struct Setup {
+    pub format: AudioFormat,
+    pub backend: SinkBuilder,
+    pub device: Option<String>,
+    pub mixer: MixerFn,
+    pub cache: Option<Cache>,
+    pub player_config: PlayerConfig,
+    pub session_config: SessionConfig,
+    pub connect_config: ConnectConfig,
+    pub mixer_config: MixerConfig,
+    pub credentials: Option<Credentials>,
+    pub enable_discovery: bool,
+    pub zeroconf_port: u16,
+    pub zeroconf_interface: Option<String>,
+    pub mdns_backend: MdnsBackend,
+    pub zeroconf_txt: Vec<String>,
+    pub device_brand: Option<String>,
+    pub device_model: Option<String>,
+
+    // spotty
+    pub command: Command,
+    pub cache_size_limit: Option<u64>,
+    pub cache_dir: Option<PathBuf>,
+    pub authenticate: bool,
+    pub start_position: u32,
+    pub client_ids: ClientIds,
+    pub reconnect: Reconnect,
+    pub status_address: Option<SocketAddr>,
+    pub control_address: Option<SocketAddr>,
+    pub control_token: Option<String>,
+    pub buffer_debug: Option<Duration>,
+    pub volume_debounce: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub runtime_threads: usize,
+    pub lyrics: bool,
+    pub canvas: bool,
+    pub color_palette: bool,
+    pub snapcast_metadata: Option<PathBuf>,
+    pub take_over: bool,
+    pub resume_on_start: bool,
+    pub alarm: Option<Alarm>,
+    pub output_profiles: OutputProfiles,
+    pub scopes: Option<String>,
+    pub save_token: Option<String>,
+    pub lms: LMS,
+    pub daemon: bool,
+    pub kill: bool,
+    pub pid_file: Option<PathBuf>,
+    pub stats_file: Option<PathBuf>,
+}
+
+/// The command line and the `LIBRESPOT_*` environment variables, parsed into the options set
+/// and the command to run.
+pub struct Args {
+    pub command: Command,
+    argv: Vec<String>,
+    matches: getopts::Matches,
+    env_vars: Vec<(String, String)>,
+    // not valid Unicode, warned about once logging is set up
+    invalid_env_vars: Vec<String>,
+}
+
+impl Args {
+    /// Parses `argv`, the program name first, along with the `LIBRESPOT_*` environment
+    /// variables. Only the command is checked here, the option values are checked by
+    /// [`get_setup`].
+    pub fn parse(argv: Vec<String>) -> Result<Self, Error> {
+        let matches = options()
+            .parse(argv.get(1..).unwrap_or_default())
+            .map_err(|e| command_error(e.to_string()))?;
+
+        let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+        let (command, subcommand_args) = match free.as_slice() {
+            [] => (None, 0),
+            ["connect", ..] => (Some(Command::Connect), 1),
+            ["play", uri, ..] => (Some(Command::Play(uri.to_string())), 2),
+            ["serve", port, ..] => (Some(Command::PlayerServer(parse_port(port)?)), 2),
+            ["token", ..] => (Some(Command::Token), 1),
+            ["cache"] | ["cache", "stats", ..] => (Some(Command::CacheStats), free.len().min(2)),
+            ["cache", "accounts", ..] => (Some(Command::ListAccounts), 2),
+            ["cache", "usage", ..] => (Some(Command::DataUsage), 2),
+            ["cache", "prefetch", uri, ..] => (Some(Command::Prefetch(uri.to_string())), 3),
+            ["cache", "export", file, ..] => {
+                (Some(Command::ExportCredentials(PathBuf::from(file))), 3)
+            }
+            ["cache", "import", file, ..] => {
+                (Some(Command::ImportCredentials(PathBuf::from(file))), 3)
+            }
+            ["play"] | ["cache", "prefetch"] => return Err(command_error("A URI is required.")),
+            ["serve"] => return Err(command_error("A port is required.")),
+            ["cache", "export"] | ["cache", "import"] => {
+                return Err(command_error("A file is required."))
+            }
+            ["cache", command, ..] => {
+                let error = format!("Unknown command: \"cache {}\"", command);
+                return Err(command_error(error));
+            }
+            [command, ..] => {
+                return Err(command_error(format!("Unknown command: \"{}\"", command)))
+            }
+        };
+
+        if let Some(arg) = free.get(subcommand_args) {
+            return Err(command_error(format!("Unexpected argument: \"{}\"", arg)));
+        }
+
+        if let Some(command) = free.first() {
+            if let Some(flag) = COMMAND_FLAGS.iter().find(|flag| matches.opt_present(flag)) {
+                let error = format!("`--{}` can't be used with the `{}` command.", flag, command);
+                return Err(command_error(error));
+            }
+        }
+
+        let mut env_vars = Vec::new();
+        let mut invalid_env_vars = Vec::new();
+        for (k, v) in env::vars_os() {
+            let key = match k.into_string() {
+                Ok(key) if key.starts_with("LIBRESPOT_") => key,
+                _ => continue,
+            };
+            let stripped_key = stripped_env_key(&key);
+            // We only care about long option/flag names.
+            if stripped_key.chars().count() > 1 && matches.opt_defined(&stripped_key) {
+                match v.into_string() {
+                    Ok(value) => env_vars.push((key, value)),
+                    Err(s) => invalid_env_vars.push(format!("{}={:?}", key, s)),
+                }
+            }
+        }
+
+        let mut args = Self {
+            command: Command::Connect,
+            argv,
+            matches,
+            env_vars,
+            invalid_env_vars,
+        };
+
+        args.command = match command {
+            Some(command) => command,
+            None => args.flag_command()?,
+        };
+
+        Ok(args)
+    }
+
+    // The command one of the flags from before there were subcommands stands for
+    fn flag_command(&self) -> Result<Command, Error> {
+        let flag = COMMAND_FLAGS.iter().find(|flag| self.opt_present(flag));
+        // a token file to write implies getting a token
+        let save_token = self
+            .opt_str(SAVE_TOKEN)
+            .map_or(false, |file| !file.is_empty());
+        let flag = match flag {
+            Some(flag) => *flag,
+            None if save_token => GET_TOKEN,
+            None => return Ok(Command::Connect),
+        };
+
+        let value = self.opt_str(flag).unwrap_or_default();
+        let command = match flag {
+            CACHE_STATS => Command::CacheStats,
+            TOKEN_INFO => Command::TokenInfo(value),
+            LIST_ACCOUNTS => Command::ListAccounts,
+            EXPORT_CREDENTIALS => Command::ExportCredentials(PathBuf::from(value)),
+            IMPORT_CREDENTIALS => Command::ImportCredentials(PathBuf::from(value)),
+            STATS => Command::DataUsage,
+            DRY_RUN | CHECK_CONFIG => Command::DryRun {
+                check_config: self.opt_present(CHECK_CONFIG),
+            },
+            SELF_UPDATE => Command::SelfUpdate,
+            PAIR => Command::Pair,
+            LOGIN_OAUTH => Command::LoginOAuth,
+            SINGLE_TRACK => Command::Play(value),
+            PLAYER_SERVER => Command::PlayerServer(parse_port(&value)?),
+            PREVIEW => Command::Preview(value),
+            GET_METADATA => Command::Metadata(value),
+            PREFETCH => Command::Prefetch(value),
+            DOWNLOAD => match self.opt_str(OUTPUT_DIR) {
+                Some(dir) => Command::Download(value, PathBuf::from(dir)),
+                None => {
+                    let error = format!("`--{}` requires `--{}`.", DOWNLOAD, OUTPUT_DIR);
+                    return Err(command_error(error));
+                }
+            },
+            // `GET_TOKEN`, or only a token file to write
+            _ => Command::Token,
+        };
+
+        Ok(command)
+    }
+
+    fn opt_present(&self, opt: &str) -> bool {
+        self.matches.opt_present(opt)
+            || self
+                .env_vars
+                .iter()
+                .any(|(k, _)| stripped_env_key(k) == opt)
+    }
+
+    fn opt_str(&self, opt: &str) -> Option<String> {
+        if self.matches.opt_present(opt) {
+            self.matches.opt_str(opt)
+        } else {
+            self.env_vars
+                .iter()
+                .find(|(k, _)| stripped_env_key(k) == opt)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    /// The name the program was run with.
+    pub fn program(&self) -> &str {
+        self.argv.first().map_or("spotty", String::as_str)
+    }
+
+    /// Whether the help text is asked for.
+    pub fn help(&self) -> bool {
+        self.opt_present(HELP)
+    }
+
+    /// Whether the version is asked for.
+    pub fn version(&self) -> bool {
+        self.opt_present(VERSION)
+    }
+
+    /// Whether the version and capabilities are asked for.
+    pub fn check(&self) -> bool {
+        self.opt_present(CHECK)
+    }
+
+    /// Whether errors are to be reported as JSON on stdout.
+    pub fn json_errors(&self) -> bool {
+        self.matches.opt_present(JSON_ERRORS)
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, Error> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => {
+            let error = format!(
+                "Invalid port: \"{}\", valid values are 1 - {}",
+                port,
+                u16::MAX
+            );
+            Err(command_error(error))
+        }
+    }
+}
+
+fn stripped_env_key(k: &str) -> String {
+    k.trim_start_matches("LIBRESPOT_")
+        .replace('_', "-")
+        .to_lowercase()
+}
+
+/// Checks the options and sets up logging, returning what spotty is to run with. The errors are
+/// logged before they're returned.
+pub fn get_setup(args: &Args) -> Result<Setup, Error> {
+    let opt_present = |opt| args.opt_present(opt);
+    let opt_str = |opt| args.opt_str(opt);
+    let matches = &args.matches;
+    let command = &args.command;
+
+    let log_format =
+        opt_str(LOG_FORMAT).map(|format| LogFormat::from_str(&format).map_err(|_| format));
+
+    let log_file_size = opt_str(LOG_FILE_SIZE).map(|size| parse_file_size(&size).map_err(|_| size));
+    let log_file_count =
+        opt_str(LOG_FILE_COUNT).map(|count| count.parse::<usize>().map_err(|_| count));
+
+    let (log_file, log_file_error) = match opt_str(LOG_FILE) {
+        Some(path) => {
+            let max_size = log_file_size.clone().and_then(Result::ok);
+            let keep = log_file_count.clone().and_then(Result::ok);
+            let file = RotatingFile::open(
+                Path::new(&path),
+                max_size.unwrap_or(RotatingFile::DEFAULT_MAX_SIZE),
+                keep.unwrap_or(RotatingFile::DEFAULT_KEEP),
+            );
+
+            match file {
+                Ok(file) => (Some(file), None),
+                Err(e) => (
+                    None,
+                    Some(format!("Can't open log file \"{}\": {}", path, e)),
+                ),
+            }
+        }
+        None => (None, None),
+    };
+
+    let log_fd = opt_str(LOG_FD).map(|fd| match fd.parse::<i32>() {
+        Ok(fd) if fd >= 2 => Ok(fd),
+        _ => Err(fd),
+    });
+
+    let log_target: Option<Box<dyn Write + Send>> = match (&log_fd, log_file) {
+        (Some(Ok(fd)), _) => log_fd_writer(*fd),
+        (_, Some(file)) => Some(Box::new(file)),
+        _ => None,
+    };
+
+    // Logs go to stderr, the log file or descriptor, stdout is reserved for audio and responses
+    if !opt_present(NO_LOG) {
+        let format = log_format.clone().and_then(Result::ok).unwrap_or_default();
+        setup_logging(opt_present(QUIET), opt_present(VERBOSE), format, log_target);
+    }
+
+    info!("{}", version_string());
+    debug!("CPU features: {:?}", dsp::cpu_features());
+
+    for var in &args.invalid_env_vars {
+        warn!(
+            "Environment variable was not valid Unicode and will not be evaluated: {}",
+            var
+        );
+    }
+
+    if !args.env_vars.is_empty() {
+        trace!("Environment variable(s):");
+
+        for (k, v) in &args.env_vars {
+            if matches!(k.as_str(), "LIBRESPOT_PASSWORD" | "LIBRESPOT_USERNAME") {
+                trace!("\t\t{}=\"XXXXXXXX\"", k);
+            } else if v.is_empty() {
+                trace!("\t\t{}=", k);
+            } else {
+                trace!("\t\t{}=\"{}\"", k, v);
+            }
+        }
+    }
+
+    let args_len = args.argv.len();
+
+    if args_len > 1 {
+        trace!("Command line argument(s):");
+
+        for (index, key) in args.argv.iter().enumerate() {
+            let opt = {
+                let key = key.trim_start_matches('-');
+
+                if let Some((s, _)) = key.split_once('=') {
+                    s
+                } else {
+                    key
+                }
+            };
+
+            if index > 0
+                && key.starts_with('-')
+                && &args.argv[index - 1] != key
+                && matches.opt_defined(opt)
+                && matches.opt_present(opt)
+            {
+                if matches!(opt, PASSWORD | PASSWORD_SHORT | USERNAME | USERNAME_SHORT) {
+                    // Don't log creds.
+                    trace!("\t\t{} \"XXXXXXXX\"", opt);
+                } else {
+                    let value = matches.opt_str(opt).unwrap_or_default();
+                    if value.is_empty() {
+                        trace!("\t\t{}", opt);
+                    } else {
+                        trace!("\t\t{} \"{}\"", opt, value);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(Err(format)) = log_format {
+        return Err(invalid_error(LOG_FORMAT, "", &format, "text, json", "text"));
+    }
+
+    if let Some(Err(size)) = log_file_size {
+        return Err(invalid_error(LOG_FILE_SIZE, "", &size, "", "10M"));
+    }
+
+    if let Some(Err(count)) = log_file_count {
+        let default_count = RotatingFile::DEFAULT_KEEP.to_string();
+        return Err(invalid_error(
+            LOG_FILE_COUNT,
+            "",
+            &count,
+            "",
+            &default_count,
+        ));
+    }
+
+    if let Some(Err(fd)) = log_fd {
+        return Err(invalid_error(
+            LOG_FD,
+            "",
+            &fd,
+            "2 or higher, 1 is stdout",
+            "2",
+        ));
+    }
+
+    if cfg!(not(unix)) && opt_present(LOG_FD) {
+        warn!("`--{}` is only supported on Unix.", LOG_FD);
+    }
+
+    if opt_present(LOG_FD) && opt_present(LOG_FILE) {
+        warn!("With `--{}` set `--{}` has no effect.", LOG_FD, LOG_FILE);
+    }
+
+    if let Some(error) = log_file_error {
+        return Err(setup_error(ExitCode::Error, &error));
+    }
+
+    if !opt_present(LOG_FILE) {
+        for a in &[LOG_FILE_SIZE, LOG_FILE_COUNT] {
+            if opt_present(a) {
+                warn!("Without `--{}` `--{}` has no effect.", LOG_FILE, a);
+            }
+        }
+    }
+
+    let mixer_type: Option<String> = opt_str(MIXER_TYPE);
+    let mixer = mixer::find(mixer_type.as_deref()).ok_or_else(|| {
+        invalid_error(
+            MIXER_TYPE,
+            MIXER_TYPE_SHORT,
+            &opt_str(MIXER_TYPE).unwrap_or_default(),
+            "alsa, softvol",
+            SoftMixer::NAME,
+        )
+    })?;
+
+    #[cfg(not(feature = "alsa-backend"))]
+    for a in &[ALSA_MIXER_DEVICE, ALSA_MIXER_INDEX, ALSA_MIXER_CONTROL] {
+        if opt_present(a) {
+            warn!("Alsa specific options have no effect if the alsa backend is not enabled at build time.");
+            break;
+        }
+    }
+
+    #[cfg(feature = "alsa-backend")]
+    if mixer_type.as_deref() != Some(AlsaMixer::NAME) {
+        for a in &[ALSA_MIXER_DEVICE, ALSA_MIXER_INDEX, ALSA_MIXER_CONTROL] {
+            if opt_present(a) {
+                warn!("Alsa specific mixer options have no effect if not using the alsa mixer.");
+                break;
+            }
+        }
+    }
+
+    let mixer_config = {
+        let mixer_default_config = MixerConfig::default();
+
+        #[cfg(feature = "alsa-backend")]
+        let device = if let Some(mixer_device) = opt_str(ALSA_MIXER_DEVICE) {
+            if mixer_device.is_empty() {
+                return Err(empty_string_error(
+                    ALSA_MIXER_DEVICE,
+                    ALSA_MIXER_DEVICE_SHORT,
+                ));
+            }
+
+            mixer_device
+        } else {
+            mixer_default_config.device
+        };
+
+        #[cfg(not(feature = "alsa-backend"))]
+        let device = mixer_default_config.device;
+
+        #[cfg(feature = "alsa-backend")]
+        let index = opt_str(ALSA_MIXER_INDEX)
+            .map(|index| {
+                index.parse::<u32>().map_err(|_| {
+                    invalid_error(
+                        ALSA_MIXER_INDEX,
+                        ALSA_MIXER_INDEX_SHORT,
+                        &index,
+                        "",
+                        &mixer_default_config.index.to_string(),
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or(mixer_default_config.index);
+
+        #[cfg(not(feature = "alsa-backend"))]
+        let index = mixer_default_config.index;
+
+        #[cfg(feature = "alsa-backend")]
+        let control = opt_str(ALSA_MIXER_CONTROL).unwrap_or(mixer_default_config.control);
+
+        #[cfg(feature = "alsa-backend")]
+        if control.is_empty() {
+            return Err(empty_string_error(
+                ALSA_MIXER_CONTROL,
+                ALSA_MIXER_CONTROL_SHORT,
+            ));
+        }
+
+        #[cfg(not(feature = "alsa-backend"))]
+        let control = mixer_default_config.control;
+
+        let volume_range = opt_str(VOLUME_RANGE)
+            .map(|range| match range.parse::<f64>() {
+                Ok(value) if (VALID_VOLUME_RANGE).contains(&value) => Ok(value),
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_VOLUME_RANGE.start(),
+                        VALID_VOLUME_RANGE.end()
+                    );
+
+                    #[cfg(feature = "alsa-backend")]
+                    let default_value = &format!(
+                        "softvol - {}, alsa - what the control supports",
+                        VolumeCtrl::DEFAULT_DB_RANGE
+                    );
+
+                    #[cfg(not(feature = "alsa-backend"))]
+                    let default_value = &VolumeCtrl::DEFAULT_DB_RANGE.to_string();
+
+                    Err(invalid_error(
+                        VOLUME_RANGE,
+                        VOLUME_RANGE_SHORT,
+                        &range,
+                        valid_values,
+                        default_value,
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or_else(|| match mixer_type.as_deref() {
+                #[cfg(feature = "alsa-backend")]
+                Some(AlsaMixer::NAME) => 0.0, // let alsa query the control
+                _ => VolumeCtrl::DEFAULT_DB_RANGE,
+            });
+
+        let volume_ctrl = opt_str(VOLUME_CTRL)
+            .as_deref()
+            .map(|volume_ctrl| {
+                VolumeCtrl::from_str_with_range(volume_ctrl, volume_range).map_err(|_| {
+                    invalid_error(
+                        VOLUME_CTRL,
+                        VOLUME_CTRL_SHORT,
+                        volume_ctrl,
+                        "cubic, fixed, linear, log",
+                        "linear",
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or(VolumeCtrl::Linear);
+
+        if opt_present(VOLUME_RANGE)
+            && !matches!(volume_ctrl, VolumeCtrl::Cubic(_) | VolumeCtrl::Log(_))
+        {
+            warn!(
+                "`--{}` has no effect with `--{}` {:?}.",
+                VOLUME_RANGE, VOLUME_CTRL, volume_ctrl
+            );
+        }
+
+        MixerConfig {
+            device,
+            control,
+            index,
+            volume_ctrl,
+        }
+    };
+
+    let (cache, cache_size_limit) = {
+        let volume_dir = opt_str(CACHE).map(|p| p.into());
+
+        let cred_dir = volume_dir.clone();
+
+        let audio_dir = if opt_present(DISABLE_AUDIO_CACHE) {
+            None
+        } else {
+            opt_str(CACHE)
+                .as_ref()
+                .map(|p| AsRef::<Path>::as_ref(p).join("files"))
+        };
+
+        let limit = if audio_dir.is_some() {
+            opt_str(CACHE_SIZE_LIMIT)
+                .as_deref()
+                .map(parse_file_size)
+                .map(|e| {
+                    e.map_err(|e| {
+                        invalid_error(
+                            CACHE_SIZE_LIMIT,
+                            CACHE_SIZE_LIMIT_SHORT,
+                            &e.to_string(),
+                            "",
+                            "",
+                        )
+                    })
+                })
+                .transpose()?
+        } else {
+            None
+        };
+
+        if audio_dir.is_none() && opt_present(CACHE_SIZE_LIMIT) {
+            warn!(
+                "Without a `--{}` / `-{}` path, and/or if the `--{}` / `-{}` flag is set, `--{}` / `-{}` has no effect.",
+                CACHE, CACHE_SHORT, DISABLE_AUDIO_CACHE, DISABLE_AUDIO_CACHE_SHORT, CACHE_SIZE_LIMIT, CACHE_SIZE_LIMIT_SHORT
+            );
+        }
+
+        // Not taken as an argument, which would show up in the process list
+        let credentials_passphrase = match opt_str(CREDENTIALS_PASSPHRASE_FILE) {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(passphrase) => Some(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string()),
+                Err(e) => {
+                    error!(
+                        "Cannot read the credentials passphrase from {}: {}",
+                        path, e
+                    );
+                    let error = format!("Cannot read the credentials passphrase from {}.", path);
+                    return Err(Error::new(ExitCode::InvalidArguments, error));
+                }
+            },
+            None => env::var("LIBRESPOT_CREDENTIALS_PASSPHRASE").ok(),
+        };
+
+        let credentials_passphrase = match credentials_passphrase {
+            Some(passphrase) => {
+                if passphrase.is_empty() {
+                    error!("The credentials passphrase can not be an empty string");
+                    let error = "The credentials passphrase can not be an empty string";
+                    return Err(Error::new(ExitCode::InvalidArguments, error));
+                }
+
+                if opt_present(CREDENTIALS_KEYRING) {
+                    warn!(
+                        "`--{}` has no effect if a credentials passphrase is set.",
+                        CREDENTIALS_KEYRING
+                    );
+                }
+
+                Some(passphrase)
+            }
+            None if opt_present(CREDENTIALS_KEYRING) => spotty::keyring_passphrase(),
+            None => None,
+        };
+
+        let cache = match Cache::new(cred_dir, volume_dir, audio_dir, limit) {
+            Ok(mut cache) => {
+                if let Some(passphrase) = credentials_passphrase {
+                    cache.set_credentials_passphrase(passphrase);
+                }
+
+                cache.set_spill_downloads(opt_present(MAX_BUFFER_MB));
+
+                Some(cache)
+            }
+            Err(e) => {
+                warn!("Cannot create cache: {}", e);
+                None
+            }
+        };
+
+        (cache, limit)
+    };
+
+    let credentials = {
+        let cached_creds = match opt_str(ACCOUNT) {
+            Some(account) => {
+                if account.is_empty() {
+                    return Err(empty_string_error(ACCOUNT, ""));
+                }
+
+                let creds = cache.as_ref().and_then(|c| c.account_credentials(&account));
+                if creds.is_none() {
+                    warn!("No cached credentials for account {}.", account);
+                }
+
+                creds
+            }
+            None => cache.as_ref().and_then(Cache::credentials),
+        };
+
+        if let Some(username) = opt_str(USERNAME) {
+            if username.is_empty() {
+                return Err(empty_string_error(USERNAME, USERNAME_SHORT));
+            }
+            let password = match (opt_str(PASSWORD), opt_str(PASSWORD_FD)) {
+                (Some(password), fd) => {
+                    if fd.is_some() {
+                        warn!(
+                            "With `--{}` / `-{}` set `--{}` has no effect.",
+                            PASSWORD, PASSWORD_SHORT, PASSWORD_FD
+                        );
+                    }
+                    Some(password)
+                }
+                (None, Some(fd)) => {
+                    let fd = match fd.parse::<i32>() {
+                        Ok(fd) if fd >= 0 => fd,
+                        _ => {
+                            return Err(invalid_error(PASSWORD_FD, "", &fd, "0 or higher", ""));
+                        }
+                    };
+                    match spotty::read_password_fd(fd) {
+                        Ok(password) => Some(password),
+                        Err(e) => {
+                            let error = format!("Cannot read the password from fd {}: {}", fd, e);
+                            return Err(setup_error(ExitCode::Error, &error));
+                        }
+                    }
+                }
+                (None, None) => spotty::systemd_password(),
+            };
+
+            if let Some(password) = password {
+                if password.is_empty() {
+                    return Err(empty_string_error(PASSWORD, PASSWORD_SHORT));
+                }
+                Some(Credentials::with_password(username, password))
+            } else {
+                let cached_creds = match cached_creds {
+                    Some(creds) if username == creds.username => Some(creds),
+                    creds => cache
+                        .as_ref()
+                        .and_then(|c| c.account_credentials(&username))
+                        .or(creds),
+                };
+
+                match cached_creds {
+                    Some(creds) if username == creds.username => Some(creds),
+                    _ if !spotty::stdin_is_tty() && cached_creds.is_none() => {
+                        let error = format!(
+                            "A password is needed for {}, but there's no terminal to ask for it. Use `--{}` instead.",
+                            username, PASSWORD_FD
+                        );
+                        return Err(setup_error(ExitCode::PasswordRequired, &error));
+                    }
+                    _ => {
+                        let prompt = &format!("Password for {}: ", username);
+                        match rpassword::prompt_password(prompt) {
+                            Ok(password) => {
+                                if !password.is_empty() {
+                                    Some(Credentials::with_password(username, password))
+                                } else {
+                                    trace!("Password was empty.");
+                                    if cached_creds.is_some() {
+                                        trace!("Using cached credentials.");
+                                    }
+                                    cached_creds
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Cannot parse password: {}", e);
+                                if cached_creds.is_some() {
+                                    trace!("Using cached credentials.");
+                                }
+                                cached_creds
+                            }
+                        }
+                    }
+                }
+            }
+        } else {
+            if cached_creds.is_some() {
+                trace!("Using cached credentials.");
+            }
+            cached_creds
+        }
+    };
+
+    let enable_discovery = !opt_present(DISABLE_DISCOVERY) && command.uses_discovery();
+
+    let no_credentials_needed = matches!(
+        command,
+        Command::Preview(_) | Command::Pair | Command::LoginOAuth | Command::SelfUpdate
+    );
+    if credentials.is_none() && !enable_discovery && !no_credentials_needed {
+        let error = "Credentials are required if discovery is disabled.";
+        return Err(setup_error(ExitCode::AuthFailed, error));
+    }
+
+    if !enable_discovery && opt_present(ZEROCONF_PORT) {
+        warn!(
+            "With the `--{}` / `-{}` flag set `--{}` / `-{}` has no effect.",
+            DISABLE_DISCOVERY, DISABLE_DISCOVERY_SHORT, ZEROCONF_PORT, ZEROCONF_PORT_SHORT
+        );
+    }
+
+    if !enable_discovery {
+        for a in &[
+            ZEROCONF_INTERFACE,
+            MDNS_BACKEND,
+            ZEROCONF_TXT,
+            DEVICE_BRAND,
+            DEVICE_MODEL,
+        ] {
+            if opt_present(a) {
+                warn!(
+                    "With the `--{}` / `-{}` flag set `--{}` has no effect.",
+                    DISABLE_DISCOVERY, DISABLE_DISCOVERY_SHORT, a
+                );
+            }
+        }
+    }
+
+    let mdns_backend = opt_str(MDNS_BACKEND)
+        .as_deref()
+        .map(|backend| {
+            let valid_values = "libmdns, avahi, dnssd";
+            match MdnsBackend::from_str(backend) {
+                Ok(backend) if backend.is_available() => Ok(backend),
+                Ok(_) => {
+                    let error = format!("spotty was built without the {} mDNS backend.", backend);
+                    Err(setup_error(ExitCode::InvalidArguments, error))
+                }
+                Err(_) => Err(invalid_error(MDNS_BACKEND, "", backend, valid_values, "")),
+            }
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let zeroconf_txt = matches.opt_strs(ZEROCONF_TXT);
+
+    for record in &zeroconf_txt {
+        match record.split_once('=') {
+            Some((key, _)) if !key.is_empty() => (),
+            _ => {
+                return Err(invalid_error(ZEROCONF_TXT, "", record, "KEY=VALUE", ""));
+            }
+        }
+    }
+
+    let zeroconf_port = if enable_discovery {
+        opt_str(ZEROCONF_PORT)
+            .map(|port| match port.parse::<u16>() {
+                Ok(value) if value != 0 => Ok(value),
+                _ => {
+                    let valid_values = &format!("1 - {}", u16::MAX);
+                    Err(invalid_error(
+                        ZEROCONF_PORT,
+                        ZEROCONF_PORT_SHORT,
+                        &port,
+                        valid_values,
+                        "",
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    let mut connect_config = {
+        let connect_default_config = ConnectConfig::default();
+
+        let name = opt_str(NAME).unwrap_or_else(|| connect_default_config.name.clone());
+
+        if name.is_empty() {
+            return Err(empty_string_error(NAME, NAME_SHORT));
+        }
+
+        let initial_volume = opt_str(INITIAL_VOLUME)
+            .map(|initial_volume| {
+                let volume = match initial_volume.parse::<u16>() {
+                    Ok(value) if (VALID_INITIAL_VOLUME_RANGE).contains(&value) => value,
+                    _ => {
+                        let valid_values = &format!(
+                            "{} - {}",
+                            VALID_INITIAL_VOLUME_RANGE.start(),
+                            VALID_INITIAL_VOLUME_RANGE.end()
+                        );
+
+                        let default_value = &connect_default_config
+                            .initial_volume
+                            .unwrap_or_default()
+                            .to_string();
+
+                        return Err(invalid_error(
+                            INITIAL_VOLUME,
+                            INITIAL_VOLUME_SHORT,
+                            &initial_volume,
+                            valid_values,
+                            default_value,
+                        ));
+                    }
+                };
+
+                Ok((volume as f32 / 100.0 * VolumeCtrl::MAX_VOLUME as f32) as u16)
+            })
+            .transpose()?
+            .or_else(|| match mixer_type.as_deref() {
+                #[cfg(feature = "alsa-backend")]
+                Some(AlsaMixer::NAME) => None,
+                _ => cache.as_ref().and_then(|cache| {
+                    cache
+                        .device_preferences(&name)
+                        .and_then(|preferences| preferences.volume)
+                        .or_else(|| cache.volume())
+                }),
+            });
+
+        let device_type = opt_str(DEVICE_TYPE)
+            .as_deref()
+            .map(|device_type| {
+                DeviceType::from_str(device_type).map_err(|_| {
+                    invalid_error(
+                        DEVICE_TYPE,
+                        "",
+                        device_type,
+                        "computer, tablet, smartphone, speaker, tv, avr, stb, audiodongle, \
+                    gameconsole, castaudio, castvideo, automobile, smartwatch, chromebook, \
+                    carthing, homething",
+                        "speaker",
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let has_volume_ctrl = !matches!(mixer_config.volume_ctrl, VolumeCtrl::Fixed);
+        let autoplay = opt_present(AUTOPLAY);
+
+        let control_policy = opt_str(CONTROL_POLICY)
+            .as_deref()
+            .map(|policy| {
+                ControlPolicy::from_str(policy).map_err(|_| {
+                    invalid_error(
+                        CONTROL_POLICY,
+                        CONTROL_POLICY_SHORT,
+                        policy,
+                        "last, lms, connect",
+                        "last",
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or(connect_default_config.control_policy);
+
+        let max_consecutive_skips = opt_str(MAX_CONSECUTIVE_SKIPS)
+            .map(|skips| match skips.parse::<u32>() {
+                Ok(value) if value > 0 => Ok(value),
+                _ => {
+                    let valid_values = &format!("1 - {}", u32::MAX);
+                    Err(invalid_error(
+                        MAX_CONSECUTIVE_SKIPS,
+                        "",
+                        &skips,
+                        valid_values,
+                        "10",
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(connect_default_config.max_consecutive_skips);
+
+        let volume_steps = opt_str(VOLUME_STEPS).map(|steps| match steps.parse::<u16>() {
+            Ok(value) if value <= 1024 => Ok(value),
+            _ => Err(invalid_error(VOLUME_STEPS, "", &steps, "0 - 1024", "64")),
+        });
+        let volume_steps = volume_steps.transpose()?;
+        let max_volume = opt_str(MAX_VOLUME)
+            .map(|max_volume| match max_volume.parse::<u16>() {
+                Ok(value) if (VALID_INITIAL_VOLUME_RANGE).contains(&value) => {
+                    Ok((value as f32 / 100.0 * VolumeCtrl::MAX_VOLUME as f32) as u16)
+                }
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_INITIAL_VOLUME_RANGE.start(),
+                        VALID_INITIAL_VOLUME_RANGE.end()
+                    );
+                    Err(invalid_error(
+                        MAX_VOLUME,
+                        "",
+                        &max_volume,
+                        valid_values,
+                        "100",
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(connect_default_config.max_volume);
+
+        let can_play_episodes = !opt_present(NO_EPISODES);
+
+        ConnectConfig {
+            name,
+            device_type,
+            initial_volume,
+            has_volume_ctrl,
+            autoplay,
+            control_policy,
+            max_consecutive_skips,
+            volume_steps,
+            max_volume,
+            quantize_volume: opt_present(QUANTIZE_VOLUME),
+            can_play_episodes,
+        }
+    };
+
+    if opt_present(AP_ADDRESS) && opt_present(AP_PORT) {
+        warn!(
+            "With `--{}` set `--{}` / `-{}` has no effect.",
+            AP_ADDRESS, AP_PORT, AP_PORT_SHORT
+        );
+    }
+
+    let session_config = SessionConfig {
+        user_agent: version::VERSION_STRING.to_string(),
+        device_id: match opt_str(DEVICE_ID) {
+            Some(id) => {
+                if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid_error(DEVICE_ID, "", &id, "a hexadecimal string", ""));
+                }
+                id.to_lowercase()
+            }
+            None => cache
+                .as_ref()
+                .and_then(|cache| cache.device_id(&connect_config.name))
+                .unwrap_or_else(|| device_id(&connect_config.name)),
+        },
+        proxy: opt_str(PROXY)
+            .or_else(|| {
+                ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+                    .iter()
+                    .find_map(|name| env::var(name).ok().filter(|s| !s.is_empty()))
+            })
+            .map(|s| match Url::parse(&s) {
+                Ok(url) => {
+                    if url.host().is_none() || url.port_or_known_default().is_none() {
+                        return Err(setup_error(ExitCode::InvalidArguments, "Invalid proxy url, only URLs on the format \"http(s)://[user:password@]host:port\" are allowed"));
+                    }
+
+                    if url.scheme() != "http" && url.scheme() != "https" {
+                        return Err(setup_error(ExitCode::InvalidArguments, "Only http:// and https:// proxies are supported"));
+                    }
+
+                    Ok(url)
+                }
+                Err(e) => {
+                    let error = format!("Invalid proxy URL: \"{}\", only URLs in the format \"http(s)://[user:password@]host:port\" are allowed", e);
+                    Err(setup_error(ExitCode::InvalidArguments, error))
+                }
+            })
+            .transpose()?,
+        ap_ports: opt_str(AP_PORT)
+            .map(|ports| {
+                ports
+                    .split(',')
+                    .map(|port| match port.trim().parse::<u16>() {
+                        Ok(value) if value != 0 => Ok(value),
+                        _ => {
+                            let valid_values = &format!("1 - {}", u16::MAX);
+                            Err(invalid_error(AP_PORT, AP_PORT_SHORT, &ports, valid_values, ""))
+                        }
+                    })
+                    .collect()
+            })
+            .transpose()?
+            .unwrap_or_default(),
+        ap_address: opt_str(AP_ADDRESS).map(|address| {
+            match address.rsplit_once(':').map(|(host, port)| (host, port.parse::<u16>())) {
+                Some((host, Ok(port))) if !host.is_empty() && port != 0 => Ok(address),
+                _ => Err(invalid_error(AP_ADDRESS, "", &address, "HOST:PORT", "")),
+            }
+        })
+        .transpose()?,
+        force_tls: opt_present(FORCE_TLS),
+        bind_address: opt_str(BIND_ADDRESS).map(|address| match address.parse::<IpAddr>() {
+            Ok(ip) => Ok(ip),
+            _ => Err(invalid_error(BIND_ADDRESS, "", &address, "", "")),
+        })
+.transpose()?,
+        dns_resolver: match (opt_str(DNS_SERVER), opt_str(DNS_OVER_HTTPS)) {
+            (Some(_), Some(_)) => {
+                let error = format!(
+                    "`--{}` and `--{}` can't be combined.",
+                    DNS_SERVER, DNS_OVER_HTTPS
+                );
+                return Err(setup_error(ExitCode::InvalidArguments, &error));
+            }
+            (Some(server), None) => match server.parse::<SocketAddr>() {
+                Ok(address) => DnsResolver::Server(address),
+                Err(_) => match server.parse::<IpAddr>() {
+                    Ok(ip) => DnsResolver::Server(SocketAddr::new(ip, 53)),
+                    Err(_) => {
+                        return Err(invalid_error(DNS_SERVER, "", &server, "", ""));
+                    }
+                },
+            },
+            (None, Some(url)) => match Url::parse(&url) {
+                Ok(url) if url.scheme() == "https" && url.host().is_some() => {
+                    DnsResolver::Https(url)
+                }
+                _ => {
+                    return Err(invalid_error(DNS_OVER_HTTPS, "", &url, "an https:// URL", ""));
+                }
+            },
+            (None, None) => DnsResolver::System,
+        },
+        data_cap: opt_str(DATA_CAP).map(|cap| match cap.parse::<u64>() {
+            Ok(value) if value != 0 && value.checked_mul(1024 * 1024).is_some() => {
+                Ok(value * 1024 * 1024)
+            }
+            _ => Err(invalid_error(DATA_CAP, DATA_CAP_SHORT, &cap, "", "")),
+        })
+.transpose()?,
+        max_download_rate: opt_str(MAX_DOWNLOAD_RATE).map(|rate| match rate.parse::<usize>() {
+            Ok(value) if value != 0 => Ok(value * 1000 / 8),
+            _ => Err(invalid_error(MAX_DOWNLOAD_RATE, "", &rate, "", "")),
+        })
+.transpose()?,
+        network_timeout: opt_str(NETWORK_TIMEOUT).map(|seconds| match seconds.parse::<u64>() {
+            Ok(value) if (1..=300).contains(&value) => Ok(Duration::from_secs(value)),
+            _ => Err(invalid_error(NETWORK_TIMEOUT, "", &seconds, "1 - 300", "")),
+        })
+.transpose()?,
+        keepalive_interval: opt_str(KEEPALIVE_INTERVAL).map(|seconds| {
+            match seconds.parse::<u64>() {
+                Ok(value) if (1..=3600).contains(&value) => Ok(Duration::from_secs(value)),
+                _ => Err(invalid_error(KEEPALIVE_INTERVAL, "", &seconds, "1 - 3600", "")),
+            }
+        })
+        .transpose()?,
+    };
+
+    if session_config.force_tls {
+        let ap_address_port = session_config
+            .ap_address
+            .as_ref()
+            .and_then(|address| address.rsplit_once(':'))
+            .map(|(_, port)| port);
+        let proxy_scheme = session_config.proxy.as_ref().map(Url::scheme);
+
+        let error = if session_config.ap_ports.iter().any(|&port| port != 443) {
+            Some(format!("`--{}` can only be 443", AP_PORT))
+        } else if ap_address_port.map_or(false, |port| port != "443") {
+            Some(format!("`--{}` has to use port 443", AP_ADDRESS))
+        } else if proxy_scheme.map_or(false, |scheme| scheme != "https") {
+            Some("only https:// proxies are supported".to_string())
+        } else {
+            None
+        };
+
+        if let Some(error) = error {
+            let error = format!("With `--{}` {}.", FORCE_TLS, error);
+            return Err(setup_error(ExitCode::InvalidArguments, &error));
+        }
+    }
+
+    if opt_present(DATA_CAP) && opt_str(CACHE).is_none() {
+        warn!(
+            "Without a cache `--{}` only applies to the current session.",
+            DATA_CAP
+        );
+    }
+
+    if opt_present(RESUME_ON_START) && opt_str(CACHE).is_none() {
+        warn!("Without a cache `--{}` has no effect.", RESUME_ON_START);
+    }
+
+    let alarm = match (matches.opt_strs(PLAY_AT), opt_str(PLAY_AT_URI)) {
+        (schedules, None) if schedules.is_empty() => None,
+        (schedules, Some(_)) if schedules.is_empty() => {
+            warn!("Without `--{}` `--{}` has no effect.", PLAY_AT, PLAY_AT_URI);
+            None
+        }
+        (_, None) => {
+            let error = format!("`--{}` requires `--{}`.", PLAY_AT, PLAY_AT_URI);
+            return Err(setup_error(ExitCode::InvalidArguments, &error));
+        }
+        (schedules, Some(uri)) => {
+            let schedules = schedules
+                .iter()
+                .map(|schedule| {
+                    schedule.parse::<spotty::Schedule>().map_err(|_| {
+                        let valid_values =
+                            "7:30, 7:30 mon-fri or a cron expression like 30 7 * * 1-5";
+                        invalid_error(PLAY_AT, "", schedule, valid_values, "")
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+
+            let fade_in = opt_str(PLAY_AT_FADE_IN)
+                .map(|seconds| match seconds.parse::<u64>() {
+                    Ok(seconds) => Ok(seconds),
+                    _ => Err(invalid_error(
+                        PLAY_AT_FADE_IN,
+                        "",
+                        &seconds,
+                        "0 or more",
+                        "30",
+                    )),
+                })
+                .transpose()?
+                .unwrap_or(30);
+            let fade_in = Some(Duration::from_secs(fade_in)).filter(|d| !d.is_zero());
+
+            Some(Alarm::new(schedules, uri, fade_in))
+        }
+    };
+
+    let position_ms = |name: &'static str| {
+        opt_str(name)
+            .map(|position| match parse_position(&position) {
+                Ok(position_ms) => Ok(position_ms),
+                _ => {
+                    let valid_values = "seconds like 90 or 90s, 90000ms, 1:30 or 00:01:30.500";
+                    Err(invalid_error(name, "", &position, valid_values, ""))
+                }
+            })
+            .transpose()
+    };
+
+    let start_position = position_ms(START_POSITION)?.unwrap_or(0);
+    let stop_position = match (position_ms(STOP_POSITION)?, position_ms(DURATION)?) {
+        (Some(stop_position), Some(_)) => {
+            warn!(
+                "With `--{}` set `--{}` has no effect.",
+                STOP_POSITION, DURATION
+            );
+            Some(stop_position)
+        }
+        (Some(stop_position), None) => Some(stop_position),
+        (None, Some(duration)) => Some(start_position.saturating_add(duration)),
+        (None, None) => None,
+    };
+
+    if let Some(stop_position) = stop_position {
+        if stop_position <= start_position {
+            let error = format!(
+                "`--{}` / `--{}` must end after `--{}`.",
+                STOP_POSITION, DURATION, START_POSITION
+            );
+            return Err(setup_error(ExitCode::InvalidArguments, &error));
+        }
+
+        if !matches!(command, Command::Play(_)) {
+            warn!(
+                "Without `--{}` `--{}` / `--{}` have no effect.",
+                SINGLE_TRACK, STOP_POSITION, DURATION
+            );
+        } else if opt_present(PASSTHROUGH) {
+            warn!(
+                "In passthrough mode `--{}` / `--{}` have no effect.",
+                STOP_POSITION, DURATION
+            );
+        }
+    }
+
+    let player_config = {
+        let player_default_config = PlayerConfig::default();
+
+        let bitrate = opt_str(BITRATE)
+            .as_deref()
+            .map(|bitrate| {
+                Bitrate::from_str(bitrate).map_err(|_| {
+                    invalid_error(BITRATE, BITRATE_SHORT, bitrate, "96, 160, 320", "160")
+                })
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.bitrate);
+
+        let gapless = !opt_present(DISABLE_GAPLESS);
+
+        let normalisation = opt_present(ENABLE_VOLUME_NORMALISATION);
+
+        let normalisation_type;
+        let normalisation_method;
+
+        if !normalisation {
+            for a in &[NORMALISATION_GAIN_TYPE, NORMALISATION_METHOD] {
+                if opt_present(a) {
+                    warn!(
+                        "Without the `--{}` / `-{}` flag normalisation options have no effect.",
+                        ENABLE_VOLUME_NORMALISATION, ENABLE_VOLUME_NORMALISATION_SHORT,
+                    );
+                    break;
+                }
+            }
+
+            normalisation_type = player_default_config.normalisation_type;
+            normalisation_method = NormalisationMethod::Basic;
+        } else {
+            normalisation_type = opt_str(NORMALISATION_GAIN_TYPE)
+                .as_deref()
+                .map(|gain_type| {
+                    NormalisationType::from_str(gain_type).map_err(|_| {
+                        invalid_error(
+                            NORMALISATION_GAIN_TYPE,
+                            NORMALISATION_GAIN_TYPE_SHORT,
+                            gain_type,
+                            "track, album, auto",
+                            &format!("{:?}", player_default_config.normalisation_type),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or(player_default_config.normalisation_type);
+
+            normalisation_method = opt_str(NORMALISATION_METHOD)
+                .as_deref()
+                .map(|method| {
+                    NormalisationMethod::from_str(method).map_err(|_| {
+                        invalid_error(
+                            NORMALISATION_METHOD,
+                            "",
+                            method,
+                            "basic, dynamic",
+                            &format!("{:?}", NormalisationMethod::Basic),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or(NormalisationMethod::Basic);
+        }
+
+        // the dynamic limiter's settings, shared with `--limiter`
+        if (!normalisation || normalisation_method != NormalisationMethod::Dynamic)
+            && !opt_present(LIMITER)
+        {
+            for a in &[
+                NORMALISATION_ATTACK,
+                NORMALISATION_RELEASE,
+                NORMALISATION_KNEE,
+            ] {
+                if opt_present(a) {
+                    warn!(
+                        "Without the dynamic normalisation method or `--{}` `--{}` has no effect.",
+                        LIMITER, a
+                    );
+                }
+            }
+        }
+
+        let normalisation_attack_cf = opt_str(NORMALISATION_ATTACK)
+            .map(|attack| match attack.parse::<u64>() {
+                Ok(value) if (VALID_NORMALISATION_ATTACK_RANGE).contains(&value) => {
+                    Ok(duration_to_coefficient(Duration::from_millis(value)))
+                }
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_NORMALISATION_ATTACK_RANGE.start(),
+                        VALID_NORMALISATION_ATTACK_RANGE.end()
+                    );
+
+                    let default_value = &format!(
+                        "{}",
+                        coefficient_to_duration(player_default_config.normalisation_attack_cf)
+                            .as_millis()
+                    );
+
+                    Err(invalid_error(
+                        NORMALISATION_ATTACK,
+                        "",
+                        &attack,
+                        valid_values,
+                        default_value,
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.normalisation_attack_cf);
+
+        let normalisation_release_cf = opt_str(NORMALISATION_RELEASE)
+            .map(|release| match release.parse::<u64>() {
+                Ok(value) if (VALID_NORMALISATION_RELEASE_RANGE).contains(&value) => {
+                    Ok(duration_to_coefficient(Duration::from_millis(value)))
+                }
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_NORMALISATION_RELEASE_RANGE.start(),
+                        VALID_NORMALISATION_RELEASE_RANGE.end()
+                    );
+
+                    let default_value = &format!(
+                        "{}",
+                        coefficient_to_duration(player_default_config.normalisation_release_cf)
+                            .as_millis()
+                    );
+
+                    Err(invalid_error(
+                        NORMALISATION_RELEASE,
+                        "",
+                        &release,
+                        valid_values,
+                        default_value,
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.normalisation_release_cf);
+
+        let normalisation_knee_db = opt_str(NORMALISATION_KNEE)
+            .map(|knee| match knee.parse::<f64>() {
+                Ok(value) if (VALID_NORMALISATION_KNEE_RANGE).contains(&value) => Ok(value),
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_NORMALISATION_KNEE_RANGE.start(),
+                        VALID_NORMALISATION_KNEE_RANGE.end()
+                    );
+
+                    Err(invalid_error(
+                        NORMALISATION_KNEE,
+                        "",
+                        &knee,
+                        valid_values,
+                        &player_default_config.normalisation_knee_db.to_string(),
+                    ))
+                }
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.normalisation_knee_db);
+
+        let ditherer = PlayerConfig::default().ditherer;
+        let passthrough = opt_present(PASSTHROUGH) || opt_present(PASS_THROUGH);
+        if passthrough && opt_present(SNAPCAST) {
+            let error = format!(
+                "`--{}` can't be used with `--{}`, Snapcast can't play Ogg.",
+                PASSTHROUGH, SNAPCAST
+            );
+            return Err(setup_error(ExitCode::InvalidArguments, &error));
+        }
+
+        let equalizer = opt_str(EQ)
+            .map(|eq| {
+                let bands = if Path::new(&eq).is_file() {
+                    fs::read_to_string(&eq).map_err(|e| {
+                        let error = format!("Unable to read equalizer file \"{}\": {}", eq, e);
+                        setup_error(ExitCode::InvalidArguments, &error)
+                    })?
+                } else {
+                    eq
+                };
+
+                parse_eq_bands(&bands).map_err(|band| {
+                    invalid_error(
+                        EQ,
+                        EQ_SHORT,
+                        &band,
+                        "FREQ:GAIN[:Q], bass:GAIN, treble:GAIN (1 - 22049 Hz, -24 - +24 dB, Q > 0)",
+                        "",
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let replaygain_tags = opt_present(REPLAYGAIN_TAGS);
+
+        if replaygain_tags && !passthrough {
+            warn!(
+                "Without the `--{}` / `-{}` flag `--{}` has no effect.",
+                PASSTHROUGH, PASSTHROUGH_SHORT, REPLAYGAIN_TAGS
+            );
+        }
+
+        let metadata_tags = opt_present(METADATA_TAGS);
+
+        if metadata_tags && !passthrough {
+            warn!(
+                "Without the `--{}` / `-{}` flag `--{}` has no effect.",
+                PASSTHROUGH, PASSTHROUGH_SHORT, METADATA_TAGS
+            );
+        }
+
+        let prefetch_bytes = opt_str(PREFETCH_BYTES)
+            .map(|size| match parse_file_size(&size) {
+                Ok(bytes) => Ok(bytes as usize),
+                _ => Err(invalid_error(
+                    PREFETCH_BYTES,
+                    "",
+                    &size,
+                    "a size like 512K or 2M",
+                    "",
+                )),
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.prefetch_bytes);
+
+        let prefetch_duration = opt_str(PREFETCH_SECONDS)
+            .map(|seconds| match seconds.parse::<f32>() {
+                Ok(value) if value >= 0.0 && value.is_finite() => {
+                    Ok(Duration::from_secs_f32(value))
+                }
+                _ => Err(invalid_error(
+                    PREFETCH_SECONDS,
+                    "",
+                    &seconds,
+                    "0 or more",
+                    "",
+                )),
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.prefetch_duration);
+
+        let max_buffer_bytes = opt_str(MAX_BUFFER_MB)
+            .map(|mb| match mb.parse::<usize>() {
+                Ok(value) if (1..=1024).contains(&value) => Ok(value * 1024 * 1024),
+                _ => Err(invalid_error(MAX_BUFFER_MB, "", &mb, "1 - 1024", "")),
+            })
+            .transpose()?;
+
+        let track_marker_fd = opt_str(TRACK_MARKER_FD)
+            .map(|fd| match fd.parse::<i32>() {
+                Ok(fd) if fd >= 2 => Ok(TrackMarker::Fd(fd)),
+                _ => Err(invalid_error(
+                    TRACK_MARKER_FD,
+                    "",
+                    &fd,
+                    "2 or higher, 1 is stdout",
+                    "",
+                )),
+            })
+            .transpose()?;
+
+        if cfg!(not(unix)) && track_marker_fd.is_some() {
+            let error = format!("`--{}` is only supported on Unix.", TRACK_MARKER_FD);
+            return Err(setup_error(ExitCode::InvalidArguments, &error));
+        }
+
+        let track_marker = opt_str(TRACK_MARKER)
+            .map(|marker| match hex::decode(&marker) {
+                Ok(bytes) if !bytes.is_empty() => Ok(TrackMarker::Inline(bytes)),
+                _ => Err(invalid_error(
+                    TRACK_MARKER,
+                    "",
+                    &marker,
+                    "hex encoded bytes",
+                    "",
+                )),
+            })
+            .transpose()?;
+
+        let track_change_lead = opt_str(TRACK_CHANGE_LEAD)
+            .map(|seconds| match seconds.parse::<f32>() {
+                Ok(value) if (0.1..=30.0).contains(&value) => Ok(Duration::from_secs_f32(value)),
+                _ => Err(invalid_error(
+                    TRACK_CHANGE_LEAD,
+                    "",
+                    &seconds,
+                    "0.1 - 30",
+                    "",
+                )),
+            })
+            .transpose()?;
+
+        if track_marker_fd.is_some() && track_marker.is_some() {
+            warn!(
+                "With `--{}` set `--{}` has no effect.",
+                TRACK_MARKER_FD, TRACK_MARKER
+            );
+        }
+
+        if passthrough && !equalizer.is_empty() {
+            warn!("In passthrough mode `--{}` has no effect.", EQ);
+        }
+
+        let pre_gain_db = opt_str(PRE_GAIN)
+            .map(|pre_gain| match pre_gain.parse::<f64>() {
+                Ok(value) if VALID_PRE_GAIN_RANGE.contains(&value) => Ok(value),
+                _ => {
+                    let valid_values = &format!(
+                        "{} - {}",
+                        VALID_PRE_GAIN_RANGE.start(),
+                        VALID_PRE_GAIN_RANGE.end()
+                    );
+                    Err(invalid_error(PRE_GAIN, "", &pre_gain, valid_values, "0"))
+                }
+            })
+            .transpose()?
+            .unwrap_or(player_default_config.pre_gain_db);
+
+        if passthrough && opt_present(PRE_GAIN) {
+            warn!("In passthrough mode `--{}` has no effect.", PRE_GAIN);
+        }
+
+        let limiter = opt_present(LIMITER);
+        if passthrough && limiter {
+            warn!("In passthrough mode `--{}` has no effect.", LIMITER);
+        }
+
+        // set through the control endpoint and remembered per device, like the volume
+        let preferences = cache
+            .as_ref()
+            .and_then(|cache| cache.device_preferences(&connect_config.name))
+            .unwrap_or_default();
+
+        let balance = opt_str(BALANCE)
+            .map(|balance| match balance.parse::<i8>() {
+                Ok(value) if BALANCE_RANGE.contains(&value) => Ok(value),
+                _ => {
+                    let valid_values =
+                        &format!("{} - {}", BALANCE_RANGE.start(), BALANCE_RANGE.end());
+                    Err(invalid_error(BALANCE, "", &balance, valid_values, "0"))
+                }
+            })
+            .transpose()?
+            .unwrap_or(preferences.balance);
+        let swap_channels = opt_present(SWAP_CHANNELS) || preferences.swap_channels;
+
+        if passthrough && (opt_present(BALANCE) || opt_present(SWAP_CHANNELS)) {
+            warn!(
+                "In passthrough mode `--{}` / `--{}` have no effect.",
+                BALANCE, SWAP_CHANNELS
+            );
+        }
+
+        let speed = opt_str(SPEED)
+            .map(|speed| match speed.parse::<f64>() {
+                Ok(value) if SPEED_RANGE.contains(&value) => Ok(value),
+                _ => {
+                    let valid_values = &format!("{} - {}", SPEED_RANGE.start(), SPEED_RANGE.end());
+                    Err(invalid_error(SPEED, "", &speed, valid_values, "1.0"))
+                }
+            })
+            .transpose()?
+            .unwrap_or(1.0);
+
+        if passthrough && opt_present(SPEED) {
+            warn!("In passthrough mode `--{}` has no effect.", SPEED);
+        }
+
+        let analyze_loudness = opt_present(ANALYZE_LOUDNESS);
+        if passthrough && analyze_loudness {
+            warn!(
+                "In passthrough mode `--{}` has no effect.",
+                ANALYZE_LOUDNESS
+            );
+        }
+
+        let offline_fallback = opt_present(OFFLINE_FALLBACK);
+        if offline_fallback && (!opt_present(CACHE) || opt_present(DISABLE_AUDIO_CACHE)) {
+            warn!(
+                "Without a `--{}` path, or if `--{}` is set, `--{}` has no effect.",
+                CACHE, DISABLE_AUDIO_CACHE, OFFLINE_FALLBACK
+            );
+        }
+
+        PlayerConfig {
+            bitrate,
+            gapless,
+            passthrough,
+            replaygain_tags,
+            metadata_tags,
+            normalisation,
+            normalisation_type,
+            normalisation_method,
+            normalisation_pregain_db: player_default_config.normalisation_pregain_db,
+            normalisation_threshold_dbfs: player_default_config.normalisation_threshold_dbfs,
+            normalisation_attack_cf,
+            normalisation_release_cf,
+            normalisation_knee_db,
+            ditherer,
+            decoders: player_default_config.decoders,
+            equalizer,
+            prefetch_bytes,
+            prefetch_duration,
+            max_buffer_bytes,
+            track_marker: track_marker_fd.or(track_marker),
+            track_change_lead,
+            filter_explicit: opt_present(NO_EXPLICIT),
+            stop_position_ms: stop_position.filter(|_| matches!(command, Command::Play(_))),
+            pre_gain_db,
+            limiter,
+            channel_mix: ChannelMix::new(balance, swap_channels),
+            speed: PlaybackSpeed::new(speed),
+            profile: player_default_config.profile,
+            analyze_loudness,
+            offline_fallback,
+            lms_connect_mode: !matches!(command, Command::Play(_) | Command::PlayerServer(_))
+                && !opt_present(SNAPCAST),
+        }
+    };
+
+    let output_profiles = {
+        let mut output_profiles = match opt_str(PROFILES) {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| OutputProfiles::parse(&json, connect_config.max_volume))
+                .map_err(|e| {
+                    let error = format!("Invalid output profiles \"{}\": {}", path, e);
+                    setup_error(ExitCode::InvalidArguments, &error)
+                })?,
+            None => OutputProfiles::new(connect_config.max_volume),
+        };
+
+        if let Some(name) = opt_str(PROFILE) {
+            output_profiles
+                .switch(Some(&name), &player_config, &mut connect_config, None)
+                .map_err(|e| setup_error(ExitCode::InvalidArguments, e))?;
+        }
+        output_profiles
+    };
+
+    let authenticate = opt_present(AUTHENTICATE);
+    let save_token = opt_str(SAVE_TOKEN).unwrap_or("".to_string());
+    let reconnect = {
+        let policy = opt_str(RECONNECT)
+            .as_deref()
+            .map(|policy| {
+                ReconnectPolicy::from_str(policy).map_err(|_| {
+                    invalid_error(RECONNECT, "", policy, "never, limited, forever", "limited")
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let parse_delay = |opt: &'static str, default: Duration| {
+            let max_delay = Reconnect::MAX_DELAY.as_secs_f64();
+            opt_str(opt)
+                .map(|delay| match delay.parse::<f64>() {
+                    Ok(value) if (0.0..=max_delay).contains(&value) => {
+                        Ok(Duration::from_secs_f64(value))
+                    }
+                    _ => {
+                        let valid_values = format!("0 - {} seconds", max_delay);
+                        Err(invalid_error(opt, "", &delay, &valid_values, ""))
+                    }
+                })
+                .unwrap_or(Ok(default))
+        };
+
+        let delay = parse_delay(RECONNECT_DELAY, Reconnect::DEFAULT_DELAY)?;
+        let max_delay = parse_delay(RECONNECT_MAX_DELAY, Reconnect::DEFAULT_MAX_DELAY)?;
+
+        if policy == ReconnectPolicy::Never
+            && (opt_present(RECONNECT_DELAY) || opt_present(RECONNECT_MAX_DELAY))
+        {
+            warn!(
+                "`--{}` and `--{}` have no effect with `--{} never`.",
+                RECONNECT_DELAY, RECONNECT_MAX_DELAY, RECONNECT
+            );
+        }
+
+        Reconnect::new(policy, delay, max_delay.max(delay))
+    };
+
+    let builtin_client_id = include_str!("client_id.txt").trim();
+    let mut client_ids = ClientIds::default();
+    let client_id_values = match matches.opt_strs(CLIENT_ID) {
+        values if values.is_empty() => {
+            vec![opt_str(CLIENT_ID).unwrap_or_else(|| builtin_client_id.to_string())]
+        }
+        values => values,
+    };
+
+    for value in client_id_values.iter().filter(|value| !value.is_empty()) {
+        if !client_ids.add(value) {
+            return Err(invalid_error(
+                CLIENT_ID,
+                CLIENT_ID_SHORT,
+                value,
+                "CLIENT_ID[:SCOPE1,SCOPE2]",
+                "",
+            ));
+        }
+    }
+
+    // for the scopes none of the given client IDs is registered for
+    client_ids.fallback(builtin_client_id);
+
+    if client_ids.for_web_api().is_none() {
+        for a in &[LYRICS, CANVAS] {
+            if opt_present(a) {
+                warn!("Without a `--{}` `--{}` has no effect.", CLIENT_ID, a);
+            }
+        }
+    }
+
+    let on_play = opt_str(ON_PLAY)
+        .as_deref()
+        .map(|on_play| {
+            OnPlay::from_str(on_play).map_err(|_| {
+                invalid_error(ON_PLAY, "", on_play, "power-on, ignore, block", "ignore")
+            })
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let lms = LMS::new(
+        opt_str(LOGITECH_MEDIA_SERVER),
+        opt_str(PLAYER_MAC),
+        opt_str(LMS_AUTH),
+        opt_present(LMS_GROUP_VOLUME),
+        on_play,
+    );
+
+    if opt_present(LMS_GROUP_VOLUME) && !lms.is_configured() {
+        warn!(
+            "Without `--{}` `--{}` has no effect.",
+            PLAYER_MAC, LMS_GROUP_VOLUME
+        );
+    }
+
+    if opt_present(ON_PLAY) && !lms.is_configured() {
+        warn!("Without `--{}` `--{}` has no effect.", PLAYER_MAC, ON_PLAY);
+    }
+
+    let daemon = opt_present(DAEMON);
+    let kill = opt_present(KILL);
+
+    if (daemon || kill) && cfg!(not(unix)) {
+        let error = format!(
+            "`--{}` and `--{}` are only supported on Unix.",
+            DAEMON, KILL
+        );
+        return Err(setup_error(ExitCode::InvalidArguments, &error));
+    }
+
+    let pid_file = opt_str(PID_FILE)
+        .map(PathBuf::from)
+        .or_else(|| opt_str(CACHE).map(|cache| Path::new(&cache).join("spotty.pid")));
+
+    if (daemon || kill) && pid_file.is_none() {
+        let error = format!(
+            "`--{}` and `--{}` require `--{}` or `--{}` / `-{}`.",
+            DAEMON, KILL, PID_FILE, CACHE, CACHE_SHORT
+        );
+        return Err(setup_error(ExitCode::InvalidArguments, &error));
+    }
+
+    if opt_present(OUTPUT_DIR) && !matches!(command, Command::Download(..)) {
+        warn!("Without `--{}` `--{}` has no effect.", DOWNLOAD, OUTPUT_DIR);
+    }
+
+    // a port on localhost, or an address to listen on
+    let listen_address = |opt: &'static str| {
+        opt_str(opt)
+            .map(|value| {
+                let address = match value.parse::<u16>() {
+                    Ok(port) => Some(SocketAddr::from(([127, 0, 0, 1], port))),
+                    Err(_) => value.parse::<SocketAddr>().ok(),
+                };
+                match address {
+                    Some(address) if address.port() != 0 => Ok(address),
+                    _ => {
+                        let valid_values = &format!("1 - {}, optionally preceded by IP:", u16::MAX);
+                        Err(invalid_error(opt, "", &value, valid_values, ""))
+                    }
+                }
+            })
+            .transpose()
+    };
+
+    let status_address = listen_address(STATUS_PORT)?;
+
+    let control_address = listen_address(CONTROL_PORT)?;
+
+    let buffer_debug = opt_str(BUFFER_DEBUG)
+        .map(|seconds| match seconds.parse::<f32>() {
+            Ok(value) if (0.1..=3600.0).contains(&value) => Ok(Duration::from_secs_f32(value)),
+            _ => Err(invalid_error(BUFFER_DEBUG, "", &seconds, "0.1 - 3600", "")),
+        })
+        .transpose()?;
+
+    let volume_debounce = opt_str(VOLUME_DEBOUNCE)
+        .map(|ms| match ms.parse::<u64>() {
+            Ok(value) if value <= 5000 => Ok(value),
+            _ => Err(invalid_error(VOLUME_DEBOUNCE, "", &ms, "0 - 5000", "0")),
+        })
+        .transpose()?
+        .filter(|&ms| ms != 0)
+        .map(Duration::from_millis);
+
+    let idle_timeout = opt_str(IDLE_TIMEOUT)
+        .map(|minutes| match minutes.parse::<u64>() {
+            Ok(value) if (1..=1440).contains(&value) => Ok(Duration::from_secs(value * 60)),
+            _ => Err(invalid_error(IDLE_TIMEOUT, "", &minutes, "1 - 1440", "")),
+        })
+        .transpose()?;
+
+    let runtime_threads = opt_str(RUNTIME_THREADS)
+        .map(|threads| match threads.parse::<usize>() {
+            Ok(value) if (1..=64).contains(&value) => Ok(value),
+            _ => Err(invalid_error(RUNTIME_THREADS, "", &threads, "1 - 64", "1")),
+        })
+        .transpose()?
+        .unwrap_or(1);
+
+    let control_token = opt_str(CONTROL_TOKEN).filter(|token| !token.is_empty());
+    if control_address.is_some() && control_token.is_none() && opt_str(CACHE).is_none() {
+        let error = format!(
+            "`--{}` needs `--{}` or a cache to save its token to.",
+            CONTROL_PORT, CONTROL_TOKEN
+        );
+        return Err(setup_error(ExitCode::InvalidArguments, &error));
+    }
+
+    if idle_timeout.is_some() && !enable_discovery && control_address.is_none() {
+        warn!(
+            "Without discovery or `--{}` nothing can reconnect after `--{}`.",
+            CONTROL_PORT, IDLE_TIMEOUT
+        );
+    }
+
+    // the player in Connect mode only keeps track of the playback for LMS, unless it streams to
+    // Snapcast
+    let (backend, device) = if opt_present(SNAPCAST) {
+        ("snapcast", opt_str(SNAPCAST))
+    } else {
+        (spotty::BACKEND, Some(NULLDEVICE.to_string()))
+    };
+    let backend = audio_backend::find(Some(backend.to_string())).unwrap();
+
+    Ok(Setup {
+        format: AudioFormat::default(),
+        backend,
+        device,
+        mixer,
+        cache,
+        player_config,
+        session_config,
+        connect_config,
+        mixer_config,
+        credentials,
+        enable_discovery,
+        zeroconf_port,
+        zeroconf_interface: opt_str(ZEROCONF_INTERFACE),
+        mdns_backend,
+        zeroconf_txt,
+        device_brand: opt_str(DEVICE_BRAND),
+        device_model: opt_str(DEVICE_MODEL),
+        // spotty
+        daemon,
+        kill,
+        pid_file,
+        stats_file: opt_str(STATS_FILE).map(PathBuf::from),
+        command: command.clone(),
+        cache_size_limit,
+        cache_dir: opt_str(CACHE).map(PathBuf::from),
+        authenticate,
+        start_position,
+        save_token: if save_token.is_empty() {
+            None
+        } else {
+            Some(save_token)
+        },
+        client_ids,
+        reconnect,
+        status_address,
+        control_address,
+        control_token,
+        buffer_debug,
+        volume_debounce,
+        idle_timeout,
+        runtime_threads,
+        lyrics: opt_present(LYRICS),
+        canvas: opt_present(CANVAS),
+        color_palette: opt_present(COLOR_PALETTE),
+        snapcast_metadata: opt_str(SNAPCAST_METADATA).map(PathBuf::from),
+        take_over: opt_present(TAKE_OVER),
+        resume_on_start: opt_present(RESUME_ON_START),
+        alarm,
+        output_profiles,
+        scopes: opt_str(SCOPE),
+        lms,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_size() {
+        assert_eq!(parse_file_size("1000").unwrap(), 1000);
+        assert_eq!(parse_file_size("500M").unwrap(), 500_000_000);
+        assert_eq!(parse_file_size("2g").unwrap(), 2_000_000_000);
+        assert_eq!(parse_file_size("1.5Ki").unwrap(), 1536);
+        assert_eq!(parse_file_size("1.5GiB").unwrap(), 1_610_612_736);
+        assert_eq!(parse_file_size(" 10KB ").unwrap(), 10_000);
+        assert_eq!(parse_file_size("10B").unwrap(), 10);
+
+        assert!(matches!(
+            parse_file_size(""),
+            Err(ParseFileSizeError::EmptyInput)
+        ));
+        assert!(matches!(
+            parse_file_size("M"),
+            Err(ParseFileSizeError::EmptyInput)
+        ));
+        assert!(matches!(
+            parse_file_size("10X"),
+            Err(ParseFileSizeError::InvalidSuffix)
+        ));
+        assert!(matches!(
+            parse_file_size("1i"),
+            Err(ParseFileSizeError::InvalidSuffix)
+        ));
+        assert!(matches!(
+            parse_file_size("ten M"),
+            Err(ParseFileSizeError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            parse_file_size("infG"),
+            Err(ParseFileSizeError::NotFinite(_))
+        ));
+        assert!(matches!(
+            parse_file_size("-5M"),
+            Err(ParseFileSizeError::Negative)
+        ));
+    }
+
+    #[test]
+    fn test_command() {
+        let command = |args: &[&str]| {
+            let argv = ["spotty"].iter().chain(args).map(|arg| arg.to_string());
+            Args::parse(argv.collect()).map(|args| args.command)
+        };
+
+        assert_eq!(command(&[]).unwrap(), Command::Connect);
+        assert_eq!(command(&["-n", "Kitchen"]).unwrap(), Command::Connect);
+        assert_eq!(
+            command(&["play", "abc"]).unwrap(),
+            command(&["--single-track", "abc"]).unwrap()
+        );
+        assert_eq!(
+            command(&["serve", "24879"]).unwrap(),
+            Command::PlayerServer(24879)
+        );
+        assert_eq!(command(&["cache"]).unwrap(), Command::CacheStats);
+        assert_eq!(
+            command(&["cache", "usage"]).unwrap(),
+            command(&["--stats"]).unwrap()
+        );
+        assert_eq!(
+            command(&["--save-token", "token.json"]).unwrap(),
+            Command::Token
+        );
+        assert_eq!(
+            command(&["--download", "abc", "--output-dir", "out"]).unwrap(),
+            Command::Download("abc".to_string(), PathBuf::from("out"))
+        );
+
+        // the first flag in the order they used to be checked in wins
+        assert_eq!(
+            command(&["--single-track", "abc", "--cache-stats"]).unwrap(),
+            Command::CacheStats
+        );
+
+        assert!(command(&["serve", "0"]).is_err());
+        assert!(command(&["--download", "abc"]).is_err());
+        assert!(command(&["play"]).is_err());
+        assert!(command(&["play", "abc", "def"]).is_err());
+        assert!(command(&["play", "abc", "--cache-stats"]).is_err());
+        assert!(command(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(parse_position("90").unwrap(), 90_000);
+        assert_eq!(parse_position("90s").unwrap(), 90_000);
+        assert_eq!(parse_position("1.5").unwrap(), 1_500);
+        assert_eq!(parse_position("90000ms").unwrap(), 90_000);
+        assert_eq!(parse_position("1:30").unwrap(), 90_000);
+        assert_eq!(parse_position("00:01:30.500").unwrap(), 90_500);
+        assert_eq!(parse_position(" 2:00:00 ").unwrap(), 7_200_000);
+        assert_eq!(parse_position("0").unwrap(), 0);
+        assert_eq!(parse_position("4294967295ms").unwrap(), u32::MAX);
+
+        assert!(matches!(
+            parse_position(""),
+            Err(ParsePositionError::EmptyInput)
+        ));
+        assert!(matches!(
+            parse_position("1:2:3:4"),
+            Err(ParsePositionError::TooManyFields)
+        ));
+        assert!(matches!(
+            parse_position("1:xx"),
+            Err(ParsePositionError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            parse_position("-5"),
+            Err(ParsePositionError::OutOfRange)
+        ));
+        assert!(matches!(
+            parse_position("-0:30"),
+            Err(ParsePositionError::OutOfRange)
+        ));
+        assert!(matches!(
+            parse_position("4294967296ms"),
+            Err(ParsePositionError::OutOfRange)
+        ));
+        assert!(matches!(
+            parse_position("1000000000:00:00"),
+            Err(ParsePositionError::OutOfRange)
+        ));
+        assert!(matches!(
+            parse_position("inf"),
+            Err(ParsePositionError::OutOfRange)
+        ));
+    }
+}
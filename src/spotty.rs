@@ -1,35 +1,80 @@
-use hyper::{Body, Client, Method, Request};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
 #[allow(unused)]
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
+use futures_util::future;
+use rand::Rng;
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
-use std::process::exit;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 
-use librespot::core::authentication::Credentials;
-use librespot::core::config::SessionConfig;
-use librespot::core::keymaster;
-use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
+use crate::connect::spirc::Spirc;
+use crate::control::json_response;
+use crate::core::authentication::Credentials;
+use crate::core::cache::Cache;
+use crate::core::config::SessionConfig;
+use crate::core::data_usage::{self, DataUsage};
+use crate::core::http;
+use crate::core::keymaster;
+use crate::core::mercury::MercuryError;
+use crate::core::oauth;
+use crate::core::session::{Session, SessionError};
+use crate::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
+use crate::protocol::authentication::AuthenticationType::AUTHENTICATION_USER_PASS;
 
-use librespot::playback::audio_backend;
-use librespot::playback::config::{AudioFormat, PlayerConfig};
-use librespot::playback::mixer::NoOpVolume;
-use librespot::playback::player::{Player, PlayerEvent};
+use crate::discovery::MdnsBackend;
+use crate::metadata::{Album, Artist, Episode, FileFormat, Metadata, Playlist, Show, Track};
+use crate::playback::audio_backend::{self, ChannelData, ChannelSink, Sink};
+use crate::playback::config::{AudioFormat, PlayerConfig};
+use crate::playback::decoder;
+use crate::playback::dsp;
+use crate::playback::mixer::NoOpVolume;
+use crate::playback::player::{
+    export_track, get_track_info, prefetch_track, ErrorCategory, Player, PlayerEvent,
+    PlayerEventChannel, UnavailableReason, AUDIO_ERROR_EXIT_CODE,
+};
+use crate::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use crate::web_api::{track_details, Lyrics};
 
 const VERSION: &'static str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
 
-const SCOPES: &str = "user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played";
+pub(crate) const SCOPES: &str = "user-read-private,playlist-read-private,playlist-read-collaborative,playlist-modify-public,playlist-modify-private,user-follow-modify,user-follow-read,user-library-read,user-library-modify,user-top-read,user-read-recently-played";
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "spotty";
+
+const SYSTEMD_PASSWORD: &str = "spotty-password";
+
+// Always write to a pipe, even if other backends were enabled at build time (eg. for the alsa mixer)
+pub const BACKEND: &str = "pipe";
 
 #[cfg(debug_assertions)]
 const DEBUGMODE: bool = true;
 #[cfg(not(debug_assertions))]
 const DEBUGMODE: bool = false;
 
-pub fn check(version_info: String) {
-    println!("ok {}", version_info.to_string());
+// What this binary supports, for `--check` to print after the line older plugins expect
+pub fn capabilities(options: Vec<String>) -> Value {
+    let mdns_backends = [
+        ("libmdns", MdnsBackend::Libmdns),
+        ("avahi", MdnsBackend::Avahi),
+        ("dnssd", MdnsBackend::DnsSd),
+    ]
+    .iter()
+    .filter(|(_, backend)| backend.is_available())
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>();
 
-    let capabilities = json!({
+    json!({
         "version": env!("CARGO_PKG_VERSION").to_string(),
         "autoplay": true,
         "lms-auth": true,
@@ -38,75 +83,664 @@ pub fn check(version_info: String) {
         "ogg-direct": true,
         "save-token": true,
         "podcasts": true,
-        "zeroconf-port": true
-    });
+        "zeroconf-port": true,
+        "equalizer": true,
+        "replaygain-tags": true,
+        "get-metadata": true,
+        "dry-run": true,
+        "check-config": true,
+        "volume-ctrl": true,
+        "alsa-mixer": cfg!(feature = "alsa-backend"),
+        "stats": true,
+        "cache-size-limit": true,
+        "prefetch": true,
+        "credentials-encryption": true,
+        "credentials-passphrase-file": true,
+        "credentials-keyring": cfg!(feature = "keyring"),
+        "accounts": true,
+        "device-type": true,
+        "zeroconf-interface": true,
+        "pair": true,
+        "login-oauth": true,
+        "token-info": true,
+        "client-id-scopes": true,
+        "reconnect": true,
+        "ap-address": true,
+        "https-proxy": true,
+        "bind-address": true,
+        "status-port": true,
+        "log-formats": ["text", "json"],
+        "log-file": true,
+        "no-log": true,
+        "log-fd": cfg!(unix),
+        "daemon": cfg!(unix),
+        "download": true,
+        "metadata-tags": true,
+        "track-marker": true,
+        "track-marker-fd": cfg!(unix),
+        "prefetch-size": true,
+        "max-download-rate": true,
+        "stream-format": true,
+        "control-port": true,
+        "control-queue": true,
+        "take-over": true,
+        "lms-group-volume": true,
+        "self-update": true,
+        "unavailable-reasons": true,
+        "max-consecutive-skips": true,
+        "no-explicit": true,
+        "stats-file": true,
+        "stop-position": true,
+        "preview": true,
+        "resume-on-start": true,
+        "play-at": true,
+        "volume-steps": true,
+        "device-id": true,
+        "export-credentials": true,
+        "password-fd": true,
+        "json-errors": true,
+        "subcommands": true,
+        "player-server": true,
+        "buffer-debug": true,
+        "balance": true,
+        "profiles": true,
+        "pre-gain": true,
+        "limiter": true,
+        "normalisation-method": true,
+        "max-volume": true,
+        "volume-debounce": true,
+        "on-play": true,
+        "idle-timeout": true,
+        "connection-events": true,
+        "error-events": true,
+        "force-tls": true,
+        "dns-resolver": true,
+        "network-timeout": true,
+        "offline-fallback": true,
+        "max-buffer": true,
+        "runtime-threads": true,
+        "track-change-lead": true,
+        "lyrics": true,
+        "cpu-features": dsp::cpu_features(),
+        "extended-metadata": true,
+        "canvas": true,
+        "color-palette": true,
+        "like-follow": true,
+        "playlist-add": true,
+        "control-load": true,
+        "seek-relative": true,
+        "chapters": true,
+        "speed": true,
+        "intent": true,
+        "snapcast": true,
+        "analyze-loudness": true,
+        "discovery": !mdns_backends.is_empty(),
+        "mdns-backends": mdns_backends,
+        "backends": audio_backend::BACKENDS
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>(),
+        "tls": "rustls",
+        "passthrough": true,
+        "decoders": decoder::DECODERS
+            .iter()
+            .map(|(name, codec, _)| json!({ "name": name, "codec": format!("{:?}", codec) }))
+            .collect::<Vec<_>>(),
+        "platform": {
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "family": std::env::consts::FAMILY,
+        },
+        "options": options,
+    })
+}
+
+// Why spotty stopped, as its exit code, so the LMS plugin can react to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ExitCode {
+    Error = 1,
+    InvalidArguments = 2,
+    PasswordRequired = 3,
+    AuthFailed = 4,
+    NetworkError = 5,
+    Unavailable = 6,
+    AudioError = AUDIO_ERROR_EXIT_CODE,
+}
+
+impl ExitCode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitCode::Error => "error",
+            ExitCode::InvalidArguments => "invalid_arguments",
+            ExitCode::PasswordRequired => "password_required",
+            ExitCode::AuthFailed => "auth_failed",
+            ExitCode::NetworkError => "network_error",
+            ExitCode::Unavailable => "unavailable",
+            ExitCode::AudioError => "audio_error",
+        }
+    }
+}
+
+/// Why a command or the runtime failed, with the exit code to report it with.
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub struct Error {
+    pub code: ExitCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ExitCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Whether a session failed because of the credentials or the network
+fn session_error_code(error: &SessionError) -> ExitCode {
+    if error.is_login_failure() {
+        ExitCode::AuthFailed
+    } else {
+        ExitCode::NetworkError
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReconnectPolicy {
+    Never,
+    Limited,
+    Forever,
+}
+
+impl FromStr for ReconnectPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "never" => Ok(Self::Never),
+            "limited" => Ok(Self::Limited),
+            "forever" => Ok(Self::Forever),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::Limited
+    }
+}
+
+/// What to do when Connect playback starts while the Squeezebox is switched off in LMS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnPlay {
+    PowerOn,
+    Ignore,
+    Block,
+}
+
+impl FromStr for OnPlay {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "power-on" => Ok(Self::PowerOn),
+            "ignore" => Ok(Self::Ignore),
+            "block" => Ok(Self::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for OnPlay {
+    fn default() -> Self {
+        Self::Ignore
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        Self::Text
+    }
+}
+
+/// A log file which is moved aside once it grows too large, keeping a number of old files as
+/// `<path>.1` (the most recent) to `<path>.<keep>`.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+    pub const DEFAULT_KEEP: usize = 3;
+
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            // rename() doesn't replace existing files on Windows
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for index in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Decides whether and when to reconnect after the connection to Spotify was lost.
+pub struct Reconnect {
+    policy: ReconnectPolicy,
+    initial_delay: Duration,
+    max_delay: Duration,
+    attempts: Vec<Instant>,
+    failures: u32,
+}
+
+impl Reconnect {
+    // only used with ReconnectPolicy::Limited
+    const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(600);
+    const RATE_LIMIT: usize = 5;
+
+    pub const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+    pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(300);
+    /// The longest delay which can be configured, a day.
+    pub const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new(policy: ReconnectPolicy, initial_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            policy,
+            initial_delay,
+            max_delay,
+            attempts: Vec::new(),
+            failures: 0,
+        }
+    }
+
+    /// Forget about earlier attempts, eg. when new credentials were received.
+    pub fn reset(&mut self) {
+        self.attempts.clear();
+        self.failures = 0;
+    }
+
+    /// The session was established, the next attempt starts with the initial delay again.
+    pub fn connected(&mut self) {
+        self.failures = 0;
+    }
 
-    println!("{}", capabilities.to_string());
-    exit(0);
+    /// Returns how long to wait before the next attempt, or `None` to give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        match self.policy {
+            ReconnectPolicy::Never => return None,
+            ReconnectPolicy::Limited => {
+                self.attempts
+                    .retain(|t| t.elapsed() < Self::RATE_LIMIT_WINDOW);
+                if self.attempts.len() > Self::RATE_LIMIT {
+                    return None;
+                }
+                self.attempts.push(Instant::now());
+            }
+            ReconnectPolicy::Forever => (),
+        }
+
+        // exponential backoff, with jitter so several devices don't reconnect in lockstep
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max_delay);
+        self.failures = self.failures.saturating_add(1);
+
+        Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)))
+    }
+}
+
+/// Client IDs to get tokens with, optionally restricted to the scopes they are registered for.
+#[derive(Debug, Default)]
+pub struct ClientIds(Vec<(String, Vec<String>)>);
+
+impl ClientIds {
+    /// Adds a client ID given as `CLIENT_ID` or `CLIENT_ID:SCOPE1,SCOPE2`. Returns `false` if
+    /// the value is invalid.
+    pub fn add(&mut self, value: &str) -> bool {
+        let (id, scopes) = match value.split_once(':') {
+            Some((id, scopes)) => (id, split_scopes(scopes)),
+            None => (value, Vec::new()),
+        };
+
+        if id.is_empty() || (value.contains(':') && scopes.is_empty()) {
+            return false;
+        }
+
+        self.0.push((id.to_string(), scopes));
+        true
+    }
+
+    /// Returns the first client ID registered for all of `scopes`, or else the first one
+    /// without any scopes.
+    pub fn for_scopes(&self, scopes: &str) -> Option<&str> {
+        let requested = split_scopes(scopes);
+
+        self.0
+            .iter()
+            .find(|(_, scopes)| {
+                !scopes.is_empty() && requested.iter().all(|scope| scopes.contains(scope))
+            })
+            .or_else(|| self.0.iter().find(|(_, scopes)| scopes.is_empty()))
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Uses `id` for the scopes none of the client IDs given as `CLIENT_ID:SCOPE1,SCOPE2` is
+    /// registered for, unless a client ID without scopes was given.
+    pub fn fallback(&mut self, id: &str) {
+        let has_fallback = self.0.iter().any(|(_, scopes)| scopes.is_empty());
+        if !id.is_empty() && !self.0.is_empty() && !has_fallback {
+            self.0.push((id.to_string(), Vec::new()));
+        }
+    }
+
+    /// The client ID for `WebApi` requests.
+    pub fn for_web_api(&self) -> Option<&str> {
+        self.for_scopes(SCOPES)
+    }
+}
+
+fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 // inspired by examples/get_token.rs
 pub async fn get_token(
-    client_id: Option<String>,
+    client_ids: ClientIds,
     scopes: Option<String>,
-    save_token: Option<String>,
     last_credentials: Option<Credentials>,
     session_config: SessionConfig,
-) {
-    match last_credentials {
-        Some(last_credentials) => {
-            if let Some(client_id) = client_id {
-                let scopes = scopes.unwrap_or(SCOPES.to_string());
-
-                match Session::connect(session_config, last_credentials, None, true).await {
-                    Ok((session, _)) => {
-                        match keymaster::get_token(&session, &client_id, &scopes).await {
-                            Ok(token) => {
-                                write_response(
-                                    json!({
-                                        "accessToken": token.access_token.to_string(),
-                                        "expiresIn": token.expires_in,
-                                    }),
-                                    save_token,
-                                );
-                            }
-                            Err(error) => {
-                                error!("Failed to fetch token: {:?}", error);
-                                write_response(
-                                    json!({
-                                        "error": "Failed to get access token."
-                                    }),
-                                    save_token,
-                                );
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        error!("Failed to create session: {:?}", error);
-                        write_response(
-                            json!({
-                                "error": "Failed to create session or connect to servers."
-                            }),
-                            save_token,
-                        );
-                    }
-                }
-            } else {
-                println!("Use --client-id to provide a CLIENT_ID");
-            }
-        }
+) -> Result<Value, Error> {
+    let last_credentials = match last_credentials {
+        Some(last_credentials) => last_credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
+
+    let scopes = scopes.unwrap_or(SCOPES.to_string());
+    let client_id = match client_ids.for_scopes(&scopes) {
+        Some(client_id) => client_id,
         None => {
-            println!("Missing credentials");
+            return Err(Error::new(
+                ExitCode::InvalidArguments,
+                "Use --client-id to provide a CLIENT_ID for these scopes",
+            ))
+        }
+    };
+
+    let session = match Session::connect(session_config, last_credentials, None, true).await {
+        Ok((session, _)) => session,
+        Err(error) => {
+            error!("Failed to create session: {:?}", error);
+            return Err(Error::new(
+                session_error_code(&error),
+                "Failed to create session or connect to servers.",
+            ));
+        }
+    };
+
+    match keymaster::get_token(&session, client_id, &scopes).await {
+        Ok(token) => Ok(json!({
+            "accessToken": token.access_token.to_string(),
+            "expiresIn": token.expires_in,
+            "expiresAt": unix_time() + token.expires_in as u64,
+            "scope": token.scope,
+            "clientId": client_id,
+        })),
+        Err(error) => {
+            error!("Failed to fetch token: {:?}", error);
+            Err(Error::new(
+                ExitCode::NetworkError,
+                "Failed to get access token.",
+            ))
         }
     }
 }
 
-fn write_response(json_token: Value, save_token: Option<String>) {
-    if let Some(save_token) = save_token {
-        fs::write(save_token.to_string(), json_token.to_string()).expect("Can't write token file");
-    } else {
-        println!("{}", json_token);
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Describe a token saved with --save-token, so LMS knows when to get a new one
+pub fn token_info(token_file: &str) -> Result<Value, Error> {
+    let token = fs::read_to_string(token_file)
+        .ok()
+        .and_then(|data| serde_json::from_str::<Value>(&data).ok())
+        .filter(|token| token["accessToken"].is_string());
+
+    let token = match token {
+        Some(token) => token,
+        None => return Err(Error::new(ExitCode::AuthFailed, "No valid token found.")),
+    };
+
+    // tokens saved by older versions don't know when they expire
+    let expires_in = token["expiresAt"]
+        .as_u64()
+        .map(|expires_at| expires_at.saturating_sub(unix_time()));
+
+    Ok(json!({
+        "clientId": token["clientId"],
+        "scope": token["scope"],
+        "expiresAt": token["expiresAt"],
+        "expiresIn": expires_in,
+        "valid": expires_in.map(|expires_in| expires_in > 0),
+    }))
+}
+
+// Snapcast: the metadata and playback status, for a stream control script to pass on to the
+// Snapcast server with the notifications of its stream plugin API
+
+/// Appends the properties of the player to a file or named pipe whenever they change, as
+/// `Plugin.Stream.Player.Properties` notifications, one per line. Clones share the properties.
+#[derive(Clone)]
+pub struct SnapcastMetadata {
+    path: PathBuf,
+    properties: Arc<Mutex<Value>>,
+}
+
+impl SnapcastMetadata {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            properties: Arc::new(Mutex::new(json!({ "playbackStatus": "stopped" }))),
+        }
+    }
+
+    /// Updates the playback status and position.
+    pub fn player_event(&self, event: &PlayerEvent) {
+        let (playback_status, position_ms) = match *event {
+            PlayerEvent::Playing { position_ms, .. } => ("playing", Some(position_ms)),
+            PlayerEvent::Paused { position_ms, .. } => ("paused", Some(position_ms)),
+            PlayerEvent::Stopped { .. } => ("stopped", None),
+            _ => return,
+        };
+
+        let mut properties = self.properties.lock().unwrap();
+        properties["playbackStatus"] = json!(playback_status);
+        properties["position"] = json!(position_ms.map(|ms| f64::from(ms) / 1000.0));
+        self.write(&properties);
     }
+
+    /// Fetches the metadata of `track_id`, which is loading, and updates it.
+    pub async fn track_loading(self, session: Session, track_id: SpotifyId) {
+        let metadata = match snapcast_track_metadata(&session, track_id).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!(
+                    "Unable to get the metadata of <{}>: {:?}",
+                    track_id.to_uri().unwrap_or_default(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut properties = self.properties.lock().unwrap();
+        properties["metadata"] = metadata;
+        self.write(&properties);
+    }
+
+    fn write(&self, properties: &Value) {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "Plugin.Stream.Player.Properties",
+            "params": properties,
+        });
+
+        let result =
+            open_for_append(&self.path).and_then(|mut file| writeln!(file, "{}", notification));
+        if let Err(e) = result {
+            debug!(
+                "Failed to write the Snapcast metadata to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+// The metadata of a track or episode, in the format of the Snapcast stream plugin API
+async fn snapcast_track_metadata(
+    session: &Session,
+    track_id: SpotifyId,
+) -> Result<Value, MercuryError> {
+    let cover_url = |cover: &FileId| format!("{}{}", COVER_URL, cover);
+
+    let mut metadata = match track_id.audio_type {
+        SpotifyAudioType::Podcast => {
+            let episode = Episode::get(session, track_id).await?;
+            let show = Show::get(session, episode.show).await?;
+            let cover = episode.covers.first().or_else(|| show.covers.first());
+            json!({
+                "title": episode.name,
+                "artist": [show.publisher],
+                "album": show.name,
+                "artUrl": cover.map(cover_url),
+                "duration": f64::from(episode.duration) / 1000.0,
+            })
+        }
+        _ => {
+            let track = Track::get(session, track_id).await?;
+            let artists = track.artists.iter().map(|id| Artist::get(session, *id));
+            let (album, artists) =
+                future::join(Album::get(session, track.album), future::join_all(artists)).await;
+            let album = album?;
+            let artists: Vec<String> = artists
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|artist| artist.name)
+                .collect();
+            json!({
+                "title": track.name,
+                "artist": artists,
+                "album": album.name,
+                "artUrl": album.covers.first().map(cover_url),
+                "duration": f64::from(track.duration) / 1000.0,
+                "trackNumber": track.number,
+            })
+        }
+    };
+
+    metadata["trackId"] = json!(track_id.to_uri().unwrap_or_default());
+    Ok(metadata)
+}
+
+// A named pipe without a reader fails to open, rather than block
+#[cfg(unix)]
+fn open_for_append(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_for_append(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().append(true).create(true).open(path)
 }
 
 // inspired by examples/play.rs
@@ -114,63 +748,1001 @@ pub async fn play_track(
     track_id: String,
     start_position: u32,
     last_credentials: Option<Credentials>,
+    cache: Option<Cache>,
     player_config: PlayerConfig,
     session_config: SessionConfig,
-) {
-    match last_credentials {
-        Some(last_credentials) => {
-            let backend = audio_backend::find(None).unwrap();
-            let audio_format = AudioFormat::default();
-
-            let track = SpotifyId::from_uri(
-                track_id
-                    .replace("spotty://", "spotify:track:")
-                    .replace("://", ":")
-                    .as_str(),
-            );
+) -> Result<(), Error> {
+    let last_credentials = match last_credentials {
+        Some(last_credentials) => last_credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
 
-            match track {
-                Ok(track) => match Session::connect(session_config, last_credentials, None, true)
-                    .await
-                {
-                    Ok((session, _)) => {
-                        let (mut player, _) =
-                            Player::new(player_config, session, Box::new(NoOpVolume), move || {
-                                backend(None, audio_format)
-                            });
-
-                        player.load(track, true, start_position);
-                        player.await_end_of_track().await;
-                    }
-                    Err(error) => {
-                        error!("Failed to create session: {:?}", error);
-                        return;
-                    }
-                },
-                Err(error) => {
-                    error!("Problem getting a Spotify ID for {}: {:?}", track_id, error);
-                    return;
+    let backend = audio_backend::find(Some(BACKEND.to_string())).unwrap();
+    let audio_format = AudioFormat::default();
+
+    // the cache has the access points, so connecting needn't wait for resolving them
+    let started = Instant::now();
+    let connection = Session::connect(session_config, last_credentials, cache, true);
+    let track = match get_spotify_id(&track_id) {
+        Some(track) => track,
+        None => return Err(Error::new(ExitCode::InvalidArguments, "Invalid track ID.")),
+    };
+
+    let session = match connection.await {
+        Ok((session, _)) => session,
+        Err(error) => {
+            let code = session_error_code(&error);
+            let error = format!("Failed to create session: {}", error);
+            return Err(Error::new(code, error));
+        }
+    };
+
+    debug!("Connected after {} ms", started.elapsed().as_millis());
+    let (mut player, _) = Player::new(player_config, session, Box::new(NoOpVolume), move || {
+        backend(None, audio_format)
+    });
+
+    // stdout is used for the audio, report a track which can't be played in the log
+    let mut events = player.get_player_event_channel();
+    let unavailable = tokio::spawn(async move {
+        let mut unavailable = None;
+        while let Some(event) = events.recv().await {
+            if let PlayerEvent::Unavailable { reason, .. } = event {
+                unavailable = Some(reason);
+            }
+        }
+        unavailable
+    });
+
+    player.load(track, true, start_position);
+    player.await_end_of_track().await;
+
+    // closes the event channel
+    drop(player);
+    match unavailable.await {
+        Ok(Some(reason)) => {
+            let error = format!("Unable to play {}: {}", track_id, reason);
+            Err(Error::new(ExitCode::Unavailable, error))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) const COVER_URL: &str = "https://i.scdn.co/image/";
+
+pub(crate) fn get_spotify_id(track_id: &str) -> Option<SpotifyId> {
+    let uri = match track_id.strip_prefix("spotty://") {
+        Some(id) if id.contains(':') => format!("spotify:{}", id),
+        Some(id) => format!("spotify:track:{}", id),
+        None => track_id.replace("://", ":"),
+    };
+
+    match SpotifyId::from_uri(&uri) {
+        Ok(track) => Some(track),
+        Err(error) => {
+            error!("Problem getting a Spotify ID for {}: {:?}", track_id, error);
+            None
+        }
+    }
+}
+
+// Let LMS decide whether to ask for passthrough or decoded output before playback
+pub async fn get_metadata(
+    track_id: String,
+    last_credentials: Option<Credentials>,
+    cache: Option<Cache>,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Result<Value, Error> {
+    let last_credentials = match last_credentials {
+        Some(last_credentials) => last_credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
+
+    let track = match get_spotify_id(&track_id) {
+        Some(track) => track,
+        None => return Err(Error::new(ExitCode::InvalidArguments, "Invalid track ID.")),
+    };
+
+    let session = match Session::connect(session_config, last_credentials, cache, true).await {
+        Ok((session, _)) => session,
+        Err(error) => {
+            error!("Failed to create session: {:?}", error);
+            return Err(Error::new(
+                session_error_code(&error),
+                "Failed to create session or connect to servers.",
+            ));
+        }
+    };
+
+    // podcast episodes have none
+    let details = match track.audio_type {
+        SpotifyAudioType::Track => match track_details(&session, track, false).await {
+            Ok(details) => Some(details),
+            Err(error) => {
+                warn!("Failed to get the details of {}: {:?}", track_id, error);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let info = match get_track_info(session, player_config, track).await {
+        Some(info) => info,
+        None => return Err(Error::new(ExitCode::Unavailable, "Track is not available.")),
+    };
+
+    let (codec, bitrate) = match info.format {
+        FileFormat::OGG_VORBIS_96 => ("vorbis", 96),
+        FileFormat::OGG_VORBIS_160 => ("vorbis", 160),
+        FileFormat::OGG_VORBIS_320 => ("vorbis", 320),
+        _ => ("unknown", 0),
+    };
+
+    let normalisation = info.normalisation_data.map(|data| {
+        json!({
+            "trackGain": data.track_gain_db,
+            "trackPeak": data.track_peak,
+            "albumGain": data.album_gain_db,
+            "albumPeak": data.album_peak,
+        })
+    });
+
+    Ok(json!({
+        "name": info.name,
+        "uri": info.uri,
+        "duration": info.duration_ms,
+        "codec": codec,
+        "bitrate": bitrate,
+        "format": format!("{:?}", info.format),
+        "sampleRate": SAMPLE_RATE,
+        "channels": NUM_CHANNELS,
+        "normalisation": normalisation,
+        "details": details,
+    }))
+}
+
+// Get the passphrase for the credentials from the OS keyring, creating a random one on first use
+#[cfg(feature = "keyring")]
+pub fn keyring_passphrase() -> Option<String> {
+    use rand::RngCore;
+
+    let entry = keyring::Entry::new(KEYRING_SERVICE, "credentials");
+
+    match entry.get_password() {
+        Ok(passphrase) => Some(passphrase),
+        Err(keyring::Error::NoEntry) => {
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            let passphrase = hex::encode(secret);
+
+            match entry.set_password(&passphrase) {
+                Ok(_) => Some(passphrase),
+                Err(e) => {
+                    warn!("Cannot store credentials passphrase in keyring: {}", e);
+                    None
                 }
-            };
+            }
+        }
+        Err(e) => {
+            warn!("Cannot read credentials passphrase from keyring: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn keyring_passphrase() -> Option<String> {
+    warn!("Built without keyring support, credentials will not be encrypted.");
+    None
+}
+
+// Read a password from a file descriptor opened by a service manager, eg. `--password-fd 3 3<file`
+#[cfg(unix)]
+pub fn read_password_fd(fd: i32) -> io::Result<String> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut password = String::new();
+    file.read_to_string(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(not(unix))]
+pub fn read_password_fd(_fd: i32) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "not supported on this platform",
+    ))
+}
+
+// The password passed with systemd's `LoadCredential=spotty-password:/path/to/file`
+pub fn systemd_password() -> Option<String> {
+    let directory = std::env::var_os("CREDENTIALS_DIRECTORY")?;
+    let password = fs::read_to_string(Path::new(&directory).join(SYSTEMD_PASSWORD)).ok()?;
+    Some(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(unix)]
+pub fn stdin_is_tty() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_tty() -> bool {
+    true
+}
+
+// Resolves once we're asked to terminate, eg. by --kill or the service manager
+#[cfg(unix)]
+pub async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(_) => future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn terminated() {
+    future::pending().await
+}
+
+// Link this device to an account without zeroconf: show a code for the user to enter on
+// another device, then log in with the token we get once they did
+pub async fn pair(
+    cache: Option<Cache>,
+    session_config: SessionConfig,
+    show_code: impl FnOnce(Value),
+) -> Result<Value, Error> {
+    if cache.is_none() {
+        return Err(Error::new(
+            ExitCode::InvalidArguments,
+            "A cache is required to store the credentials.",
+        ));
+    }
+
+    let code = match oauth::device_code(oauth::CLIENT_ID, oauth::SCOPES, &session_config).await {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Failed to start pairing: {}", e);
+            return Err(Error::new(
+                ExitCode::NetworkError,
+                "Failed to start pairing.",
+            ));
+        }
+    };
+
+    show_code(json!({
+        "userCode": code.user_code,
+        "verificationUri": code.verification_uri,
+        "verificationUriComplete": code.verification_uri_complete,
+        "expiresIn": code.expires_in,
+    }));
+
+    let token = match oauth::device_token(oauth::CLIENT_ID, &code, &session_config).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Pairing failed: {}", e);
+            return Err(Error::new(ExitCode::AuthFailed, e.to_string()));
+        }
+    };
+
+    login_with_token(token, cache, session_config).await
+}
+
+// Log in through the browser on this machine, eg. when LMS runs on a desktop computer
+pub async fn login_oauth(
+    cache: Option<Cache>,
+    session_config: SessionConfig,
+    show_url: impl FnOnce(&str),
+) -> Result<Value, Error> {
+    if cache.is_none() {
+        return Err(Error::new(
+            ExitCode::InvalidArguments,
+            "A cache is required to store the credentials.",
+        ));
+    }
+
+    let token = oauth::authorization_code_token(
+        oauth::CLIENT_ID,
+        oauth::SCOPES,
+        oauth::REDIRECT_PORT,
+        &session_config,
+        show_url,
+    )
+    .await;
+
+    match token {
+        Ok(token) => login_with_token(token, cache, session_config).await,
+        Err(e) => {
+            error!("OAuth login failed: {}", e);
+            Err(Error::new(ExitCode::AuthFailed, e.to_string()))
+        }
+    }
+}
+
+// Convert the token into reusable credentials, which the session stores in the cache
+async fn login_with_token(
+    token: oauth::OAuthToken,
+    cache: Option<Cache>,
+    config: SessionConfig,
+) -> Result<Value, Error> {
+    let credentials = Credentials::with_access_token(token.access_token);
+    match Session::connect(config, credentials, cache, true).await {
+        Ok((session, _)) => Ok(json!({ "username": session.username() })),
+        Err(e) => {
+            error!("Failed to create session: {:?}", e);
+            Err(Error::new(
+                ExitCode::AuthFailed,
+                "Failed to log in with the token.",
+            ))
+        }
+    }
+}
+
+// The tracks of an album or playlist, the episodes of a show, the top tracks of an artist, or
+// the track itself
+pub(crate) async fn context_tracks(
+    session: &Session,
+    uri: &str,
+    id: SpotifyId,
+) -> Result<Vec<SpotifyId>, MercuryError> {
+    if uri.contains(":album:") {
+        Album::get(session, id).await.map(|album| album.tracks)
+    } else if uri.contains(":playlist:") {
+        Playlist::get(session, id)
+            .await
+            .map(|playlist| playlist.tracks)
+    } else if uri.contains(":artist:") {
+        Artist::get(session, id)
+            .await
+            .map(|artist| artist.top_tracks)
+    } else if uri.contains(":show:") {
+        let show = Show::get(session, id).await?;
+        let episodes = show
+            .episodes
+            .into_iter()
+            .map(|mut episode| {
+                episode.audio_type = SpotifyAudioType::Podcast;
+                episode
+            })
+            .collect();
+        Ok(episodes)
+    } else {
+        Ok(vec![id])
+    }
+}
+
+// Download a track, album or playlist into the audio cache, without playing it
+pub async fn prefetch(
+    uri: String,
+    last_credentials: Option<Credentials>,
+    cache: Option<Cache>,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Result<Value, Error> {
+    let last_credentials = match last_credentials {
+        Some(last_credentials) => last_credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
+
+    if !cache.as_ref().map_or(false, Cache::has_audio_cache) {
+        return Err(Error::new(
+            ExitCode::InvalidArguments,
+            "No audio cache available.",
+        ));
+    }
+
+    let id = match get_spotify_id(&uri) {
+        Some(id) => id,
+        None => return Err(Error::new(ExitCode::InvalidArguments, "Invalid URI.")),
+    };
+
+    let session = match Session::connect(session_config, last_credentials, cache, true).await {
+        Ok((session, _)) => session,
+        Err(error) => {
+            error!("Failed to create session: {:?}", error);
+            return Err(Error::new(
+                session_error_code(&error),
+                "Failed to create session or connect to servers.",
+            ));
+        }
+    };
+
+    let tracks = match context_tracks(&session, &uri, id).await {
+        Ok(tracks) => tracks,
+        Err(error) => {
+            error!("Failed to get tracks for {}: {:?}", uri, error);
+            return Err(Error::new(ExitCode::NetworkError, "Failed to get tracks."));
+        }
+    };
+
+    let mut prefetched = 0;
+    for track in &tracks {
+        if prefetch_track(session.clone(), player_config.clone(), *track).await {
+            prefetched += 1;
+        }
+    }
+
+    if prefetched == 0 && !tracks.is_empty() {
+        return Err(Error::new(
+            ExitCode::Unavailable,
+            "None of the tracks could be prefetched.",
+        ));
+    }
+
+    Ok(json!({
+        "tracks": tracks.len(),
+        "prefetched": prefetched,
+        "failed": tracks.len() - prefetched,
+    }))
+}
+
+// The front cover as a METADATA_BLOCK_PICTURE comment, see
+// https://wiki.xiph.org/VorbisComment#Cover_art
+fn cover_comment(image: &[u8]) -> String {
+    const FRONT_COVER: u32 = 3;
+    const MIME_TYPE: &str = "image/jpeg";
+
+    let mut block = Vec::with_capacity(image.len() + 32 + MIME_TYPE.len());
+    block.extend_from_slice(&FRONT_COVER.to_be_bytes());
+    block.extend_from_slice(&(MIME_TYPE.len() as u32).to_be_bytes());
+    block.extend_from_slice(MIME_TYPE.as_bytes());
+    // no description, and let players read width, height and colour depth from the image
+    block.extend_from_slice(&[0; 4 * 5]);
+    block.extend_from_slice(&(image.len() as u32).to_be_bytes());
+    block.extend_from_slice(image);
+
+    format!("METADATA_BLOCK_PICTURE={}", base64::encode(block))
+}
+
+// The cover of the album of `track` as a Vorbis comment
+async fn track_cover(
+    session: &Session,
+    session_config: &SessionConfig,
+    track: SpotifyId,
+) -> Option<String> {
+    let track = Track::get(session, track).await.ok()?;
+    let album = Album::get(session, track.album).await.ok()?;
+    let url = format!("{}{}", COVER_URL, album.covers.first()?);
+
+    match http::get(&url, session_config).await {
+        Ok(image) => Some(cover_comment(&image)),
+        Err(error) => {
+            warn!("Failed to get cover art from {}: {}", url, error);
+            None
+        }
+    }
+}
+
+// Save the Ogg files of a track, album or playlist, eg. so LMS can precache them ahead of
+// scheduled playback
+pub async fn download(
+    uri: String,
+    output_dir: PathBuf,
+    last_credentials: Option<Credentials>,
+    cache: Option<Cache>,
+    mut player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Result<Value, Error> {
+    let last_credentials = match last_credentials {
+        Some(last_credentials) => last_credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
+
+    player_config.metadata_tags = true;
+
+    if let Err(error) = fs::create_dir_all(&output_dir) {
+        error!("Failed to create {}: {}", output_dir.display(), error);
+        return Err(Error::new(
+            ExitCode::Error,
+            "Can't create the output directory.",
+        ));
+    }
+
+    let id = match get_spotify_id(&uri) {
+        Some(id) => id,
+        None => return Err(Error::new(ExitCode::InvalidArguments, "Invalid URI.")),
+    };
+
+    let session =
+        match Session::connect(session_config.clone(), last_credentials, cache, true).await {
+            Ok((session, _)) => session,
+            Err(error) => {
+                error!("Failed to create session: {:?}", error);
+                return Err(Error::new(
+                    session_error_code(&error),
+                    "Failed to create session or connect to servers.",
+                ));
+            }
+        };
+
+    let tracks = match context_tracks(&session, &uri, id).await {
+        Ok(tracks) => tracks,
+        Err(error) => {
+            error!("Failed to get tracks for {}: {:?}", uri, error);
+            return Err(Error::new(ExitCode::NetworkError, "Failed to get tracks."));
+        }
+    };
+
+    let mut files = Vec::new();
+    for track in &tracks {
+        let name = match track.to_base62() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = output_dir.join(format!("{}.ogg", name));
+        // only complete files get their final name
+        let partial = output_dir.join(format!("{}.ogg.part", name));
+
+        let file = match fs::File::create(&partial) {
+            Ok(file) => io::BufWriter::new(file),
+            Err(error) => {
+                error!("Failed to create {}: {}", partial.display(), error);
+                continue;
+            }
+        };
+
+        let comments = track_cover(&session, &session_config, *track)
+            .await
+            .into_iter()
+            .collect();
+        let exported = export_track(
+            session.clone(),
+            player_config.clone(),
+            *track,
+            comments,
+            file,
+        )
+        .await;
+
+        if exported && fs::rename(&partial, &path).is_ok() {
+            files.push(path.to_string_lossy().into_owned());
+        } else {
+            let _ = fs::remove_file(&partial);
+        }
+    }
+
+    if files.is_empty() && !tracks.is_empty() {
+        return Err(Error::new(
+            ExitCode::Unavailable,
+            "None of the tracks could be downloaded.",
+        ));
+    }
+
+    Ok(json!({
+        "tracks": tracks.len(),
+        "downloaded": files.len(),
+        "failed": tracks.len() - files.len(),
+        "files": files,
+    }))
+}
+
+pub fn cache_stats(cache: Option<&Cache>, size_limit: Option<u64>) -> Result<Value, Error> {
+    match cache.and_then(Cache::audio_cache_size) {
+        Some((files, size)) => Ok(json!({
+            "files": files,
+            "size": size,
+            "sizeLimit": size_limit,
+        })),
+        None => Err(Error::new(
+            ExitCode::InvalidArguments,
+            "No audio cache available.",
+        )),
+    }
+}
+
+pub fn list_accounts(cache: Option<&Cache>) -> Result<Value, Error> {
+    match cache {
+        Some(cache) => Ok(json!({ "accounts": cache.accounts() })),
+        None => Err(Error::new(
+            ExitCode::InvalidArguments,
+            "No cache available.",
+        )),
+    }
+}
+
+// Write the cached credentials to a file, to log in on another machine with --import-credentials
+pub fn export_credentials(
+    cache: Option<&Cache>,
+    credentials: Option<&Credentials>,
+    path: &Path,
+) -> Result<Value, Error> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            return Err(Error::new(
+                ExitCode::InvalidArguments,
+                "No cache available.",
+            ))
+        }
+    };
+
+    let credentials = match credentials {
+        Some(credentials) if credentials.auth_type != AUTHENTICATION_USER_PASS => credentials,
+        _ => {
+            return Err(Error::new(
+                ExitCode::PasswordRequired,
+                "No credentials cached.",
+            ))
+        }
+    };
+
+    match cache.export_credentials(path, credentials) {
+        Ok(()) => {
+            warn!(
+                "{} gives access to the Spotify account {}. Keep it private, and delete it once imported.",
+                path.display(),
+                credentials.username
+            );
+            Ok(json!({ "exported": credentials.username }))
+        }
+        Err(e) => {
+            let error = format!("Failed to export the credentials: {}", e);
+            Err(Error::new(ExitCode::Error, error))
+        }
+    }
+}
+
+// Cache credentials written by --export-credentials on another machine
+pub fn import_credentials(cache: Option<&Cache>, path: &Path) -> Result<Value, Error> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            return Err(Error::new(
+                ExitCode::InvalidArguments,
+                "No cache available.",
+            ))
+        }
+    };
+
+    match cache.import_credentials(path) {
+        Ok(credentials) => {
+            warn!(
+                "Imported the credentials of {}, delete {} now.",
+                credentials.username,
+                path.display()
+            );
+            Ok(json!({ "imported": credentials.username }))
         }
+        Err(e) => {
+            let error = format!("Failed to import the credentials: {}", e);
+            Err(Error::new(ExitCode::Error, error))
+        }
+    }
+}
+
+fn usage_json(usage: DataUsage) -> Value {
+    json!({
+        "audio": usage.audio,
+        "metadata": usage.metadata,
+        "total": usage.total(),
+    })
+}
+
+pub fn stats(cache: Option<&Cache>, data_cap: Option<u64>) -> Result<Value, Error> {
+    let history = match cache.and_then(Cache::data_usage) {
+        Some(history) => history,
         None => {
-            println!("Missing credentials");
+            return Err(Error::new(
+                ExitCode::InvalidArguments,
+                "No cache folder defined.",
+            ))
+        }
+    };
+
+    let today = data_usage::today();
+    let month = history.month(today);
+
+    Ok(json!({
+        "today": usage_json(history.day(today)),
+        "thisMonth": usage_json(month),
+        "last30Days": usage_json(history.last_days(today, 30)),
+        "dataCap": data_cap,
+        "dataCapExceeded": data_cap.map_or(false, |cap| month.total() > cap),
+    }))
+}
+
+fn check_result(result: Result<(), String>) -> Value {
+    match result {
+        Ok(_) => json!({ "ok": true }),
+        Err(error) => json!({ "ok": false, "error": error }),
+    }
+}
+
+fn check_cache(cache_dir: &Path) -> Result<(), String> {
+    let probe = cache_dir.join(".spotty-dry-run");
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("Cache folder {:?} is not writable: {}", cache_dir, e))
+}
+
+// Validate the setup without playing anything, eg. for installer scripts. The credentials are
+// only checked by logging in if a session config is given. The report's "ok" is false if
+// anything failed.
+pub async fn dry_run(
+    lms: &LMS,
+    cache_dir: Option<&Path>,
+    open_sink: impl FnOnce() -> Box<dyn Sink>,
+    credentials: Option<Credentials>,
+    verify_credentials: Option<SessionConfig>,
+    enable_discovery: bool,
+) -> Value {
+    let has_credentials = credentials.is_some();
+    let verify = has_credentials && verify_credentials.is_some();
+
+    let lms_check = lms.check_connection().await;
+
+    let cache_check = match cache_dir {
+        Some(cache_dir) => check_cache(cache_dir),
+        None => Err("No cache folder defined".to_string()),
+    };
+
+    let backend_check = {
+        let mut sink = open_sink();
+        sink.start()
+            .and_then(|_| sink.stop())
+            .map_err(|e| e.to_string())
+    };
+
+    let credentials_check = match (credentials, verify_credentials) {
+        (Some(credentials), Some(session_config)) => {
+            Session::connect(session_config, credentials, None, false)
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("Login failed: {}", e))
+        }
+        (None, _) if !enable_discovery => Err("No credentials found".to_string()),
+        _ => Ok(()),
+    };
+
+    // only true once the login succeeded
+    let verified = verify && credentials_check.is_ok();
+
+    let ok = lms_check.is_ok()
+        && cache_check.is_ok()
+        && backend_check.is_ok()
+        && credentials_check.is_ok();
+
+    json!({
+        "ok": ok,
+        "config": check_result(Ok(())),
+        "lms": check_result(lms_check),
+        "cache": check_result(cache_check),
+        "backend": check_result(backend_check),
+        "credentials": check_result(credentials_check),
+        "cachedCredentials": has_credentials,
+        "credentialsVerified": verified,
+        "discovery": enable_discovery,
+    })
+}
+
+// A time to start an alarm: "7:30", optionally limited to some days ("7:30 mon-fri"), or a cron
+// expression ("30 7 * * 1-5").
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // cron matches either the day of the month or the weekday if both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Parse a cron field like "*", "*/15", "1-5" or "mon,wed,fri" into a bit mask of its values.
+fn parse_schedule_field(field: &str, min: u32, max: u32, names: &[&str]) -> Option<u64> {
+    let value = |s: &str| {
+        let s = s.to_lowercase();
+        match names.iter().position(|name| *name == s) {
+            Some(index) => Some(min + index as u32),
+            None => s.parse::<u32>().ok().filter(|v| (min..=max).contains(v)),
+        }
+    };
+
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return None;
+        }
+        for v in (first..=last).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Some(mask)
+}
+
+impl FromStr for Schedule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let (minute, hour, day, month, weekday) = match fields[..] {
+            [minute, hour, day, month, weekday] => (minute, hour, day, month, weekday),
+            [time] | [time, _] => {
+                let (hour, minute) = time.split_once(':').ok_or(())?;
+                let weekday = fields.get(1).copied().unwrap_or("*");
+                (minute, hour, "*", "*", weekday)
+            }
+            _ => return Err(()),
+        };
+
+        let weekdays = parse_schedule_field(weekday, 0, 7, &WEEKDAYS).ok_or(())?;
+        Ok(Schedule {
+            minutes: parse_schedule_field(minute, 0, 59, &[]).ok_or(())?,
+            hours: parse_schedule_field(hour, 0, 23, &[]).ok_or(())?,
+            days: parse_schedule_field(day, 1, 31, &[]).ok_or(())?,
+            months: parse_schedule_field(month, 1, 12, &MONTHS).ok_or(())?,
+            // 7 is Sunday, too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+impl Schedule {
+    fn matches(&self, time: &DateTime<Local>) -> bool {
+        let is_set = |mask: u64, value: u32| mask & (1 << value) != 0;
+
+        let day = is_set(self.days, time.day());
+        let weekday = is_set(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        };
+
+        day_matches
+            && is_set(self.months, time.month())
+            && is_set(self.hours, time.hour())
+            && is_set(self.minutes, time.minute())
+    }
+
+    // The first matching minute after `time`, looking ahead at most a year.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)?;
+        for _ in 0..366 * 24 * 60 {
+            next = next + chrono::Duration::minutes(1);
+            if self.matches(&next) {
+                return Some(next);
+            }
+        }
+        None
+    }
+}
+
+// Starts playing a context on this device at the scheduled times.
+#[derive(Clone)]
+pub struct Alarm {
+    schedules: Vec<Schedule>,
+    uri: String,
+    fade_in: Option<Duration>,
+}
+
+impl Alarm {
+    pub fn new(schedules: Vec<Schedule>, uri: String, fade_in: Option<Duration>) -> Self {
+        Self {
+            schedules,
+            uri,
+            fade_in,
+        }
+    }
+
+    // How long until the alarm goes off next, if ever.
+    pub fn next_in(&self) -> Option<Duration> {
+        let now = Local::now();
+        // don't go off twice if the timer fired a bit early
+        let after = now + chrono::Duration::seconds(1);
+        let next = self
+            .schedules
+            .iter()
+            .filter_map(|schedule| schedule.next_after(after))
+            .min()?;
+
+        info!("Next alarm at {}", next.format("%Y-%m-%d %H:%M"));
+        Some((next - now).to_std().unwrap_or_default())
+    }
+
+    pub async fn start(self, session: Session, spirc: Spirc, lms: LMS) {
+        info!("Alarm: playing {}", self.uri);
+
+        let id = match get_spotify_id(&self.uri) {
+            Some(id) => id,
+            None => return,
+        };
+
+        if lms.is_configured() {
+            lms.power_on().await;
+        }
+
+        match context_tracks(&session, &self.uri, id).await {
+            Ok(tracks) => spirc.load_context(self.uri, tracks, 0, None, self.fade_in),
+            Err(error) => error!("Failed to get tracks for {}: {:?}", self.uri, error),
         }
     }
 }
 
 // Connect mode support
 
+// Up to ERROR_BURST errors of a category are reported to LMS per ERROR_INTERVAL, so a file which
+// fails to decode packet after packet doesn't flood it. The errors dropped in between are counted
+// with the next one reported.
+const ERROR_BURST: u32 = 3;
+const ERROR_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct ErrorRateLimit {
+    since: Option<Instant>,
+    reported: u32,
+    suppressed: u32,
+}
+
+impl ErrorRateLimit {
+    /// Returns the number of errors suppressed since the last one reported, or `None` if this one
+    /// is to be suppressed as well.
+    fn check(&mut self, now: Instant) -> Option<u32> {
+        let expired = match self.since {
+            Some(since) => now - since >= ERROR_INTERVAL,
+            None => true,
+        };
+        if expired {
+            self.since = Some(now);
+            self.reported = 0;
+        }
+
+        if self.reported >= ERROR_BURST {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.reported += 1;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
 #[derive(Clone)]
 pub struct LMS {
     base_url: Option<String>,
     player_mac: Option<String>,
     auth: Option<String>,
+    pub(crate) group_volume: bool,
+    on_play: OnPlay,
+    // the other players of the sync group, as of the last check
+    sync_group: Arc<Mutex<Vec<String>>>,
+    error_rate_limits: Arc<Mutex<HashMap<ErrorCategory, ErrorRateLimit>>>,
 }
 
 #[allow(unused)]
 impl LMS {
-    pub fn new(base_url: Option<String>, player_mac: Option<String>, auth: Option<String>) -> LMS {
+    pub fn new(
+        base_url: Option<String>,
+        player_mac: Option<String>,
+        auth: Option<String>,
+        group_volume: bool,
+        on_play: OnPlay,
+    ) -> LMS {
         LMS {
             base_url: Some(format!(
                 "http://{}/jsonrpc.js",
@@ -178,29 +1750,178 @@ impl LMS {
             )),
             player_mac: player_mac,
             auth: auth,
+            group_volume,
+            on_play,
+            sync_group: Arc::new(Mutex::new(Vec::new())),
+            error_rate_limits: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    pub fn on_play(&self) -> OnPlay {
+        self.on_play
+    }
+
+    pub fn sync_group(&self) -> Vec<String> {
+        self.sync_group.lock().unwrap().clone()
+    }
+
     pub fn is_configured(&self) -> bool {
         if self.base_url != None {
             if self.player_mac != None {
                 return true;
             }
         }
-
-        return false;
+
+        return false;
+    }
+
+    fn build_request(&self, base_url: &str, json: String) -> Request<Body> {
+        let mut auth_header = "".to_string();
+        if let Some(ref auth) = self.auth {
+            auth_header = auth.trim().to_string();
+        }
+
+        Request::builder()
+            .method(Method::POST)
+            .uri(base_url.to_string())
+            .header("user-agent", VERSION)
+            .header("content-type", "application/json")
+            .header("authorization", format!("Basic {}", auth_header))
+            .header("x-scanner", "1")
+            .body(Body::from(json))
+            .unwrap()
+    }
+
+    pub async fn check_connection(&self) -> Result<(), String> {
+        if !self.is_configured() {
+            return Err("LMS connection is not configured".to_string());
+        }
+
+        let base_url = self.base_url.as_ref().unwrap();
+        let json = r#"{"id": 1,"method":"slim.request","params":["",["version","?"]]}"#;
+
+        let req = self.build_request(base_url, json.to_string());
+
+        match Client::new().request(req).await {
+            Ok(resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(format!("{} returned {}", base_url, resp.status())),
+            Err(error) => Err(format!("Problem posting to {}: {}", base_url, error)),
+        }
+    }
+
+    async fn player_request(&self, player_mac: &str, command: &str) -> Result<Value, String> {
+        let base_url = self
+            .base_url
+            .as_ref()
+            .ok_or("LMS connection is not configured")?;
+        let json = format!(
+            r#"{{"id": 1,"method":"slim.request","params":["{}",{}]}}"#,
+            player_mac, command
+        );
+
+        let req = self.build_request(base_url, json);
+        let resp = Client::new()
+            .request(req)
+            .await
+            .map_err(|e| format!("Problem posting to {}: {}", base_url, e))?;
+        if !resp.status().is_success() {
+            return Err(format!("{} returned {}", base_url, resp.status()));
+        }
+
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        serde_json::from_slice(&body).map_err(|e| e.to_string())
+    }
+
+    // Switch the Squeezebox on, eg. before an alarm starts playing.
+    pub async fn power_on(&self) {
+        if let Some(ref player_mac) = self.player_mac {
+            if let Err(e) = self.player_request(player_mac, r#"["power","1"]"#).await {
+                warn!("Unable to power on {}: {}", player_mac, e);
+            }
+        }
+    }
+
+    // Whether the Squeezebox is switched on, `None` if LMS couldn't tell.
+    pub async fn is_powered(&self) -> Option<bool> {
+        let player_mac = self.player_mac.as_ref()?;
+
+        match self.player_request(player_mac, r#"["power","?"]"#).await {
+            // a string in most LMS versions, a number in some
+            Ok(response) => {
+                let power = &response["result"]["_power"];
+                power
+                    .as_str()
+                    .and_then(|power| power.parse::<u8>().ok())
+                    .or_else(|| power.as_u64().map(|power| power as u8))
+                    .map(|power| power == 1)
+            }
+            Err(e) => {
+                warn!("Unable to get the power state of {}: {}", player_mac, e);
+                None
+            }
+        }
+    }
+
+    // Check which players the Squeezebox is synced with, and tell if that changed.
+    pub(crate) async fn update_sync_group(&self) -> Vec<String> {
+        let player_mac = match self.player_mac {
+            Some(ref player_mac) => player_mac,
+            None => return Vec::new(),
+        };
+
+        let members = match self.player_request(player_mac, r#"["sync","?"]"#).await {
+            // "-" if the player isn't synced, a comma separated list of players otherwise
+            Ok(response) => response["result"]["_sync"]
+                .as_str()
+                .unwrap_or("-")
+                .split(',')
+                .map(str::trim)
+                .filter(|member| {
+                    !member.is_empty() && *member != "-" && *member != player_mac.as_str()
+                })
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                warn!("Unable to get the sync group of {}: {}", player_mac, e);
+                return self.sync_group();
+            }
+        };
+
+        let mut sync_group = self.sync_group.lock().unwrap();
+        if *sync_group != members {
+            if members.is_empty() {
+                info!("{} is no longer synced with other players", player_mac);
+            } else {
+                info!("{} is synced with {}", player_mac, members.join(", "));
+            }
+            *sync_group = members.clone();
+        }
+
+        members
+    }
+
+    // Apply a volume change to all players synced with the Squeezebox.
+    async fn set_group_volume(&self, volume: u32) {
+        for member in self.update_sync_group().await {
+            let command = format!(r#"["mixer","volume",{}]"#, volume);
+            if let Err(e) = self.player_request(&member, &command).await {
+                warn!("Unable to set the volume of {}: {}", member, e);
+            }
+        }
     }
 
     pub async fn signal_event(&self, event: PlayerEvent) {
         let mut command = r#"["spottyconnect","change"]"#.to_string();
+        let mut group_volume = None;
 
         match event {
             PlayerEvent::Changed {
                 old_track_id,
                 new_track_id,
             } => {
-                #[cfg(debug_assertions)]
-                info!(
+                debug!(
                     "event: changed, old track: {}, new track: {}",
                     old_track_id.to_base62().unwrap_or_default(),
                     new_track_id.to_base62().unwrap_or_default()
@@ -212,8 +1933,7 @@ impl LMS {
                 );
             }
             PlayerEvent::Started { track_id, .. } => {
-                #[cfg(debug_assertions)]
-                info!(
+                debug!(
                     "event: started, track: {}",
                     track_id.to_base62().unwrap_or_default()
                 );
@@ -223,8 +1943,7 @@ impl LMS {
                 );
             }
             PlayerEvent::Stopped { track_id, .. } => {
-                #[cfg(debug_assertions)]
-                info!(
+                debug!(
                     "event: stopped, track: {}",
                     track_id.to_base62().unwrap_or_default()
                 );
@@ -236,8 +1955,7 @@ impl LMS {
                 position_ms,
                 ..
             } => {
-                #[cfg(debug_assertions)]
-                info!(
+                debug!(
                     "event: playing, track: {}, duration: {}, position: {}",
                     track_id.to_base62().unwrap_or_default(),
                     duration_ms,
@@ -256,8 +1974,7 @@ impl LMS {
                 position_ms,
                 ..
             } => {
-                #[cfg(debug_assertions)]
-                info!(
+                debug!(
                     "event: paused, track: {}, duration: {}, position: {}",
                     track_id.to_base62().unwrap_or_default(),
                     duration_ms,
@@ -275,60 +1992,227 @@ impl LMS {
                     new_volume = 100;
                 };
 
-                #[cfg(debug_assertions)]
-                info!("event: volume: {}", volume);
+                debug!("event: volume: {}", volume);
                 // we're not using the volume here, as LMS will read player state anyway
                 command = format!(r#"["spottyconnect","volume",{}]"#, new_volume.to_string());
+                group_volume = Some(new_volume);
+            }
+            PlayerEvent::PlaybackModeChanged { shuffle, repeat } => {
+                debug!("event: shuffle: {}, repeat: {}", shuffle, repeat);
+            }
+            PlayerEvent::Normalisation { track_id, data, .. } => {
+                debug!(
+                    "event: normalisation, track: {}, data: {:?}",
+                    track_id.to_base62().unwrap_or_default(),
+                    data
+                );
+                command = format!(
+                    r#"["spottyconnect","normalisation","{}",{},{},{},{}]"#,
+                    track_id.to_base62().unwrap_or_default(),
+                    data.track_gain_db,
+                    data.track_peak,
+                    data.album_gain_db,
+                    data.album_peak
+                );
+            }
+            PlayerEvent::Loudness {
+                track_id, loudness, ..
+            } => {
+                debug!(
+                    "event: loudness, track: {}, loudness: {:?}",
+                    track_id.to_base62().unwrap_or_default(),
+                    loudness
+                );
+                command = format!(
+                    r#"["spottyconnect","loudness","{}",{},{}]"#,
+                    track_id.to_base62().unwrap_or_default(),
+                    json!(loudness.integrated_lufs),
+                    json!(Some(loudness.true_peak_dbtp).filter(|peak| peak.is_finite()))
+                );
+            }
+            PlayerEvent::Unavailable {
+                track_id, reason, ..
+            } => {
+                debug!(
+                    "event: unavailable, track: {}, reason: {}",
+                    track_id.to_base62().unwrap_or_default(),
+                    reason
+                );
+                command = format!(
+                    r#"["spottyconnect","unavailable","{}","{}"]"#,
+                    track_id.to_base62().unwrap_or_default(),
+                    reason.code()
+                );
+            }
+            PlayerEvent::TrackChanging {
+                track_id,
+                next_track_id,
+                remaining_ms,
+                ..
+            } => {
+                let next_track_id = next_track_id.and_then(|id| id.to_base62().ok());
+                debug!(
+                    "event: changing, track: {}, next: {:?}, remaining: {} ms",
+                    track_id.to_base62().unwrap_or_default(),
+                    next_track_id,
+                    remaining_ms
+                );
+                command = json!([
+                    "spottyconnect",
+                    "changing",
+                    track_id.to_base62().unwrap_or_default(),
+                    next_track_id,
+                    remaining_ms
+                ])
+                .to_string();
             }
+            PlayerEvent::Offline { track_id, .. } => {
+                debug!(
+                    "event: offline, track: {}",
+                    track_id.to_base62().unwrap_or_default()
+                );
+                command = format!(
+                    r#"["spottyconnect","offline","{}"]"#,
+                    track_id.to_base62().unwrap_or_default()
+                );
+            }
+            PlayerEvent::CommandOverridden {
+                source,
+                command: overridden,
+                overridden_by,
+            } => {
+                debug!(
+                    "event: overridden, {} command: {}, by: {}",
+                    source, overridden, overridden_by
+                );
+                // lets LMS tell the user why eg. their pause didn't take
+                command = json!([
+                    "spottyconnect",
+                    "overridden",
+                    source.to_string(),
+                    overridden,
+                    overridden_by.to_string()
+                ])
+                .to_string();
+            }
+            PlayerEvent::Error {
+                category,
+                message,
+                recoverable,
+            } => return self.signal_error(category, &message, recoverable).await,
             _ => return,
         }
 
+        self.send_command(&command).await;
+
+        if let Some(volume) = group_volume.filter(|_| self.group_volume) {
+            self.set_group_volume(volume).await;
+        }
+    }
+
+    /// Tells LMS about the state of the Connect session, eg. "reconnecting" with the error that
+    /// caused it, so it can tell the user.
+    pub async fn signal_connection(&self, state: &str, reason: Option<&str>) {
+        debug!("connection: {}, reason: {:?}", state, reason);
+        let command = json!(["spottyconnect", "connection", state, reason]).to_string();
+        self.send_command(&command).await;
+    }
+
+    /// Sends the lyrics of `track_id` as text, or null if it has none.
+    pub async fn signal_lyrics(&self, track_id: SpotifyId, lyrics: Option<&Lyrics>) {
+        let track = track_id.to_base62().unwrap_or_default();
+        debug!("lyrics: {}, available: {}", track, lyrics.is_some());
+        let command = json!([
+            "spottyconnect",
+            "lyrics",
+            track,
+            lyrics.map_or(false, |lyrics| lyrics.synced),
+            lyrics.map(Lyrics::text)
+        ]);
+        self.send_command(&command.to_string()).await;
+    }
+
+    /// Sends the album, artists, genres and popularity of `track_id`, and its Canvas, see
+    /// `fetch_track_details`.
+    pub async fn signal_track_details(&self, track_id: SpotifyId, details: Value) {
+        let track = track_id.to_base62().unwrap_or_default();
+        debug!("details: {}", track);
+        let command = json!(["spottyconnect", "details", track, details]);
+        self.send_command(&command.to_string()).await;
+    }
+
+    /// Sends the line of synced lyrics which is sung now.
+    pub async fn signal_lyrics_line(&self, track_id: SpotifyId, line: usize, words: &str) {
+        let track = track_id.to_base62().unwrap_or_default();
+        let command = json!(["spottyconnect", "lyricsline", track, line, words]);
+        self.send_command(&command.to_string()).await;
+    }
+
+    /// Reports an error, unless too many of its category were reported recently.
+    pub async fn signal_error(&self, category: ErrorCategory, message: &str, recoverable: bool) {
+        let suppressed = self
+            .error_rate_limits
+            .lock()
+            .unwrap()
+            .entry(category)
+            .or_default()
+            .check(Instant::now());
+
+        let suppressed = match suppressed {
+            Some(suppressed) => suppressed,
+            None => {
+                debug!("error: {}, suppressed: {}", category.code(), message);
+                return;
+            }
+        };
+
+        debug!(
+            "error: {}, message: {}, recoverable: {}, suppressed before: {}",
+            category.code(),
+            message,
+            recoverable,
+            suppressed
+        );
+        let command = json!([
+            "spottyconnect",
+            "error",
+            category.code(),
+            message,
+            recoverable,
+            suppressed
+        ])
+        .to_string();
+        self.send_command(&command).await;
+    }
+
+    async fn send_command(&self, command: &str) {
         if !self.is_configured() {
-            #[cfg(debug_assertions)]
-            warn!("LMS connection is not configured");
-            #[cfg(debug_assertions)]
-            info!("{}", command);
+            debug!("LMS connection is not configured");
+            debug!("{}", command);
             return;
         }
 
         if let Some(ref base_url) = self.base_url {
-            #[cfg(debug_assertions)]
-            info!("Base URL to talk to LMS: {}", base_url);
+            debug!("Base URL to talk to LMS: {}", base_url);
 
             if let Some(ref player_mac) = self.player_mac {
-                #[cfg(debug_assertions)]
-                info!("Player MAC address to control: {}", player_mac);
+                debug!("Player MAC address to control: {}", player_mac);
 
-                #[cfg(debug_assertions)]
-                info!("Command to send to player: {}", command);
+                debug!("Command to send to player: {}", command);
 
                 let json = format!(
                     r#"{{"id": 1,"method":"slim.request","params":["{}",{}]}}"#,
                     player_mac, command
                 );
 
-                let mut auth_header = "".to_string();
-                if let Some(ref auth) = self.auth {
-                    auth_header = auth.trim().to_string();
-                }
-
-                let req = Request::builder()
-                    .method(Method::POST)
-                    .uri(base_url.to_string())
-                    .header("user-agent", format!("{}", VERSION))
-                    .header("content-type", "application/json")
-                    .header("authorization", format!("Basic {}", auth_header))
-                    .header("x-scanner", "1")
-                    .body(Body::from(json.clone()))
-                    .unwrap();
+                let req = self.build_request(base_url, json.clone());
 
                 let client = Client::new();
                 let resp = client.request(req).await;
 
                 match resp {
                     Ok(resp) => {
-                        #[cfg(debug_assertions)]
-                        info!("Response: {}", resp.status());
+                        debug!("Response: {}", resp.status());
                     }
                     Err(error) => {
                         warn!("Problem posting to {} / {}: {:?}", base_url, json, error);
@@ -338,3 +2222,777 @@ impl LMS {
         }
     }
 }
+
+// Playback statistics, to find out why some tracks stutter
+
+// statistics are kept for this many of the most recent tracks
+const STATS_MAX_TRACKS: usize = 100;
+
+struct TrackStats {
+    play_request_id: u64,
+    track_id: SpotifyId,
+    started_at: u64,
+    ms_played: u64,
+    playing_since: Option<Instant>,
+    underruns: u32,
+    bitrates: Vec<u32>,
+    skipped: bool,
+    unavailable: Option<UnavailableReason>,
+}
+
+impl TrackStats {
+    fn new(play_request_id: u64, track_id: SpotifyId) -> Self {
+        TrackStats {
+            play_request_id,
+            track_id,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            ms_played: 0,
+            playing_since: None,
+            underruns: 0,
+            bitrates: Vec::new(),
+            skipped: false,
+            unavailable: None,
+        }
+    }
+
+    fn pause(&mut self) {
+        if let Some(since) = self.playing_since.take() {
+            self.ms_played += since.elapsed().as_millis() as u64;
+        }
+    }
+
+    fn ms_played(&self) -> u64 {
+        let playing_ms = self
+            .playing_since
+            .map(|since| since.elapsed().as_millis() as u64);
+        self.ms_played + playing_ms.unwrap_or_default()
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "uri": self.track_id.to_uri().ok(),
+            "startedAt": self.started_at,
+            "msPlayed": self.ms_played(),
+            "underruns": self.underruns,
+            "bitrates": self.bitrates,
+            "skipped": self.skipped,
+            "unavailable": self.unavailable.map(|reason| reason.code()),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct PlaybackStats {
+    current: Option<TrackStats>,
+    tracks: VecDeque<TrackStats>,
+    // totals of all finished tracks
+    tracks_played: u32,
+    skips: u32,
+    unavailable: u32,
+    underruns: u32,
+    ms_played: u64,
+    bitrates: BTreeMap<u32, u32>,
+}
+
+impl PlaybackStats {
+    pub fn player_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Loading {
+                play_request_id,
+                track_id,
+                ..
+            } if self.track(play_request_id).is_none() => {
+                self.finish(false);
+                self.current = Some(TrackStats::new(play_request_id, track_id));
+            }
+            PlayerEvent::Playing {
+                play_request_id, ..
+            } => {
+                if let Some(track) = self.track(play_request_id) {
+                    track.playing_since.get_or_insert_with(Instant::now);
+                }
+            }
+            PlayerEvent::Paused {
+                play_request_id, ..
+            } => {
+                if let Some(track) = self.track(play_request_id) {
+                    track.pause();
+                }
+            }
+            PlayerEvent::BufferUnderrun {
+                play_request_id,
+                position_ms,
+                ..
+            } => {
+                if let Some(track) = self.track(play_request_id) {
+                    info!(
+                        "Buffer underrun playing <{}> at {} ms",
+                        track.track_id.to_uri().unwrap_or_default(),
+                        position_ms
+                    );
+                    track.underruns += 1;
+                }
+            }
+            PlayerEvent::FormatChanged {
+                play_request_id,
+                format,
+                ..
+            } => {
+                if let Some(track) = self.track(play_request_id) {
+                    if !track.bitrates.contains(&format.bitrate()) {
+                        track.bitrates.push(format.bitrate());
+                    }
+                }
+            }
+            PlayerEvent::Unavailable {
+                play_request_id,
+                track_id,
+                reason,
+            } => {
+                // a failed preload is reported with the request of the track playing before it
+                if let Some(track) = self.track(play_request_id) {
+                    if track.track_id == track_id {
+                        track.unavailable = Some(reason);
+                    }
+                }
+            }
+            PlayerEvent::EndOfTrack {
+                play_request_id, ..
+            } if self.track(play_request_id).is_some() => self.finish(true),
+            PlayerEvent::Stopped {
+                play_request_id, ..
+            } if self.track(play_request_id).is_some() => self.finish(false),
+            _ => (),
+        }
+    }
+
+    fn track(&mut self, play_request_id: u64) -> Option<&mut TrackStats> {
+        self.current
+            .as_mut()
+            .filter(|track| track.play_request_id == play_request_id)
+    }
+
+    fn finish(&mut self, completed: bool) {
+        let mut track = match self.current.take() {
+            Some(track) => track,
+            None => return,
+        };
+
+        track.pause();
+        track.skipped = !completed && track.unavailable.is_none();
+
+        if track.unavailable.is_some() {
+            self.unavailable += 1;
+        } else {
+            self.tracks_played += 1;
+        }
+        if track.skipped {
+            self.skips += 1;
+        }
+        self.underruns += track.underruns;
+        self.ms_played += track.ms_played;
+        for bitrate in &track.bitrates {
+            *self.bitrates.entry(*bitrate).or_default() += 1;
+        }
+
+        debug!("Playback statistics: {}", track.to_json());
+
+        if self.tracks.len() == STATS_MAX_TRACKS {
+            self.tracks.pop_front();
+        }
+        self.tracks.push_back(track);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let current = self.current.as_ref();
+        let bitrates: Vec<Value> = self
+            .bitrates
+            .iter()
+            .map(|(bitrate, tracks)| json!({ "bitrate": bitrate, "tracks": tracks }))
+            .collect();
+
+        json!({
+            "tracksPlayed": self.tracks_played,
+            "skips": self.skips,
+            "unavailable": self.unavailable,
+            "underruns": self.underruns + current.map_or(0, |track| track.underruns),
+            "msPlayed": self.ms_played + current.map_or(0, TrackStats::ms_played),
+            "bitrates": bitrates,
+            "current": current.map(TrackStats::to_json),
+            "tracks": self.tracks.iter().map(TrackStats::to_json).collect::<Vec<Value>>(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, format!("{}\n", self.to_json()))
+    }
+}
+
+// Player server: keeps the session and a player open and streams a track per request, so LMS
+// doesn't start a process per track. The player goes on to the next track LMS announced without
+// a gap, its audio waits in the channel until LMS requests it.
+
+// Packets of audio buffered, written ahead of what the client read
+const PLAYER_SERVER_BUFFER_PACKETS: usize = 16;
+
+struct PlayerServer {
+    credentials: Credentials,
+    cache: Option<Cache>,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+    session: tokio::sync::Mutex<Option<Session>>,
+}
+
+impl PlayerServer {
+    // The open session, connecting again if it was lost
+    async fn session(&self) -> Result<Session, SessionError> {
+        let mut session = self.session.lock().await;
+        if let Some(ref session) = *session {
+            if !session.is_invalid() {
+                return Ok(session.clone());
+            }
+        }
+
+        let connection = Session::connect(
+            self.session_config.clone(),
+            self.credentials.clone(),
+            self.cache.clone(),
+            true,
+        );
+        let (new_session, _) = connection.await?;
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+}
+
+// A track requested by the client, and the response to stream it to
+struct PlayRequest {
+    track: SpotifyId,
+    start_position: u32,
+    // to play on with when `track` ended, without a gap
+    next: Option<SpotifyId>,
+    body: hyper::body::Sender,
+}
+
+// The response the audio of the track `uri` goes to, from the boundary it starts at on
+struct Listener {
+    uri: String,
+    body: hyper::body::Sender,
+    started: bool,
+}
+
+impl Listener {
+    // Passes the audio of its track on. Returns false once the client hung up.
+    async fn forward(&mut self, data: ChannelData) -> bool {
+        match data {
+            ChannelData::TrackBoundary(uri) => self.started = uri == self.uri,
+            ChannelData::Audio(data) if self.started => {
+                return self.body.send_data(data.into()).await.is_ok();
+            }
+            // what's left of the track played before
+            ChannelData::Audio(_) => (),
+        }
+        true
+    }
+}
+
+// Plays the requested tracks with one player, and streams the audio of each to its response.
+// tokio::select! expands to poll_fn, which is newer than the MSRV
+#[allow(clippy::incompatible_msrv)]
+async fn play_requests(
+    server: Arc<PlayerServer>,
+    mut requests: tokio::sync::mpsc::UnboundedReceiver<PlayRequest>,
+) {
+    let (audio_tx, mut audio_rx) = tokio::sync::mpsc::channel(PLAYER_SERVER_BUFFER_PACKETS);
+    let mut player: Option<(Player, Session)> = None;
+    let mut events: Option<PlayerEventChannel> = None;
+    let mut listener: Option<Listener> = None;
+    let mut next = None;
+    // the track the player went on to after the last one ended, until it's requested
+    let mut continued = None;
+
+    loop {
+        let event = async {
+            match events {
+                Some(ref mut events) => events.recv().await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            request = requests.recv() => {
+                let request = match request {
+                    Some(request) => request,
+                    None => break,
+                };
+
+                // a new player for a new session, after the connection was lost
+                let session = match server.session().await {
+                    Ok(session) => session,
+                    Err(error) => {
+                        error!("Failed to create session: {}", error);
+                        continue;
+                    }
+                };
+                let session_id = session.session_id();
+                if player.as_ref().map_or(true, |(_, current)| current.session_id() != session_id) {
+                    if let Some((old_player, _)) = player.take() {
+                        // its thread may wait for the channel, which is read on below
+                        tokio::task::spawn_blocking(move || drop(old_player));
+                    }
+                    let audio_tx = audio_tx.clone();
+                    let audio_format = AudioFormat::default();
+                    let (new_player, new_events) = Player::new(
+                        server.player_config.clone(),
+                        session.clone(),
+                        Box::new(NoOpVolume),
+                        move || Box::new(ChannelSink::new(audio_tx, audio_format)),
+                    );
+                    player = Some((new_player, session));
+                    events = Some(new_events);
+                    continued = None;
+                }
+                let (player, _) = player.as_mut().unwrap();
+
+                let uri = request.track.to_uri().unwrap_or_default();
+                if continued.take() == Some(request.track) && request.start_position == 0 {
+                    debug!("Continuing with {}", uri);
+                } else {
+                    debug!("Playing {} from {} ms", uri, request.start_position);
+                    player.load(request.track, true, request.start_position);
+                }
+                next = request.next;
+                if let Some(next) = next {
+                    player.preload(next);
+                }
+
+                // ends the response to the track played before, if it's still going
+                listener = Some(Listener {
+                    uri,
+                    body: request.body,
+                    started: false,
+                });
+            }
+            // waits while the audio of the track the player went on to wasn't requested yet
+            data = audio_rx.recv(), if listener.is_some() || continued.is_none() => {
+                let data = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                if let Some(ref mut current) = listener {
+                    if !current.forward(data).await {
+                        debug!("The client stopped listening to {}", current.uri);
+                        listener = None;
+                        if let Some((ref player, _)) = player {
+                            player.stop();
+                        }
+                    }
+                }
+            }
+            event = event => match event {
+                Some(PlayerEvent::Unavailable { track_id, reason, .. }) => {
+                    let uri = track_id.to_uri().unwrap_or_default();
+                    warn!("Unable to play {}: {}", uri, reason);
+                    if listener.as_ref().map_or(false, |current| current.uri == uri) {
+                        listener = None;
+                    }
+                    if continued == Some(track_id) {
+                        continued = None;
+                    }
+                }
+                Some(PlayerEvent::EndOfTrack { track_id, .. }) => {
+                    let uri = track_id.to_uri().unwrap_or_default();
+                    match listener {
+                        Some(ref mut current) if current.started && current.uri == uri => {
+                            // the whole track was written to the channel before
+                            while let Ok(data) = audio_rx.try_recv() {
+                                if !current.forward(data).await {
+                                    break;
+                                }
+                            }
+                        }
+                        // a track the client isn't listening to any more
+                        _ => continue,
+                    }
+                    listener = None;
+
+                    if let (Some(next), Some((ref mut player, _))) = (next.take(), &mut player) {
+                        player.load(next, true, 0);
+                        continued = Some(next);
+                    }
+                }
+                Some(_) => (),
+                // the player thread is gone
+                None => {
+                    player = None;
+                    events = None;
+                    listener = None;
+                    continued = None;
+                }
+            },
+        }
+    }
+}
+
+// GET /play/<track ID>?start=<ms>&next=<track ID> streams the audio of a track, in the format of
+// --single-track, until it ended or the client hung up. The player goes on to `next` then, which
+// is to be requested next.
+async fn player_server_response(
+    request: Request<Body>,
+    server: Arc<PlayerServer>,
+    requests: tokio::sync::mpsc::UnboundedSender<PlayRequest>,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "Tracks must be requested with GET." }),
+        );
+    }
+
+    let track_id = match request.uri().path().strip_prefix("/play/") {
+        Some(track_id) => track_id.to_string(),
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found." })),
+    };
+    let track = match get_spotify_id(&track_id) {
+        Some(track) => track,
+        None => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "Invalid track ID." }),
+            )
+        }
+    };
+    let query = |name: &str| {
+        request.uri().query().and_then(|query| {
+            query
+                .split('&')
+                .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+        })
+    };
+    let start_position = query("start")
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(0);
+    let next = match query("next").map(get_spotify_id) {
+        Some(None) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "Invalid next track ID." }),
+            )
+        }
+        Some(next) => next,
+        None => None,
+    };
+
+    if let Err(error) = server.session().await {
+        error!("Failed to create session: {}", error);
+        return json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": error.to_string() }),
+        );
+    }
+
+    let (body_tx, body) = Body::channel();
+    let _ = requests.send(PlayRequest {
+        track,
+        start_position,
+        next,
+        body: body_tx,
+    });
+
+    let content_type = if server.player_config.passthrough {
+        "audio/ogg"
+    } else {
+        "application/octet-stream"
+    };
+
+    Response::builder()
+        .header("content-type", content_type)
+        .body(body)
+        .unwrap()
+}
+
+// Only listens on localhost, as there's no authentication
+// tokio::select! expands to poll_fn, which is newer than the MSRV
+#[allow(clippy::incompatible_msrv)]
+pub async fn serve_player(
+    port: u16,
+    last_credentials: Option<Credentials>,
+    cache: Option<Cache>,
+    player_config: PlayerConfig,
+    session_config: SessionConfig,
+) -> Result<(), Error> {
+    let credentials = match last_credentials {
+        Some(credentials) => credentials,
+        None => return Err(Error::new(ExitCode::AuthFailed, "Missing credentials")),
+    };
+
+    let server = Arc::new(PlayerServer {
+        credentials,
+        cache,
+        player_config,
+        session_config,
+        session: tokio::sync::Mutex::new(None),
+    });
+
+    // connect ahead of the first request
+    if let Err(error) = server.session().await {
+        let code = session_error_code(&error);
+        let error = format!("Failed to create session: {}", error);
+        return Err(Error::new(code, error));
+    }
+
+    let (requests, requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(play_requests(server.clone(), requests_rx));
+
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let make_service = make_service_fn(move |_| {
+        let (server, requests) = (server.clone(), requests.clone());
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                let (server, requests) = (server.clone(), requests.clone());
+                async move {
+                    let response = player_server_response(request, server, requests).await;
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+
+    let server = match hyper::Server::try_bind(&address) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            let error = format!("Could not start the player server on port {}: {}", port, e);
+            return Err(Error::new(ExitCode::Error, error));
+        }
+    };
+
+    info!("Player server listening on {}", server.local_addr());
+
+    tokio::select! {
+        result = server => result.map_err(|e| {
+            Error::new(ExitCode::Error, format!("Player server failed: {}", e))
+        }),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminated() => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_client_ids() {
+        let mut client_ids = ClientIds::default();
+        assert!(client_ids.add("lyrics:user-read-private,user-read-email"));
+        assert!(client_ids.add("playlists:playlist-read-private"));
+        assert!(!client_ids.add("invalid:"));
+        assert!(!client_ids.add(":user-read-private"));
+
+        assert_eq!(client_ids.for_scopes("user-read-email"), Some("lyrics"));
+        assert_eq!(
+            client_ids.for_scopes("playlist-read-private"),
+            Some("playlists")
+        );
+        assert_eq!(client_ids.for_scopes("user-follow-read"), None);
+
+        client_ids.fallback("default");
+        assert_eq!(client_ids.for_scopes("user-follow-read"), Some("default"));
+        assert_eq!(client_ids.for_scopes("user-read-email"), Some("lyrics"));
+
+        let mut client_ids = ClientIds::default();
+        assert!(client_ids.add("mine"));
+        client_ids.fallback("default");
+        assert_eq!(client_ids.for_scopes("user-follow-read"), Some("mine"));
+
+        let mut client_ids = ClientIds::default();
+        client_ids.fallback("default");
+        assert_eq!(client_ids.for_scopes("user-follow-read"), None);
+    }
+
+    // the delay before the next attempt, without the jitter
+    fn next_delay(reconnect: &mut Reconnect) -> Option<Duration> {
+        let delay = reconnect.next_delay()?;
+        let expected = reconnect
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(reconnect.failures - 1))
+            .min(reconnect.max_delay);
+        assert!(delay <= expected && delay >= expected / 2);
+        Some(expected)
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let second = Duration::from_secs(1);
+        let mut reconnect = Reconnect::new(ReconnectPolicy::Forever, second, 5 * second);
+
+        let delays: Vec<_> = (0..5).filter_map(|_| next_delay(&mut reconnect)).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+
+        reconnect.connected();
+        assert_eq!(next_delay(&mut reconnect), Some(second));
+
+        // doesn't overflow however often it failed
+        for _ in 0..100 {
+            assert_eq!(
+                next_delay(&mut reconnect).map(|delay| delay <= 5 * second),
+                Some(true)
+            );
+        }
+
+        let mut reconnect = Reconnect::new(
+            ReconnectPolicy::Forever,
+            Reconnect::MAX_DELAY,
+            Reconnect::MAX_DELAY,
+        );
+        for _ in 0..100 {
+            assert_eq!(next_delay(&mut reconnect), Some(Reconnect::MAX_DELAY));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        let second = Duration::from_secs(1);
+
+        let mut reconnect = Reconnect::new(ReconnectPolicy::Never, second, second);
+        assert_eq!(reconnect.next_delay(), None);
+
+        let mut reconnect = Reconnect::new(ReconnectPolicy::Limited, second, second);
+        for _ in 0..=Reconnect::RATE_LIMIT {
+            assert!(reconnect.next_delay().is_some());
+        }
+        assert_eq!(reconnect.next_delay(), None);
+
+        reconnect.reset();
+        assert!(reconnect.next_delay().is_some());
+    }
+
+    #[test]
+    fn test_parse_schedule_field() {
+        let mask = |values: &[u32]| values.iter().fold(0u64, |mask, v| mask | 1 << v);
+
+        assert_eq!(
+            parse_schedule_field("*", 0, 5, &[]),
+            Some(mask(&[0, 1, 2, 3, 4, 5]))
+        );
+        assert_eq!(
+            parse_schedule_field("*/15", 0, 59, &[]),
+            Some(mask(&[0, 15, 30, 45]))
+        );
+        assert_eq!(
+            parse_schedule_field("1-5", 0, 59, &[]),
+            Some(mask(&[1, 2, 3, 4, 5]))
+        );
+        assert_eq!(
+            parse_schedule_field("10-20/5", 0, 59, &[]),
+            Some(mask(&[10, 15, 20]))
+        );
+        assert_eq!(
+            parse_schedule_field("1,3,5", 0, 59, &[]),
+            Some(mask(&[1, 3, 5]))
+        );
+        assert_eq!(
+            parse_schedule_field("mon,WED,fri", 0, 7, &WEEKDAYS),
+            Some(mask(&[1, 3, 5]))
+        );
+        assert_eq!(
+            parse_schedule_field("jan-mar", 1, 12, &MONTHS),
+            Some(mask(&[1, 2, 3]))
+        );
+
+        assert_eq!(parse_schedule_field("", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("60", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("0", 1, 31, &[]), None);
+        assert_eq!(parse_schedule_field("5-1", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("*/0", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("*/x", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("1-", 0, 59, &[]), None);
+        assert_eq!(parse_schedule_field("foo", 1, 12, &MONTHS), None);
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(Schedule::from_str("7:30"), Schedule::from_str("30 7 * * *"));
+        assert_eq!(
+            Schedule::from_str("7:30 mon-fri"),
+            Schedule::from_str("30 7 * * 1-5")
+        );
+        assert_eq!(
+            Schedule::from_str("0 8 * * 7"),
+            Schedule::from_str("0 8 * * sun")
+        );
+
+        for invalid in &[
+            "",
+            "7",
+            "7:60",
+            "24:00",
+            "7:30 someday",
+            "* * * *",
+            "* * * * * *",
+            "0 8 32 * *",
+            "0 8 * 13 *",
+            "0 8 * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert_eq!(Schedule::from_str(invalid), Err(()), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_schedule_next_after() {
+        let time = |day, hour, minute| Local.ymd(2024, 1, day).and_hms(hour, minute, 0);
+        let next = |schedule: &str, after| Schedule::from_str(schedule).unwrap().next_after(after);
+
+        // Friday after the alarm went off, Monday's is next
+        assert_eq!(next("7:30 mon-fri", time(5, 8, 0)), Some(time(8, 7, 30)));
+        assert_eq!(next("30 7 * * 1-5", time(5, 8, 0)), Some(time(8, 7, 30)));
+        // not at the same minute again
+        assert_eq!(next("7:30", time(5, 7, 30)), Some(time(6, 7, 30)));
+        assert_eq!(next("*/15 * * * *", time(5, 10, 7)), Some(time(5, 10, 15)));
+        assert_eq!(
+            next("0 12-14/2 * * *", time(5, 12, 0)),
+            Some(time(5, 14, 0))
+        );
+        // either the day of the month or the weekday, like cron, Jan 1st is a Monday
+        assert_eq!(next("0 8 1 * mon", time(1, 9, 0)), Some(time(8, 8, 0)));
+        assert_eq!(
+            next("0 8 1 * *", time(1, 9, 0)),
+            Some(Local.ymd(2024, 2, 1).and_hms(8, 0, 0))
+        );
+        // there is no February 30th
+        assert_eq!(next("0 0 30 2 *", time(1, 0, 0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_listener_forward() {
+        let (body, received) = Body::channel();
+        let received = tokio::spawn(hyper::body::to_bytes(received));
+
+        let mut listener = Listener {
+            uri: "spotify:track:b".to_string(),
+            body,
+            started: false,
+        };
+        for data in [
+            ChannelData::Audio(vec![1]),
+            ChannelData::TrackBoundary("spotify:track:a".to_string()),
+            ChannelData::Audio(vec![2]),
+            ChannelData::TrackBoundary("spotify:track:b".to_string()),
+            ChannelData::Audio(vec![3]),
+            ChannelData::Audio(vec![4]),
+        ] {
+            assert!(listener.forward(data).await);
+        }
+        drop(listener);
+
+        // only the audio after the boundary of its track
+        assert_eq!(&received.await.unwrap().unwrap()[..], [3, 4]);
+    }
+}
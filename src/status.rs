@@ -0,0 +1,618 @@
+//! Status endpoint, for monitoring systems to probe.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::core::cache::Cache;
+use crate::core::data_usage::DataUsage;
+use crate::core::session::Session;
+use crate::core::spotify_id::SpotifyId;
+use crate::playback::player::{
+    BufferFill, NormalisationData, Player, PlayerEvent, SinkStats, StreamFormat, UnavailableReason,
+};
+use crate::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use crate::spotty::{PlaybackStats, LMS};
+use crate::web_api::Lyrics;
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Connection {
+    Disconnected,
+    Connecting,
+    Connected,
+    Parked,
+}
+
+impl Serialize for Connection {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            Connection::Disconnected => "disconnected",
+            Connection::Connecting => "connecting",
+            Connection::Connected => "connected",
+            Connection::Parked => "parked",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Playback {
+    Stopped,
+    Loading,
+    Playing,
+    Paused,
+}
+
+impl Serialize for Playback {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            Playback::Stopped => "stopped",
+            Playback::Loading => "loading",
+            Playback::Playing => "playing",
+            Playback::Paused => "paused",
+        })
+    }
+}
+
+pub struct Status {
+    connection: Connection,
+    username: Option<String>,
+    // for its data usage
+    session: Option<Session>,
+    track: Option<SpotifyId>,
+    playback: Playback,
+    position_ms: u32,
+    duration_ms: u32,
+    format: Option<StreamFormat>,
+    normalisation: Option<NormalisationData>,
+    unavailable: Option<(SpotifyId, UnavailableReason)>,
+    shuffle: bool,
+    repeat: bool,
+    stats: PlaybackStats,
+    position_updated: Instant,
+    // how fast the position advances while playing
+    speed: f64,
+    buffer_fill: Option<BufferFill>,
+    sink_stats: Option<SinkStats>,
+    // of the current track, and the line last reported, see `Lyrics`
+    lyrics: Option<Lyrics>,
+    lyrics_line: Option<usize>,
+    // as of the last check for `OnPlay`
+    squeezebox_powered: Option<bool>,
+    // the counters at the last `buffer_debug()`, as of `reported`
+    reported: (Instant, u32, u32, DataUsage),
+    started: Instant,
+}
+
+impl Status {
+    pub fn new() -> SharedStatus {
+        Arc::new(Mutex::new(Status {
+            connection: Connection::Disconnected,
+            username: None,
+            session: None,
+            track: None,
+            playback: Playback::Stopped,
+            position_ms: 0,
+            duration_ms: 0,
+            format: None,
+            normalisation: None,
+            unavailable: None,
+            shuffle: false,
+            repeat: false,
+            stats: PlaybackStats::default(),
+            position_updated: Instant::now(),
+            speed: 1.0,
+            buffer_fill: None,
+            sink_stats: None,
+            lyrics: None,
+            lyrics_line: None,
+            squeezebox_powered: None,
+            reported: (Instant::now(), 0, 0, DataUsage::default()),
+            started: Instant::now(),
+        }))
+    }
+
+    pub fn connecting(&mut self) {
+        self.connection = Connection::Connecting;
+    }
+
+    pub fn connected(&mut self, session: &Session, player: &Player) {
+        self.connection = Connection::Connected;
+        self.username = Some(session.username());
+        self.session = Some(session.clone());
+        self.buffer_fill = Some(player.buffer_fill());
+        self.sink_stats = Some(player.sink_stats());
+        // a new player and session count from zero
+        self.reported = (Instant::now(), 0, 0, DataUsage::default());
+    }
+
+    pub fn disconnected(&mut self) {
+        self.connection = Connection::Disconnected;
+        self.session = None;
+        self.track = None;
+        self.format = None;
+        self.unavailable = None;
+        self.lyrics = None;
+        self.playback = Playback::Stopped;
+        self.buffer_fill = None;
+        self.sink_stats = None;
+    }
+
+    // disconnected after being idle, until the next play command
+    pub fn parked(&mut self) {
+        self.disconnected();
+        self.connection = Connection::Parked;
+    }
+
+    pub fn stats(&self) -> &PlaybackStats {
+        &self.stats
+    }
+
+    pub fn squeezebox_powered(&mut self, powered: Option<bool>) {
+        self.squeezebox_powered = powered;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback == Playback::Playing
+    }
+
+    pub fn track(&self) -> Option<SpotifyId> {
+        self.track
+    }
+
+    /// The current track with its position and duration in ms, unless stopped.
+    pub fn track_position(&self) -> Option<(SpotifyId, u32, u32)> {
+        let track_id = self.track.filter(|_| self.playback != Playback::Stopped)?;
+        Some((track_id, self.position_ms() as u32, self.duration_ms))
+    }
+
+    pub fn player_event(&mut self, event: &PlayerEvent) {
+        self.stats.player_event(event);
+
+        match *event {
+            PlayerEvent::FormatChanged { format, .. } => {
+                self.format = Some(format);
+                return;
+            }
+            PlayerEvent::Normalisation { data, .. } => {
+                self.normalisation = Some(data);
+                return;
+            }
+            PlayerEvent::PlaybackModeChanged { shuffle, repeat } => {
+                self.shuffle = shuffle;
+                self.repeat = repeat;
+                return;
+            }
+            PlayerEvent::Unavailable {
+                track_id, reason, ..
+            } => {
+                self.unavailable = Some((track_id, reason));
+                return;
+            }
+            _ => (),
+        }
+
+        let (track_id, playback, position_ms, duration_ms, speed) = match *event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                speed,
+                ..
+            } => (track_id, Playback::Playing, position_ms, duration_ms, speed),
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => (track_id, Playback::Paused, position_ms, duration_ms, 1.0),
+            PlayerEvent::Loading {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.format = None;
+                self.normalisation = None;
+                (
+                    track_id,
+                    Playback::Loading,
+                    position_ms,
+                    self.duration_ms,
+                    1.0,
+                )
+            }
+            PlayerEvent::Stopped { track_id, .. } => {
+                self.format = None;
+                self.normalisation = None;
+                (track_id, Playback::Stopped, 0, 0, 1.0)
+            }
+            _ => return,
+        };
+
+        self.track = Some(track_id);
+        self.playback = playback;
+        self.position_ms = position_ms;
+        self.duration_ms = duration_ms;
+        self.position_updated = Instant::now();
+        self.speed = speed;
+    }
+
+    /// The lyrics for `track_id`, unless it isn't the current track any more. Returns whether
+    /// they were taken.
+    pub fn set_lyrics(&mut self, track_id: SpotifyId, lyrics: Option<Lyrics>) -> bool {
+        if self.track != Some(track_id) {
+            return false;
+        }
+        self.lyrics = lyrics;
+        self.lyrics_line = None;
+        true
+    }
+
+    /// The line of synced lyrics sung now, if it changed since the last call.
+    pub fn next_lyrics_line(&mut self) -> Option<(SpotifyId, usize, String)> {
+        let lyrics = self
+            .lyrics
+            .as_ref()
+            .filter(|lyrics| Some(lyrics.track_id) == self.track)?;
+        let line = lyrics.line_at(self.position_ms() as u32)?;
+        if self.lyrics_line == Some(line) {
+            return None;
+        }
+
+        self.lyrics_line = Some(line);
+        Some((lyrics.track_id, line, lyrics.lines[line].1.clone()))
+    }
+
+    fn lyrics_json(&self) -> Option<Value> {
+        self.lyrics
+            .as_ref()
+            .filter(|lyrics| Some(lyrics.track_id) == self.track)
+            .map(Lyrics::to_json)
+    }
+
+    fn position_ms(&self) -> u64 {
+        let mut position_ms = self.position_ms as u64;
+        if self.playback == Playback::Playing {
+            position_ms += (self.position_updated.elapsed().as_millis() as f64 * self.speed) as u64;
+        }
+        position_ms.min(self.duration_ms as u64)
+    }
+
+    fn data_usage_json(&self) -> Option<Value> {
+        let session = self.session.as_ref()?;
+        let usage = session.data_usage();
+        Some(json!({
+            "audio": usage.audio,
+            "metadata": usage.metadata,
+            "thisMonth": session.monthly_data_usage(),
+            "dataCap": session.config().data_cap,
+            "dataCapExceeded": session.data_cap_exceeded(),
+        }))
+    }
+
+    /// The data usage in the Prometheus text format. The counters are those of the current
+    /// session, so they start from zero after reconnecting.
+    fn metrics(&self) -> String {
+        let session = match self.session {
+            Some(ref session) => session,
+            None => return String::new(),
+        };
+        let usage = session.data_usage();
+
+        let mut metrics = format!(
+            "# HELP spotty_received_bytes_total Bytes received from Spotify by the session.\n\
+             # TYPE spotty_received_bytes_total counter\n\
+             spotty_received_bytes_total{{kind=\"audio\"}} {}\n\
+             spotty_received_bytes_total{{kind=\"metadata\"}} {}\n\
+             # HELP spotty_monthly_received_bytes Bytes received this calendar month (UTC).\n\
+             # TYPE spotty_monthly_received_bytes gauge\n\
+             spotty_monthly_received_bytes {}\n",
+            usage.audio,
+            usage.metadata,
+            session.monthly_data_usage()
+        );
+        if let Some(cap) = session.config().data_cap {
+            metrics += &format!(
+                "# HELP spotty_data_cap_bytes The soft monthly limit set by --data-cap.\n\
+                 # TYPE spotty_data_cap_bytes gauge\n\
+                 spotty_data_cap_bytes {}\n\
+                 # HELP spotty_data_cap_exceeded Whether the lowest bitrate is used because of it.\n\
+                 # TYPE spotty_data_cap_exceeded gauge\n\
+                 spotty_data_cap_exceeded {}\n",
+                cap,
+                session.data_cap_exceeded() as u8
+            );
+        }
+        metrics
+    }
+
+    fn to_json(&self) -> Value {
+        let position_ms = self.position_ms();
+
+        let format = self.format.map(|format| {
+            json!({
+                "codec": format.codec(),
+                "bitrate": format.bitrate(),
+                "sampleRate": format.sample_rate(),
+                "channels": format.channels(),
+                "passthrough": format.passthrough,
+                "description": format.to_string(),
+            })
+        });
+
+        let normalisation = self.normalisation.map(|data| {
+            json!({
+                "trackGainDb": data.track_gain_db,
+                "trackPeak": data.track_peak,
+                "albumGainDb": data.album_gain_db,
+                "albumPeak": data.album_peak,
+            })
+        });
+
+        let track = self.track.map(|track_id| {
+            json!({
+                "uri": track_id.to_uri().ok(),
+                "state": self.playback,
+                "positionMs": position_ms,
+                "durationMs": self.duration_ms,
+                "format": format,
+                "normalisation": normalisation,
+            })
+        });
+
+        // the most recent track which couldn't be played, and why
+        let unavailable = self.unavailable.map(|(track_id, reason)| {
+            json!({
+                "uri": track_id.to_uri().ok(),
+                "reason": reason.code(),
+                "message": reason.to_string(),
+            })
+        });
+
+        json!({
+            "status": self.connection,
+            "username": self.username,
+            "uptime": self.started.elapsed().as_secs(),
+            "track": track,
+            "lastUnavailable": unavailable,
+            "shuffle": self.shuffle,
+            "repeat": self.repeat,
+            "bufferMs": self.buffer_fill.as_ref().map(BufferFill::ms),
+            "squeezeboxPower": self.squeezebox_powered,
+            "dataUsage": self.data_usage_json(),
+            "stats": self.stats.to_json(),
+        })
+    }
+
+    /// The buffer fill, and how fast the sink took audio and the network delivered it since the
+    /// last call, for `--buffer-debug`. `data_usage` is that of the current session.
+    pub fn buffer_debug(&mut self, data_usage: DataUsage) -> Value {
+        let (reported, blocked_us, samples, usage) = self.reported;
+        let elapsed = reported.elapsed().as_secs_f64().max(0.001);
+
+        let sink = self.sink_stats.as_ref().map(|stats| {
+            let blocked_us = stats.blocked_us().wrapping_sub(blocked_us);
+            let samples = stats.samples().wrapping_sub(samples);
+            let written_ms = samples as f64 * 1000.0 / (NUM_CHANNELS as f64 * SAMPLE_RATE as f64);
+            json!({
+                "blockedPercent": (blocked_us as f64 / 10_000.0 / elapsed * 10.0).round() / 10.0,
+                "writtenMsPerSecond": (written_ms / elapsed).round(),
+            })
+        });
+
+        let received = data_usage.total().saturating_sub(usage.total());
+        let (blocked_us, samples) = match self.sink_stats {
+            Some(ref stats) => (stats.blocked_us(), stats.samples()),
+            None => (0, 0),
+        };
+        self.reported = (Instant::now(), blocked_us, samples, data_usage);
+
+        json!({
+            "playback": self.playback,
+            "bufferMs": self.buffer_fill.as_ref().map(BufferFill::ms),
+            "sink": sink,
+            "networkKbps": (received as f64 * 8.0 / 1000.0 / elapsed).round(),
+        })
+    }
+}
+
+// How long the size of the audio cache is reused for, as counting it walks the whole folder.
+const CACHE_USAGE_TTL: Duration = Duration::from_secs(60);
+
+// when the audio cache was last counted, and its number of files and size if there is one
+type CountedUsage = Option<(Instant, Option<(usize, u64)>)>;
+
+/// The number of files and size of the audio cache, counted at most once per `CACHE_USAGE_TTL`.
+#[derive(Clone)]
+struct CacheUsage {
+    cache: Cache,
+    counted: Arc<Mutex<CountedUsage>>,
+}
+
+impl CacheUsage {
+    fn new(cache: Cache) -> Self {
+        Self {
+            cache,
+            counted: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn get(&self) -> Option<(usize, u64)> {
+        if let Some((at, usage)) = *self.counted.lock().unwrap() {
+            if at.elapsed() < CACHE_USAGE_TTL {
+                return usage;
+            }
+        }
+
+        let cache = self.cache.clone();
+        let usage = tokio::task::spawn_blocking(move || cache.audio_cache_size())
+            .await
+            .unwrap_or_default();
+        *self.counted.lock().unwrap() = Some((Instant::now(), usage));
+        usage
+    }
+}
+
+// How long the state of the connection to LMS is reused for, so probes don't each query LMS.
+const LMS_STATE_TTL: Duration = Duration::from_secs(10);
+
+// when LMS was last checked, whether it could be reached and the sync group if asked for
+type CheckedLms = Option<(Instant, bool, Option<Vec<String>>)>;
+
+/// Whether LMS can be reached, and the players the Squeezebox is synced with, checked at most
+/// once per `LMS_STATE_TTL`.
+#[derive(Clone)]
+struct LmsState {
+    lms: LMS,
+    checked: Arc<Mutex<CheckedLms>>,
+}
+
+impl LmsState {
+    fn new(lms: LMS) -> Self {
+        Self {
+            lms,
+            checked: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn get(&self) -> (bool, Option<Vec<String>>) {
+        if let Some((at, connected, ref sync_group)) = *self.checked.lock().unwrap() {
+            if at.elapsed() < LMS_STATE_TTL {
+                return (connected, sync_group.clone());
+            }
+        }
+
+        let connected = self.lms.check_connection().await.is_ok();
+        let sync_group = if self.lms.group_volume && connected {
+            Some(self.lms.update_sync_group().await)
+        } else {
+            None
+        };
+        *self.checked.lock().unwrap() = Some((Instant::now(), connected, sync_group.clone()));
+        (connected, sync_group)
+    }
+}
+
+async fn status_response(
+    status: &SharedStatus,
+    lms: &LmsState,
+    cache_usage: Option<&CacheUsage>,
+) -> Response<Body> {
+    // don't hold the lock while talking to LMS
+    let (connected, session) = {
+        let status = status.lock().unwrap();
+        (status.connection == Connection::Connected, status.to_json())
+    };
+
+    let cache_usage = match cache_usage {
+        Some(cache_usage) => cache_usage.get().await,
+        None => None,
+    }
+    .map(|(files, size)| {
+        json!({
+            "files": files,
+            "size": size,
+        })
+    });
+
+    let (lms_connected, sync_group) = if lms.lms.is_configured() {
+        let (connected, sync_group) = lms.get().await;
+        (Some(connected), sync_group)
+    } else {
+        (None, None)
+    };
+
+    let body = json!({
+        "session": session,
+        "cache": cache_usage,
+        "lms": {
+            "configured": lms.lms.is_configured(),
+            "connected": lms_connected,
+            "syncGroup": sync_group,
+        },
+    });
+
+    let code = if connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn metrics_response(status: &SharedStatus) -> Response<Body> {
+    let metrics = status.lock().unwrap().metrics();
+
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(metrics))
+        .unwrap()
+}
+
+fn lyrics_response(status: &SharedStatus) -> Response<Body> {
+    let (code, body) = match status.lock().unwrap().lyrics_json() {
+        Some(lyrics) => (StatusCode::OK, lyrics),
+        None => (StatusCode::NOT_FOUND, json!({ "error": "No lyrics" })),
+    };
+
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// Answer /lyrics with those of the current track, /metrics with the data usage for Prometheus,
+// every other request with the current status, as JSON. Responds with 503 while there's no
+// session, so simple HTTP probes can tell whether spotty is usable.
+pub fn serve_status(
+    address: SocketAddr,
+    status: SharedStatus,
+    lms: LMS,
+    cache: Option<Cache>,
+) -> Result<(), hyper::Error> {
+    let lms = LmsState::new(lms);
+    let cache = cache.map(CacheUsage::new);
+
+    let make_service = make_service_fn(move |_| {
+        let (status, lms, cache) = (status.clone(), lms.clone(), cache.clone());
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                let (status, lms, cache) = (status.clone(), lms.clone(), cache.clone());
+                async move {
+                    let response = match request.uri().path() {
+                        "/lyrics" => lyrics_response(&status),
+                        "/metrics" => metrics_response(&status),
+                        _ => status_response(&status, &lms, cache.as_ref()).await,
+                    };
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&address)?.serve(make_service);
+
+    info!("Status endpoint listening on {}", server.local_addr());
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("Status endpoint failed: {}", e);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,570 @@
+//! Web API and spclient requests on behalf of the logged in user.
+
+use hyper::{Method, StatusCode};
+#[allow(unused)]
+use log::{debug, error, info, warn};
+
+use futures_util::future;
+use hyper::body::Bytes;
+use protobuf::Message;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::control::ControlCommand;
+use crate::core::http;
+use crate::core::keymaster;
+use crate::core::mercury::MercuryError;
+use crate::core::session::Session;
+use crate::core::spotify_id::SpotifyId;
+use crate::metadata::{Album, Artist, Metadata, Track};
+use crate::protocol::canvaz::{
+    EntityCanvazRequest, EntityCanvazRequest_Entity, EntityCanvazResponse,
+};
+use crate::spotty::{COVER_URL, LMS, SCOPES};
+use crate::status::SharedStatus;
+
+// renew tokens this long before they expire
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(60);
+
+const LYRICS_URL: &str = "https://spclient.wg.spotify.com/color-lyrics/v2/track/";
+
+const CANVAS_URL: &str = "https://spclient.wg.spotify.com/canvaz-cache/v0/canvases";
+
+const SAVED_TRACKS_URL: &str = "https://api.spotify.com/v1/me/tracks";
+
+const FOLLOWED_ARTISTS_URL: &str = "https://api.spotify.com/v1/me/following?type=artist";
+
+const USER_PLAYLISTS_URL: &str = "https://api.spotify.com/v1/me/playlists?limit=50";
+
+const PLAYLIST_URL: &str = "https://api.spotify.com/v1/playlists/";
+
+const SEARCH_URL: &str = "https://api.spotify.com/v1/search";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebApiError {
+    #[error("failed to get an access token")]
+    Token,
+    #[error(transparent)]
+    Http(#[from] http::HttpError),
+    #[error("invalid response")]
+    InvalidResponse,
+    #[error("failed to get metadata")]
+    Metadata,
+}
+
+/// Sends requests with a keymaster token for `SCOPES`, which is renewed shortly before it
+/// expires. Clones share the token.
+#[derive(Clone)]
+pub struct WebApi {
+    session: Session,
+    client_id: String,
+    token: Arc<tokio::sync::Mutex<Option<(String, Instant)>>>,
+}
+
+impl WebApi {
+    pub fn new(session: Session, client_id: &str) -> Self {
+        Self {
+            session,
+            client_id: client_id.to_string(),
+            token: Arc::default(),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    async fn token(&self) -> Result<String, WebApiError> {
+        let mut token = self.token.lock().await;
+        if let Some((access_token, renew_at)) = token.as_ref() {
+            if Instant::now() < *renew_at {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let new_token = keymaster::get_token(&self.session, &self.client_id, SCOPES)
+            .await
+            .map_err(|_| WebApiError::Token)?;
+        let expires_in = Duration::from_secs(new_token.expires_in as u64);
+        let renew_at = Instant::now() + expires_in.saturating_sub(TOKEN_RENEWAL_MARGIN);
+        *token = Some((new_token.access_token.clone(), renew_at));
+
+        Ok(new_token.access_token)
+    }
+
+    /// Sends `body` as JSON to `url`, and returns the response, `Null` if it's empty.
+    pub async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<Value>,
+    ) -> Result<Value, WebApiError> {
+        let response = match body {
+            Some(body) => {
+                let body = body.to_string().into();
+                self.send(method, url, Some("application/json"), body)
+                    .await?
+            }
+            None => self.send(method, url, None, Bytes::new()).await?,
+        };
+
+        if response.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&response).map_err(|_| WebApiError::InvalidResponse)
+    }
+
+    /// Sends `body` of `content_type` to `url`, and returns the response as is.
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+    ) -> Result<Bytes, WebApiError> {
+        let authorization = format!("Bearer {}", self.token().await?);
+        let mut headers = vec![
+            ("authorization", authorization.as_str()),
+            ("app-platform", "WebPlayer"),
+        ];
+        if let Some(content_type) = content_type {
+            headers.push(("content-type", content_type));
+        }
+
+        Ok(http::request(method, url, &headers, body, self.session.config()).await?)
+    }
+}
+
+// Save `track_id` to the library, or with `artists` follow its artists, and undo it unless `add`.
+// Returns the URIs of what was saved or followed.
+async fn update_library(
+    web_api: &WebApi,
+    track_id: SpotifyId,
+    artists: bool,
+    add: bool,
+) -> Result<Vec<String>, WebApiError> {
+    let ids = if artists {
+        Track::get(web_api.session(), track_id)
+            .await
+            .map_err(|_| WebApiError::Metadata)?
+            .artists
+    } else {
+        vec![track_id]
+    };
+
+    let base62 = ids
+        .iter()
+        .filter_map(|id| id.to_base62().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let url = if artists {
+        format!("{}&ids={}", FOLLOWED_ARTISTS_URL, base62)
+    } else {
+        format!("{}?ids={}", SAVED_TRACKS_URL, base62)
+    };
+    let method = if add { Method::PUT } else { Method::DELETE };
+    web_api.request(method, &url, None).await?;
+
+    let uri = |id: &SpotifyId| match id.to_base62() {
+        Ok(id) if artists => format!("spotify:artist:{}", id),
+        _ => id.to_uri().unwrap_or_default(),
+    };
+    Ok(ids.iter().map(uri).collect())
+}
+
+// The playlists the user can add tracks to, their own and collaborative ones
+async fn editable_playlists(web_api: &WebApi) -> Result<Vec<Value>, WebApiError> {
+    let username = web_api.session().username();
+    let mut playlists = Vec::new();
+
+    let mut url = Some(USER_PLAYLISTS_URL.to_string());
+    while let Some(page_url) = url {
+        let page = web_api.request(Method::GET, &page_url, None).await?;
+        let items = page["items"]
+            .as_array()
+            .ok_or(WebApiError::InvalidResponse)?;
+
+        for playlist in items {
+            if playlist["owner"]["id"].as_str() == Some(username.as_str())
+                || playlist["collaborative"].as_bool() == Some(true)
+            {
+                playlists.push(json!({
+                    "uri": playlist["uri"],
+                    "name": playlist["name"],
+                    "tracks": playlist["tracks"]["total"],
+                }));
+            }
+        }
+
+        url = page["next"].as_str().map(String::from);
+    }
+
+    Ok(playlists)
+}
+
+// Append `track_id` to `playlist`, and return its URI
+async fn add_to_playlist(
+    web_api: &WebApi,
+    playlist: SpotifyId,
+    track_id: SpotifyId,
+) -> Result<String, WebApiError> {
+    let url = format!(
+        "{}{}/tracks",
+        PLAYLIST_URL,
+        playlist.to_base62().unwrap_or_default()
+    );
+    let uri = track_id.to_uri().unwrap_or_default();
+    web_api
+        .request(Method::POST, &url, Some(json!({ "uris": [uri] })))
+        .await?;
+
+    Ok(uri)
+}
+
+// Runs a command of `ControlRequest::run_library`. All but listing the playlists need the
+// current track.
+pub(crate) async fn library_command(
+    web_api: &WebApi,
+    command: ControlCommand,
+    track_id: Option<SpotifyId>,
+) -> Result<Value, WebApiError> {
+    let uris = match (command, track_id) {
+        (ControlCommand::Playlists, _) => {
+            let playlists = editable_playlists(web_api).await?;
+            return Ok(json!({ "playlists": playlists }));
+        }
+        (ControlCommand::SetLiked(like), Some(track_id)) => {
+            update_library(web_api, track_id, false, like).await?
+        }
+        (ControlCommand::SetFollowed(follow), Some(track_id)) => {
+            update_library(web_api, track_id, true, follow).await?
+        }
+        (ControlCommand::AddToPlaylist(playlist), Some(track_id)) => {
+            vec![add_to_playlist(web_api, playlist, track_id).await?]
+        }
+        _ => Vec::new(),
+    };
+
+    Ok(json!({ "ok": true, "uris": uris }))
+}
+
+// The URI of the best match of `kind` ("track", "album" etc.) for `query`, in the user's market
+pub(crate) async fn search_uri(
+    web_api: &WebApi,
+    kind: &str,
+    query: &str,
+) -> Result<Option<String>, WebApiError> {
+    let params = [
+        ("q", query),
+        ("type", kind),
+        ("limit", "1"),
+        ("market", "from_token"),
+    ];
+    let url =
+        Url::parse_with_params(SEARCH_URL, &params).map_err(|_| WebApiError::InvalidResponse)?;
+    let results = web_api.request(Method::GET, url.as_str(), None).await?;
+
+    Ok(results[format!("{}s", kind).as_str()]["items"][0]["uri"]
+        .as_str()
+        .map(String::from))
+}
+
+/// The URL of the short looping video Spotify shows with `track_id`, if it has one.
+async fn canvas_url(web_api: &WebApi, track_id: SpotifyId) -> Result<Option<String>, WebApiError> {
+    let mut entity = EntityCanvazRequest_Entity::new();
+    entity.set_entity_uri(track_id.to_uri().unwrap_or_default());
+    let mut request = EntityCanvazRequest::new();
+    request.mut_entities().push(entity);
+    let body = request
+        .write_to_bytes()
+        .map_err(|_| WebApiError::InvalidResponse)?;
+
+    let content_type = Some("application/x-protobuf");
+    let response = web_api
+        .send(Method::POST, CANVAS_URL, content_type, body.into())
+        .await?;
+    let response = EntityCanvazResponse::parse_from_bytes(&response)
+        .map_err(|_| WebApiError::InvalidResponse)?;
+
+    Ok(response
+        .get_canvases()
+        .iter()
+        .map(|canvas| canvas.get_url())
+        .find(|url| !url.is_empty())
+        .map(String::from))
+}
+
+/// The lyrics of a track, with the start of each line if they're synced.
+#[derive(Clone, Debug)]
+pub struct Lyrics {
+    pub(crate) track_id: SpotifyId,
+    pub(crate) synced: bool,
+    language: Option<String>,
+    provider: Option<String>,
+    pub(crate) lines: Vec<(u32, String)>,
+}
+
+impl Lyrics {
+    /// Returns `None` if there are no lyrics for the track.
+    pub async fn get(web_api: &WebApi, track_id: SpotifyId) -> Result<Option<Self>, WebApiError> {
+        let url = format!(
+            "{}{}?format=json&market=from_token",
+            LYRICS_URL,
+            track_id.to_base62().unwrap_or_default()
+        );
+        let response = match web_api.request(Method::GET, &url, None).await {
+            Ok(response) => response,
+            Err(WebApiError::Http(http::HttpError::Status(StatusCode::NOT_FOUND))) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let lyrics = &response["lyrics"];
+        let lines = lyrics["lines"]
+            .as_array()
+            .ok_or(WebApiError::InvalidResponse)?
+            .iter()
+            .map(|line| {
+                // the times are strings
+                let start_ms = line["startTimeMs"]
+                    .as_str()
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or_default();
+                let words = line["words"].as_str().unwrap_or_default();
+                (start_ms, words.to_string())
+            })
+            .collect();
+
+        Ok(Some(Self {
+            track_id,
+            synced: lyrics["syncType"].as_str() == Some("LINE_SYNCED"),
+            language: lyrics["language"].as_str().map(String::from),
+            provider: lyrics["provider"].as_str().map(String::from),
+            lines,
+        }))
+    }
+
+    /// The index of the line sung at `position_ms`, if the lyrics are synced.
+    pub(crate) fn line_at(&self, position_ms: u32) -> Option<usize> {
+        if !self.synced {
+            return None;
+        }
+        self.lines
+            .iter()
+            .rposition(|(start_ms, _)| *start_ms <= position_ms)
+    }
+
+    pub(crate) fn text(&self) -> String {
+        let lines: Vec<&str> = self.lines.iter().map(|(_, words)| words.as_str()).collect();
+        lines.join("\n")
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let lines: Vec<Value> = self
+            .lines
+            .iter()
+            .map(|(start_ms, words)| {
+                json!({
+                    "startMs": Some(start_ms).filter(|_| self.synced),
+                    "words": words,
+                })
+            })
+            .collect();
+
+        json!({
+            "uri": self.track_id.to_uri().ok(),
+            "synced": self.synced,
+            "language": self.language,
+            "provider": self.provider,
+            "lines": lines,
+        })
+    }
+}
+
+/// Gets the lyrics of `track_id` for the status and tells LMS, unless another track started
+/// meanwhile.
+pub async fn fetch_lyrics(web_api: WebApi, track_id: SpotifyId, status: SharedStatus, lms: LMS) {
+    let lyrics = match Lyrics::get(&web_api, track_id).await {
+        Ok(lyrics) => lyrics,
+        Err(e) => {
+            warn!(
+                "Unable to get the lyrics of <{}>: {}",
+                track_id.to_uri().unwrap_or_default(),
+                e
+            );
+            None
+        }
+    };
+
+    if status.lock().unwrap().set_lyrics(track_id, lyrics.clone()) {
+        lms.signal_lyrics(track_id, lyrics.as_ref()).await;
+    }
+}
+
+// how many colours the palette of a cover has at most
+const PALETTE_SIZE: usize = 5;
+
+// The dominant colours of a JPEG image as "#rrggbb", the most common first. Similar colours are
+// counted as one, by the upper 4 bits of each channel.
+fn color_palette(image: &[u8]) -> Option<Vec<String>> {
+    let mut decoder = jpeg_decoder::Decoder::new(image);
+    let pixels = decoder.decode().ok()?;
+    let channels = match decoder.info()?.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        jpeg_decoder::PixelFormat::L8 => 1,
+        _ => return None,
+    };
+
+    // the sum of each channel, and the number of pixels
+    let mut buckets: HashMap<u16, ([u32; 3], u32)> = HashMap::new();
+    for pixel in pixels.chunks_exact(channels) {
+        let rgb = match *pixel {
+            [r, g, b] => [r, g, b],
+            [l] => [l, l, l],
+            _ => continue,
+        };
+        let key = rgb
+            .iter()
+            .fold(0, |key, value| (key << 4) | u16::from(value >> 4));
+        let bucket = buckets.entry(key).or_default();
+        for (sum, value) in bucket.0.iter_mut().zip(&rgb) {
+            *sum += u32::from(*value);
+        }
+        bucket.1 += 1;
+    }
+
+    let mut buckets = buckets.values().collect::<Vec<_>>();
+    buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket.1));
+    let palette = buckets
+        .iter()
+        .take(PALETTE_SIZE)
+        .map(|(sums, pixels)| {
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                sums[0] / pixels,
+                sums[1] / pixels,
+                sums[2] / pixels
+            )
+        })
+        .collect();
+
+    Some(palette)
+}
+
+// Download a cover and get its palette, see `color_palette`
+async fn cover_palette(session: &Session, url: &str) -> Option<Vec<String>> {
+    let image = match http::get(url, session.config()).await {
+        Ok(image) => image,
+        Err(error) => {
+            warn!("Failed to get cover art from {}: {}", url, error);
+            return None;
+        }
+    };
+
+    // decoding takes a while, don't hold up the other tasks
+    tokio::task::spawn_blocking(move || color_palette(&image))
+        .await
+        .ok()
+        .flatten()
+}
+
+// The release date, label, genres and popularity of a track, its album and artists, which the
+// Connect protocol doesn't tell LMS. With `palette` the dominant colours of the album's cover.
+pub(crate) async fn track_details(
+    session: &Session,
+    track_id: SpotifyId,
+    palette: bool,
+) -> Result<Value, MercuryError> {
+    let track = Track::get(session, track_id).await?;
+    let album = Album::get(session, track.album).await?;
+    let artists = future::join_all(track.artists.iter().map(|id| Artist::get(session, *id))).await;
+
+    let palette = match album.covers.first() {
+        Some(cover) if palette => cover_palette(session, &format!("{}{}", COVER_URL, cover)).await,
+        _ => None,
+    };
+
+    let mut genres = album.genres.clone();
+    for artist in artists.iter().flatten() {
+        for genre in &artist.genres {
+            if !genres.contains(genre) {
+                genres.push(genre.clone());
+            }
+        }
+    }
+
+    let artists = artists
+        .into_iter()
+        .flatten()
+        .map(|artist| {
+            json!({
+                "uri": artist.id.to_uri().unwrap_or_default(),
+                "name": artist.name,
+                "popularity": artist.popularity,
+                "genres": artist.genres,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "popularity": track.popularity,
+        "explicit": track.explicit,
+        "genres": genres,
+        "album": {
+            "uri": album.id.to_uri().unwrap_or_default(),
+            "name": album.name,
+            "releaseDate": album.release_date.map(|date| date.to_string()),
+            "year": album.release_date.map(|date| date.year),
+            "label": album.label,
+            "popularity": album.popularity,
+            "genres": album.genres,
+            "palette": palette,
+        },
+        "artists": artists,
+    }))
+}
+
+// `web_api` adds the URL of the track's Canvas to the details, `palette` the colours of its cover
+pub async fn fetch_track_details(
+    session: Session,
+    web_api: Option<WebApi>,
+    palette: bool,
+    track_id: SpotifyId,
+    lms: LMS,
+) {
+    let canvas = async {
+        match web_api {
+            Some(ref web_api) => canvas_url(web_api, track_id).await,
+            None => Ok(None),
+        }
+    };
+    let details = track_details(&session, track_id, palette);
+    let (details, canvas) = future::join(details, canvas).await;
+
+    let mut details = match details {
+        Ok(details) => details,
+        Err(e) => {
+            warn!(
+                "Unable to get the details of <{}>: {:?}",
+                track_id.to_uri().unwrap_or_default(),
+                e
+            );
+            return;
+        }
+    };
+
+    match canvas {
+        Ok(canvas) => details["canvas"] = json!(canvas),
+        Err(e) => warn!(
+            "Unable to get the Canvas of <{}>: {}",
+            track_id.to_uri().unwrap_or_default(),
+            e
+        ),
+    }
+
+    lms.signal_track_details(track_id, details).await;
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use librespot::core::authentication::Credentials;
+use librespot::core::config::ConnectConfig;
+use librespot::core::mock_ap::MockAp;
+use librespot::playback::config::PlayerConfig;
+use librespot::protocol::keyexchange::ErrorCode;
+use librespot::runtime::{Builder, Runtime};
+use librespot::spotty::{ExitCode, Reconnect, ReconnectPolicy};
+
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+
+fn builder(ap: &MockAp) -> Builder {
+    let reconnect = Reconnect::new(
+        ReconnectPolicy::Forever,
+        Duration::from_millis(10),
+        Duration::from_millis(100),
+    );
+
+    Runtime::builder(
+        ap.session_config(),
+        PlayerConfig::default(),
+        ConnectConfig::default(),
+    )
+    .credentials(Some(Credentials::with_password("test", "test")))
+    .reconnect(reconnect)
+}
+
+async fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    timeout(Duration::from_secs(10), async {
+        while !condition() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Timed out waiting for {}", what));
+}
+
+fn subscribed(ap: &MockAp) -> bool {
+    let requests = ap.requests();
+    requests.iter().any(|uri| uri == "hm://remote/user/test/")
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    let ap = MockAp::start().await.unwrap();
+    let runtime = builder(&ap).build();
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let test = async {
+        wait_for("the login", || ap.logins() == 1 && subscribed(&ap)).await;
+
+        ap.disconnect();
+        wait_for("the reconnect", || ap.logins() == 2).await;
+
+        let _ = stop.send(());
+    };
+    let shutdown = async {
+        let _ = stopped.await;
+    };
+
+    let (result, _) = tokio::join!(runtime.run_until(shutdown), test);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn test_login_failure() {
+    let ap = MockAp::start().await.unwrap();
+    ap.reject_logins(Some(ErrorCode::BadCredentials));
+
+    let error = builder(&ap).build().run().await.unwrap_err();
+    assert_eq!(error.code, ExitCode::AuthFailed);
+    assert_eq!(error.message, "Login failed with reason: Bad credentials");
+    assert_eq!(ap.logins(), 0);
+}